@@ -0,0 +1,405 @@
+use super::{ArchiveState, ContentStore};
+
+use abst_fs::{self as fs, AbstPath, Endpoint, Format};
+use fs_vcs::{Commit, CommitID, Delta, DeltaNode, FSNode, FSTree};
+use hasher::Hash;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+
+/// Whole state of an endpoint, packed in a single file to move it to another
+/// archive. The content is stored once per hash, so duplicated files take no
+/// extra space
+#[derive(Serialize, Deserialize)]
+struct EndpointExport {
+    /// Node of the endpoint's directory
+    node: FSNode,
+    /// Commits made at the endpoint or under it, from the oldest to the most
+    /// recent, with their endpoint relative to the exported one
+    commits: Vec<Commit>,
+    /// Content of the files, by hash
+    files: Vec<(Hash, Vec<u8>)>,
+    /// Endpoints of the symlinks, by hash
+    symlinks: Vec<(Hash, Endpoint)>,
+}
+
+/// Pack the tree, the commits and the content of the endpoint in the file at
+/// `file`. The content is checked against the stored tree while being read
+pub fn export(archive_root: &AbstPath, endpoint: &AbstPath, file: &AbstPath) -> Result<()> {
+    let state = ArchiveState::load(archive_root).context("failed to load archive's state")?;
+    let node = match (endpoint.is_empty(), state.archive_tree.get_at(endpoint)) {
+        (false, Some(node @ FSNode::Dir(_, _, _))) => node.clone(),
+        _ => anyhow::bail!("endpoint [{endpoint}] is not a directory of the archive"),
+    };
+
+    let mut commits: Vec<Commit> = state
+        .commit_list
+        .log(endpoint)
+        .into_iter()
+        .rev()
+        .cloned()
+        .collect();
+    for commit in &mut commits {
+        // Every commit of the log is under the endpoint
+        commit.endpoint = commit
+            .endpoint
+            .relative_to(endpoint)
+            .unwrap_or_else(AbstPath::empty);
+    }
+
+    let mut export = EndpointExport {
+        node,
+        commits,
+        files: Vec::new(),
+        symlinks: Vec::new(),
+    };
+    if let FSNode::Dir(_, _, tree) = &export.node {
+        let mut seen = HashSet::new();
+        pack_content(
+            &archive_root.append(endpoint),
+            tree,
+            &mut seen,
+            &mut export.files,
+            &mut export.symlinks,
+        )?;
+    }
+
+    fs::save_as(file, &export, Format::Bin).context("failed to save export")?;
+    println!(
+        "exported endpoint [{endpoint}]: {} commits, {} files, {} symlinks",
+        export.commits.len(),
+        export.files.len(),
+        export.symlinks.len()
+    );
+    Ok(())
+}
+fn pack_content(
+    path: &AbstPath,
+    FSTree(tree): &FSTree,
+    seen: &mut HashSet<String>,
+    files: &mut Vec<(Hash, Vec<u8>)>,
+    symlinks: &mut Vec<(Hash, Endpoint)>,
+) -> Result<()> {
+    for (name, node) in tree {
+        let path = path.add_last(name);
+        match node {
+            FSNode::File(_, hash, _, _) => {
+                if !seen.insert(hash.to_string()) {
+                    continue;
+                }
+                let mut content = Vec::new();
+                fs::read_file(&path)
+                    .context("failed to open file to export")?
+                    .read_to_end(&mut content)
+                    .with_context(|| format!("failed to read file to export\npath: {path}"))?;
+                if hasher::hash_bytes(&content) != *hash {
+                    anyhow::bail!("file content does not match the stored tree\npath: {path}");
+                }
+                files.push((hash.clone(), content));
+            }
+            FSNode::SymLink(_, hash) => {
+                if !seen.insert(hash.to_string()) {
+                    continue;
+                }
+                let endpoint = fs::read_link(&path).context("failed to read symlink to export")?;
+                if hasher::hash_bytes(endpoint.as_bytes()) != *hash {
+                    anyhow::bail!("symlink endpoint does not match the stored tree\npath: {path}");
+                }
+                symlinks.push((hash.clone(), endpoint));
+            }
+            FSNode::Dir(_, _, subtree) => pack_content(&path, subtree, seen, files, symlinks)?,
+        }
+    }
+    Ok(())
+}
+
+/// Recreate at `endpoint` the state exported in the file at `file`: the content
+/// is written in the archive, the tree is added to the archive's tree and the
+/// commits are added to the commit list, keeping their ids so that the clients
+/// of the exported endpoint can keep syncing. The endpoint must not exist yet,
+/// while its missing ancestors are created as empty directories
+pub fn import(archive_root: &AbstPath, endpoint: &AbstPath, file: &AbstPath) -> Result<()> {
+    let mut state = ArchiveState::load(archive_root).context("failed to load archive's state")?;
+    let export: EndpointExport = fs::load_as(file, Format::Bin).context("failed to load export")?;
+
+    if !endpoint.is_confined() {
+        anyhow::bail!("endpoint [{endpoint}] is not a path inside the archive");
+    }
+    let endpoint_path = archive_root.append(endpoint);
+    if state.archive_tree.get_at(endpoint).is_some() || endpoint_path.exists() {
+        anyhow::bail!("endpoint [{endpoint}] already exists in the archive");
+    }
+    let known_ids: HashSet<CommitID> = state.commit_list.ids().into_iter().collect();
+    if let Some(commit) = export
+        .commits
+        .iter()
+        .find(|commit| known_ids.contains(&commit.commit_id))
+    {
+        anyhow::bail!(
+            "commit {} of the export is already in the archive",
+            commit.commit_id
+        );
+    }
+    let (mtime, tree) = match &export.node {
+        FSNode::Dir(mtime, hash, tree) if tree.root_hash() == *hash => (mtime, tree),
+        _ => anyhow::bail!("exported tree is corrupted"),
+    };
+
+    // Verify all the content before writing anything
+    let mut files = HashMap::new();
+    for (hash, content) in &export.files {
+        if hasher::hash_bytes(content) != *hash {
+            anyhow::bail!("content of the export does not match its hash\nhash: {hash}");
+        }
+        files.insert(hash.to_string(), content);
+    }
+    let mut symlinks = HashMap::new();
+    for (hash, link) in &export.symlinks {
+        if hasher::hash_bytes(link.as_bytes()) != *hash {
+            anyhow::bail!("symlink of the export does not match its hash\nhash: {hash}");
+        }
+        symlinks.insert(hash.to_string(), link);
+    }
+    check_content(&AbstPath::empty(), tree, &files, &symlinks)?;
+
+    // The deepest ancestor of the endpoint already in the tree, under which the
+    // missing ones get created
+    let mut existing = endpoint.strip_last();
+    while !existing.is_empty() && state.archive_tree.get_at(&existing).is_none() {
+        existing = existing.strip_last();
+    }
+    if !existing.is_empty()
+        && !matches!(state.archive_tree.get_at(&existing), Some(FSNode::Dir(..)))
+    {
+        anyhow::bail!("[{existing}] is not a directory of the archive");
+    }
+
+    fs::create_dir(&endpoint_path).context("failed to create endpoint directory")?;
+    write_content(&endpoint_path, tree, &files, &symlinks)?;
+    if let Some(store) = ContentStore::open(archive_root) {
+        store.link_tree(&endpoint_path, tree)?;
+    }
+    fs::set_mtime(&endpoint_path, mtime).context("failed to set mtime of endpoint")?;
+    // Creating the endpoint touched the existing ancestor, whose mtime in the
+    // tree stays the same
+    if let Some(FSNode::Dir(mtime, _, _)) = state.archive_tree.get_at(&existing) {
+        fs::set_mtime(&archive_root.append(&existing), mtime)
+            .context("failed to restore mtime of endpoint's parent")?;
+    }
+
+    let mut node = export.node.clone();
+    let mut path = endpoint.clone();
+    while path.len() > existing.len() + 1 {
+        let name = path.components().last().unwrap_or_default().to_string();
+        path = path.strip_last();
+        let subtree = FSTree(HashMap::from([(name, node)]));
+        let mtime = fs::get_mtime(&archive_root.append(&path))
+            .context("failed to get mtime of created ancestor")?;
+        node = FSNode::Dir(mtime, subtree.root_hash(), subtree);
+    }
+    let name = path.components().last().unwrap_or_default().to_string();
+    let delta = Delta(HashMap::from([(name, DeltaNode::Leaf(None, Some(node)))]));
+    state
+        .archive_tree
+        .apply_delta_at_endpoint(&delta, existing)
+        .context("failed to add the imported tree to the archive's tree")?;
+
+    let commit_count = export.commits.len();
+    for mut commit in export.commits {
+        commit.endpoint = endpoint.append(&commit.endpoint);
+        state.commit_list.push(commit);
+    }
+    state.save(archive_root)?;
+
+    println!("imported endpoint [{endpoint}]: {commit_count} commits");
+    Ok(())
+}
+/// Check that the export has the content of every file and symlink of the tree
+fn check_content(
+    path: &AbstPath,
+    FSTree(tree): &FSTree,
+    files: &HashMap<String, &Vec<u8>>,
+    symlinks: &HashMap<String, &Endpoint>,
+) -> Result<()> {
+    for (name, node) in tree {
+        let path = path.add_last(name);
+        match node {
+            FSNode::File(_, hash, _, _) if !files.contains_key(&hash.to_string()) => {
+                anyhow::bail!("export is missing the content of a file\npath: {path}")
+            }
+            FSNode::SymLink(_, hash) if !symlinks.contains_key(&hash.to_string()) => {
+                anyhow::bail!("export is missing the endpoint of a symlink\npath: {path}")
+            }
+            FSNode::Dir(_, _, subtree) => check_content(&path, subtree, files, symlinks)?,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+fn write_content(
+    path: &AbstPath,
+    FSTree(tree): &FSTree,
+    files: &HashMap<String, &Vec<u8>>,
+    symlinks: &HashMap<String, &Endpoint>,
+) -> Result<()> {
+    for (name, node) in tree {
+        let path = path.add_last(name);
+        let errmsg = |msg: &str| -> String { format!("could not {msg} to import\npath: {path}") };
+        match node {
+            FSNode::File(mtime, hash, mode, _) => {
+                let mut file = fs::atomic_create_file(&path).context(errmsg("create file"))?;
+                file.write_all(files[&hash.to_string()])
+                    .context(errmsg("write file"))?;
+                file.persist().context(errmsg("persist file"))?;
+                fs::set_mode(&path, mode).context(errmsg("set mode of file"))?;
+                fs::set_mtime(&path, mtime).context(errmsg("set mtime of file"))?;
+            }
+            FSNode::SymLink(mtime, hash) => {
+                fs::create_symlink(&path, symlinks[&hash.to_string()].clone())
+                    .context(errmsg("create symlink"))?;
+                fs::set_mtime(&path, mtime).context(errmsg("set mtime of symlink"))?;
+            }
+            FSNode::Dir(mtime, _, subtree) => {
+                fs::create_dir(&path).context(errmsg("create directory"))?;
+                write_content(&path, subtree, files, symlinks)?;
+                fs::set_mtime(&path, mtime).context(errmsg("set mtime of directory"))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{export, import, ArchiveState, EndpointExport};
+    use abst_fs::{self as fs, AbstPath, Endpoint, Format};
+    use fs_vcs::{
+        generate_fstree, get_delta, Commit, CommitID, CommitMetadata, ExcludeList, FSNode, FSTree,
+    };
+    use std::path::PathBuf;
+
+    fn commit(endpoint: &str, delta: fs_vcs::Delta) -> Commit {
+        Commit {
+            commit_id: CommitID::gen_valid(),
+            endpoint: AbstPath::from(endpoint),
+            delta,
+            metadata: CommitMetadata::default(),
+        }
+    }
+
+    /// Archive with a `docs/notes` endpoint with two commits and an `other`
+    /// endpoint with one commit
+    fn gen_source(root: &AbstPath) {
+        let notes = root.add_last("docs").add_last("notes");
+        std::fs::create_dir_all(notes.add_last("dir").to_path_buf()).unwrap();
+        std::fs::create_dir_all(root.add_last("other").to_path_buf()).unwrap();
+        std::fs::write(notes.add_last("a.txt").to_path_buf(), "same content").unwrap();
+        std::fs::write(
+            notes.add_last("dir").add_last("b.txt").to_path_buf(),
+            "same content",
+        )
+        .unwrap();
+        std::fs::write(notes.add_last("c.txt").to_path_buf(), "other content").unwrap();
+        fs::create_symlink(&notes.add_last("link"), Endpoint::Unix("a.txt".to_string())).unwrap();
+        std::fs::write(root.add_last("other").add_last("d.txt").to_path_buf(), "d").unwrap();
+
+        let tree = generate_fstree(root, &ExcludeList::from(&vec![]).unwrap()).unwrap();
+        let notes_tree = match tree.get_at(&AbstPath::from("docs/notes")) {
+            Some(FSNode::Dir(_, _, subtree)) => subtree.clone(),
+            _ => panic!("missing endpoint"),
+        };
+        let mut state = ArchiveState::from(fs_vcs::CommitList::base_commit_list(), tree);
+        state.commit_list.push(commit(
+            "docs/notes",
+            get_delta(&FSTree::empty(), &notes_tree),
+        ));
+        state
+            .commit_list
+            .push(commit("other", fs_vcs::Delta::empty()));
+        state
+            .commit_list
+            .push(commit("docs/notes/dir", fs_vcs::Delta::empty()));
+        state.save(root).unwrap();
+    }
+
+    fn assert_same_endpoint(src: &AbstPath, dst: &AbstPath, endpoint: &AbstPath) {
+        let src_state = ArchiveState::load(src).unwrap();
+        let dst_state = ArchiveState::load(dst).unwrap();
+
+        let src_node = src_state.archive_tree.get_at(endpoint).unwrap();
+        let dst_node = dst_state.archive_tree.get_at(endpoint).unwrap();
+        assert_eq!(src_node, dst_node);
+        match (src_node, dst_node) {
+            (FSNode::Dir(_, _, src_tree), FSNode::Dir(_, _, dst_tree)) => {
+                assert_eq!(src_tree.structurally_eq(dst_tree), Ok(()))
+            }
+            _ => panic!("endpoint is not a directory"),
+        }
+
+        let src_log = src_state.commit_list.log(endpoint);
+        let dst_log = dst_state.commit_list.log(endpoint);
+        assert_eq!(src_log.len(), 2);
+        assert_eq!(src_log.len(), dst_log.len());
+        for (src_commit, dst_commit) in src_log.into_iter().zip(dst_log) {
+            assert_eq!(src_commit.commit_id, dst_commit.commit_id);
+            assert_eq!(src_commit.endpoint, dst_commit.endpoint);
+            assert_eq!(src_commit.delta, dst_commit.delta);
+        }
+
+        // The archive matches its tree, ancestors included
+        let actual = generate_fstree(dst, &ExcludeList::from(&vec![]).unwrap()).unwrap();
+        assert_eq!(actual.root_hash(), dst_state.archive_tree.root_hash());
+    }
+
+    #[test]
+    fn test() {
+        let path_bf = PathBuf::from("/tmp/bbup-test-server-export");
+        assert!(!path_bf.exists());
+        std::fs::create_dir(&path_bf).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            let root = AbstPath::from(&path_bf);
+            let src = root.add_last("src");
+            let dst = root.add_last("dst");
+            let file = root.add_last("notes.bin");
+            let endpoint = AbstPath::from("docs/notes");
+            gen_source(&src);
+            std::fs::create_dir(dst.to_path_buf()).unwrap();
+            ArchiveState::init_state().save(&dst).unwrap();
+
+            export(&src, &endpoint, &file).unwrap();
+            let packed: EndpointExport = fs::load_as(&file, Format::Bin).unwrap();
+            // Duplicated content is stored once
+            assert_eq!(packed.files.len(), 2);
+            assert_eq!(packed.symlinks.len(), 1);
+            assert_eq!(packed.commits.len(), 2);
+
+            import(&dst, &endpoint, &file).unwrap();
+            assert_same_endpoint(&src, &dst, &endpoint);
+
+            // The endpoint can't be imported twice, not even somewhere else, as
+            // the commit ids would collide
+            assert!(import(&dst, &endpoint, &file).is_err());
+            assert!(import(&dst, &AbstPath::from("elsewhere"), &file).is_err());
+            assert!(import(&dst, &AbstPath::from("../out"), &file).is_err());
+
+            // Tampered content is rejected before anything is written
+            let fresh = root.add_last("fresh");
+            std::fs::create_dir(fresh.to_path_buf()).unwrap();
+            ArchiveState::init_state().save(&fresh).unwrap();
+            let mut tampered = packed;
+            tampered.files[0].1 = b"tampered".to_vec();
+            let tampered_file = root.add_last("tampered.bin");
+            fs::save_as(&tampered_file, &tampered, Format::Bin).unwrap();
+            assert!(import(&fresh, &endpoint, &tampered_file).is_err());
+            assert!(!fresh.add_last("docs").exists());
+        });
+
+        std::fs::remove_dir_all(&path_bf).unwrap();
+
+        assert!(result.is_ok())
+    }
+}
@@ -0,0 +1,24 @@
+use super::ArchiveState;
+
+use abst_fs::AbstPath;
+
+use anyhow::{Context, Result};
+
+/// Print every endpoint of the archive, with its head commit and the number of
+/// commits made on it
+pub fn list(archive_root: &AbstPath) -> Result<()> {
+    let state = ArchiveState::load(archive_root).context("failed to load archive's state")?;
+
+    let endpoints = state.commit_list.endpoints();
+    if endpoints.is_empty() {
+        println!("archive has no endpoints");
+        return Ok(());
+    }
+    for summary in endpoints {
+        println!(
+            "[{}] head: {} ({} commits)",
+            summary.endpoint, summary.head, summary.commit_count
+        );
+    }
+    Ok(())
+}
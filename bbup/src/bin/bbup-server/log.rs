@@ -0,0 +1,171 @@
+use super::ArchiveState;
+
+use abst_fs::AbstPath;
+use fs_vcs::{Commit, CommitList};
+
+use anyhow::{Context, Result};
+
+use std::fmt::Write;
+
+/// Print the commits made at the endpoint (or at any path under it), from the
+/// most recent to the oldest, showing at most `limit` commits if given
+pub fn log(archive_root: &AbstPath, endpoint: &AbstPath, limit: Option<usize>) -> Result<()> {
+    let state = ArchiveState::load(archive_root).context("failed to load archive's state")?;
+    print!("{}", format_log(&state.commit_list, endpoint, limit));
+    Ok(())
+}
+
+/// The log printed by `log`
+fn format_log(commit_list: &CommitList, endpoint: &AbstPath, limit: Option<usize>) -> String {
+    let commits = commit_list.log(endpoint);
+    if commits.is_empty() {
+        return format!("no commits at endpoint [{}]\n", endpoint);
+    }
+    let limit = limit.unwrap_or(commits.len());
+    let mut log = String::new();
+    for (i, commit) in commits.into_iter().take(limit).enumerate() {
+        if i > 0 {
+            log.push('\n');
+        }
+        log.push_str(&format_commit(commit));
+    }
+    log
+}
+
+fn format_commit(commit: &Commit) -> String {
+    let metadata = &commit.metadata;
+    // Writing to a String never fails
+    let mut text = String::new();
+    let _ = writeln!(text, "commit {} [{}]", commit.commit_id, commit.endpoint);
+    if let Some(timestamp) = metadata.timestamp {
+        let _ = writeln!(text, "Date:    {}", format_timestamp(timestamp));
+    }
+    if let Some(author) = &metadata.author {
+        let _ = writeln!(text, "Author:  {}", author);
+    }
+    if let Some(message) = &metadata.message {
+        for line in message.lines() {
+            let _ = writeln!(text, "    {}", line);
+        }
+    }
+    let stats = commit.delta.stats();
+    let _ = writeln!(
+        text,
+        "    {} added, {} edited, {} removed",
+        stats.added.total(),
+        stats.edited.total(),
+        stats.removed.total()
+    );
+    text
+}
+
+/// Format seconds since the Unix epoch as a UTC date, e.g. `2022-07-14 09:21:05 UTC`
+fn format_timestamp(timestamp: i64) -> String {
+    let days = timestamp.div_euclid(86400);
+    let seconds = timestamp.rem_euclid(86400);
+
+    // Convert days since the epoch to a civil date (proleptic Gregorian)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_log, format_timestamp};
+
+    use abst_fs::AbstPath;
+    use fs_vcs::{get_delta, Commit, CommitID, CommitList, CommitMetadata, FSTree};
+
+    fn commit(endpoint: &str, before: &FSTree, after: &FSTree, message: &str) -> Commit {
+        Commit {
+            commit_id: CommitID::gen_valid(),
+            endpoint: AbstPath::from(endpoint),
+            delta: get_delta(before, after),
+            metadata: CommitMetadata {
+                author: Some(String::from("someone")),
+                message: Some(String::from(message)),
+                timestamp: Some(1667000000),
+            },
+        }
+    }
+
+    #[test]
+    fn test() {
+        let empty = FSTree::empty();
+        let first = FSTree::gen_from(|t| {
+            t.add_file("a.txt", (1667000000, 0), "a");
+            t.add_file("b.txt", (1667000000, 0), "b");
+        });
+        let second = FSTree::gen_from(|t| {
+            t.add_file("b.txt", (1667000100, 0), "edited b");
+            t.add_file("c.txt", (1667000100, 0), "c");
+            t.add_file("d.txt", (1667000100, 0), "d");
+        });
+        let commits = [
+            commit("docs", &empty, &first, "first"),
+            commit("photos", &empty, &first, "elsewhere"),
+            commit("docs/inner", &first, &second, "second\nwith two lines"),
+        ];
+        let mut commit_list = CommitList::base_commit_list();
+        for commit in &commits {
+            commit_list.push(commit.clone());
+        }
+
+        // Most recent first, with the summary of the changes of each commit,
+        //	and only the commits at the endpoint or under it
+        let log = format_log(&commit_list, &AbstPath::from("docs"), None);
+        assert_eq!(
+            log,
+            format!(
+                "commit {} [docs/inner]\n\
+                 Date:    2022-10-28 23:33:20 UTC\n\
+                 Author:  someone\n    second\n    with two lines\n\
+                 \x20   2 added, 1 edited, 1 removed\n\
+                 \n\
+                 commit {} [docs]\n\
+                 Date:    2022-10-28 23:33:20 UTC\n\
+                 Author:  someone\n    first\n\
+                 \x20   2 added, 0 edited, 0 removed\n",
+                commits[2].commit_id, commits[0].commit_id
+            )
+        );
+
+        // At most `limit` commits, starting from the most recent
+        let log = format_log(&commit_list, &AbstPath::empty(), Some(2));
+        let ids: Vec<&str> = log
+            .lines()
+            .filter_map(|line| line.strip_prefix("commit "))
+            .collect();
+        assert_eq!(
+            ids,
+            vec![
+                format!("{} [docs/inner]", commits[2].commit_id),
+                format!("{} [photos]", commits[1].commit_id),
+            ]
+        );
+
+        assert_eq!(
+            format_log(&commit_list, &AbstPath::from("music"), None),
+            "no commits at endpoint [music]\n"
+        );
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_timestamp(951782400), "2000-02-29 00:00:00 UTC");
+    }
+}
@@ -1,11 +1,17 @@
-use std::sync::Arc;
+use std::{io::IsTerminal, sync::Arc};
 
 use abst_fs as fs;
 
+mod export;
+mod list;
+mod log;
 mod model;
 use model::*;
 mod process;
 mod setup;
+mod store;
+use store::ContentStore;
+mod verify;
 
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
@@ -23,8 +29,75 @@ enum SubCommand {
         /// Show progress during file transfer
         #[clap(short, long)]
         progress: bool,
+
+        /// Limit file transfers to the given bytes per second (e.g. 500K, 2M).
+        /// 0 means unlimited
+        #[clap(long, value_parser = bbup::com::parse_rate, default_value = "0")]
+        limit_rate: u64,
+
+        /// Compress file transfers when requested by the client
+        #[clap(short = 'z', long, value_parser)]
+        compress: bool,
+
+        /// Transfer file content in chunks of the given size (e.g. 256K, 4M).
+        /// Larger chunks perform better on high-latency links
+        #[clap(long, value_parser = bbup::com::parse_chunk_size)]
+        chunk_size: Option<usize>,
+
+        /// Drop the connection if the client sends nothing for the given number
+        /// of seconds. 0 means wait forever
+        #[clap(long, value_parser, default_value = "300")]
+        read_timeout: u64,
+    },
+    #[clap(version)]
+    /// Check that the archive matches the stored tree, without modifying anything
+    Verify {
+        /// Check only the given endpoint (relative to archive root)
+        #[clap(short, long)]
+        endpoint: Option<String>,
+    },
+    #[clap(version)]
+    /// List the endpoints of the archive, with their head commit and commit count
+    List,
+    #[clap(version)]
+    /// Show the commits made at an endpoint, from the most recent to the oldest
+    Log {
+        /// Show only the commits of the given endpoint (relative to archive root)
+        #[clap(value_parser)]
+        endpoint: Option<String>,
+
+        /// Show at most the given number of commits
+        #[clap(short = 'n', long)]
+        limit: Option<usize>,
+    },
+    #[clap(version)]
+    /// Pack the tree, the commits and the content of an endpoint in a single file
+    Export {
+        /// Endpoint to export (relative to archive root)
+        #[clap(value_parser)]
+        endpoint: String,
+
+        /// File to write the export to
+        #[clap(value_parser)]
+        file: String,
+    },
+    #[clap(version)]
+    /// Recreate an endpoint from a file written by export. The endpoint must not
+    /// exist yet
+    Import {
+        /// Endpoint to import to (relative to archive root)
+        #[clap(value_parser)]
+        endpoint: String,
+
+        /// File to read the export from
+        #[clap(value_parser)]
+        file: String,
     },
     #[clap(version)]
+    /// Store the content of the archive by hash, so that files with the same
+    /// content and metadata take the space of a single one
+    EnableStore,
+    #[clap(version)]
     /// Initialize bbup client
     Setup {
         /// Set server port
@@ -46,46 +119,116 @@ struct Args {
     /// Set fake home directory
     #[clap(short = 'H', long)]
     home_dir: Option<String>,
+
+    /// Take the fields of the server config from the BBUP_* environment
+    /// variables that are set, with precedence over the config file
+    #[clap(long)]
+    config_from_env: bool,
+
+    /// Never use colors in the output. By default colors are used only if the
+    /// output is a terminal
+    #[clap(long)]
+    no_color: bool,
+
+    /// Also append the log, as structured lines with the endpoint and the PID of
+    /// the connection, to the given file. The log level is info with --verbose
+    /// and warn otherwise, unless set with the BBUP_LOG environment variable
+    /// (error, warn, info, debug or trace)
+    #[clap(long)]
+    log_file: Option<String>,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // Parse command line arguments
     let args = Args::parse();
+    fs_vcs::set_colors(!args.no_color && std::io::stdout().is_terminal());
+    let verbose = matches!(args.cmd, SubCommand::Run { verbose: true, .. });
+    let log_file = args.log_file.map(|val| fs::AbstPath::from(val).normalize());
+    bbup::logger::init(verbose, log_file.as_ref())?;
     let home_dir = match args.home_dir {
-        Some(val) => fs::AbstPath::from(val),
+        Some(val) => fs::AbstPath::from(val).normalize(),
         None => fs::home_dir().context("could not resolve home_dir path")?,
     };
 
     match args.cmd {
         SubCommand::Setup { server_port, archive_root } => setup::setup(home_dir, server_port, archive_root),
-        SubCommand::Run { verbose, progress } => {
-            let server_config = ServerConfig::load(&home_dir)?;
+        SubCommand::Verify { endpoint } => {
+            let server_config = ServerConfig::load(&home_dir, args.config_from_env)?;
+            let archive_root = home_dir.append(&server_config.archive_root);
+            let endpoint = match endpoint {
+                Some(val) => fs::AbstPath::from(val).normalize(),
+                None => fs::AbstPath::empty(),
+            };
+
+            verify::verify(&archive_root, &endpoint)
+        }
+        SubCommand::List => {
+            let server_config = ServerConfig::load(&home_dir, args.config_from_env)?;
+            let archive_root = home_dir.append(&server_config.archive_root);
+
+            list::list(&archive_root)
+        }
+        SubCommand::Log { endpoint, limit } => {
+            let server_config = ServerConfig::load(&home_dir, args.config_from_env)?;
+            let archive_root = home_dir.append(&server_config.archive_root);
+            let endpoint = match endpoint {
+                Some(val) => fs::AbstPath::from(val).normalize(),
+                None => fs::AbstPath::empty(),
+            };
+
+            log::log(&archive_root, &endpoint, limit)
+        }
+        SubCommand::Export { endpoint, file } => {
+            let server_config = ServerConfig::load(&home_dir, args.config_from_env)?;
+            let archive_root = home_dir.append(&server_config.archive_root);
+            let endpoint = fs::AbstPath::from(endpoint).normalize();
+            let file = fs::AbstPath::from(file).normalize();
+
+            export::export(&archive_root, &endpoint, &file)
+        }
+        SubCommand::Import { endpoint, file } => {
+            let server_config = ServerConfig::load(&home_dir, args.config_from_env)?;
+            let archive_root = home_dir.append(&server_config.archive_root);
+            let endpoint = fs::AbstPath::from(endpoint).normalize();
+            let file = fs::AbstPath::from(file).normalize();
+
+            export::import(&archive_root, &endpoint, &file)
+        }
+        SubCommand::EnableStore => {
+            let server_config = ServerConfig::load(&home_dir, args.config_from_env)?;
+            let archive_root = home_dir.append(&server_config.archive_root);
+
+            store::enable_store(&archive_root)
+        }
+        SubCommand::Run { verbose: _, progress, limit_rate, compress, chunk_size, read_timeout } => {
+            let server_config = ServerConfig::load(&home_dir, args.config_from_env)?;
             let archive_root = home_dir.append(&server_config.archive_root);
 
-            let archive_state =
+            let mut archive_state =
                 ArchiveState::load(&archive_root).context("failed to load aarchive's state")?;
-            let archive_config = ArchiveConfig { archive_root };
+            let temp_dir = fs::resolve_temp_dir(&archive_root, server_config.temp_dir.as_ref());
+            let archive_config = ArchiveConfig { archive_root, temp_dir };
+            process::recover(&archive_config, &mut archive_state)
+                .context("failed to recover interrupted push")?;
             let state = Arc::new(Mutex::new(archive_state));
+            let flags = Flags { progress, limit_rate, compress, chunk_size, read_timeout };
 
             // Start TCP server
             let listener =
-                TcpListener::bind(format!("127.0.0.1:{}", server_config.server_port)).await?;
+                TcpListener::bind((server_config.bind_address, server_config.server_port)).await?;
 
             // Spawn a task for each connection
             loop {
                 let (socket, _) = listener.accept().await?;
                 let state = state.clone();
                 let config = archive_config.clone();
+                let flags = flags.clone();
                 tokio::spawn(async move {
-                    let result = process::process_connection(config, socket, state, progress).await;
+                    let result = process::process_connection(config, socket, state, flags).await;
                     match result {
-                        Ok(()) => {
-                            if verbose {
-                                println!("connection processed correctly")
-                            }
-                        }
-                        Err(err) => println!("Error: {err}"),
+                        Ok(()) => ::log::info!("connection processed correctly"),
+                        Err(err) => ::log::error!("{err}"),
                     }
                 });
             }
@@ -1,16 +1,33 @@
-use abst_fs::{self as fs, AbstPath};
-use fs_vcs::{CommitList, FSTree};
+use abst_fs::{self as fs, AbstPath, Durability};
+use fs_vcs::{CommitID, CommitList, CommitMetadata, Delta, FSTree, HardLinkGroups};
 
 use serde::{Deserialize, Serialize};
 
 use anyhow::{Context, Result};
 
+use std::net::{IpAddr, Ipv4Addr};
+
+/// Config of the server. The fields that can be given by environment variables
+/// (see `ServerConfig::overlay_env`) may be missing from the config file
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ServerConfig {
+    #[serde(default)]
     pub server_port: u16,
+    #[serde(default = "AbstPath::empty")]
     pub archive_root: AbstPath,
+    /// Directory where the uploaded content is staged before being moved in the
+    /// archive, relative to the archive root if not absolute. Defaults to
+    /// `.bbup/temp` in the archive root. It should not be inside an endpoint
+    pub temp_dir: Option<AbstPath>,
+    /// Address of the interface the daemon listens on. Defaults to 127.0.0.1, as
+    /// clients usually connect through an ssh tunnel ending on the same host
+    #[serde(default = "ServerConfig::default_bind_address")]
+    pub bind_address: IpAddr,
 }
 impl ServerConfig {
+    fn default_bind_address() -> IpAddr {
+        IpAddr::V4(Ipv4Addr::LOCALHOST)
+    }
     fn path(home_dir: &AbstPath) -> AbstPath {
         home_dir
             .add_last(".config")
@@ -21,22 +38,55 @@ impl ServerConfig {
         ServerConfig {
             server_port,
             archive_root,
+            temp_dir: None,
+            bind_address: ServerConfig::default_bind_address(),
         }
     }
     pub fn exists(home_dir: &AbstPath) -> bool {
         ServerConfig::path(home_dir).exists()
     }
-    pub fn load(home_dir: &AbstPath) -> Result<ServerConfig> {
+    /// Load the server config. If `from_env` is set, the fields given by
+    /// environment variables take precedence over the ones of the config file,
+    /// which can then be missing altogether
+    pub fn load(home_dir: &AbstPath, from_env: bool) -> Result<ServerConfig> {
         let path = ServerConfig::path(home_dir);
-        if !path.exists() {
-            anyhow::bail!("Bbup server isn't set up. Try using 'bbup-server setup'")
+        let mut server_config: ServerConfig = match (path.exists(), from_env) {
+            (true, _) => fs::load(&path).context("failed to load server config")?,
+            (false, true) => ServerConfig::from(0, AbstPath::empty()),
+            (false, false) => {
+                anyhow::bail!("Bbup server isn't set up. Try using 'bbup-server setup'")
+            }
+        };
+        if from_env {
+            server_config
+                .overlay_env()
+                .context("invalid server config in environment")?;
+        }
+        if server_config.server_port == 0 {
+            anyhow::bail!("setting server_port is missing from the server config (it can also be given by BBUP_SERVER_PORT with --config-from-env)")
+        }
+        if server_config.archive_root.is_empty() {
+            anyhow::bail!("setting archive_root is missing from the server config (it can also be given by BBUP_ARCHIVE_ROOT with --config-from-env)")
         }
-        let server_config: ServerConfig =
-            fs::load(&path).context("failed to load server config")?;
         Ok(server_config)
     }
+    /// Override the config with the environment variables that are set, among
+    /// `BBUP_SERVER_PORT`, `BBUP_ARCHIVE_ROOT` and `BBUP_BIND_ADDRESS`
+    fn overlay_env(&mut self) -> Result<()> {
+        if let Some(server_port) = fs::env_var("BBUP_SERVER_PORT")? {
+            self.server_port = server_port;
+        }
+        if let Some(archive_root) = fs::env_var::<String>("BBUP_ARCHIVE_ROOT")? {
+            self.archive_root = AbstPath::from(archive_root).normalize();
+        }
+        if let Some(bind_address) = fs::env_var("BBUP_BIND_ADDRESS")? {
+            self.bind_address = bind_address;
+        }
+        Ok(())
+    }
     pub fn save(&self, home_dir: &AbstPath) -> Result<()> {
-        fs::save(&ServerConfig::path(home_dir), self).context("failed to save server config")?;
+        fs::save_with(&ServerConfig::path(home_dir), self, Durability::Unsynced)
+            .context("failed to save server config")?;
         Ok(())
     }
 }
@@ -44,6 +94,17 @@ impl ServerConfig {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ArchiveConfig {
     pub archive_root: AbstPath,
+    pub temp_dir: AbstPath,
+}
+
+/// Options of the daemon, applied to every connection
+#[derive(Debug, Clone)]
+pub struct Flags {
+    pub progress: bool,
+    pub limit_rate: u64,
+    pub compress: bool,
+    pub chunk_size: Option<usize>,
+    pub read_timeout: u64,
 }
 
 #[derive(Debug)]
@@ -68,10 +129,10 @@ impl ArchiveState {
         archive_root.add_last(".bbup").add_last("archive-tree.bin")
     }
     pub fn load(archive_root: &AbstPath) -> Result<ArchiveState> {
-        let commit_list: CommitList = fs::load(&ArchiveState::cl_path(archive_root))
+        let commit_list = CommitList::load(&ArchiveState::cl_path(archive_root))
             .context("failed to load archive's commit list")?;
 
-        let archive_tree: FSTree = fs::load(&ArchiveState::at_path(archive_root))
+        let archive_tree = FSTree::load(&ArchiveState::at_path(archive_root))
             .context("failed to load archive's tree")?;
 
         Ok(ArchiveState {
@@ -89,3 +150,95 @@ impl ArchiveState {
         Ok(())
     }
 }
+
+/// Record of a push that is being applied to the archive. It is saved before the
+/// archive gets modified and removed only once both the archive and its state
+/// have been updated, so that a push interrupted halfway can be completed
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Journal {
+    pub commit_id: CommitID,
+    pub endpoint: AbstPath,
+    pub delta: Delta,
+    pub hardlinks: HardLinkGroups,
+    pub updated_archive_tree: FSTree,
+    #[serde(default)]
+    pub metadata: CommitMetadata,
+}
+impl Journal {
+    fn path(archive_root: &AbstPath) -> AbstPath {
+        archive_root.add_last(".bbup").add_last("journal.bin")
+    }
+    pub fn load(archive_root: &AbstPath) -> Result<Option<Journal>> {
+        let path = Journal::path(archive_root);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let journal: Journal = fs::load(&path).context("failed to load archive's journal")?;
+        Ok(Some(journal))
+    }
+    pub fn save(&self, archive_root: &AbstPath) -> Result<()> {
+        fs::save(&Journal::path(archive_root), self).context("failed to save archive's journal")?;
+        Ok(())
+    }
+    pub fn remove(archive_root: &AbstPath) -> Result<()> {
+        fs::remove_file(&Journal::path(archive_root))
+            .context("failed to remove archive's journal")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ServerConfig;
+
+    use abst_fs::{self as fs, AbstPath};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    fn bind_address(home_dir: &AbstPath) {
+        let write = |config: &str| {
+            let path = ServerConfig::path(home_dir);
+            fs::ensure_parent(&path).unwrap();
+            std::fs::write(path.to_path_buf(), config).unwrap();
+        };
+        let settings = "server_port = 4000\narchive_root = [\"archive\"]\n";
+
+        // Localhost unless set otherwise
+        write(settings);
+        let config = ServerConfig::load(home_dir, false).unwrap();
+        assert_eq!(config.bind_address, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(
+            ServerConfig::from(4000, AbstPath::from("/archive")).bind_address,
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
+        );
+
+        write(&format!("{settings}bind_address = \"0.0.0.0\"\n"));
+        let config = ServerConfig::load(home_dir, false).unwrap();
+        assert_eq!(config.bind_address, IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        write(&format!("{settings}bind_address = \"::1\"\n"));
+        let config = ServerConfig::load(home_dir, false).unwrap();
+        assert_eq!(config.bind_address, IpAddr::V6(Ipv6Addr::LOCALHOST));
+
+        // Invalid addresses are rejected when the config is loaded
+        for address in ["localhost", "256.0.0.1", "10.0.0", ""] {
+            write(&format!("{settings}bind_address = \"{address}\"\n"));
+            assert!(ServerConfig::load(home_dir, false).is_err());
+        }
+
+        // The default is kept when the config is saved and loaded again
+        let config = ServerConfig::from(4000, AbstPath::from("/archive"));
+        config.save(home_dir).unwrap();
+        let config = ServerConfig::load(home_dir, false).unwrap();
+        assert_eq!(config.bind_address, IpAddr::V4(Ipv4Addr::LOCALHOST));
+    }
+
+    #[test]
+    fn test() {
+        let test_path = AbstPath::from("/tmp/bbup-test-server-model");
+        assert!(!test_path.exists());
+        fs::create_dir(&test_path).unwrap();
+
+        let result = std::panic::catch_unwind(|| bind_address(&test_path));
+        fs::remove_dir_all(&test_path).unwrap();
+        assert!(result.is_ok());
+    }
+}
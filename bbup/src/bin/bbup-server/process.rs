@@ -1,22 +1,31 @@
-use super::{ArchiveConfig, ArchiveState};
+use super::{ArchiveConfig, ArchiveState, ContentStore, Flags, Journal};
 
 use abst_fs::{self as fs, AbstPath};
-use fs_vcs::{Action, Commit, Delta};
+use fs_vcs::{
+    generate_fstree, get_delta, Action, Actions, Commit, CommitID, CommitMetadata, Delta,
+    ExcludeList, FSNode, FSTree, HardLinkGroups,
+};
 
-use bbup::com::{BbupCom, JobType, Queryable};
+use bbup::com::{partial_path, BbupCom, JobType, Queryable, MAX_BATCH_SIZE};
 
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use hasher::Hash;
+use log::{debug, error, warn};
 use tokio::{net::TcpStream, sync::Mutex};
 
 async fn pull(
     config: &ArchiveConfig,
-    state: &ArchiveState,
+    state: &mut ArchiveState,
     com: &mut BbupCom,
     endpoint: &AbstPath,
 ) -> Result<()> {
-    let last_known_commit: String = com.get_struct().await.context("could not get lkc")?;
+    // A push whose commit id was not confirmed is completed first, as the client
+    //	might have saved the id anyway
+    recover(config, state)?;
+
+    let last_known_commit: CommitID = com.get_struct().await.context("could not get lkc")?;
 
     // calculate update for client
     // TODO maybe this should panic because it means a broken server state
@@ -37,8 +46,8 @@ async fn pull(
     let mut queryables = Vec::new();
     for (path, action) in &delta.to_actions() {
         match action {
-            Action::AddFile(_, _)
-            | Action::EditFile(_, Some(_))
+            Action::AddFile(_, _, _)
+            | Action::EditFile(_, Some(_), _)
             | Action::AddSymLink(_, _)
             | Action::EditSymLink(_, Some(_)) => queryables.push(path.clone()),
 
@@ -54,13 +63,94 @@ async fn pull(
     Ok(())
 }
 
+/// Remove from the temp directory whatever previous pushes left in it, except for
+/// the partial files of the files about to be queried, so that their transfer
+/// can be resumed. A partial file left by a different version of a file is
+/// kept as well, but the other party only resumes from it if its content
+/// matches the beginning of the file
+fn clean_temp_dir(temp_dir: &AbstPath, queries: &[(Queryable, AbstPath, Hash)]) -> Result<()> {
+    fs::create_dir(temp_dir)?;
+    let partials = queries
+        .iter()
+        .filter(|(queryable, _, _)| matches!(queryable, Queryable::File))
+        .filter_map(|(_, path, _)| partial_path(&temp_dir.append(path)))
+        .collect::<Vec<AbstPath>>();
+
+    // Returns whether the directory is left empty
+    fn clean(dir: &AbstPath, partials: &[AbstPath]) -> Result<bool> {
+        let mut empty = true;
+        for path in fs::list_dir_content(dir)? {
+            match path.object_type() {
+                Some(fs::ObjectType::Dir) => match clean(&path, partials)? {
+                    true => fs::remove_dir(&path)?,
+                    false => empty = false,
+                },
+                Some(_) if partials.contains(&path) => empty = false,
+                Some(fs::ObjectType::SymLink) => fs::remove_symlink(&path)?,
+                Some(_) => fs::remove_file(&path)?,
+                None => {}
+            }
+        }
+        Ok(empty)
+    }
+    clean(temp_dir, &partials)?;
+    Ok(())
+}
+
+/// Check that a push to the reserved block at the endpoint only touches objects
+/// inside of the block
+fn check_block(endpoint: &AbstPath, actions: &Actions) -> Result<()> {
+    if !endpoint.is_confined() {
+        bail!("push to reserved block rejected\nendpoint {endpoint} is not a valid block");
+    }
+    for (path, action) in actions {
+        let original = match action {
+            Action::AddHardLink(original)
+            | Action::Move(original, ..)
+            | Action::Copy(original, ..) => Some(original),
+            _ => None,
+        };
+        for path in std::iter::once(path).chain(original) {
+            if !path.is_confined() {
+                bail!(
+                    "push to reserved block rejected\npath {path} is outside of block {endpoint}"
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that an add-only push doesn't delete nor overwrite anything, not even
+/// the objects of the archive that the client doesn't know about
+fn check_add_only(archive_tree: &FSTree, endpoint: &AbstPath, actions: &Actions) -> Result<()> {
+    for (path, action) in actions {
+        if !action.is_addition() {
+            bail!("add-only push rejected\naction {action} on path {path} is not an addition");
+        }
+        let exists = archive_tree.get_at(&endpoint.append(path)).is_some();
+        if exists && !matches!(action, Action::EditDir(_)) {
+            bail!("add-only push rejected\npath {path} already exists in the archive");
+        }
+    }
+    Ok(())
+}
+
+/// Apply the changes pushed by the client to the endpoint. If `block` is set, the
+/// endpoint is a block reserved to the client and the push is rejected if it
+/// touches anything outside of it. If `add_only` is set, the push is rejected if
+/// it deletes or overwrites anything
 async fn push(
     config: &ArchiveConfig,
     state: &mut ArchiveState,
     com: &mut BbupCom,
     endpoint: &AbstPath,
+    block: bool,
+    add_only: bool,
 ) -> Result<()> {
-    fs::make_clean_dir(&config.archive_root.add_last(".bbup").add_last("temp"))?;
+    // A previous push that failed while being applied has to be completed before
+    //	the archive can be modified again
+    recover(config, state)?;
 
     // Reply with green light for push
     com.send_ok()
@@ -73,11 +163,44 @@ async fn push(
         .await
         .context("could not get delta from client")?;
 
+    // Files that are hard links to the same object are uploaded only once
+    let hardlinks: HardLinkGroups = com
+        .get_struct()
+        .await
+        .context("could not get hard links from client")?;
+    // Clients older than protocol version 3 send no metadata. The time of the
+    //	commit is the time of the server, regardless of what the client sent
+    let mut metadata: CommitMetadata = match com.protocol_version >= 3 {
+        true => com
+            .get_struct()
+            .await
+            .context("could not get commit metadata from client")?,
+        false => CommitMetadata::default(),
+    };
+    metadata.timestamp = Some(now());
+    // Clients older than protocol version 4 can answer one query at a time
+    let batch_size: u64 = match com.protocol_version >= 4 {
+        true => com
+            .get_struct()
+            .await
+            .context("could not get batch size from client")?,
+        false => 1,
+    };
+    let mut actions = local_delta.to_actions();
+    actions.link_hardlinks(&hardlinks);
+    actions.detect_moves(&local_delta);
+    if block {
+        check_block(endpoint, &actions)?;
+    }
+    if add_only {
+        check_add_only(&state.archive_tree, endpoint, &actions)?;
+    }
+
     // Get all files that need to be uploaded from client
     let mut queries = Vec::new();
-    for (path, action) in &local_delta.to_actions() {
+    for (path, action) in &actions {
         match action {
-            Action::AddFile(_, hash) | Action::EditFile(_, Some(hash)) => {
+            Action::AddFile(_, hash, _) | Action::EditFile(_, Some(hash), _) => {
                 queries.push((Queryable::File, path.clone(), hash.clone()))
             }
 
@@ -88,9 +211,13 @@ async fn push(
             _ => {}
         }
     }
+    clean_temp_dir(&config.temp_dir, &queries)
+        .context("could not clean temp directory to apply push")?;
     com.query_files(
         queries,
-        &config.archive_root.add_last(".bbup").add_last("temp"),
+        &config.temp_dir,
+        Some(&config.archive_root.append(endpoint)),
+        batch_size.clamp(1, MAX_BATCH_SIZE) as usize,
     )
     .await
     .context("could not query files to apply push")?;
@@ -99,13 +226,55 @@ async fn push(
     let mut updated_archive_tree = state.archive_tree.clone();
     updated_archive_tree.apply_delta_at_endpoint(&local_delta, endpoint.clone())?;
 
-    for (path, action) in local_delta.to_actions() {
+    let journal = Journal {
+        commit_id: CommitID::gen_valid(),
+        endpoint: endpoint.clone(),
+        delta: local_delta,
+        hardlinks,
+        updated_archive_tree,
+        metadata,
+    };
+    journal
+        .save(&config.archive_root)
+        .context("could not save journal of the push")?;
+
+    apply_actions(config, endpoint, actions)?;
+
+    // The commit is finalized only once the client confirms that it saved its
+    //	id. If the connection drops before that, the journal is left for the
+    //	recovery, which completes the push on the next connection. Clients older
+    //	than protocol version 6 don't confirm
+    let commit_id = journal.commit_id.clone();
+    match com.protocol_version >= 6 {
+        true => com.send_struct_confirmed(commit_id).await,
+        false => com.send_struct(commit_id).await,
+    }
+    .context("could not send commit id for the push")?;
+    complete_push(&config.archive_root, state, journal).context("could not save push update")?;
+
+    Ok(())
+}
+
+/// Current time, in seconds since the Unix epoch
+fn now() -> i64 {
+    match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_secs() as i64,
+        Err(err) => -(err.duration().as_secs() as i64),
+    }
+}
+
+/// Apply the actions to the archive at the endpoint, taking the added or edited
+/// content from the temp directory. The changes are synced to the disk before
+/// returning, so that they are not lost if the commit recording them is saved.
+/// If the archive has a content store, the added and edited files are linked to
+/// it
+fn apply_actions(config: &ArchiveConfig, endpoint: &AbstPath, actions: Actions) -> Result<()> {
+    let dirs = actions.parent_dirs();
+    let store = ContentStore::open(&config.archive_root);
+    for (path, action) in actions {
         let to_path = config.archive_root.append(endpoint).append(&path);
-        let from_temp_path = config
-            .archive_root
-            .add_last(".bbup")
-            .add_last("temp")
-            .append(&path);
+        let from_temp_path = config.temp_dir.append(&path);
+        debug!("apply action: {}: {}", action, path);
 
         let errmsg = |msg: &str| -> String {
             format!(
@@ -118,27 +287,65 @@ async fn push(
             Action::AddDir => {
                 fs::create_dir(&to_path).context(errmsg("create added directory"))?;
             }
-            Action::AddFile(mtime, _) => {
+            Action::AddFile(mtime, hash, mode) => {
                 fs::rename_file(&from_temp_path, &to_path)
                     .context(errmsg("move added file from temp"))?;
+                fs::set_mode(&to_path, &mode).context(errmsg("set mode of added file"))?;
                 fs::set_mtime(&to_path, &mtime).context(errmsg("set mtime of added file"))?;
+                if let Some(store) = &store {
+                    store.link(&to_path, Some(&hash))?;
+                }
             }
             Action::AddSymLink(mtime, _) => {
                 fs::rename_symlink(&from_temp_path, &to_path)
                     .context(errmsg("move added symlink from temp"))?;
                 fs::set_mtime(&to_path, &mtime).context(errmsg("set mtime of added symlink"))?;
             }
+            Action::AddHardLink(original) => {
+                let original = config.archive_root.append(endpoint).append(&original);
+                fs::create_hardlink(&original, &to_path)
+                    .context(errmsg("create added hard link"))?;
+            }
+            Action::Move(from, mtime, hash, mode) => {
+                let from = config.archive_root.append(endpoint).append(&from);
+                fs::rename_file(&from, &to_path).context(errmsg("move renamed file"))?;
+                if let Some(store) = &store {
+                    store.unlink_for_edit(&to_path, Some(&mode), Some(&mtime))?;
+                }
+                fs::set_mode(&to_path, &mode).context(errmsg("set mode of moved file"))?;
+                fs::set_mtime(&to_path, &mtime).context(errmsg("set mtime of moved file"))?;
+                if let Some(store) = &store {
+                    store.link(&to_path, Some(&hash))?;
+                }
+            }
+            Action::Copy(from, mtime, hash, mode) => {
+                let from = config.archive_root.append(endpoint).append(&from);
+                fs::copy_file(&from, &to_path).context(errmsg("copy file"))?;
+                fs::set_mode(&to_path, &mode).context(errmsg("set mode of copied file"))?;
+                fs::set_mtime(&to_path, &mtime).context(errmsg("set mtime of copied file"))?;
+                if let Some(store) = &store {
+                    store.link(&to_path, Some(&hash))?;
+                }
+            }
             Action::EditDir(mtime) => {
                 fs::set_mtime(&to_path, &mtime).context(errmsg("set mtime of edited directory"))?;
             }
-            Action::EditFile(optm, opth) => {
+            Action::EditFile(optm, opth, optp) => {
                 if opth.is_some() {
                     fs::rename_file(&from_temp_path, &to_path)
                         .context(errmsg("move edited file from temp"))?;
+                } else if let Some(store) = &store {
+                    store.unlink_for_edit(&to_path, optp.as_ref(), optm.as_ref())?;
+                }
+                if let Some(mode) = optp {
+                    fs::set_mode(&to_path, &mode).context(errmsg("set mode of edited file"))?;
                 }
                 if let Some(mtime) = optm {
                     fs::set_mtime(&to_path, &mtime).context(errmsg("set mtime of edited file"))?;
                 }
+                if let Some(store) = &store {
+                    store.link(&to_path, opth.as_ref())?;
+                }
             }
             Action::EditSymLink(optm, opth) => {
                 if opth.is_some() {
@@ -171,21 +378,113 @@ async fn push(
             }
         }
     }
+    // The directories removed by the actions are skipped, their removal being
+    //	synced with their parent
+    for dir in dirs {
+        let path = config.archive_root.append(endpoint).append(&dir);
+        if path.object_type() == Some(fs::ObjectType::Dir) {
+            fs::sync_dir(&path).context("could not sync new commit to disk")?;
+        }
+    }
 
-    let commit_id = Commit::gen_valid_id();
-    state.commit_list.push(Commit {
-        commit_id: commit_id.clone(),
-        endpoint: endpoint.clone(),
-        delta: local_delta.clone(),
-    });
-    state.archive_tree = updated_archive_tree;
-    state
-        .save(&config.archive_root)
-        .context("could not save push update")?;
+    Ok(())
+}
 
-    com.send_struct(commit_id)
-        .await
-        .context("could not send commit id for the push")?;
+/// Add the commit of the journal to the commit list and save the updated state,
+/// then remove the journal as the push is complete. The commit is not added
+/// twice if the commit list had already been saved
+fn complete_push(
+    archive_root: &AbstPath,
+    state: &mut ArchiveState,
+    journal: Journal,
+) -> Result<()> {
+    if state.commit_list.most_recent_commit().commit_id != journal.commit_id {
+        state.commit_list.push(Commit {
+            commit_id: journal.commit_id,
+            endpoint: journal.endpoint,
+            delta: journal.delta,
+            metadata: journal.metadata,
+        });
+    }
+    state.archive_tree = journal.updated_archive_tree;
+    state.save(archive_root)?;
+    Journal::remove(archive_root)?;
+
+    // The blobs of the content that was removed or replaced are left in the
+    //	store until now, so that it is never needed again for a push to complete
+    if let Some(store) = ContentStore::open(archive_root) {
+        if let Err(err) = store.prune() {
+            warn!("{:?}", err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Complete the push that was being applied when the server stopped, if any, so
+/// that the archive, its tree and its commit list are consistent again. The
+/// actions still to apply are found comparing the archive with the tree it
+/// should have, and the content they need is still in the temp directory
+pub fn recover(config: &ArchiveConfig, state: &mut ArchiveState) -> Result<()> {
+    let archive_root = &config.archive_root;
+    let journal = match Journal::load(archive_root)? {
+        Some(journal) => journal,
+        None => return Ok(()),
+    };
+    warn!("recovering interrupted push [{}]", journal.commit_id);
+
+    // If the commit list has been saved, the archive had already been updated
+    if state.commit_list.most_recent_commit().commit_id != journal.commit_id {
+        let endpoint = &journal.endpoint;
+        let empty = FSTree::empty();
+        let target_tree = match endpoint.is_empty() {
+            true => &journal.updated_archive_tree,
+            false => match journal.updated_archive_tree.get_at(endpoint) {
+                Some(FSNode::Dir(_, _, subtree)) => subtree,
+                _ => &empty,
+            },
+        };
+        let actual_tree =
+            generate_fstree(&archive_root.append(endpoint), &ExcludeList::from(&vec![])?)
+                .context("could not generate archive's tree")?;
+
+        // Hard links whose original has already been added are linked to it, as
+        //	their content was never uploaded
+        let HardLinkGroups(groups) = &journal.hardlinks;
+        let remaining = get_delta(&actual_tree, target_tree);
+        let mut actions = Actions::new();
+        for (path, action) in remaining.to_actions() {
+            let action = match action {
+                Action::AddFile(mtime, hash, mode) if !config.temp_dir.append(&path).exists() => {
+                    let original = groups
+                        .iter()
+                        .filter(|group| group.contains(&path))
+                        .flatten()
+                        .find(|other| match actual_tree.get_at(other) {
+                            Some(FSNode::File(_, other_hash, _, _)) => *other_hash == hash,
+                            _ => false,
+                        });
+                    match original {
+                        Some(original) => Action::AddHardLink(original.clone()),
+                        None => Action::AddFile(mtime, hash, mode),
+                    }
+                }
+                action => action,
+            };
+            actions.push(path, action);
+        }
+        actions.link_hardlinks(&journal.hardlinks);
+        // Files not moved yet are still at their old path, while their content
+        //	was never uploaded
+        actions.detect_moves(&remaining);
+        actions.sort_for_apply();
+
+        apply_actions(config, endpoint, actions)
+            .context("could not apply remaining actions of interrupted push")?;
+    }
+
+    complete_push(archive_root, state, journal)
+        .context("could not save update of interrupted push")?;
     Ok(())
 }
 
@@ -193,9 +492,11 @@ pub async fn process_connection(
     config: ArchiveConfig,
     socket: TcpStream,
     state: Arc<Mutex<ArchiveState>>,
-    progress: bool,
+    flags: Flags,
 ) -> Result<()> {
-    let mut com = BbupCom::from(socket, progress);
+    let mut com = BbupCom::from(socket, flags.progress, flags.limit_rate, flags.chunk_size);
+    let read_timeout = flags.read_timeout;
+    com.set_read_timeout((read_timeout > 0).then_some(Duration::from_secs(read_timeout)));
 
     // Try to lock state and get conversation privilege
     let mut state = match state.try_lock() {
@@ -214,10 +515,17 @@ pub async fn process_connection(
             .await
             .context("could not send greenlight for conversation")?;
 
+        com.answer_version()
+            .await
+            .context("client is not compatible with this server")?;
+
+        com.answer_compression(flags.compress).await?;
+
         let endpoint: AbstPath = com
             .get_struct()
             .await
             .context("could not get backup endpoint")?;
+        bbup::logger::set_endpoint(&endpoint);
 
         loop {
             let jt: JobType = com.get_struct().await.context("could not get job type")?;
@@ -227,10 +535,16 @@ pub async fn process_connection(
                     break;
                 }
                 JobType::Pull => {
-                    pull(&config, &state, &mut com, &endpoint).await?;
+                    pull(&config, &mut state, &mut com, &endpoint).await?;
                 }
                 JobType::Push => {
-                    push(&config, &mut state, &mut com, &endpoint).await?;
+                    push(&config, &mut state, &mut com, &endpoint, false, false).await?;
+                }
+                JobType::BlockPush => {
+                    push(&config, &mut state, &mut com, &endpoint, true, false).await?;
+                }
+                JobType::AddOnlyPush { block } => {
+                    push(&config, &mut state, &mut com, &endpoint, block, true).await?;
                 }
             }
         }
@@ -242,9 +556,545 @@ pub async fn process_connection(
         Ok(()) => Ok(()),
         Err(error) => {
             if let Err(err) = com.send_error(1, "error propagated from server").await {
-                println!("Could not propagate error to client, because {:#?}", err)
+                error!("Could not propagate error to client, because {:#?}", err)
             }
             Err(error)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_actions, process_connection, recover};
+    use crate::{ArchiveConfig, ArchiveState, Flags, Journal};
+
+    use abst_fs::{self as fs, AbstPath};
+    use bbup::com::{BbupCom, JobType, Queryable};
+    use fs_vcs::{
+        generate_fstree, get_delta, Action, Actions, CommitID, CommitMetadata, Delta, DeltaNode,
+        ExcludeList, FSNode, FSTree, HardLinkGroups,
+    };
+
+    use std::{collections::HashMap, future::Future, sync::Arc, time::Duration};
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        sync::Mutex,
+    };
+
+    /// Archive and link, both empty, under `test_path`
+    fn setup(test_path: &AbstPath) -> (ArchiveConfig, Arc<Mutex<ArchiveState>>, AbstPath) {
+        let archive_root = test_path.add_last("archive");
+        let link_root = test_path.add_last("link");
+        fs::create_dir(&archive_root).unwrap();
+        fs::create_dir(&link_root).unwrap();
+        ArchiveState::init_state().save(&archive_root).unwrap();
+
+        let config = ArchiveConfig {
+            archive_root: archive_root.clone(),
+            temp_dir: archive_root.add_last(".bbup").add_last("temp"),
+        };
+        let state = Arc::new(Mutex::new(ArchiveState::load(&archive_root).unwrap()));
+        (config, state, link_root)
+    }
+
+    fn write(path: &AbstPath, content: &[u8]) {
+        fs::ensure_parent(path).unwrap();
+        std::fs::write(path.to_path_buf(), content).unwrap();
+    }
+
+    /// Serve a single connection of `client`, which is given the port to connect
+    /// to, returning what the client and the server returned
+    fn serve<F, Fut>(
+        config: &ArchiveConfig,
+        state: &Arc<Mutex<ArchiveState>>,
+        client: F,
+    ) -> (Fut::Output, anyhow::Result<()>)
+    where
+        F: FnOnce(u16) -> Fut,
+        Fut: Future,
+    {
+        let flags = Flags {
+            progress: false,
+            limit_rate: 0,
+            compress: false,
+            chunk_size: None,
+            read_timeout: 0,
+        };
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let server = tokio::spawn({
+                let (config, state) = (config.clone(), state.clone());
+                async move {
+                    let (socket, _) = listener.accept().await.unwrap();
+                    process_connection(config, socket, state, flags).await
+                }
+            });
+            let client = client(port).await;
+            (client, server.await.unwrap())
+        })
+    }
+
+    /// Start a conversation with the server at port about the endpoint
+    async fn connect(port: u16, endpoint: &AbstPath, limit_rate: u64) -> BbupCom {
+        let socket = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let mut com = BbupCom::from(socket, false, limit_rate, None);
+        com.check_ok().await.unwrap();
+        com.request_version().await.unwrap();
+        com.request_compression(false).await.unwrap();
+        com.send_struct(endpoint.clone()).await.unwrap();
+        com
+    }
+
+    /// Changes of the link from `before` to its current content
+    fn changes(link_root: &AbstPath, before: &FSTree) -> Delta {
+        let tree = generate_fstree(link_root, &ExcludeList::from(&vec![]).unwrap()).unwrap();
+        get_delta(before, &tree)
+    }
+
+    /// Push `delta` as a job of type `job`, supplying the content it adds from
+    /// the link (under the prefix of `com`), without confirming the commit id
+    /// that the server sends back
+    async fn push_unconfirmed(
+        com: &mut BbupCom,
+        job: JobType,
+        link_root: &AbstPath,
+        delta: Delta,
+    ) -> Result<CommitID, bbup::com::Error> {
+        com.send_struct(job).await?;
+        com.check_ok().await?;
+        com.send_struct(delta.clone()).await?;
+        com.send_struct(HardLinkGroups::empty()).await?;
+        com.send_struct(CommitMetadata::default()).await?;
+        com.send_struct(1u64).await?;
+        let queryable = delta
+            .to_actions()
+            .into_iter()
+            .filter(|(_, action)| {
+                matches!(
+                    action,
+                    Action::AddFile(..) | Action::EditFile(_, Some(_), _) | Action::AddSymLink(..)
+                )
+            })
+            .filter_map(|(path, _)| path.relative_to(&com.prefix))
+            .collect::<Vec<AbstPath>>();
+        com.supply_files(&queryable, link_root).await?;
+
+        com.get_struct().await
+    }
+
+    /// Same as `push_unconfirmed`, confirming the commit id and then quitting
+    async fn push(
+        com: &mut BbupCom,
+        job: JobType,
+        link_root: &AbstPath,
+        delta: Delta,
+    ) -> Result<CommitID, bbup::com::Error> {
+        let commit_id = push_unconfirmed(com, job, link_root, delta).await?;
+        com.confirm().await?;
+        com.send_struct(JobType::Quit).await?;
+        com.check_ok().await?;
+        Ok(commit_id)
+    }
+
+    fn unconfirmed_push(test_path: &AbstPath) {
+        let (config, state, link_root) = setup(test_path);
+        let archive_root = &config.archive_root;
+        write(&link_root.add_last("notes.txt"), b"some notes");
+
+        let (commit_id, served) = serve(&config, &state, |port| async move {
+            let mut com = connect(port, &AbstPath::empty(), 0).await;
+            let delta = changes(&link_root, &FSTree::empty());
+            push_unconfirmed(&mut com, JobType::Push, &link_root, delta)
+                .await
+                .unwrap()
+        });
+        assert!(served.is_err());
+
+        // The content is in the archive, but the commit is not finalized
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut state = runtime.block_on(state.lock_owned());
+        assert!(archive_root.add_last("notes.txt").exists());
+        assert_ne!(state.commit_list.most_recent_commit().commit_id, commit_id);
+        assert_eq!(
+            Journal::load(archive_root).unwrap().unwrap().commit_id,
+            commit_id
+        );
+        let saved = ArchiveState::load(archive_root).unwrap();
+        assert_ne!(saved.commit_list.most_recent_commit().commit_id, commit_id);
+
+        // The recovery finalizes it
+        recover(&config, &mut state).unwrap();
+        assert_eq!(state.commit_list.most_recent_commit().commit_id, commit_id);
+        assert!(Journal::load(archive_root).unwrap().is_none());
+    }
+
+    fn resumed_push(test_path: &AbstPath) {
+        let (config, state, link_root) = setup(test_path);
+        let content: Vec<u8> = (0..400_000u32).map(|n| (n % 251) as u8).collect();
+        write(&link_root.add_last("dir").add_last("big.bin"), &content);
+
+        // The connection drops in the middle of the transfer, which is slowed
+        //	down so that it can't complete in time
+        let (interrupted, served) = serve(&config, &state, |port| {
+            let link_root = link_root.clone();
+            async move {
+                let mut com = connect(port, &AbstPath::empty(), 100_000).await;
+                let delta = changes(&link_root, &FSTree::empty());
+                tokio::time::timeout(
+                    Duration::from_secs(1),
+                    push(&mut com, JobType::Push, &link_root, delta),
+                )
+                .await
+            }
+        });
+        assert!(interrupted.is_err());
+        assert!(served.is_err());
+        let partial = config
+            .temp_dir
+            .add_last("dir")
+            .add_last(".big.bin.bbup-partial");
+        let received = std::fs::read(partial.to_path_buf()).unwrap();
+        assert!(!received.is_empty() && received.len() < content.len());
+        assert_eq!(received, content[..received.len()]);
+
+        // What is left by other pushes doesn't survive the next one
+        let stale = [
+            config.temp_dir.add_last("stale.txt"),
+            config
+                .temp_dir
+                .add_last("gone")
+                .add_last(".old.bbup-partial"),
+        ];
+        for path in &stale {
+            write(path, b"stale");
+        }
+
+        // The next push resumes the transfer from where it stopped
+        let (sent, served) = serve(&config, &state, |port| {
+            let link_root = link_root.clone();
+            async move {
+                let mut com = connect(port, &AbstPath::empty(), 0).await;
+                let delta = changes(&link_root, &FSTree::empty());
+                push(&mut com, JobType::Push, &link_root, delta)
+                    .await
+                    .unwrap();
+                com.traffic().bytes_sent
+            }
+        });
+        served.unwrap();
+        assert!(sent < (content.len() - received.len()) as u64 + 4096);
+        let archived = config.archive_root.add_last("dir").add_last("big.bin");
+        assert_eq!(std::fs::read(archived.to_path_buf()).unwrap(), content);
+        for path in stale.iter().chain([&partial]) {
+            assert!(!path.exists());
+        }
+        assert!(!config.temp_dir.add_last("gone").exists());
+    }
+
+    /// Push `delta` from the link to the endpoint as a job of type `job`
+    fn push_to(
+        config: &ArchiveConfig,
+        state: &Arc<Mutex<ArchiveState>>,
+        endpoint: &str,
+        job: JobType,
+        link_root: &AbstPath,
+        delta: Delta,
+    ) -> anyhow::Result<()> {
+        let (pushed, served) = serve(config, state, |port| {
+            let link_root = link_root.clone();
+            async move {
+                let mut com = connect(port, &AbstPath::from(endpoint), 0).await;
+                push(&mut com, job, &link_root, delta).await
+            }
+        });
+        assert_eq!(pushed.is_ok(), served.is_ok());
+        served
+    }
+
+    fn block_push(test_path: &AbstPath) {
+        let (config, state, link_root) = setup(test_path);
+        let archive_root = &config.archive_root;
+        write(&link_root.add_last("shared.txt"), b"shared");
+        write(
+            &link_root.add_last("block").add_last("readme.txt"),
+            b"readme",
+        );
+        let delta = changes(&link_root, &FSTree::empty());
+        push_to(&config, &state, "", JobType::Push, &link_root, delta).unwrap();
+
+        // A push inside of the block is applied
+        let block_link = test_path.add_last("block-link");
+        write(&block_link.add_last("notes.txt"), b"notes");
+        let delta = Delta(HashMap::from([(
+            String::from("notes.txt"),
+            DeltaNode::Leaf(None, Some(FSNode::file((1667000000, 0), "notes"))),
+        )]));
+        push_to(
+            &config,
+            &state,
+            "block",
+            JobType::BlockPush,
+            &block_link,
+            delta,
+        )
+        .unwrap();
+        let notes = archive_root.add_last("block").add_last("notes.txt");
+        assert_eq!(std::fs::read(notes.to_path_buf()).unwrap(), b"notes");
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let last_commit = |state: &Arc<Mutex<ArchiveState>>| {
+            let state = runtime.block_on(state.lock());
+            state.commit_list.most_recent_commit().commit_id.clone()
+        };
+        let committed = last_commit(&state);
+
+        // A push reaching outside of the block is rejected as a whole
+        let escape = Delta(HashMap::from([
+            (
+                String::from(".."),
+                DeltaNode::Branch(
+                    None,
+                    Delta(HashMap::from([(
+                        String::from("shared.txt"),
+                        DeltaNode::Leaf(Some(FSNode::file((1667000000, 0), "shared")), None),
+                    )])),
+                ),
+            ),
+            (
+                String::from("notes.txt"),
+                DeltaNode::Leaf(Some(FSNode::file((1667000000, 0), "notes")), None),
+            ),
+        ]));
+        let pushed = push_to(
+            &config,
+            &state,
+            "block",
+            JobType::BlockPush,
+            &block_link,
+            escape,
+        );
+        assert!(format!("{:?}", pushed.unwrap_err()).contains("outside of block"));
+        assert!(archive_root.add_last("shared.txt").exists());
+        assert!(notes.exists());
+
+        // And so is a block that is not confined to the archive
+        for endpoint in ["", "block/.."] {
+            let pushed = push_to(
+                &config,
+                &state,
+                endpoint,
+                JobType::BlockPush,
+                &block_link,
+                Delta::empty(),
+            );
+            assert!(format!("{:?}", pushed.unwrap_err()).contains("not a valid block"));
+        }
+        assert_eq!(last_commit(&state), committed);
+    }
+
+    fn add_only_push(test_path: &AbstPath) {
+        let (config, state, link_root) = setup(test_path);
+        let archive_root = &config.archive_root;
+        write(&link_root.add_last("existing.txt"), b"existing");
+        let delta = changes(&link_root, &FSTree::empty());
+        push_to(&config, &state, "", JobType::Push, &link_root, delta).unwrap();
+
+        let add_only = JobType::AddOnlyPush { block: false };
+        let added = |name: &str, content: &str| {
+            Delta(HashMap::from([(
+                String::from(name),
+                DeltaNode::Leaf(None, Some(FSNode::file((1667000000, 0), content))),
+            )]))
+        };
+
+        // New objects are added
+        let other_link = test_path.add_last("other-link");
+        write(&other_link.add_last("new.txt"), b"new");
+        let delta = added("new.txt", "new");
+        push_to(&config, &state, "", add_only.clone(), &other_link, delta).unwrap();
+        assert!(archive_root.add_last("new.txt").exists());
+
+        // Objects that already exist are not overwritten, even if the client
+        //	doesn't know about them
+        write(&other_link.add_last("existing.txt"), b"overwritten");
+        let delta = added("existing.txt", "overwritten");
+        let pushed = push_to(&config, &state, "", add_only.clone(), &other_link, delta);
+        assert!(format!("{:?}", pushed.unwrap_err()).contains("already exists"));
+
+        // Nor removed
+        let delta = Delta(HashMap::from([(
+            String::from("new.txt"),
+            DeltaNode::Leaf(Some(FSNode::file((1667000000, 0), "new")), None),
+        )]));
+        let pushed = push_to(&config, &state, "", add_only, &other_link, delta);
+        assert!(format!("{:?}", pushed.unwrap_err()).contains("is not an addition"));
+
+        let existing = archive_root.add_last("existing.txt");
+        assert_eq!(std::fs::read(existing.to_path_buf()).unwrap(), b"existing");
+        assert!(archive_root.add_last("new.txt").exists());
+    }
+
+    fn crashed_push(test_path: &AbstPath) {
+        let (config, state, link_root) = setup(test_path);
+        let archive_root = &config.archive_root;
+        write(&link_root.add_last("removed.txt"), b"removed");
+        write(&link_root.add_last("edited.txt"), b"before");
+        write(&link_root.add_last("dir").add_last("kept.txt"), b"kept");
+        let before = generate_fstree(&link_root, &ExcludeList::from(&vec![]).unwrap()).unwrap();
+        let delta = changes(&link_root, &FSTree::empty());
+        push_to(&config, &state, "", JobType::Push, &link_root, delta).unwrap();
+
+        fs::remove_file(&link_root.add_last("removed.txt")).unwrap();
+        write(&link_root.add_last("edited.txt"), b"after");
+        write(&link_root.add_last("dir").add_last("added.txt"), b"added");
+        write(&link_root.add_last("new").add_last("added.txt"), b"new");
+        let delta = changes(&link_root, &before);
+
+        // The push is interrupted after the content has been uploaded and the
+        //	journal saved, while the actions are being applied
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut state = runtime.block_on(state.lock_owned());
+        let actions = delta.to_actions();
+        for (path, action) in &actions {
+            if let Action::AddFile(..) | Action::EditFile(_, Some(_), _) = action {
+                let content = std::fs::read(link_root.append(path).to_path_buf()).unwrap();
+                write(&config.temp_dir.append(path), &content);
+            }
+        }
+        let mut updated_archive_tree = state.archive_tree.clone();
+        updated_archive_tree
+            .apply_delta_at_endpoint(&delta, AbstPath::empty())
+            .unwrap();
+        let journal = Journal {
+            commit_id: CommitID::gen_valid(),
+            endpoint: AbstPath::empty(),
+            delta,
+            hardlinks: HardLinkGroups::empty(),
+            updated_archive_tree,
+            metadata: CommitMetadata::default(),
+        };
+        journal.save(archive_root).unwrap();
+        let count = (&actions).into_iter().count();
+        assert!(count > 3);
+        let mut applied = Actions::new();
+        for (path, action) in actions.into_iter().take(count / 2) {
+            applied.push(path, action);
+        }
+        apply_actions(&config, &AbstPath::empty(), applied).unwrap();
+        let previous = state.commit_list.most_recent_commit().commit_id.clone();
+        assert_ne!(previous, journal.commit_id);
+
+        // The recovery applies the remaining actions and saves the commit
+        recover(&config, &mut state).unwrap();
+        assert_eq!(
+            state.commit_list.most_recent_commit().commit_id,
+            journal.commit_id
+        );
+        assert!(Journal::load(archive_root).unwrap().is_none());
+        let archived = generate_fstree(archive_root, &ExcludeList::from(&vec![]).unwrap()).unwrap();
+        assert_eq!(archived, state.archive_tree);
+        let linked = generate_fstree(&link_root, &ExcludeList::from(&vec![]).unwrap()).unwrap();
+        assert!(get_delta(&linked, &archived).to_actions().is_empty());
+        let saved = ArchiveState::load(archive_root).unwrap();
+        assert_eq!(
+            saved.commit_list.most_recent_commit().commit_id,
+            journal.commit_id
+        );
+        assert_eq!(saved.archive_tree, state.archive_tree);
+
+        // Recovering again finds nothing to do
+        recover(&config, &mut state).unwrap();
+        assert_eq!(
+            state.commit_list.most_recent_commit().commit_id,
+            journal.commit_id
+        );
+    }
+
+    fn injective_sync(test_path: &AbstPath) {
+        let (config, state, link_root) = setup(test_path);
+        let archive_root = &config.archive_root;
+        let endpoint = AbstPath::from("backups/desktop");
+        write(&link_root.add_last("unrelated.txt"), b"unrelated");
+        std::fs::create_dir_all(link_root.append(&endpoint).to_path_buf()).unwrap();
+        let delta = changes(&link_root, &FSTree::empty());
+        push_to(&config, &state, "", JobType::Push, &link_root, delta).unwrap();
+
+        // An injective link pushes its changes to the archive root, prefixed with
+        //	its endpoint
+        let desktop = test_path.add_last("desktop");
+        write(&desktop.add_last("notes.txt"), b"notes");
+        let pushed = changes(&desktop, &FSTree::empty());
+        let (result, served) = serve(&config, &state, |port| {
+            let (desktop, delta) = (desktop.clone(), pushed.clone().prefixed(&endpoint));
+            let endpoint = endpoint.clone();
+            async move {
+                let mut com = connect(port, &AbstPath::empty(), 0).await;
+                com.prefix = endpoint;
+                push(&mut com, JobType::Push, &desktop, delta).await
+            }
+        });
+        result.unwrap();
+        served.unwrap();
+        let read = |path: AbstPath| std::fs::read(path.to_path_buf()).unwrap();
+        assert_eq!(
+            read(archive_root.append(&endpoint).add_last("notes.txt")),
+            b"notes"
+        );
+        assert_eq!(read(archive_root.add_last("unrelated.txt")), b"unrelated");
+
+        // Another injective link on the same endpoint pulls the updates of the
+        //	whole archive, and gets back exactly what was pushed once the
+        //	endpoint is stripped
+        let laptop = test_path.add_last("laptop");
+        fs::create_dir(&laptop).unwrap();
+        let (pulled, served) = serve(&config, &state, |port| {
+            let (laptop, endpoint) = (laptop.clone(), endpoint.clone());
+            async move {
+                let mut com = connect(port, &AbstPath::empty(), 0).await;
+                com.prefix = endpoint.clone();
+                com.send_struct(JobType::Pull).await.unwrap();
+                com.send_struct(CommitID::null()).await.unwrap();
+                let delta: Delta = com.get_struct().await.unwrap();
+                let _: CommitID = com.get_struct().await.unwrap();
+                let delta = delta.get_subdelta_tree_copy(&endpoint).unwrap();
+                com.query_files(
+                    vec![(
+                        Queryable::File,
+                        AbstPath::from("notes.txt"),
+                        hasher::hash_bytes(b"notes"),
+                    )],
+                    &laptop,
+                    None,
+                    1,
+                )
+                .await
+                .unwrap();
+                com.send_struct(JobType::Quit).await.unwrap();
+                com.check_ok().await.unwrap();
+                delta
+            }
+        });
+        served.unwrap();
+        assert_eq!(pulled, pushed);
+        assert_eq!(read(laptop.add_last("notes.txt")), b"notes");
+    }
+
+    #[test]
+    fn test() {
+        let test_path = AbstPath::from("/tmp/bbup-test-server-process");
+        assert!(!test_path.exists());
+        fs::create_dir(&test_path).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            unconfirmed_push(&test_path.add_last("unconfirmed"));
+            resumed_push(&test_path.add_last("resumed"));
+            block_push(&test_path.add_last("block"));
+            add_only_push(&test_path.add_last("add-only"));
+            crashed_push(&test_path.add_last("crashed"));
+            injective_sync(&test_path.add_last("injective"));
+        });
+        fs::remove_dir_all(&test_path).unwrap();
+        assert!(result.is_ok());
+    }
+}
@@ -0,0 +1,245 @@
+use super::ArchiveState;
+
+use abst_fs::{self as fs, AbstPath, Mode, Mtime, ObjectType};
+use fs_vcs::{FSNode, FSTree};
+use hasher::Hash;
+
+use anyhow::{Context, Result};
+
+/// Content-addressed store of the archive, kept in `.bbup/store` only if
+/// enabled for the archive. Every file of the archive is then a hard link to a
+/// blob of the store named after its hash, so that the files with the same
+/// content take the space of a single one, even across endpoints. As hard links
+/// share mode and mtime, these are part of the name of the blob as well
+pub struct ContentStore {
+    root: AbstPath,
+}
+impl ContentStore {
+    fn path(archive_root: &AbstPath) -> AbstPath {
+        archive_root.add_last(".bbup").add_last("store")
+    }
+    /// Store of the archive, if enabled
+    pub fn open(archive_root: &AbstPath) -> Option<ContentStore> {
+        let root = ContentStore::path(archive_root);
+        match root.object_type() {
+            Some(ObjectType::Dir) => Some(ContentStore { root }),
+            _ => None,
+        }
+    }
+    /// Enable the store for the archive, leaving it empty
+    pub fn enable(archive_root: &AbstPath) -> Result<ContentStore> {
+        let root = ContentStore::path(archive_root);
+        fs::create_dir(&root).context("could not create content store")?;
+        Ok(ContentStore { root })
+    }
+
+    fn blob_path(&self, hash: &Hash, mode: &Mode, mtime: &Mtime) -> AbstPath {
+        let hash = hash.to_string();
+        let mtime: String = mtime
+            .to_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        self.root
+            .add_last(&hash[..2])
+            .add_last(format!("{hash}-{mode}-{mtime}"))
+    }
+
+    /// Make the file at path a hard link to the blob of its content, mode and
+    /// mtime, adding the file to the store as the blob if there is none yet. The
+    /// hash of the content is computed if not given
+    pub fn link(&self, path: &AbstPath, hash: Option<&Hash>) -> Result<()> {
+        let errmsg =
+            |msg: &str| format!("could not {msg} to link file to the content store\npath: {path}");
+        let hash = match hash {
+            Some(hash) => hash.clone(),
+            None => {
+                let file = fs::read_file(path).context(errmsg("open file"))?;
+                hasher::hash_stream(file).context(errmsg("hash file"))?
+            }
+        };
+        let mode = fs::get_mode(path).context(errmsg("get mode"))?;
+        let mtime = fs::get_mtime(path).context(errmsg("get mtime"))?;
+        let blob = self.blob_path(&hash, &mode, &mtime);
+        match blob.exists() {
+            true => keeping_parent_mtime(path, || fs::replace_with_hardlink(&blob, path))
+                .context(errmsg("link blob")),
+            false => fs::create_hardlink(path, &blob).context(errmsg("add blob")),
+        }
+    }
+
+    /// Link every file of the tree, found at path, to the store
+    pub fn link_tree(&self, path: &AbstPath, FSTree(tree): &FSTree) -> Result<()> {
+        for (name, node) in tree {
+            match node {
+                FSNode::File(_, hash, _, _) => self.link(&path.add_last(name), Some(hash))?,
+                FSNode::Dir(_, _, subtree) => self.link_tree(&path.add_last(name), subtree)?,
+                FSNode::SymLink(_, _) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Prepare the file at path to have its mode or mtime changed to the given
+    /// ones, giving it its own copy of the content if they differ from the
+    /// current ones, so that the other files linked to the same blob are not
+    /// affected. The file should be linked again once changed
+    pub fn unlink_for_edit(
+        &self,
+        path: &AbstPath,
+        mode: Option<&Mode>,
+        mtime: Option<&Mtime>,
+    ) -> Result<()> {
+        let errmsg = |msg: &str| format!("could not {msg} to edit linked file\npath: {path}");
+        let mode_changes = match mode {
+            Some(mode) => fs::get_mode(path).context(errmsg("get mode"))? != *mode,
+            None => false,
+        };
+        let mtime_changes = match mtime {
+            Some(mtime) => fs::get_mtime(path).context(errmsg("get mtime"))? != *mtime,
+            None => false,
+        };
+        if mode_changes || mtime_changes {
+            keeping_parent_mtime(path, || fs::unshare_file(path))
+                .context(errmsg("copy content"))?;
+        }
+        Ok(())
+    }
+
+    /// Remove the blobs that no file of the archive links to anymore, returning
+    /// how many were removed
+    pub fn prune(&self) -> Result<usize> {
+        let errmsg = "could not prune content store";
+        let mut removed = 0;
+        for dir in fs::list_dir_content(&self.root).context(errmsg)? {
+            if dir.object_type() != Some(ObjectType::Dir) {
+                continue;
+            }
+            for blob in fs::list_dir_content(&dir).context(errmsg)? {
+                if fs::hardlink_count(&blob).context(errmsg)? <= 1 {
+                    fs::remove_file(&blob).context(errmsg)?;
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Number of blobs in the store
+    pub fn blob_count(&self) -> Result<usize> {
+        let errmsg = "could not count blobs of content store";
+        let mut count = 0;
+        for dir in fs::list_dir_content(&self.root).context(errmsg)? {
+            if dir.object_type() == Some(ObjectType::Dir) {
+                count += fs::list_dir_content(&dir).context(errmsg)?.len();
+            }
+        }
+        Ok(count)
+    }
+}
+
+/// Replacing a file touches its parent directory, whose mtime is restored as
+/// the archive should only change as the commits say
+fn keeping_parent_mtime(
+    path: &AbstPath,
+    replace: impl FnOnce() -> Result<(), fs::Error>,
+) -> Result<(), fs::Error> {
+    let parent = path.strip_last();
+    let mtime = fs::get_mtime(&parent)?;
+    replace()?;
+    fs::set_mtime(&parent, &mtime)
+}
+
+/// Enable the content store for the archive, linking the files already in it
+pub fn enable_store(archive_root: &AbstPath) -> Result<()> {
+    if ContentStore::open(archive_root).is_some() {
+        println!("content store is already enabled");
+        return Ok(());
+    }
+    let state = ArchiveState::load(archive_root).context("failed to load archive's state")?;
+    let store = ContentStore::enable(archive_root)?;
+    store.link_tree(archive_root, &state.archive_tree)?;
+
+    let summary = state.archive_tree.summary();
+    println!(
+        "content store enabled: {} files stored as {} blobs",
+        summary.count.files,
+        store.blob_count()?
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ContentStore;
+    use abst_fs::{self as fs, AbstPath, Mtime};
+    use std::io::Read;
+
+    #[test]
+    fn test() {
+        let test_path = AbstPath::from("/tmp/bbup-test-content-store");
+        assert!(!test_path.exists());
+        fs::create_dir(&test_path).unwrap();
+
+        let result = std::panic::catch_unwind(|| dedup(&test_path));
+        fs::remove_dir_all(&test_path).unwrap();
+        assert!(result.is_ok());
+    }
+
+    fn read(path: &AbstPath) -> String {
+        let mut content = String::new();
+        fs::read_file(path)
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        content
+    }
+
+    fn add(store: &ContentStore, path: &AbstPath, content: &str, mtime: &Mtime) {
+        let mut file = fs::create_file(path).unwrap();
+        std::io::Write::write_all(&mut file, content.as_bytes()).unwrap();
+        drop(file);
+        fs::set_mtime(path, mtime).unwrap();
+        store
+            .link(path, Some(&hasher::hash_bytes(content)))
+            .unwrap();
+    }
+
+    fn dedup(test_path: &AbstPath) {
+        let store = ContentStore::enable(test_path).unwrap();
+        assert!(ContentStore::open(test_path).is_some());
+
+        let mtime = Mtime::from(1667000000, 0);
+        let first = test_path.add_last("first.txt");
+        let second = test_path.add_last("second.txt");
+        add(&store, &first, "same content", &mtime);
+        add(&store, &second, "same content", &mtime);
+
+        // The same content is stored once, and both paths read it back
+        assert_eq!(store.blob_count().unwrap(), 1);
+        assert_eq!(read(&first), "same content");
+        assert_eq!(read(&second), "same content");
+        assert_eq!(fs::hardlink_count(&first).unwrap(), 3);
+
+        // A different mtime needs its own blob, as hard links share it
+        let third = test_path.add_last("third.txt");
+        add(&store, &third, "same content", &Mtime::from(1667000100, 0));
+        assert_eq!(store.blob_count().unwrap(), 2);
+        assert_eq!(fs::get_mtime(&first).unwrap(), mtime);
+
+        // Editing the metadata of a linked file leaves the others untouched
+        let edited = Mtime::from(1667000200, 0);
+        store.unlink_for_edit(&second, None, Some(&edited)).unwrap();
+        fs::set_mtime(&second, &edited).unwrap();
+        store.link(&second, None).unwrap();
+        assert_eq!(fs::get_mtime(&first).unwrap(), mtime);
+        assert_eq!(read(&second), "same content");
+        assert_eq!(store.blob_count().unwrap(), 3);
+
+        // Blobs no file links to anymore are pruned
+        fs::remove_file(&third).unwrap();
+        assert_eq!(store.prune().unwrap(), 1);
+        assert_eq!(store.blob_count().unwrap(), 2);
+        assert_eq!(read(&first), "same content");
+    }
+}
@@ -0,0 +1,106 @@
+use super::ArchiveState;
+
+use abst_fs::AbstPath;
+use fs_vcs::{generate_fstree, get_delta, Delta, ExcludeList, FSNode, FSTree};
+
+use anyhow::{Context, Result};
+
+/// Regenerate the tree of the archive at the endpoint and compare it with the
+/// stored tree, reporting every path that differs. Nothing is modified
+pub fn verify(archive_root: &AbstPath, endpoint: &AbstPath) -> Result<()> {
+    let state = ArchiveState::load(archive_root).context("failed to load archive's state")?;
+    let drift = drift(archive_root, &state, endpoint)?;
+
+    if drift.is_empty() {
+        println!("archive matches the stored tree");
+        Ok(())
+    } else {
+        println!("archive differs from the stored tree:\n{}", drift.stats());
+        println!("{}", drift.to_actions());
+        anyhow::bail!("archive at [{endpoint}] drifted from the stored tree")
+    }
+}
+
+/// Changes of the archive at the endpoint with respect to the stored tree
+fn drift(archive_root: &AbstPath, state: &ArchiveState, endpoint: &AbstPath) -> Result<Delta> {
+    let empty = FSTree::empty();
+    let stored_tree = match endpoint.is_empty() {
+        true => &state.archive_tree,
+        false => match state.archive_tree.get_at(endpoint) {
+            Some(FSNode::Dir(_, _, subtree)) => subtree,
+            _ => {
+                println!("endpoint [{endpoint}] is not in the stored tree");
+                &empty
+            }
+        },
+    };
+
+    let actual_tree = generate_fstree(&archive_root.append(endpoint), &ExcludeList::from(&vec![])?)
+        .context("could not generate archive's tree")?;
+    // Equal root hashes mean equal trees, no need to walk them to diff them
+    Ok(match stored_tree.root_hash() == actual_tree.root_hash() {
+        true => Delta::empty(),
+        false => get_delta(stored_tree, &actual_tree),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{drift, verify};
+    use crate::ArchiveState;
+
+    use abst_fs::{self as fs, AbstPath};
+    use fs_vcs::{generate_fstree, Action, CommitList, ExcludeList};
+
+    fn tampered(archive_root: &AbstPath) {
+        let write = |path: &str, content: &str| {
+            let path = archive_root.append(&AbstPath::from(path));
+            fs::ensure_parent(&path).unwrap();
+            std::fs::write(path.to_path_buf(), content).unwrap();
+        };
+        write("docs/report.txt", "report");
+        write("docs/notes.txt", "notes");
+        write("photos/cat.jpg", "cat");
+        let tree = generate_fstree(archive_root, &ExcludeList::from(&vec![]).unwrap()).unwrap();
+        ArchiveState::from(CommitList::base_commit_list(), tree)
+            .save(archive_root)
+            .unwrap();
+
+        let state = ArchiveState::load(archive_root).unwrap();
+        assert!(drift(archive_root, &state, &AbstPath::empty())
+            .unwrap()
+            .is_empty());
+        assert!(verify(archive_root, &AbstPath::empty()).is_ok());
+
+        // Same size and mtime, different content
+        let notes = archive_root.append(&AbstPath::from("docs/notes.txt"));
+        let mtime = fs::get_mtime(&notes).unwrap();
+        write("docs/notes.txt", "NOTES");
+        fs::set_mtime(&notes, &mtime).unwrap();
+
+        // Exactly the tampered file is flagged, from the root and from the
+        //	endpoint containing it
+        for (endpoint, path) in [("", "docs/notes.txt"), ("docs", "notes.txt")] {
+            let endpoint = AbstPath::from(endpoint);
+            let drift = drift(archive_root, &state, &endpoint).unwrap();
+            let actions: Vec<(AbstPath, Action)> = drift.to_actions().into_iter().collect();
+            assert_eq!(actions.len(), 1);
+            assert_eq!(actions[0].0, AbstPath::from(path));
+            assert!(matches!(actions[0].1, Action::EditFile(_, Some(_), _)));
+            assert!(verify(archive_root, &endpoint).is_err());
+        }
+        // Other endpoints are not affected
+        assert!(verify(archive_root, &AbstPath::from("photos")).is_ok());
+    }
+
+    #[test]
+    fn test() {
+        let test_path = AbstPath::from("/tmp/bbup-test-server-verify");
+        assert!(!test_path.exists());
+        fs::create_dir(&test_path).unwrap();
+
+        let result = std::panic::catch_unwind(|| tampered(&test_path));
+        fs::remove_dir_all(&test_path).unwrap();
+        assert!(result.is_ok());
+    }
+}
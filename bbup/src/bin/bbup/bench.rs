@@ -0,0 +1,188 @@
+use abst_fs::{self as fs, AbstPath};
+use fs_vcs::{generate_fstree, get_delta, ExcludeList, FSTree};
+
+use anyhow::{Context, Result};
+
+use std::io::Write;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+/// Number of files in each directory of the synthetic tree
+const FILES_PER_DIR: usize = 100;
+
+/// Time taken by one of the benchmarked operations
+struct Measure {
+    name: &'static str,
+    items: usize,
+    bytes: Option<u64>,
+    elapsed: Duration,
+}
+impl Measure {
+    fn items_per_sec(&self) -> f64 {
+        self.items as f64 / self.seconds()
+    }
+    fn mib_per_sec(&self) -> Option<f64> {
+        self.bytes
+            .map(|bytes| bytes as f64 / (1024.0 * 1024.0) / self.seconds())
+    }
+    fn seconds(&self) -> f64 {
+        // Avoid dividing by zero on operations too fast to be measured
+        self.elapsed.as_secs_f64().max(1e-9)
+    }
+}
+impl std::fmt::Display for Measure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} items in {:.3}s ({:.0} items/s",
+            self.name,
+            self.items,
+            self.elapsed.as_secs_f64(),
+            self.items_per_sec()
+        )?;
+        if let Some(mib_per_sec) = self.mib_per_sec() {
+            write!(f, ", {:.1} MiB/s", mib_per_sec)?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Names of the directories of the synthetic tree, with the indices of the
+/// files each of them contains
+fn layout(files: usize) -> impl Iterator<Item = (String, Range<usize>)> {
+    (0..files.div_ceil(FILES_PER_DIR)).map(move |dir| {
+        let first = dir * FILES_PER_DIR;
+        (format!("d{dir}"), first..(first + FILES_PER_DIR).min(files))
+    })
+}
+
+/// Content of the file with the given index, different if the file is edited
+fn content(index: usize, file_size: usize, edited: bool) -> String {
+    let seed = match edited {
+        true => format!("{index}* "),
+        false => format!("{index} "),
+    };
+    seed.chars().cycle().take(file_size).collect()
+}
+
+/// Synthetic tree of `files` files of `file_size` bytes each. If `edit` is set,
+/// every other file has a different content and mtime
+fn synthetic_tree(files: usize, file_size: usize, edit: bool) -> FSTree {
+    FSTree::gen_from(|t| {
+        for (dir, indices) in layout(files) {
+            t.add_dir(dir, (0, 0), |t| {
+                for index in indices.clone() {
+                    let edited = edit && index.is_multiple_of(2);
+                    let mtime = (edited as i64, 0);
+                    t.add_file(
+                        format!("f{index}"),
+                        mtime,
+                        content(index, file_size, edited),
+                    );
+                }
+            });
+        }
+    })
+}
+
+/// Write the synthetic tree (without edits) to the file system at `root`
+fn write_synthetic_tree(root: &AbstPath, files: usize, file_size: usize) -> Result<()> {
+    for (dir, indices) in layout(files) {
+        let dir = root.add_last(dir);
+        fs::create_dir(&dir).context("could not create directory of synthetic tree")?;
+        for index in indices {
+            let mut file = fs::create_file(&dir.add_last(format!("f{index}")))
+                .context("could not create file of synthetic tree")?;
+            file.write_all(content(index, file_size, false).as_bytes())
+                .context("could not write file of synthetic tree")?;
+        }
+    }
+    Ok(())
+}
+
+/// Time the generation of the tree of a synthetic directory at `root`, the
+/// delta between two synthetic trees and the actions of that delta
+fn run(root: &AbstPath, files: usize, file_size: usize) -> Result<Vec<Measure>> {
+    fs::make_clean_dir(root).context("could not create directory for benchmark")?;
+    write_synthetic_tree(root, files, file_size)?;
+
+    let start = Instant::now();
+    let tree = generate_fstree(root, &ExcludeList::from(&Vec::new())?)
+        .context("could not generate tree of synthetic directory")?;
+    let elapsed = start.elapsed();
+    let summary = tree.summary();
+    let generate = Measure {
+        name: "generate_fstree",
+        items: summary.count.total(),
+        bytes: Some(summary.total_size),
+        elapsed,
+    };
+
+    let before = synthetic_tree(files, file_size, false);
+    let after = synthetic_tree(files, file_size, true);
+    let start = Instant::now();
+    let delta = get_delta(&before, &after);
+    let elapsed = start.elapsed();
+    let delta_measure = Measure {
+        name: "get_delta",
+        items: before.summary().count.total() + after.summary().count.total(),
+        bytes: None,
+        elapsed,
+    };
+
+    let start = Instant::now();
+    let actions = delta.to_actions();
+    let elapsed = start.elapsed();
+    let actions_measure = Measure {
+        name: "to_actions",
+        items: (&actions).into_iter().count(),
+        bytes: None,
+        elapsed,
+    };
+
+    Ok(vec![generate, delta_measure, actions_measure])
+}
+
+/// Time the operations that dominate a sync on a synthetic tree of `files`
+/// files of `file_size` bytes each, and print their throughput. The tree is
+/// written to a temporary directory, removed afterwards
+pub fn bench(files: usize, file_size: usize) -> Result<()> {
+    let root =
+        AbstPath::from(std::env::temp_dir()).add_last(format!("bbup-bench-{}", std::process::id()));
+    let measures = run(&root, files, file_size);
+    if root.exists() {
+        fs::remove_dir_all(&root).context("could not remove directory of benchmark")?;
+    }
+
+    for measure in measures? {
+        println!("{measure}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::run;
+    use abst_fs::{self as fs, AbstPath};
+
+    #[test]
+    fn test() {
+        let root = AbstPath::from("/tmp/bbup-test-bench");
+        assert!(!root.exists());
+
+        let result = std::panic::catch_unwind(|| {
+            // A tiny tree, spanning more than one directory
+            let measures = run(&root, 150, 16).unwrap();
+            let names: Vec<&str> = measures.iter().map(|measure| measure.name).collect();
+            assert_eq!(names, vec!["generate_fstree", "get_delta", "to_actions"]);
+            for measure in &measures {
+                assert!(measure.items > 0);
+                assert!(measure.items_per_sec() > 0.0);
+            }
+            assert!(measures[0].mib_per_sec().unwrap() > 0.0);
+            assert!(measures[0].to_string().contains("MiB/s"));
+        });
+        fs::remove_dir_all(&root).unwrap();
+        assert!(result.is_ok());
+    }
+}
@@ -0,0 +1,55 @@
+use anyhow::Result;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// Handle Ctrl-C for the rest of the process: the first one asks the sync to
+/// stop at the next safe point, keeping the work already completed, while the
+/// second one quits immediately
+pub fn install_handler() {
+    tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        cancel();
+        eprintln!(
+            "cancelling sync after the current action, press Ctrl-C again to quit immediately"
+        );
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+}
+
+/// Ask the sync to stop at the next safe point
+pub fn cancel() {
+    CANCELLED.store(true, Ordering::SeqCst);
+}
+
+/// Forget a previous cancellation, as the tests cancel more than one sync
+#[cfg(test)]
+pub fn reset() {
+    CANCELLED.store(false, Ordering::SeqCst);
+}
+
+/// Lock held by the tests that run a sync, or part of it, as a test cancelling
+/// its sync would cancel every other sync running at the same time
+#[cfg(test)]
+pub fn lock() -> std::sync::MutexGuard<'static, ()> {
+    static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+pub fn is_cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+/// Fail if the sync has been cancelled, to be called before starting any new
+/// step of the sync
+pub fn check() -> Result<()> {
+    if is_cancelled() {
+        anyhow::bail!("sync cancelled");
+    }
+    Ok(())
+}
@@ -4,7 +4,12 @@ use abst_fs::AbstPath;
 
 use anyhow::Result;
 
-pub fn init(cwd: &AbstPath, opt_endpoint: Option<String>, no_exclude_list: bool) -> Result<()> {
+pub fn init(
+    cwd: &AbstPath,
+    opt_endpoint: Option<String>,
+    link_type: LinkType,
+    no_exclude_list: bool,
+) -> Result<()> {
     if LinkConfig::exists(cwd) {
         anyhow::bail!("Current directory [{cwd}] is already initialized as a backup source")
     }
@@ -25,14 +30,14 @@ pub fn init(cwd: &AbstPath, opt_endpoint: Option<String>, no_exclude_list: bool)
             println!("add regex rules in string form. To stop, enter empty string");
             loop {
                 let rule = input::get("rule: ")?;
-                if rule.eq("") {
+                if rule.is_empty() {
                     break;
                 }
                 exclude_list.push(rule);
             }
         }
     }
-    LinkConfig::from(LinkType::Bijection, endpoint, exclude_list).save(cwd)?;
+    LinkConfig::from(link_type, endpoint, exclude_list).save(cwd)?;
     ProcessState::init_state().save(cwd)?;
 
     println!("backup source initialized correctly!");
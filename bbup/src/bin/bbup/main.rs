@@ -1,17 +1,25 @@
 mod model;
 use model::*;
+mod bench;
+mod cancel;
 mod init;
 mod protocol;
+mod repair;
 mod setup;
+mod status;
 mod sync;
+mod undo;
 
-use abst_fs as fs;
+use abst_fs::{self as fs, MtimePrecision};
 
-use fs_vcs::ExcludeList;
+use fs_vcs::{CommitID, CommitMetadata, ConflictStrategy, DivergencePolicy, TargetOs};
+use ssh_tunnel::SshOptions;
 
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 
+use std::io::IsTerminal;
+
 #[derive(Subcommand, Debug, PartialEq)]
 enum SubCommand {
     /// Pull updates from server and push local updates
@@ -23,13 +31,199 @@ enum SubCommand {
         /// Show progress during file transfer
         #[clap(short, long, value_parser)]
         progress: bool,
+
+        /// Show the actions that would be performed, without applying them
+        #[clap(long, value_parser)]
+        dry_run: bool,
+
+        /// Print the preview of a dry run as JSON
+        #[clap(long, value_parser, requires = "dry-run")]
+        json: bool,
+
+        /// Write a line of JSON for each action applied locally (path, kind,
+        /// bytes written and duration in seconds) to the given file or named
+        /// pipe as soon as it is applied, or to stdout if '-'
+        #[clap(long, value_parser, value_name = "PATH")]
+        events: Option<String>,
+
+        /// Limit file transfers to the given bytes per second (e.g. 500K, 2M).
+        /// 0 means unlimited
+        #[clap(long, value_parser = bbup::com::parse_rate, default_value = "0")]
+        limit_rate: u64,
+
+        /// Compress file transfers, if the server accepts to
+        #[clap(short = 'z', long, value_parser)]
+        compress: bool,
+
+        /// Transfer file content in chunks of the given size (e.g. 256K, 4M).
+        /// Larger chunks perform better on high-latency links
+        #[clap(long, value_parser = bbup::com::parse_chunk_size)]
+        chunk_size: Option<usize>,
+
+        /// How to resolve conflicts between local changes and pulled updates
+        /// (prefer-local, prefer-remote, abort or keep-both, which takes the
+        /// pulled version of conflicting files and keeps the local version in a
+        /// copy named <file>.conflict-<timestamp>)
+        #[clap(long, value_parser, default_value = "abort")]
+        on_conflict: ConflictStrategy,
+
+        /// How to handle local files and symlinks whose content changed without
+        /// their mtime moving forward since the last sync, as happens when they
+        /// are restored out of band from an older copy (trust-local pushes them
+        /// as usual, ignore leaves them out of the push without restoring them,
+        /// so every sync finds them again, and abort stops the sync)
+        #[clap(long, value_parser, default_value = "trust-local")]
+        on_divergence: DivergencePolicy,
+
+        /// Maximum number of attempts to connect to the server, in case of
+        /// transient connection failures
+        #[clap(long, value_parser = clap::value_parser!(u32).range(1..), default_value = "5")]
+        max_attempts: u32,
+
+        /// Delay in milliseconds before retrying to connect, doubled at every
+        /// failed attempt
+        #[clap(long, value_parser, default_value = "1000")]
+        retry_delay: u64,
+
+        /// Maximum number of times an operation on the file system is retried,
+        /// in case of transient failures while applying the pulled update
+        #[clap(long, value_parser, default_value = "3")]
+        io_retries: u32,
+
+        /// Give up if the server sends nothing for the given number of seconds.
+        /// 0 means wait forever
+        #[clap(long, value_parser, default_value = "300")]
+        read_timeout: u64,
+
+        /// Transfer up to the given number of files and symlinks per round trip,
+        /// instead of waiting for each one before asking for the next. Speeds up
+        /// syncs of many small files over high-latency connections
+        #[clap(long, value_parser = clap::value_parser!(u64).range(1..=bbup::com::MAX_BATCH_SIZE), default_value = "1")]
+        parallel: u64,
+
+        /// Push files that are hard links to the same object only once, and
+        /// recreate the links on the server
+        #[clap(long, value_parser)]
+        hardlinks: bool,
+
+        /// Push only the local changes at or under the given path (relative to
+        /// the link root). Other local changes are kept for a later sync
+        #[clap(long, value_parser)]
+        only: Option<String>,
+
+        /// Precision at which mtimes are compared to detect local changes (full,
+        /// ms or s). Useful when the link is on a file system with coarse mtimes
+        #[clap(long, value_parser, default_value = "full")]
+        mtime_precision: MtimePrecision,
+
+        /// Remove from the endpoint the tracked objects that now match the
+        /// exclude list. By default they are kept on the endpoint, untouched
+        #[clap(long, value_parser)]
+        delete_excluded: bool,
+
+        /// Only push new objects: local removals and edits are not pushed, and
+        /// the server rejects the push if it would delete or overwrite anything.
+        /// Meant for append-only endpoints
+        #[clap(long, value_parser, conflicts_with = "delete-excluded")]
+        add_only: bool,
+
+        /// Check that the names of the link are valid on the given operating
+        /// system (unix or windows) and abort before transferring anything if
+        /// they are not. With windows, names with characters such as ':' or
+        /// '?', reserved names such as 'con.txt' and paths too long are reported
+        #[clap(long, value_parser)]
+        target_os: Option<TargetOs>,
+
+        /// Bootstrap the link from the given commit: the current content of the
+        /// link is taken as the snapshot of that commit, and only the commits
+        /// after it are pulled
+        #[clap(long, value_parser)]
+        since: Option<CommitID>,
+
+        /// Remove the directories left empty by the removals pulled from the
+        /// server. Directories still containing something (such as excluded
+        /// objects) are kept
+        #[clap(long, value_parser)]
+        prune_empty_dirs: bool,
+
+        /// Before removing an object to apply the pulled update, check that it
+        /// still has the content it had at the last sync, and keep it (reporting
+        /// it) otherwise, so that changes made out of band are never deleted
+        #[clap(long, value_parser)]
+        safe_delete: bool,
+
+        /// Hash the content of every file of the link. By default, the files
+        /// whose mtime and size did not change since the last sync are assumed
+        /// to be unchanged and are not read again
+        #[clap(long, value_parser)]
+        rehash: bool,
+
+        /// Compare files by content only, for when mtimes can't be trusted (e.g.
+        /// after restoring a backup). Every file is hashed as with --rehash, and
+        /// the objects whose content didn't change get their known mtime back
+        /// instead of having their new mtime pushed
+        #[clap(long, value_parser)]
+        checksum: bool,
+
+        /// Read back every file written by the pulled update and check its hash,
+        /// querying it again from the server if it doesn't match
+        #[clap(long, value_parser)]
+        verify: bool,
+
+        /// Message recorded in the commit created by the push
+        #[clap(short, long, value_parser)]
+        message: Option<String>,
+
+        /// Exclude objects matching the given pattern, for this sync only.
+        /// Added after the rules of the link's exclude list. Can be repeated
+        #[clap(long = "exclude", value_parser)]
+        excludes: Vec<String>,
+
+        /// Look for local changes only down to the given number of levels below
+        /// the link root (1 for the objects directly in it). The directories at
+        /// the last level are taken as they were last synced: the changes under
+        /// them are neither pushed nor pulled, and nothing under them is removed
+        #[clap(long, value_parser = clap::value_parser!(u64).range(1..), conflicts_with_all = &["subpath", "since"])]
+        max_depth: Option<u64>,
+
+        /// Sync only the given directory of the link (relative to the link
+        /// root): local changes are looked for and pushed only under it, and
+        /// only the updates under it are pulled
+        #[clap(value_parser, conflicts_with = "since")]
+        subpath: Option<String>,
+    },
+    /// Show local changes that would be pushed, without connecting to the server
+    Status {
+        /// Print the changes as JSON
+        #[clap(long, value_parser)]
+        json: bool,
+
+        /// Precision at which mtimes are compared to detect local changes (full,
+        /// ms or s)
+        #[clap(long, value_parser, default_value = "full")]
+        mtime_precision: MtimePrecision,
     },
+    /// Undo the changes applied locally by the last sync
+    Undo {
+        /// Maximum number of times an operation on the file system is retried,
+        /// in case of transient failures
+        #[clap(long, value_parser, default_value = "3")]
+        io_retries: u32,
+    },
+    /// Replace the stored tree of the link with the current content of the
+    /// link, for when it got corrupted or deleted. Nothing is modified locally,
+    /// but local changes made since the last sync will not be pushed
+    Repair,
     /// Initialize link
     Init {
         /// Set endpoint
         #[clap(short, long)]
         endpoint: Option<String>,
 
+        /// Set link type
+        #[clap(short = 't', long, value_enum, default_value = "bijection")]
+        link_type: LinkType,
+
         /// Set exclude list to empty
         #[clap(short, long)]
         no_exclude_list: bool,
@@ -52,6 +246,18 @@ enum SubCommand {
         #[clap(short = 'a', long, value_parser)]
         host_address: Option<String>,
     },
+    /// Time tree generation, delta and actions computation on a synthetic tree,
+    /// to tell whether syncs are bound by the CPU or by the disk
+    #[clap(hide = true)]
+    Bench {
+        /// Number of files of the synthetic tree
+        #[clap(long, value_parser, default_value = "1000")]
+        files: usize,
+
+        /// Size in bytes of each file of the synthetic tree
+        #[clap(long, value_parser, default_value = "4096")]
+        file_size: usize,
+    },
 }
 
 #[derive(Parser, Debug)]
@@ -67,47 +273,139 @@ struct Args {
     /// Set fake current working directory
     #[clap(short = 'C', long)]
     cwd: Option<String>,
+
+    /// Take the settings of the client config from the BBUP_* environment
+    /// variables that are set, with precedence over the config file
+    #[clap(long)]
+    config_from_env: bool,
+
+    /// Never use colors in the output. By default colors are used only if the
+    /// output is a terminal
+    #[clap(long)]
+    no_color: bool,
+
+    /// Read files smaller than the given size (e.g. 64K, 1M) whole when hashing
+    /// them, streaming only the larger ones. Raising it speeds up archives made
+    /// of many tiny files. 0 streams every file
+    #[clap(long, value_parser = bbup::com::parse_size, default_value = "64K")]
+    small_file_size: u64,
+
+    /// Also append the log, as structured lines with the endpoint and the PID of
+    /// the connection, to the given file. The log level is info with --verbose
+    /// and warn otherwise, unless set with the BBUP_LOG environment variable
+    /// (error, warn, info, debug or trace)
+    #[clap(long)]
+    log_file: Option<String>,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // Parse command line arguments
     let args = Args::parse();
+    fs_vcs::set_colors(!args.no_color && std::io::stdout().is_terminal());
+    fs_vcs::set_small_file_size(args.small_file_size);
+    let verbose = matches!(args.cmd, SubCommand::Sync { verbose: true, .. });
+    let log_file = args.log_file.map(|val| abst_fs::AbstPath::from(val).normalize());
+    bbup::logger::init(verbose, log_file.as_ref())?;
     let home_dir = match args.home_dir {
-        Some(val) => abst_fs::AbstPath::from(val),
+        Some(val) => abst_fs::AbstPath::from(val).normalize(),
         None => fs::home_dir().context("could not resolve home_dir path")?,
     };
     //dbg!(&abst_fs::AbstPath::from(args.home_dir.unwrap()));
     //let home_dir = fs::home_dir().context("could not resolve home_dir path")?;
     //dbg!(&home_dir);
     let cwd = match args.cwd {
-        Some(val) => abst_fs::AbstPath::from(val),
+        Some(val) => abst_fs::AbstPath::from(val).normalize(),
         None => fs::cwd().context("could not resolve current working directory")?,
     };
 
     match args.cmd {
         SubCommand::Setup { local_port, server_port, host_name, host_address } => setup::setup(&home_dir, local_port, server_port, host_name, host_address),
-        SubCommand::Init { endpoint, no_exclude_list } => init::init(&cwd, endpoint, no_exclude_list),
-        SubCommand::Sync { verbose, progress } 
+        SubCommand::Init { endpoint, link_type, no_exclude_list } => init::init(&cwd, endpoint, link_type, no_exclude_list),
+        SubCommand::Status { json, mtime_precision } => status::status(&cwd, &ClientConfig::load_default_excludes(&home_dir)?, json, mtime_precision),
+        SubCommand::Undo { io_retries } => undo::undo(&cwd, &ClientConfig::load_default_excludes(&home_dir)?, io_retries),
+        SubCommand::Repair => repair::repair(&cwd, &ClientConfig::load_default_excludes(&home_dir)?),
+        SubCommand::Bench { files, file_size } => bench::bench(files, file_size),
+        SubCommand::Sync { verbose: _, progress, dry_run, json, limit_rate, compress, chunk_size, on_conflict, on_divergence, max_attempts, retry_delay, io_retries, read_timeout, parallel, hardlinks, only, mtime_precision, delete_excluded, add_only, target_os, since, prune_empty_dirs, safe_delete, rehash, checksum, verify, message, excludes, max_depth, subpath, events } 
 		// | SubCommand::OtherTypeOfSync when I'll have one
 		//	such as SubCommand::Pull
 		=> {
-			let client_config = ClientConfig::load(&home_dir)?;
+			let client_config = ClientConfig::load(&home_dir, args.config_from_env)?;
 			let link_config = LinkConfig::load(&cwd)?;
+            let exclude_list = link_config.sync_exclude_list(
+                &cwd,
+                &client_config.settings.default_excludes,
+                &excludes,
+            )?;
 
+            let ssh_options = SshOptions {
+                known_hosts: client_config.settings.known_hosts.clone(),
+                strict_host_key_checking: match &client_config.settings.strict_host_key_checking {
+                    Some(val) => Some(val.parse().context("invalid ssh settings in client config")?),
+                    None => None,
+                },
+                identity_file: client_config.settings.identity_file.clone(),
+                port: client_config.settings.ssh_port,
+                proxy_jump: match &client_config.settings.proxy_jump {
+                    Some(val) => Some(val.parse().context("invalid ssh settings in client config")?),
+                    None => None,
+                },
+            };
             let connection = Connection {
                 local_port: client_config.settings.local_port,
                 server_port: client_config.settings.server_port,
                 host_name: client_config.settings.host_name.clone(),
                 host_address: client_config.settings.host_address.clone(),
+                ssh_options,
+            };
+            let flags = Flags {
+                progress,
+                dry_run,
+                json,
+                events,
+                limit_rate,
+                compress,
+                chunk_size,
+                on_conflict,
+                on_divergence,
+                max_attempts,
+                retry_delay,
+                io_retries,
+                read_timeout,
+                parallel: parallel as usize,
+                hardlinks,
+                only: only.map(|only| abst_fs::AbstPath::from(only).normalize()),
+                subpath: subpath
+                    .map(|subpath| abst_fs::AbstPath::from(subpath).normalize())
+                    .filter(|subpath| !subpath.is_empty()),
+                max_depth: max_depth.map(|max_depth| max_depth as usize),
+                mtime_precision,
+                delete_excluded,
+                add_only,
+                target_os,
+                since,
+                prune_empty_dirs,
+                safe_delete,
+                rehash,
+                checksum,
+                verify,
             };
-            let flags = Flags { verbose, progress };
+            let temp_dir = client_config.settings.temp_dir.as_ref().map(abst_fs::AbstPath::from);
             let config = ProcessConfig {
                 link_root: cwd.clone(),
-                exclude_list: ExcludeList::from(&link_config.exclude_list)?,
+                temp_dir: fs::resolve_temp_dir(&cwd, temp_dir.as_ref()),
+                link_type: link_config.link_type,
+                exclude_list,
                 endpoint: link_config.endpoint,
                 connection,
                 flags,
+                commit_metadata: CommitMetadata {
+                    author: client_config.settings.author.clone().or_else(|| std::env::var("USER").ok()),
+                    message,
+                    timestamp: None,
+                },
+                ignore_dir_mtimes: link_config.ignore_dir_mtimes,
+                dereference: link_config.dereference,
             };
 
             sync::process_link(config).await
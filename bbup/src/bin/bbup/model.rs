@@ -1,57 +1,247 @@
 use serde::{Deserialize, Serialize};
 
-use abst_fs::{self as fs, AbstPath};
-use fs_vcs::{Commit, Delta, ExcludeList, FSTree};
+use abst_fs::{self as fs, AbstPath, Durability, MtimePrecision};
+use fs_vcs::{
+    Actions, Commit, CommitID, CommitMetadata, ConflictStrategy, Delta, DeltaOptions, DeltaStats,
+    DivergencePolicy, ExcludeList, FSTree, TargetOs,
+};
+use ssh_tunnel::SshOptions;
 
 use anyhow::{Context, Result};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Settings of the client. The fields that can be given by environment variables
+/// (see `ClientSettings::overlay_env`) may be missing from the config file
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct ClientSettings {
+    #[serde(default)]
     pub local_port: u16,
+    #[serde(default)]
     pub server_port: u16,
+    #[serde(default)]
     pub host_name: String,
+    #[serde(default)]
     pub host_address: String,
+    /// Path of the known_hosts file used to verify the server, instead of ssh's default
+    pub known_hosts: Option<String>,
+    /// Value of ssh's StrictHostKeyChecking option (yes, no or accept-new)
+    pub strict_host_key_checking: Option<String>,
+    /// Identity file used to authenticate to the server
+    pub identity_file: Option<String>,
+    /// Port of the ssh server on the host, instead of ssh's default
+    pub ssh_port: Option<u16>,
+    /// Jump hosts used to reach the host, as for ssh's ProxyJump option
+    /// (comma separated list of [user@]host[:port])
+    pub proxy_jump: Option<String>,
+    /// Author recorded in the commits pushed by this client. Defaults to the
+    /// USER environment variable
+    pub author: Option<String>,
+    /// Directory where the downloaded content is staged before being moved in the
+    /// link, relative to the link root if not absolute. Defaults to `.bbup/temp`
+    /// in the link root. A directory elsewhere in the link should be excluded
+    pub temp_dir: Option<String>,
+    /// Exclude patterns applied to every link before its own exclude list,
+    /// unless the link opts out of them
+    #[serde(default)]
+    pub default_excludes: Vec<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl ClientSettings {
+    /// Override the settings with the environment variables that are set, among
+    /// `BBUP_LOCAL_PORT`, `BBUP_SERVER_PORT`, `BBUP_HOST_NAME`,
+    /// `BBUP_HOST_ADDRESS` and `BBUP_IDENTITY_FILE`
+    fn overlay_env(&mut self) -> Result<()> {
+        if let Some(local_port) = fs::env_var("BBUP_LOCAL_PORT")? {
+            self.local_port = local_port;
+        }
+        if let Some(server_port) = fs::env_var("BBUP_SERVER_PORT")? {
+            self.server_port = server_port;
+        }
+        if let Some(host_name) = fs::env_var("BBUP_HOST_NAME")? {
+            self.host_name = host_name;
+        }
+        if let Some(host_address) = fs::env_var("BBUP_HOST_ADDRESS")? {
+            self.host_address = host_address;
+        }
+        if let Some(identity_file) = fs::env_var("BBUP_IDENTITY_FILE")? {
+            self.identity_file = Some(identity_file);
+        }
+        Ok(())
+    }
+    /// Check that the settings needed to reach the server are given
+    fn check_required(&self) -> Result<()> {
+        let missing = [
+            (self.local_port == 0, "local_port", "BBUP_LOCAL_PORT"),
+            (self.server_port == 0, "server_port", "BBUP_SERVER_PORT"),
+            (self.host_name.is_empty(), "host_name", "BBUP_HOST_NAME"),
+            (
+                self.host_address.is_empty(),
+                "host_address",
+                "BBUP_HOST_ADDRESS",
+            ),
+        ];
+        for (is_missing, field, var) in missing {
+            if is_missing {
+                anyhow::bail!(
+                    "setting {field} is missing from the client config (it can also be given by {var} with --config-from-env)"
+                )
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, clap::ValueEnum)]
 pub enum LinkType {
+    /// Local changes are pushed to the endpoint and changes to the endpoint
+    /// are pulled locally
     Bijection,
+    /// The endpoint is a subtree of a larger archive: changes are synced both
+    /// ways as for Bijection, but the link talks to the server about the whole
+    /// archive, prefixing its changes with the endpoint and taking its part of
+    /// the updates
     Injection,
+    /// The endpoint is a block reserved to this link: local changes (removals
+    /// included) are pushed to the endpoint, but nothing is pulled and the
+    /// server rejects pushes touching anything outside of the endpoint
     BlockInjection,
 }
 
 pub struct Flags {
-    pub verbose: bool,
     pub progress: bool,
+    pub dry_run: bool,
+    pub json: bool,
+    /// Where to write the events of the actions applied, '-' for stdout
+    pub events: Option<String>,
+    pub limit_rate: u64,
+    pub compress: bool,
+    pub chunk_size: Option<usize>,
+    pub on_conflict: ConflictStrategy,
+    pub on_divergence: DivergencePolicy,
+    pub max_attempts: u32,
+    pub retry_delay: u64,
+    pub io_retries: u32,
+    pub read_timeout: u64,
+    pub parallel: usize,
+    pub hardlinks: bool,
+    pub only: Option<AbstPath>,
+    pub subpath: Option<AbstPath>,
+    /// Levels below the link root to look for local changes in, see
+    /// `generate_fstree_to_depth`
+    pub max_depth: Option<usize>,
+    pub mtime_precision: MtimePrecision,
+    pub delete_excluded: bool,
+    pub add_only: bool,
+    pub target_os: Option<TargetOs>,
+    pub since: Option<CommitID>,
+    pub prune_empty_dirs: bool,
+    pub safe_delete: bool,
+    pub rehash: bool,
+    pub checksum: bool,
+    pub verify: bool,
 }
 pub struct Connection {
     pub local_port: u16,
     pub server_port: u16,
     pub host_name: String,
     pub host_address: String,
+    pub ssh_options: SshOptions,
 }
 pub struct ProcessConfig {
     pub link_root: AbstPath,
+    pub temp_dir: AbstPath,
+    pub link_type: LinkType,
     pub exclude_list: ExcludeList,
     pub endpoint: AbstPath,
     pub connection: Connection,
     pub flags: Flags,
+    /// Metadata of the commit created by the push, if any
+    pub commit_metadata: CommitMetadata,
+    /// Never sync the mtimes of directories, see `LinkConfig::ignore_dir_mtimes`
+    pub ignore_dir_mtimes: bool,
+    /// Symlinks synced as their targets, see `LinkConfig::dereference`
+    pub dereference: Vec<AbstPath>,
+}
+impl ProcessConfig {
+    /// Options with which the local trees are compared
+    pub fn delta_options(&self) -> DeltaOptions {
+        DeltaOptions {
+            precision: self.flags.mtime_precision,
+            ignore_dir_mtimes: self.ignore_dir_mtimes,
+        }
+    }
+    /// Config of a sync of the bijective link at `link_root` with the archive
+    /// root, with no exclude rule and the default value of every flag
+    #[cfg(test)]
+    pub fn for_link(link_root: &AbstPath) -> ProcessConfig {
+        ProcessConfig {
+            link_root: link_root.clone(),
+            temp_dir: fs::resolve_temp_dir(link_root, None),
+            link_type: LinkType::Bijection,
+            exclude_list: ExcludeList::from(&vec![]).unwrap(),
+            endpoint: AbstPath::empty(),
+            connection: Connection {
+                local_port: 0,
+                server_port: 0,
+                host_name: String::new(),
+                host_address: String::new(),
+                ssh_options: SshOptions::default(),
+            },
+            flags: Flags {
+                progress: false,
+                dry_run: false,
+                json: false,
+                events: None,
+                limit_rate: 0,
+                compress: false,
+                chunk_size: None,
+                on_conflict: ConflictStrategy::Abort,
+                on_divergence: DivergencePolicy::TrustLocal,
+                max_attempts: 5,
+                retry_delay: 1000,
+                io_retries: 3,
+                read_timeout: 300,
+                parallel: 1,
+                hardlinks: false,
+                only: None,
+                subpath: None,
+                max_depth: None,
+                mtime_precision: MtimePrecision::Full,
+                delete_excluded: false,
+                add_only: false,
+                target_os: None,
+                since: None,
+                prune_empty_dirs: false,
+                safe_delete: false,
+                rehash: false,
+                checksum: false,
+                verify: false,
+            },
+            commit_metadata: CommitMetadata::default(),
+            ignore_dir_mtimes: false,
+            dereference: Vec::new(),
+        }
+    }
 }
 pub struct ProcessState {
-    pub last_known_commit: String,
+    pub last_known_commit: CommitID,
     pub last_known_fstree: FSTree,
     pub new_tree: Option<FSTree>,
     pub local_delta: Option<Delta>,
-    pub update: Option<(String, Delta)>,
+    pub update: Option<(CommitID, Delta)>,
+    /// Whether the server has updates outside of the synced subpath, which are
+    /// not pulled. The link is then not up to date with any commit, so the last
+    /// known commit and tree are not saved and the next sync pulls again
+    pub partial: bool,
 }
 impl ProcessState {
-    pub fn from(lkc: String, last_known_fstree: FSTree) -> ProcessState {
+    pub fn from(lkc: CommitID, last_known_fstree: FSTree) -> ProcessState {
         ProcessState {
             last_known_commit: lkc,
             last_known_fstree,
             new_tree: None,
             local_delta: None,
             update: None,
+            partial: false,
         }
     }
     pub fn init_state() -> ProcessState {
@@ -65,10 +255,14 @@ impl ProcessState {
     fn ofst_path(link_root: &AbstPath) -> AbstPath {
         link_root.add_last(".bbup").add_last("old-fstree.bin")
     }
+    /// Load only the last known commit, without the old fstree
+    pub fn load_last_known_commit(link_root: &AbstPath) -> Result<CommitID> {
+        fs::load(&ProcessState::lkc_path(link_root))
+            .context("failed to load link's last known commit")
+    }
     pub fn load(link_root: &AbstPath) -> Result<ProcessState> {
-        let lkc: String = fs::load(&ProcessState::lkc_path(link_root))
-            .context("failed to load link's last known commit")?;
-        let last_known_fstree: FSTree = fs::load(&ProcessState::ofst_path(link_root))
+        let lkc = ProcessState::load_last_known_commit(link_root)?;
+        let last_known_fstree = FSTree::load(&ProcessState::ofst_path(link_root))
             .context("failed to load link's old fstree")?;
 
         Ok(ProcessState::from(lkc, last_known_fstree))
@@ -83,6 +277,92 @@ impl ProcessState {
     }
 }
 
+/// Machine-readable summary of some changes and of the actions applying them,
+/// printed in place of the human-readable output when `--json` is given
+#[derive(Serialize, Debug)]
+pub struct ActionsReport {
+    pub stats: DeltaStats,
+    pub actions: Actions,
+}
+
+/// Machine-readable preview of a sync: what would be pulled (if anything) and
+/// what would be pushed
+#[derive(Serialize, Debug)]
+pub struct PreviewReport {
+    pub pull: Option<ActionsReport>,
+    pub push: ActionsReport,
+}
+
+/// What is needed to undo the changes that the last sync applied to the link
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LastSync {
+    /// Last known commit before the sync
+    pub previous_commit: CommitID,
+    /// Last known tree before the sync
+    pub previous_fstree: FSTree,
+    /// Changes applied to the link root by the sync
+    pub applied: Delta,
+    /// Tree of the link root right after the sync
+    pub post_tree: FSTree,
+}
+impl LastSync {
+    fn path(link_root: &AbstPath) -> AbstPath {
+        link_root.add_last(".bbup").add_last("last-sync.bin")
+    }
+    /// Directory where the objects replaced or removed by the sync are kept
+    pub fn backup_path(link_root: &AbstPath) -> AbstPath {
+        link_root.add_last(".bbup").add_last("last-sync-backup")
+    }
+    pub fn exists(link_root: &AbstPath) -> bool {
+        LastSync::path(link_root).exists()
+    }
+    pub fn load(link_root: &AbstPath) -> Result<LastSync> {
+        fs::load(&LastSync::path(link_root)).context("failed to load link's last sync")
+    }
+    pub fn save(&self, link_root: &AbstPath) -> Result<()> {
+        fs::save(&LastSync::path(link_root), self).context("failed to save link's last sync")
+    }
+    /// Forget the last sync, together with its backup
+    pub fn clear(link_root: &AbstPath) -> Result<()> {
+        fs::remove_file(&LastSync::path(link_root)).context("failed to remove link's last sync")?;
+        let backup = LastSync::backup_path(link_root);
+        if backup.exists() {
+            fs::remove_dir_all(&backup).context("failed to remove backup of link's last sync")?;
+        }
+        Ok(())
+    }
+}
+
+/// Lock preventing multiple bbup processes from operating on the same link at the
+/// same time. The lock is held until dropped
+pub struct LinkLock {
+    _lock: fs::FileLock,
+}
+impl LinkLock {
+    fn path(link_root: &AbstPath) -> AbstPath {
+        link_root.add_last(".bbup").add_last("lock")
+    }
+    pub fn acquire(link_root: &AbstPath) -> Result<LinkLock> {
+        match fs::try_lock_file(&LinkLock::path(link_root)).context("failed to lock link")? {
+            Some(lock) => Ok(LinkLock { _lock: lock }),
+            None => anyhow::bail!("bbup is already running on link [{link_root}]"),
+        }
+    }
+}
+
+/// The only part of the client config needed by the commands that don't connect
+/// to the server, read without requiring the connection settings
+#[derive(Deserialize)]
+struct DefaultExcludes {
+    #[serde(default)]
+    settings: DefaultExcludesSettings,
+}
+#[derive(Deserialize, Default)]
+struct DefaultExcludesSettings {
+    #[serde(default)]
+    default_excludes: Vec<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ClientConfig {
     pub links: Vec<String>,
@@ -101,17 +381,41 @@ impl ClientConfig {
     pub fn from(settings: ClientSettings, links: Vec<String>) -> ClientConfig {
         ClientConfig { settings, links }
     }
-    pub fn load(home_dir: &AbstPath) -> Result<ClientConfig> {
+    /// Load the client config. If `from_env` is set, the settings given by
+    /// environment variables take precedence over the ones of the config file,
+    /// which can then be missing altogether
+    pub fn load(home_dir: &AbstPath, from_env: bool) -> Result<ClientConfig> {
         let path = ClientConfig::path(home_dir);
-        if !path.exists() {
-            anyhow::bail!("Bbup client isn't set up. Try using 'bbup setup'")
+        let mut client_config: ClientConfig = match (path.exists(), from_env) {
+            (true, _) => fs::load(&path).context("failed to laod client config")?,
+            (false, true) => ClientConfig::from(ClientSettings::default(), Vec::new()),
+            (false, false) => anyhow::bail!("Bbup client isn't set up. Try using 'bbup setup'"),
+        };
+        if from_env {
+            client_config
+                .settings
+                .overlay_env()
+                .context("invalid client settings in environment")?;
         }
-        let client_config: ClientConfig =
-            fs::load(&path).context("failed to laod client config")?;
+        client_config.settings.check_required()?;
         Ok(client_config)
     }
+    /// Default exclude patterns of the client, for the commands that don't need
+    /// the rest of the config. Empty if the client isn't set up. The connection
+    /// settings are not checked, as they might be given by environment variables
+    pub fn load_default_excludes(home_dir: &AbstPath) -> Result<Vec<String>> {
+        match ClientConfig::exists(home_dir) {
+            true => {
+                let config: DefaultExcludes = fs::load(&ClientConfig::path(home_dir))
+                    .context("failed to load default excludes from client config")?;
+                Ok(config.settings.default_excludes)
+            }
+            false => Ok(Vec::new()),
+        }
+    }
     pub fn save(&self, home_dir: &AbstPath) -> Result<()> {
-        fs::save(&ClientConfig::path(home_dir), &self).context("failed to save client config")?;
+        fs::save_with(&ClientConfig::path(home_dir), &self, Durability::Unsynced)
+            .context("failed to save client config")?;
         Ok(())
     }
 }
@@ -121,6 +425,17 @@ pub struct LinkConfig {
     pub link_type: LinkType,
     pub endpoint: AbstPath,
     pub exclude_list: Vec<String>,
+    /// Ignore the mtimes of directories: a directory whose mtime alone changed
+    /// is not pushed, and pulled updates don't edit the mtimes of directories
+    #[serde(default)]
+    pub ignore_dir_mtimes: bool,
+    /// Symlinks (relative to the link root) synced as the files or directories
+    /// they point to, instead of as symlinks
+    #[serde(default)]
+    pub dereference: Vec<AbstPath>,
+    /// Don't apply the default exclude patterns of the client to this link
+    #[serde(default)]
+    pub no_default_excludes: bool,
 }
 impl LinkConfig {
     fn path(link_root: &AbstPath) -> AbstPath {
@@ -134,6 +449,9 @@ impl LinkConfig {
             link_type,
             endpoint,
             exclude_list,
+            ignore_dir_mtimes: false,
+            dereference: Vec::new(),
+            no_default_excludes: false,
         }
     }
     pub fn load(link_root: &AbstPath) -> Result<LinkConfig> {
@@ -144,8 +462,78 @@ impl LinkConfig {
         let link_config: LinkConfig = fs::load(&path).context("failed to load link config")?;
         Ok(link_config)
     }
+    /// Exclude list of the link: the default patterns of the client followed
+    /// by the link's own, unless the link opts out of the defaults, and by the
+    /// patterns of the `.bbupignore` files of the link at `link_root`
+    pub fn exclude_list(
+        &self,
+        link_root: &AbstPath,
+        default_excludes: &Vec<String>,
+    ) -> Result<ExcludeList> {
+        let no_defaults = Vec::new();
+        let defaults = match self.no_default_excludes {
+            true => &no_defaults,
+            false => default_excludes,
+        };
+        ExcludeList::with_defaults(defaults, &self.exclude_list)
+            .context("invalid exclude list in link config")?
+            .join_ignore_files(link_root)
+            .context("invalid .bbupignore file in link")
+    }
+    /// Exclude list of a sync of the link: the exclude list of the link (see
+    /// `exclude_list`) followed by the patterns given for this sync only, which
+    /// are never saved in the config
+    pub fn sync_exclude_list(
+        &self,
+        link_root: &AbstPath,
+        default_excludes: &Vec<String>,
+        excludes: &Vec<String>,
+    ) -> Result<ExcludeList> {
+        self.exclude_list(link_root, default_excludes)?
+            .join(excludes)
+            .context("invalid pattern given to --exclude")
+    }
     pub fn save(&self, link_root: &AbstPath) -> Result<()> {
-        fs::save(&LinkConfig::path(link_root), &self).context("failed to save link config")?;
+        fs::save_with(&LinkConfig::path(link_root), &self, Durability::Unsynced)
+            .context("failed to save link config")?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ClientConfig;
+    use abst_fs::{self as fs, AbstPath};
+
+    #[test]
+    fn test() {
+        let home_dir = AbstPath::from("/tmp/bbup-test-model");
+        assert!(!home_dir.exists());
+        fs::create_dir(&home_dir).unwrap();
+
+        let result = std::panic::catch_unwind(|| default_excludes(&home_dir));
+        fs::remove_dir_all(&home_dir).unwrap();
+        assert!(result.is_ok());
+    }
+
+    fn default_excludes(home_dir: &AbstPath) {
+        // The client isn't set up
+        assert!(ClientConfig::load_default_excludes(home_dir)
+            .unwrap()
+            .is_empty());
+
+        // The connection settings are left to environment variables
+        let config_dir = home_dir.add_last(".config").add_last("bbup-client");
+        std::fs::create_dir_all(config_dir.to_path_buf()).unwrap();
+        std::fs::write(
+            config_dir.add_last("config.toml").to_path_buf(),
+            "links = []\n\n[settings]\ndefault_excludes = [\"\\\\.tmp$\"]\n",
+        )
+        .unwrap();
+        assert!(ClientConfig::load(home_dir, false).is_err());
+        assert_eq!(
+            ClientConfig::load_default_excludes(home_dir).unwrap(),
+            vec![String::from("\\.tmp$")]
+        );
+    }
+}
@@ -1,41 +1,235 @@
-use fs_vcs::{generate_fstree, get_actions_or_conflicts, get_delta, Action, Delta};
+use fs_vcs::{
+    dereference_symlinks, find_hardlinks, generate_fstree_at, generate_fstree_to_depth,
+    generate_fstree_with_prior, generate_fstree_with_skipped, get_actions_with_strategy, get_delta,
+    get_delta_with_options, reconcile_divergence, Action, Actions, CommitID, Delta, DeltaOptions,
+    DivergencePolicy, ExcludeList, FSNode, FSTree, HardLinkGroups, TargetOs,
+};
 
-use super::{ProcessConfig, ProcessState};
+use super::{
+    cancel, ActionsReport, LastSync, LinkType, PreviewReport, ProcessConfig, ProcessState,
+};
 
-use abst_fs as fs;
-use bbup::com::{BbupCom, Queryable};
+use abst_fs::{self as fs, AbstPath, Durability};
+use bbup::com::{BbupCom, OverallProgress, Queryable};
+use hasher::Hash;
 
 use anyhow::{Context, Result};
+use log::{debug, info, warn};
 
-pub fn get_local_delta(config: &ProcessConfig, state: &mut ProcessState) -> Result<()> {
-    if config.flags.verbose {
-        println!("calculating local delta...")
-    }
+use std::time::{Duration, Instant};
 
-    let new_tree = generate_fstree(&config.link_root, &config.exclude_list)?;
-    let local_delta = get_delta(&state.last_known_fstree, &new_tree);
+pub fn get_local_delta(config: &ProcessConfig, state: &mut ProcessState) -> Result<()> {
+    info!("calculating local delta...");
 
-    if config.flags.verbose {
-        if local_delta.is_empty() {
-            println!("local delta: no local changes to push")
-        } else {
-            println!("local delta:\n{}", local_delta)
+    // Unless asked otherwise, trust the hashes of the last known tree for the
+    //	objects whose mtime and size did not change
+    let prior = match config.flags.rehash || config.flags.checksum {
+        true => None,
+        false => Some(&state.last_known_fstree),
+    };
+    let (mut new_tree, skipped) = generate_tree(config, &state.last_known_fstree, prior)?;
+    warn_skipped(&skipped);
+    if let Some(target_os) = config.flags.target_os {
+        check_names(&new_tree, target_os)?;
+    }
+    if let Some(since) = &config.flags.since {
+        // The content of the link is the snapshot of the given commit, so there
+        //	is nothing to push and only the commits after it get pulled
+        state.last_known_commit = since.clone();
+        state.last_known_fstree = new_tree.clone();
+    }
+    let divergent = reconcile_divergence(
+        &state.last_known_fstree,
+        &mut new_tree,
+        config.flags.on_divergence,
+    )
+    .context("the link was changed out of band, choose how to sync it with --on-divergence")?;
+    if !divergent.is_empty() {
+        let paths: Vec<String> = divergent.iter().map(|path| format!("\n\t{path}")).collect();
+        match config.flags.on_divergence {
+            DivergencePolicy::Ignore => warn!(
+                "ignoring objects changed out of band, they are not pushed nor restored:{}",
+                paths.concat()
+            ),
+            _ => warn!("pushing objects changed out of band:{}", paths.concat()),
         }
     }
+    let mut local_delta = local_changes(
+        &state.last_known_fstree,
+        &new_tree,
+        &config.exclude_list,
+        config.flags.delete_excluded,
+        &config.delta_options(),
+    )?;
+    if config.flags.checksum {
+        reset_mtimes(config, &mut local_delta, &mut new_tree)?;
+    }
+
+    if local_delta.is_empty() {
+        info!("local delta: no local changes to push")
+    } else {
+        info!("local delta:\n{}", local_delta)
+    }
 
     state.new_tree = Some(new_tree);
     state.local_delta = Some(local_delta);
     Ok(())
 }
 
+/// Generate the tree of the link, hashing only the objects that changed since
+/// `prior` if given. If syncing a subpath, only the objects under it are looked
+/// at, and the others are taken from `known`. The same goes for the objects
+/// deeper than the maximum depth, if any
+fn generate_tree(
+    config: &ProcessConfig,
+    known: &FSTree,
+    prior: Option<&FSTree>,
+) -> Result<(FSTree, Vec<AbstPath>)> {
+    let generated = match (&config.flags.subpath, config.flags.max_depth) {
+        (Some(subpath), _) => generate_fstree_at(
+            &config.link_root,
+            subpath,
+            &config.exclude_list,
+            known,
+            prior,
+        )
+        .context("could not generate tree of the subpath to sync")?,
+        (None, Some(max_depth)) => generate_fstree_to_depth(
+            &config.link_root,
+            &config.exclude_list,
+            known,
+            prior,
+            max_depth,
+        )
+        .context("could not generate tree of the link down to the maximum depth")?,
+        (None, None) => generate_fstree_with_prior(&config.link_root, &config.exclude_list, prior)?,
+    };
+    let (mut tree, mut skipped) = generated;
+    skipped.extend(
+        dereference_symlinks(
+            &config.link_root,
+            &mut tree,
+            &config.dereference,
+            &config.exclude_list,
+            prior,
+        )
+        .context("could not dereference symlinks of the link")?,
+    );
+    Ok((tree, skipped))
+}
+
+/// Generate the tree of the link at `link_root`, with the symlinks at the paths
+/// in `dereference` replaced by their targets. Returns the tree and the paths of
+/// the special objects skipped
+pub fn generate_link_tree(
+    link_root: &AbstPath,
+    exclude_list: &ExcludeList,
+    dereference: &[AbstPath],
+) -> Result<(FSTree, Vec<AbstPath>)> {
+    let (mut tree, mut skipped) = generate_fstree_with_skipped(link_root, exclude_list)?;
+    skipped.extend(
+        dereference_symlinks(link_root, &mut tree, dereference, exclude_list, None)
+            .context("could not dereference symlinks of the link")?,
+    );
+    Ok((tree, skipped))
+}
+
+/// Take out of the local delta the objects whose only change is their mtime, and
+/// set their mtime back to the known one (unless during a dry run), as mtimes are
+/// not trusted when comparing by content only
+fn reset_mtimes(
+    config: &ProcessConfig,
+    local_delta: &mut Delta,
+    new_tree: &mut FSTree,
+) -> Result<()> {
+    let touched = local_delta.take_mtime_only_edits();
+    if touched.is_empty() || config.flags.dry_run {
+        return Ok(());
+    }
+    info!(
+        "resetting the mtime of {} objects with unchanged content",
+        touched.stats().edited.total()
+    );
+    // Only metadata is edited, so no content is taken from the source
+    apply_actions(
+        &config.link_root,
+        touched.invert().to_actions(),
+        &config.link_root,
+        None,
+        config.flags.io_retries,
+        Durability::Unsynced,
+    )
+    .context("could not reset mtimes of objects with unchanged content")?;
+    new_tree
+        .undo_delta(&touched)
+        .context("could not reset mtimes in the new tree")?;
+    Ok(())
+}
+
+/// Warn about the special objects (FIFOs, sockets and devices) of the link that
+/// have been skipped, as they can't be synchronized
+pub fn warn_skipped(skipped: &[AbstPath]) {
+    if skipped.is_empty() {
+        return;
+    }
+    let paths: Vec<String> = skipped.iter().map(|path| format!("  {path}")).collect();
+    warn!(
+        "skipped {} special objects (FIFOs, sockets or devices):\n{}",
+        skipped.len(),
+        paths.join("\n")
+    );
+}
+
+/// Fail, listing the offending paths, if some names of the tree can't be created
+/// on the target os
+fn check_names(tree: &FSTree, target_os: TargetOs) -> Result<()> {
+    let invalid = tree.invalid_names(target_os);
+    if invalid.is_empty() {
+        return Ok(());
+    }
+    let lines: Vec<String> = invalid
+        .iter()
+        .map(|(path, issue)| format!("  {path}: {issue}"))
+        .collect();
+    anyhow::bail!(
+        "{} objects can't be created on {:?}, rename them or exclude them to sync:\n{}",
+        invalid.len(),
+        target_os,
+        lines.join("\n")
+    )
+}
+
+/// Get the changes from the last known tree to the new tree that should be
+/// pushed, comparing the trees as specified by `options`. Tracked objects that
+/// now match the exclude list are kept on the endpoint, unless `delete_excluded`
+/// is set
+pub fn local_changes(
+    last_known_fstree: &FSTree,
+    new_tree: &FSTree,
+    exclude_list: &ExcludeList,
+    delete_excluded: bool,
+    options: &DeltaOptions,
+) -> Result<Delta> {
+    // Nothing changed locally: skip walking the two trees
+    if last_known_fstree.root_hash() == new_tree.root_hash() {
+        return Ok(Delta::empty());
+    }
+    let mut local_delta = get_delta_with_options(last_known_fstree, new_tree, options);
+    let excluded_removals = local_delta.take_excluded_removals(exclude_list);
+    if delete_excluded {
+        local_delta = excluded_removals
+            .compose(&local_delta)
+            .context("could not add removals of excluded objects to local delta")?;
+    }
+    Ok(local_delta)
+}
+
 pub async fn pull_update_delta(
     config: &ProcessConfig,
     state: &mut ProcessState,
     com: &mut BbupCom,
 ) -> Result<()> {
-    if config.flags.verbose {
-        println!("pulling from server...")
-    }
+    info!("pulling from server...");
     // [PULL] Send last known commit to pull updates in case of any
     com.send_struct(&state.last_known_commit)
         .await
@@ -46,20 +240,52 @@ pub async fn pull_update_delta(
         .get_struct()
         .await
         .context("could not get update delta from server")?;
-    let id: String = com
+    let id: CommitID = com
         .get_struct()
         .await
         .context("could not get update id from server")?;
 
+    // [PULL] Injective links get the updates of the whole archive, of which they
+    //	only take the ones under their endpoint
+    if config.link_type == LinkType::Injection {
+        delta = delta
+            .get_subdelta_tree_copy(&config.endpoint)
+            .unwrap_or_else(Delta::empty);
+    }
+
     // [PULL] Filter out updates that match the exclude_list
     delta.filter_out(&config.exclude_list);
 
-    if config.flags.verbose {
-        if delta.is_empty() {
-            println!("pull delta: no missed change to pull")
-        } else {
-            println!("pull delta:\n{}", delta)
+    // [PULL] Only the updates under the synced subpath get pulled
+    if let Some(subpath) = &config.flags.subpath {
+        let scoped = delta.filter_subpath(subpath);
+        state.partial = scoped != delta;
+        if state.partial {
+            warn!(
+                "the server has updates outside of [{}], which are not pulled. Run a full sync to get them",
+                subpath
+            )
         }
+        delta = scoped;
+    }
+    // [PULL] The updates below the maximum depth don't get pulled either, as the
+    //	local changes there are not known
+    if let Some(max_depth) = config.flags.max_depth {
+        let scoped = delta.filter_depth(max_depth);
+        if scoped != delta {
+            state.partial = true;
+            warn!(
+                "the server has updates deeper than {} levels, which are not pulled. Run a full sync to get them",
+                max_depth
+            )
+        }
+        delta = scoped;
+    }
+
+    if delta.is_empty() {
+        info!("pull delta: no missed change to pull")
+    } else {
+        info!("pull delta:\n{}", delta)
     }
 
     state.update = Some((id, delta));
@@ -67,6 +293,577 @@ pub async fn pull_update_delta(
     Ok(())
 }
 
+/// Part of the local delta that gets pushed: only the changes under the synced
+/// subpath and under the path given by `--only`, if any, or the whole delta
+/// otherwise. With `--add-only`, only the additions of new objects
+fn pushed_delta(config: &ProcessConfig, local_delta: &Delta) -> Delta {
+    let mut pushed_delta = [&config.flags.subpath, &config.flags.only]
+        .into_iter()
+        .flatten()
+        .fold(local_delta.clone(), |delta, path| {
+            delta.filter_subpath(path)
+        });
+    if config.flags.add_only {
+        pushed_delta.strip_to_additions();
+    }
+    pushed_delta
+}
+
+/// Part of the pulled update that gets applied locally: the whole update, or
+/// the update without the changes to the mtimes of directories if these are
+/// ignored
+fn applied_delta(config: &ProcessConfig, update_delta: &Delta) -> Delta {
+    let mut applied_delta = update_delta.clone();
+    if config.ignore_dir_mtimes {
+        applied_delta.strip_dir_mtimes();
+    }
+    applied_delta
+}
+
+/// Print the actions that a sync would perform, both locally and on the
+/// server, without applying any of them
+pub fn preview_actions(config: &ProcessConfig, state: &ProcessState) -> Result<()> {
+    let local_delta = match &state.local_delta {
+        Some(val) => val,
+        None => anyhow::bail!(
+            "Some part of the state was required for previewing actions but is missing\nstate.local_delta: false"
+        ),
+    };
+
+    let pull = match &state.update {
+        Some((_, update_delta)) => {
+            let applied = applied_delta(config, update_delta);
+            let mut necessary_actions = match get_actions_with_strategy(
+                local_delta,
+                &applied,
+                config.flags.on_conflict,
+            ) {
+                Ok(actions) => actions,
+                Err(conflicts) => {
+                    println!("conflicts:\n{}", conflicts);
+                    anyhow::bail!(
+                        "found {} conflicts between pulled update and local changes. Resolve manually",
+                        conflicts.paths().len()
+                    )
+                }
+            };
+            necessary_actions.detect_moves(&applied);
+            Some(ActionsReport {
+                stats: update_delta.stats(),
+                actions: necessary_actions,
+            })
+        }
+        None => None,
+    };
+    let pushed_delta = pushed_delta(config, local_delta);
+    let mut pushed_actions = pushed_delta.to_actions();
+    pushed_actions.detect_moves(&pushed_delta);
+    let push = ActionsReport {
+        stats: pushed_delta.stats(),
+        actions: pushed_actions,
+    };
+
+    if config.flags.json {
+        let report = serde_json::to_string_pretty(&PreviewReport { pull, push })
+            .context("could not serialize preview")?;
+        println!("{report}");
+        return Ok(());
+    }
+
+    if let Some(pull) = pull {
+        println!("pull summary:\n{}", pull.stats);
+        if pull.actions.is_empty() {
+            println!("local actions: nothing to apply locally")
+        } else {
+            println!("local actions:\n{}", pull.actions)
+        }
+    }
+
+    println!("push summary:\n{}", push.stats);
+    if push.actions.is_empty() {
+        println!("remote actions: nothing to push to [{}]", config.endpoint)
+    } else {
+        println!("remote actions:\n{}", push.actions)
+    }
+
+    Ok(())
+}
+
+/// Delay before retrying a file system operation that failed with a transient error
+const IO_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Run the file system operation, retrying it at most `retries` times if it fails
+/// with a transient error
+fn retry<F>(retries: u32, operation: F) -> Result<(), fs::Error>
+where
+    F: FnMut() -> Result<(), fs::Error>,
+{
+    fs::retry_transient(retries, IO_RETRY_DELAY, operation)
+}
+
+/// Apply the actions to the link root, taking the added or edited content from
+/// `source`. If `backup` is given, objects that get replaced or removed are moved
+/// there instead of being deleted. Each operation on the file system failing with
+/// a transient error is retried at most `retries` times. If `durability` asks so,
+/// the changes are synced to the disk before returning
+pub fn apply_actions(
+    link_root: &AbstPath,
+    actions: Actions,
+    source: &AbstPath,
+    backup: Option<&AbstPath>,
+    retries: u32,
+    durability: Durability,
+) -> Result<()> {
+    let dirs = actions.parent_dirs();
+    for (path, action) in actions {
+        apply_action(link_root, &path, action, source, backup, retries)?;
+    }
+    if durability == Durability::Synced {
+        sync_dirs(link_root, &dirs)?;
+    }
+
+    Ok(())
+}
+
+/// Sync to the disk the entries of the directories (relative to the link root)
+/// changed by some actions, so that the changes survive a power loss before the
+/// tree recording them is saved. The directories removed by the actions are
+/// skipped, their removal being synced with their parent
+fn sync_dirs(link_root: &AbstPath, dirs: &[AbstPath]) -> Result<()> {
+    for dir in dirs {
+        let path = link_root.append(dir);
+        if path.object_type() == Some(fs::ObjectType::Dir) {
+            fs::sync_dir(&path).context("could not sync applied actions to disk")?;
+        }
+    }
+    Ok(())
+}
+
+/// Apply a single action, as done by `apply_actions`
+fn apply_action(
+    link_root: &AbstPath,
+    path: &AbstPath,
+    action: Action,
+    source: &AbstPath,
+    backup: Option<&AbstPath>,
+    retries: u32,
+) -> Result<()> {
+    let to_path = link_root.append(path);
+    let from_source_path = source.append(path);
+    let errmsg = |msg: &str| -> String {
+        format!(
+            "could not {} to apply actions\npath: {}",
+            msg,
+            to_path.clone()
+        )
+    };
+    let backup_path = backup.map(|backup| backup.append(path));
+    debug!("apply action: {}: {}", action, path);
+    match action {
+        Action::AddDir => {
+            retry(retries, || fs::create_dir(&to_path))
+                .context(errmsg("create added directory"))?;
+        }
+        Action::AddFile(mtime, _, mode) => {
+            retry(retries, || fs::rename_file(&from_source_path, &to_path))
+                .context(errmsg("move added file from source"))?;
+            retry(retries, || fs::set_mode(&to_path, &mode))
+                .context(errmsg("set mode of added file"))?;
+            retry(retries, || fs::set_mtime(&to_path, &mtime))
+                .context(errmsg("set mtime of added file"))?;
+        }
+        Action::AddSymLink(mtime, _) => {
+            retry(retries, || fs::rename_symlink(&from_source_path, &to_path))
+                .context(errmsg("move added symlink from source"))?;
+            retry(retries, || fs::set_mtime(&to_path, &mtime))
+                .context(errmsg("set mtime of added symlink"))?;
+        }
+        Action::AddHardLink(original) => {
+            let original = link_root.append(&original);
+            retry(retries, || fs::create_hardlink(&original, &to_path))
+                .context(errmsg("create added hard link"))?;
+        }
+        Action::Move(from, mtime, _, mode) => {
+            let from_path = link_root.append(&from);
+            match backup {
+                // The moved file is also kept in the backup, as its old path is
+                //	restored from there when undoing
+                Some(backup) => {
+                    retry(retries, || fs::create_hardlink(&from_path, &to_path))
+                        .context(errmsg("link moved file"))?;
+                    retry(retries, || {
+                        fs::rename_file(&from_path, &backup.append(&from))
+                    })
+                    .context(errmsg("back up old path of moved file"))?;
+                }
+                None => retry(retries, || fs::rename_file(&from_path, &to_path))
+                    .context(errmsg("move renamed file"))?,
+            }
+            retry(retries, || fs::set_mode(&to_path, &mode))
+                .context(errmsg("set mode of moved file"))?;
+            retry(retries, || fs::set_mtime(&to_path, &mtime))
+                .context(errmsg("set mtime of moved file"))?;
+        }
+        Action::Copy(from, mtime, _, mode) => {
+            let from_path = link_root.append(&from);
+            retry(retries, || fs::copy_file(&from_path, &to_path)).context(errmsg("copy file"))?;
+            retry(retries, || fs::set_mode(&to_path, &mode))
+                .context(errmsg("set mode of copied file"))?;
+            retry(retries, || fs::set_mtime(&to_path, &mtime))
+                .context(errmsg("set mtime of copied file"))?;
+        }
+        Action::EditDir(mtime) => {
+            retry(retries, || fs::set_mtime(&to_path, &mtime))
+                .context(errmsg("set mtime of edited directory"))?;
+        }
+        Action::EditFile(optm, opth, optp) => {
+            if opth.is_some() {
+                if let Some(backup_path) = &backup_path {
+                    retry(retries, || fs::rename_file(&to_path, backup_path))
+                        .context(errmsg("back up edited file"))?;
+                }
+                retry(retries, || fs::rename_file(&from_source_path, &to_path))
+                    .context(errmsg("move edited file from source"))?;
+            }
+            if let Some(mode) = optp {
+                retry(retries, || fs::set_mode(&to_path, &mode))
+                    .context(errmsg("set mode of edited file"))?;
+            }
+            if let Some(mtime) = optm {
+                retry(retries, || fs::set_mtime(&to_path, &mtime))
+                    .context(errmsg("set mtime of edited file"))?;
+            }
+        }
+        Action::EditSymLink(optm, opth) => {
+            if opth.is_some() {
+                if let Some(backup_path) = &backup_path {
+                    retry(retries, || fs::rename_symlink(&to_path, backup_path))
+                        .context(errmsg("back up edited symlink"))?;
+                }
+                retry(retries, || fs::rename_symlink(&from_source_path, &to_path))
+                    .context(errmsg("move edited symlink from source"))?;
+            }
+            if let Some(mtime) = optm {
+                retry(retries, || fs::set_mtime(&to_path, &mtime))
+                    .context(errmsg("set mtime of edited symlink"))?;
+            }
+        }
+        Action::RemoveDir => {
+            if let Some(backup_path) = &backup_path {
+                retry(retries, || fs::rename_dir(&to_path, backup_path))
+                    .context(errmsg("back up deleted dir"))?;
+                return Ok(());
+            }
+            // Why remove_dir_all instead of just remove_dir here?
+            // One would think that, because the delta.flatten() flattens a
+            //	removed directory by recursively adding a removefile/
+            //	removesymlink/removedir for all the nested childs, once we get
+            //	at a removedir we can be sure that the directory is actually
+            //	empty.
+            // This is not true because the directory could contain some
+            //	ignored object, which wouldn't appear as a remove*** and
+            //	wouldn't be removed, so we have to forcefully remove it
+            //	together with the directory itself
+            retry(retries, || fs::remove_dir_all(&to_path))
+                .context(errmsg("remove deleted dir"))?;
+        }
+        Action::RemoveFile => match &backup_path {
+            Some(backup_path) => retry(retries, || fs::rename_file(&to_path, backup_path))
+                .context(errmsg("back up deleted file"))?,
+            None => retry(retries, || fs::remove_file(&to_path))
+                .context(errmsg("remove deleted file"))?,
+        },
+        Action::RemoveSymLink => match &backup_path {
+            Some(backup_path) => retry(retries, || fs::rename_symlink(&to_path, backup_path))
+                .context(errmsg("back up deleted symlink"))?,
+            None => retry(retries, || fs::remove_symlink(&to_path))
+                .context(errmsg("remove deleted symlink"))?,
+        },
+    }
+
+    Ok(())
+}
+
+/// Remove the directories left empty by the removal of the objects at `removed`
+/// (relative to the link root), walking up from each removed object until
+/// reaching the link root or a directory that is not empty, for example because
+/// it contains excluded objects
+fn prune_empty_dirs(link_root: &AbstPath, removed: &[AbstPath], retries: u32) -> Result<()> {
+    for path in removed {
+        let mut parent = path.parent();
+        while let Some(dir) = parent.filter(|dir| !dir.is_empty()) {
+            let to_path = link_root.append(&dir);
+            if to_path.object_type() != Some(fs::ObjectType::Dir) {
+                // Already pruned while walking up from another removed object
+                parent = dir.parent();
+                continue;
+            }
+            let content = fs::list_dir_content(&to_path).with_context(|| {
+                format!("could not list content of dir to prune it\npath: {to_path}")
+            })?;
+            if !content.is_empty() {
+                break;
+            }
+            retry(retries, || fs::remove_dir(&to_path))
+                .with_context(|| format!("could not remove empty dir\npath: {to_path}"))?;
+            parent = dir.parent();
+        }
+    }
+    Ok(())
+}
+
+/// Object that has to be queried to the server before applying the action, if any
+fn query_for(action: &Action) -> Option<(Queryable, &Hash)> {
+    match action {
+        Action::AddFile(_, hash, _) | Action::EditFile(_, Some(hash), _) => {
+            Some((Queryable::File, hash))
+        }
+        Action::AddSymLink(_, hash) | Action::EditSymLink(_, Some(hash)) => {
+            Some((Queryable::SymLink, hash))
+        }
+        _ => None,
+    }
+}
+
+/// Turn each move whose source doesn't have the expected content anymore back into
+/// the removal of the source and the addition of the file, for example because the
+/// source was changed locally and the local change is being discarded
+fn check_moves(link_root: &AbstPath, actions: Actions) -> Result<Actions> {
+    let mut checked = Actions::new();
+    for (path, action) in actions {
+        match action {
+            Action::Move(from, mtime, hash, mode) => {
+                let from_path = link_root.append(&from);
+                let unchanged = from_path.object_type() == Some(fs::ObjectType::File)
+                    && check_written(&from_path, &hash)?;
+                match unchanged {
+                    true => checked.push(path, Action::Move(from, mtime, hash, mode)),
+                    false => {
+                        checked.push(from, Action::RemoveFile);
+                        checked.push(path, Action::AddFile(mtime, hash, mode));
+                    }
+                }
+            }
+            action => checked.push(path, action),
+        }
+    }
+    Ok(checked)
+}
+
+/// Drop the removals of the objects that don't have the content they had in
+/// `known` anymore (the tree the removals were computed from), returning the
+/// remaining actions and the paths of the objects kept. A directory is kept if
+/// anything in it changed, or if it contains objects that are neither in `known`
+/// nor excluded, as they would be removed with it. Objects that are not there
+/// anymore have nothing to lose, so their removals are kept
+fn keep_changed_removals(
+    link_root: &AbstPath,
+    actions: Actions,
+    known: &FSTree,
+    exclude_list: &ExcludeList,
+) -> Result<(Actions, Vec<AbstPath>)> {
+    let mut checked = Actions::new();
+    let mut kept = Vec::new();
+    for (path, action) in actions {
+        let removal = matches!(
+            action,
+            Action::RemoveDir | Action::RemoveFile | Action::RemoveSymLink
+        );
+        let unchanged = match (removal, known.get_at(&path)) {
+            (false, _) => true,
+            (true, Some(node)) => unchanged_since(link_root, &path, node, exclude_list)?,
+            (true, None) => !link_root.append(&path).exists(),
+        };
+        match unchanged {
+            true => checked.push(path, action),
+            false => kept.push(path),
+        }
+    }
+    Ok((checked, kept))
+}
+
+/// Whether the object at path (relative to the link root) still has the content
+/// of `node`, or doesn't exist anymore. See `keep_changed_removals`
+fn unchanged_since(
+    link_root: &AbstPath,
+    path: &AbstPath,
+    node: &FSNode,
+    exclude_list: &ExcludeList,
+) -> Result<bool> {
+    let to_path = link_root.append(path);
+    let errmsg = || format!("could not check object before removing it\npath: {to_path}");
+    match (node, to_path.object_type()) {
+        (_, None) => Ok(true),
+        (FSNode::File(_, hash, _, _), Some(fs::ObjectType::File)) => check_written(&to_path, hash),
+        (FSNode::SymLink(_, hash), Some(fs::ObjectType::SymLink)) => {
+            let endpoint = fs::read_link(&to_path).with_context(errmsg)?;
+            Ok(hasher::hash_bytes(endpoint.as_bytes()) == *hash)
+        }
+        (FSNode::Dir(_, _, FSTree(subtree)), Some(fs::ObjectType::Dir)) => {
+            for entry in fs::list_dir_content(&to_path).with_context(errmsg)? {
+                let name = entry.file_name().with_context(errmsg)?;
+                let subpath = path.add_last(&name);
+                let unchanged = match subtree.get(&name) {
+                    Some(node) => unchanged_since(link_root, &subpath, node, exclude_list)?,
+                    None => exclude_list.should_exclude(
+                        &AbstPath::single(".").append(&subpath),
+                        entry.object_type() == Some(fs::ObjectType::Dir),
+                    ),
+                };
+                if !unchanged {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Number of times a written file that fails verification is queried again
+/// before giving up
+const VERIFY_REFETCHES: u32 = 2;
+
+/// Read back the file written at `path` and check that its content matches the
+/// expected hash
+fn check_written(path: &AbstPath, expected: &Hash) -> Result<bool> {
+    let file = fs::read_file(path)
+        .with_context(|| format!("could not open written file to verify it\npath: {path}"))?;
+    let found = hasher::hash_stream(file)
+        .with_context(|| format!("could not hash written file to verify it\npath: {path}"))?;
+    Ok(found == *expected)
+}
+
+/// Verify the file just written at `path` (relative to the link root), querying
+/// it again into `source` and replacing it, keeping the mode and mtime already
+/// set, for as long as its content doesn't match the expected hash. Gives up
+/// after `VERIFY_REFETCHES` attempts
+async fn verify_written(
+    com: &mut BbupCom,
+    link_root: &AbstPath,
+    path: &AbstPath,
+    expected: &Hash,
+    source: &AbstPath,
+    retries: u32,
+) -> Result<()> {
+    let to_path = link_root.append(path);
+    let from_source_path = source.append(path);
+    let mut refetches = 0;
+    while !check_written(&to_path, expected)? {
+        if refetches == VERIFY_REFETCHES {
+            anyhow::bail!(
+                "written file does not match the expected hash after being queried {} more times\npath: {}",
+                VERIFY_REFETCHES,
+                to_path
+            );
+        }
+        refetches += 1;
+        warn!("written file does not match the expected hash, querying it again\npath: {to_path}");
+
+        let mtime = fs::get_mtime(&to_path).context("could not get mtime of corrupted file")?;
+        let mode = fs::get_mode(&to_path).context("could not get mode of corrupted file")?;
+        // The corrupted file is not used as basis, as any block taken from it
+        //	could be corrupted as well
+        com.query_object(Queryable::File, path, expected, source, None)
+            .await
+            .context("could not query again corrupted file")?;
+        retry(retries, || fs::rename_file(&from_source_path, &to_path))
+            .context("could not replace corrupted file")?;
+        retry(retries, || fs::set_mode(&to_path, &mode))
+            .context("could not set mode of replaced file")?;
+        retry(retries, || fs::set_mtime(&to_path, &mtime))
+            .context("could not set mtime of replaced file")?;
+    }
+    Ok(())
+}
+
+/// Same as `apply_actions`, but the content needed by the actions is queried to
+/// the other party as the actions are applied: each object is queried into
+/// `source` right before applying its action, so that at most one object at a
+/// time waits there instead of the whole update. Actions are applied in order,
+/// so directories are still created before their content.
+/// If `parallel` is more than one and the other party supports it, the objects
+/// are queried in batches of `parallel` objects, so that at most that many
+/// objects wait in `source`.
+/// If `verify` is set, each written file is read back and checked against its
+/// expected hash, and queried again if it doesn't match.
+/// The changes are synced to the disk before returning, as the tree recording
+/// them is saved right after.
+/// The overall progress is reported to the progress reporter of `com`, if any,
+/// weighing each action by its `work` (see `Actions::work`), along with each
+/// action as soon as it is applied
+#[allow(clippy::too_many_arguments)]
+async fn stream_actions(
+    com: &mut BbupCom,
+    link_root: &AbstPath,
+    actions: Actions,
+    work: Vec<u64>,
+    source: &AbstPath,
+    backup: Option<&AbstPath>,
+    retries: u32,
+    verify: bool,
+    parallel: usize,
+) -> Result<()> {
+    let dirs = actions.parent_dirs();
+    let actions = actions.into_iter().collect::<Vec<(AbstPath, Action)>>();
+    let batched = parallel > 1 && com.protocol_version >= 4;
+    let mut progress = OverallProgress::start(com.reporter.clone(), work);
+    // Index of the first action whose object has not been queried yet
+    let mut queried = 0;
+    for index in 0..actions.len() {
+        // The actions already applied are kept, and recorded by the caller
+        if cancel::is_cancelled() {
+            com.end_queries()
+                .await
+                .context("could not end queries to apply actions")?;
+            anyhow::bail!(
+                "sync cancelled after applying {index} of {} actions",
+                actions.len()
+            );
+        }
+        if batched && index >= queried {
+            let mut batch = Vec::new();
+            queried = index;
+            while queried < actions.len() && batch.len() < parallel {
+                let (path, action) = &actions[queried];
+                if let Some((queryable, hash)) = query_for(action) {
+                    batch.push((queryable, path.clone(), hash.clone()));
+                }
+                queried += 1;
+            }
+            if !batch.is_empty() {
+                com.query_batch(&batch, source, Some(link_root))
+                    .await
+                    .context("could not query objects to apply actions")?;
+            }
+        }
+
+        let started = Instant::now();
+        let (path, action) = actions[index].clone();
+        let kind = action.kind();
+        let mut written = None;
+        if let Some((queryable, hash)) = query_for(&action) {
+            if verify && matches!(queryable, Queryable::File) {
+                written = Some(hash.clone());
+            }
+            if !batched {
+                com.query_object(queryable, &path, hash, source, Some(link_root))
+                    .await
+                    .context("could not query object to apply actions")?;
+            }
+        }
+        apply_action(link_root, &path, action, source, backup, retries)?;
+        if let Some(expected) = written {
+            verify_written(com, link_root, &path, &expected, source, retries).await?;
+        }
+        progress.complete_action(index, &path, kind, started.elapsed());
+    }
+    com.end_queries()
+        .await
+        .context("could not end queries to apply actions")?;
+    sync_dirs(link_root, &dirs)
+}
+
 pub async fn apply_update_or_get_conflicts(
     config: &ProcessConfig,
     state: &mut ProcessState,
@@ -75,124 +872,131 @@ pub async fn apply_update_or_get_conflicts(
     match (&state.local_delta, &state.update) {
         (Some(local_delta), Some((update_id, update_delta))) => {
             // Check for conflicts or get the necessary actions
-            let necessary_actions = match get_actions_or_conflicts(local_delta, update_delta) {
+            let applied = applied_delta(config, update_delta);
+            let mut necessary_actions = match get_actions_with_strategy(
+                local_delta,
+                &applied,
+                config.flags.on_conflict,
+            ) {
                 Ok(actions) => actions,
                 Err(conflicts) => {
                     println!("conflicts:\n{}", conflicts);
                     anyhow::bail!(
-                        "found conflicts between pulled update and local changes. Resolve manually"
-                    )
+                            "found {} conflicts between pulled update and local changes. Resolve manually",
+                            conflicts.paths().len()
+                        )
                 }
             };
+            // Renamed files are moved locally instead of being downloaded again
+            necessary_actions.detect_moves(&applied);
+            let mut necessary_actions = check_moves(&config.link_root, necessary_actions)?;
+            if config.flags.safe_delete {
+                let (checked, kept) = keep_changed_removals(
+                    &config.link_root,
+                    necessary_actions,
+                    &state.last_known_fstree,
+                    &config.exclude_list,
+                )?;
+                if !kept.is_empty() {
+                    let paths: Vec<String> =
+                        kept.iter().map(|path| format!("\n\t{path}")).collect();
+                    warn!(
+                        "not removing objects changed since the last sync, the next sync pushes them back:{}",
+                        paths.concat()
+                    );
+                }
+                necessary_actions = checked;
+            }
 
             // Check if it is possible to apply the update or something went wrong
             let mut updated_fstree = state.last_known_fstree.clone();
             updated_fstree.apply_delta(update_delta)?;
 
-            // Download files that need to be downloaded
-            let mut queries = Vec::new();
-            for (path, action) in &necessary_actions {
-                match action {
-                    Action::AddFile(_, hash) | Action::EditFile(_, Some(hash)) => {
-                        queries.push((Queryable::File, path.clone(), hash.clone()))
-                    }
-
-                    Action::AddSymLink(_, hash) | Action::EditSymLink(_, Some(hash)) => {
-                        queries.push((Queryable::SymLink, path.clone(), hash.clone()))
-                    }
-
-                    _ => {}
-                }
+            // Download the files that need to be downloaded while applying the
+            //	actions, keeping what gets replaced or removed to be able to undo
+            //	the sync
+            let backup = LastSync::backup_path(&config.link_root);
+            let applied_any = !necessary_actions.is_empty();
+            let removed = (&necessary_actions)
+                .into_iter()
+                .filter_map(|(path, action)| match action {
+                    Action::RemoveDir | Action::RemoveFile | Action::RemoveSymLink => Some(path),
+                    Action::Move(from, ..) => Some(from),
+                    _ => None,
+                })
+                .cloned()
+                .collect::<Vec<AbstPath>>();
+            if applied_any {
+                fs::make_clean_dir(&backup).context("could not clean backup of last sync")?;
             }
-            com.query_files(
-                queries,
-                &config.link_root.add_last(".bbup").add_last("temp"),
+            let work = necessary_actions.work(&updated_fstree);
+            let applied = stream_actions(
+                com,
+                &config.link_root,
+                necessary_actions,
+                work,
+                &config.temp_dir,
+                Some(&backup),
+                config.flags.io_retries,
+                config.flags.verify,
+                config.flags.parallel,
             )
             .await
-            .context("could not query files and symlinks to apply update")?;
-
-            // Apply actions
-            for (path, action) in necessary_actions {
-                let to_path = config.link_root.append(&path);
-                let from_temp_path = config
-                    .link_root
-                    .add_last(".bbup")
-                    .add_last("temp")
-                    .append(&path);
-                let errmsg = |msg: &str| -> String {
-                    format!(
-                        "could not {} to apply update\npath: {}",
-                        msg,
-                        to_path.clone()
-                    )
-                };
-                match action {
-                    Action::AddDir => {
-                        fs::create_dir(&to_path).context(errmsg("create added directory"))?;
-                    }
-                    Action::AddFile(mtime, _) => {
-                        fs::rename_file(&from_temp_path, &to_path)
-                            .context(errmsg("move added file from temp"))?;
-                        fs::set_mtime(&to_path, &mtime)
-                            .context(errmsg("set mtime of added file"))?;
-                    }
-                    Action::AddSymLink(mtime, _) => {
-                        fs::rename_symlink(&from_temp_path, &to_path)
-                            .context(errmsg("move added symlink from temp"))?;
-                        fs::set_mtime(&to_path, &mtime)
-                            .context(errmsg("set mtime of added symlink"))?;
-                    }
-                    Action::EditDir(mtime) => {
-                        fs::set_mtime(&to_path, &mtime)
-                            .context(errmsg("set mtime of edited directory"))?;
-                    }
-                    Action::EditFile(optm, opth) => {
-                        if opth.is_some() {
-                            fs::rename_file(&from_temp_path, &to_path)
-                                .context(errmsg("move edited file from temp"))?;
-                        }
-                        if let Some(mtime) = optm {
-                            fs::set_mtime(&to_path, &mtime)
-                                .context(errmsg("set mtime of edited file"))?;
-                        }
-                    }
-                    Action::EditSymLink(optm, opth) => {
-                        if opth.is_some() {
-                            fs::rename_symlink(&from_temp_path, &to_path)
-                                .context(errmsg("move edited symlink from temp"))?;
-                        }
-                        if let Some(mtime) = optm {
-                            fs::set_mtime(&to_path, &mtime)
-                                .context(errmsg("set mtime of edited symlink"))?;
-                        }
-                    }
-                    Action::RemoveDir => {
-                        // Why remove_dir_all instead of just remove_dir here?
-                        // One would think that, because the delta.flatten() flattens a
-                        //	removed directory by recursively adding a removefile/
-                        //	removesymlink/removedir for all the nested childs, once we get
-                        //	at a removedir we can be sure that the directory is actually
-                        //	empty.
-                        // This is not true because the directory could contain some
-                        //	ignored object, which wouldn't appear as a remove*** and
-                        //	wouldn't be removed, so we have to forcefully remove it
-                        //	together with the directory itself
-                        fs::remove_dir_all(&to_path).context(errmsg("remove deleted dir"))?;
-                    }
-                    Action::RemoveFile => {
-                        fs::remove_file(&to_path).context(errmsg("remove deleted file"))?;
-                    }
-                    Action::RemoveSymLink => {
-                        fs::remove_symlink(&to_path).context(errmsg("remove deleted symlink"))?;
+            .and_then(|()| match config.flags.prune_empty_dirs {
+                true => prune_empty_dirs(&config.link_root, &removed, config.flags.io_retries),
+                false => Ok(()),
+            });
+            if let Err(err) = applied {
+                // Keep track of the actions applied before the failure, so that
+                //	they can be undone
+                if let Some(pre_tree) = &state.new_tree {
+                    let (partial_tree, _) = generate_tree(config, &state.last_known_fstree, None)?;
+                    LastSync {
+                        previous_commit: state.last_known_commit.clone(),
+                        previous_fstree: state.last_known_fstree.clone(),
+                        applied: get_delta(pre_tree, &partial_tree),
+                        post_tree: partial_tree,
                     }
+                    .save(&config.link_root)?;
                 }
+                return Err(match cancel::is_cancelled() {
+                    true => err.context(
+                        "update partially applied, the next sync completes it, or use 'bbup undo' to revert the actions already applied",
+                    ),
+                    false => err.context(
+                        "could not apply update, use 'bbup undo' to revert the actions already applied",
+                    ),
+                });
             }
+
+            let previous_commit = state.last_known_commit.clone();
+            let previous_fstree = state.last_known_fstree.clone();
             state.last_known_commit = update_id.clone();
             state.last_known_fstree = updated_fstree;
-            state.save(&config.link_root)?;
+            if !state.partial {
+                state.save(&config.link_root)?;
+            }
 
-            let new_tree = generate_fstree(&config.link_root, &config.exclude_list)?;
-            let local_delta = get_delta(&state.last_known_fstree, &new_tree);
+            let (new_tree, _) = generate_tree(config, &state.last_known_fstree, None)?;
+            let local_delta = local_changes(
+                &state.last_known_fstree,
+                &new_tree,
+                &config.exclude_list,
+                config.flags.delete_excluded,
+                &config.delta_options(),
+            )?;
+
+            if applied_any {
+                if let Some(pre_tree) = &state.new_tree {
+                    LastSync {
+                        previous_commit,
+                        previous_fstree,
+                        applied: get_delta(pre_tree, &new_tree),
+                        post_tree: new_tree.clone(),
+                    }
+                    .save(&config.link_root)?;
+                }
+            }
 
             state.new_tree = Some(new_tree);
             state.local_delta = Some(local_delta);
@@ -219,13 +1023,41 @@ pub async fn upload_changes(
             // Await green light to procede
             com.check_ok().await?;
 
-            com.send_struct(local_delta).await?;
+            let local_delta = &pushed_delta(config, local_delta);
+            let mut hardlinks = match config.flags.hardlinks {
+                true => find_hardlinks(&config.link_root, new_tree)
+                    .context("could not find hard links to push")?,
+                false => HardLinkGroups::empty(),
+            };
+            // Injective links push to the archive root, with their changes
+            //	under the endpoint
+            match config.link_type {
+                LinkType::Injection => {
+                    com.send_struct(local_delta.clone().prefixed(&config.endpoint))
+                        .await?;
+                    let HardLinkGroups(groups) = &mut hardlinks;
+                    for path in groups.iter_mut().flatten() {
+                        *path = config.endpoint.append(path);
+                    }
+                }
+                _ => com.send_struct(local_delta).await?,
+            }
+            com.send_struct(hardlinks).await?;
+
+            // Servers older than protocol version 3 take no metadata
+            if com.protocol_version >= 3 {
+                com.send_struct(&config.commit_metadata).await?;
+            }
+            // Servers older than protocol version 4 query one object at a time
+            if com.protocol_version >= 4 {
+                com.send_struct(config.flags.parallel as u64).await?;
+            }
 
             let mut queryables = Vec::new();
             for (path, action) in &local_delta.to_actions() {
                 match action {
-                    Action::AddFile(_, _)
-                    | Action::EditFile(_, Some(_))
+                    Action::AddFile(_, _, _)
+                    | Action::EditFile(_, Some(_), _)
                     | Action::AddSymLink(_, _)
                     | Action::EditSymLink(_, Some(_)) => queryables.push(path.clone()),
 
@@ -237,8 +1069,20 @@ pub async fn upload_changes(
                 .context("could not supply files and symlinks to upload push")?;
 
             state.last_known_commit = com.get_struct().await?;
-            state.last_known_fstree = new_tree.clone();
-            state.save(&config.link_root)?;
+            // The endpoint might still contain objects that are missing from the
+            //	new tree (now excluded, or outside of the pushed subpath), so the last known tree is updated
+            //	with what has actually been pushed
+            state.last_known_fstree.apply_delta(local_delta)?;
+            if !state.partial {
+                state.save(&config.link_root)?;
+            }
+            // The server finalizes the commit only once it knows that it has been
+            //	saved. Servers older than protocol version 6 don't wait for it
+            if com.protocol_version >= 6 {
+                com.confirm()
+                    .await
+                    .context("could not confirm commit id of the push")?;
+            }
 
             Ok(())
         }
@@ -251,3 +1095,519 @@ pub async fn upload_changes(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        apply_actions, apply_update_or_get_conflicts, get_local_delta, keep_changed_removals,
+        prune_empty_dirs, query_for, stream_actions, verify_written,
+    };
+    use crate::{
+        cancel, sync::loopback, LastSync, LinkConfig, LinkType, ProcessConfig, ProcessState,
+    };
+    use abst_fs::{self as fs, AbstPath, Durability, Mode, Mtime};
+    use bbup::com::{ProgressEvent, ProgressReporter, Queryable};
+    use fs_vcs::{
+        generate_fstree, get_delta, Action, Actions, CommitID, Delta, ExcludeList, FSTree, TargetOs,
+    };
+    use hasher::Hash;
+
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[test]
+    fn test() {
+        let test_path = AbstPath::from("/tmp/bbup-test-protocol");
+        assert!(!test_path.exists());
+        fs::create_dir(&test_path).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            safe_delete(&scenario(&test_path, "safe-delete"));
+            streamed_actions(&scenario(&test_path, "streamed"));
+            parallel_queries(&scenario(&test_path, "parallel"));
+            cancelled_update(&scenario(&test_path, "cancelled"));
+            verified_writes(&scenario(&test_path, "verified"));
+            pruned_dirs(&scenario(&test_path, "pruned"));
+            invalid_names(&scenario(&test_path, "names"));
+            cli_excludes(&scenario(&test_path, "cli-excludes"));
+        });
+        fs::remove_dir_all(&test_path).unwrap();
+        assert!(result.is_ok());
+    }
+
+    /// Empty directory for a scenario of the test
+    fn scenario(test_path: &AbstPath, name: &str) -> AbstPath {
+        let path = test_path.add_last(name);
+        fs::create_dir(&path).unwrap();
+        path
+    }
+
+    fn write(path: &AbstPath, content: &str) {
+        let mut file = fs::create_file(path).unwrap();
+        std::io::Write::write_all(&mut file, content.as_bytes()).unwrap();
+    }
+
+    fn tree(path: &AbstPath) -> FSTree {
+        generate_fstree(path, &ExcludeList::from(&vec![]).unwrap()).unwrap()
+    }
+
+    /// Content of the other party: many small files in nested directories, and
+    /// a larger one. Returns its tree
+    fn remote(root: &AbstPath) -> FSTree {
+        fs::create_dir(root).unwrap();
+        for dir in 0..3 {
+            let dir_path = root.add_last(format!("dir-{dir}"));
+            fs::create_dir(&dir_path).unwrap();
+            fs::create_dir(&dir_path.add_last("nested")).unwrap();
+            for file in 0..4 {
+                let content = format!("content of file {file} of dir {dir}");
+                write(&dir_path.add_last(format!("file-{file}.txt")), &content);
+                write(
+                    &dir_path
+                        .add_last("nested")
+                        .add_last(format!("file-{file}.txt")),
+                    &content.repeat(file + 1),
+                );
+            }
+        }
+        write(
+            &root.add_last("large.txt"),
+            &"large content\n".repeat(20_000),
+        );
+        tree(root)
+    }
+
+    /// Objects that have to be queried to apply the actions
+    fn queries(actions: &Actions) -> Vec<(Queryable, AbstPath, Hash)> {
+        actions
+            .into_iter()
+            .filter_map(|(path, action)| {
+                query_for(action).map(|(queryable, hash)| (queryable, path.clone(), hash.clone()))
+            })
+            .collect()
+    }
+
+    /// Apply the actions to the link with `stream_actions`, querying the objects
+    /// to the other end of a loopback connection, which supplies them from the
+    /// remote root
+    fn stream(
+        link_root: &AbstPath,
+        remote_root: &AbstPath,
+        actions: Actions,
+        verify: bool,
+        parallel: usize,
+    ) -> anyhow::Result<()> {
+        let queryable: Vec<AbstPath> = queries(&actions)
+            .into_iter()
+            .map(|(_, path, _)| path)
+            .collect();
+        let work = vec![1; (&actions).into_iter().count()];
+        let (link_root, source) = (link_root.clone(), fs::resolve_temp_dir(link_root, None));
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let (mut client, mut server) = loopback().await;
+            // The client is dropped as soon as it is done, so that the other
+            //	party stops waiting for queries even if it failed
+            let (applied, _) = tokio::join!(
+                async move {
+                    stream_actions(
+                        &mut client,
+                        &link_root,
+                        actions,
+                        work,
+                        &source,
+                        None,
+                        0,
+                        verify,
+                        parallel,
+                    )
+                    .await
+                },
+                server.supply_files(&queryable, remote_root)
+            );
+            applied
+        })
+    }
+
+    /// Apply the actions to the link as a whole: all the objects are queried
+    /// first, and only then the actions are applied
+    fn batch(link_root: &AbstPath, remote_root: &AbstPath, actions: Actions) {
+        let queries = queries(&actions);
+        let queryable: Vec<AbstPath> = queries.iter().map(|(_, path, _)| path.clone()).collect();
+        let source = fs::resolve_temp_dir(link_root, None);
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let (mut client, mut server) = loopback().await;
+            let (queried, supplied) = tokio::join!(
+                client.query_files(queries, &source, None, 1),
+                server.supply_files(&queryable, remote_root)
+            );
+            queried.unwrap();
+            supplied.unwrap();
+        });
+        apply_actions(link_root, actions, &source, None, 0, Durability::Synced).unwrap();
+    }
+
+    fn streamed_actions(test_path: &AbstPath) {
+        let remote_root = test_path.add_last("remote");
+        let remote_tree = remote(&remote_root);
+        let delta = get_delta(&FSTree::empty(), &remote_tree);
+
+        // Applying the actions while the objects stream in gives the same link
+        //	as applying them once all the objects are there
+        let streamed = scenario(test_path, "streamed");
+        stream(&streamed, &remote_root, delta.to_actions(), false, 1).unwrap();
+        let batched = scenario(test_path, "batched");
+        batch(&batched, &remote_root, delta.to_actions());
+
+        let streamed_tree = tree(&streamed);
+        assert_eq!(streamed_tree, remote_tree);
+        assert_eq!(streamed_tree, tree(&batched));
+    }
+
+    /// Reporter cancelling the sync once the given number of actions is applied
+    struct CancelAfter(usize, AtomicUsize);
+    impl ProgressReporter for CancelAfter {
+        fn report(&self, event: ProgressEvent) {
+            if let ProgressEvent::ActionDone { .. } = event {
+                if self.1.fetch_add(1, Ordering::SeqCst) + 1 == self.0 {
+                    cancel::cancel();
+                }
+            }
+        }
+    }
+
+    fn cancelled_update(test_path: &AbstPath) {
+        let remote_root = test_path.add_last("remote");
+        fs::create_dir(&remote_root).unwrap();
+        for name in ["a", "b", "c", "d", "e", "f"] {
+            write(&remote_root.add_last(name), &format!("content of {name}"));
+        }
+        let update = get_delta(&FSTree::empty(), &tree(&remote_root));
+        let queryable: Vec<AbstPath> = queries(&update.to_actions())
+            .into_iter()
+            .map(|(_, path, _)| path)
+            .collect();
+
+        let link_root = scenario(test_path, "link");
+        ProcessState::init_state().save(&link_root).unwrap();
+        let config = ProcessConfig::for_link(&link_root);
+        let mut state = ProcessState::init_state();
+        state.new_tree = Some(FSTree::empty());
+        state.local_delta = Some(Delta::empty());
+        state.update = Some((CommitID::gen_valid(), update.clone()));
+
+        // The sync is cancelled after applying two of the actions
+        let _lock = cancel::lock();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let applied = runtime.block_on(async {
+            let (mut client, mut server) = loopback().await;
+            client.set_reporter(Arc::new(CancelAfter(2, AtomicUsize::new(0))));
+            let (applied, supplied) = tokio::join!(
+                apply_update_or_get_conflicts(&config, &mut state, &mut client),
+                server.supply_files(&queryable, &remote_root)
+            );
+            supplied.unwrap();
+            applied
+        });
+        cancel::reset();
+        let error = format!("{:#}", applied.unwrap_err());
+        assert!(error.contains("sync cancelled after applying 2 of 6 actions"));
+
+        // The last sync records exactly the two actions applied, and the link
+        //	has nothing else
+        let last_sync = LastSync::load(&link_root).unwrap();
+        let expected: Vec<(AbstPath, Action)> = update.to_actions().into_iter().take(2).collect();
+        let mut recorded: Vec<(AbstPath, Action)> =
+            last_sync.applied.to_actions().into_iter().collect();
+        recorded.sort_by_key(|(path, _)| expected.iter().position(|(other, _)| other == path));
+        assert_eq!(recorded, expected);
+        assert_eq!(last_sync.post_tree, tree(&link_root));
+        let mut applied_tree = FSTree::empty();
+        applied_tree.apply_delta(&last_sync.applied).unwrap();
+        assert_eq!(applied_tree, tree(&link_root));
+
+        // The link state still refers to the tree before the sync, so that the
+        //	next sync completes it
+        let stored = ProcessState::load(&link_root).unwrap();
+        assert_eq!(stored.last_known_fstree, FSTree::empty());
+        assert_eq!(
+            LastSync::load(&link_root).unwrap().previous_fstree,
+            FSTree::empty()
+        );
+    }
+
+    /// Write of a faulty disk: the file keeps its size, but some of its bytes
+    /// are flipped
+    fn corrupt(path: &AbstPath) {
+        let mut content = std::fs::read(path.to_path_buf()).unwrap();
+        for byte in content.iter_mut().step_by(7) {
+            *byte = !*byte;
+        }
+        std::fs::write(path.to_path_buf(), content).unwrap();
+    }
+
+    /// Verify the file at path of the link, querying it again to the other end
+    /// of a loopback connection if needed, which supplies it from the remote root
+    fn verify(
+        link_root: &AbstPath,
+        remote_root: &AbstPath,
+        path: &AbstPath,
+        expected: &Hash,
+    ) -> anyhow::Result<()> {
+        let source = fs::resolve_temp_dir(link_root, None);
+        let queryable = [path.clone()];
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let (mut client, mut server) = loopback().await;
+            let (verified, _) = tokio::join!(
+                async move {
+                    let verified =
+                        verify_written(&mut client, link_root, path, expected, &source, 0).await;
+                    client.end_queries().await.unwrap();
+                    verified
+                },
+                server.supply_files(&queryable, remote_root)
+            );
+            verified
+        })
+    }
+
+    fn verified_writes(test_path: &AbstPath) {
+        let remote_root = test_path.add_last("remote");
+        let link_root = scenario(test_path, "link");
+        fs::create_dir(&remote_root).unwrap();
+        let path = AbstPath::from("data.txt");
+        let content = "content to verify\n".repeat(1000);
+        let expected = hasher::hash_bytes(content.as_bytes());
+        write(&remote_root.append(&path), &content);
+
+        // Written files that are fine are left as they are
+        let delta = get_delta(&FSTree::empty(), &tree(&remote_root));
+        stream(&link_root, &remote_root, delta.to_actions(), true, 1).unwrap();
+        assert_eq!(tree(&link_root), tree(&remote_root));
+
+        // A corrupted write is detected and the file is queried again, keeping
+        //	the mode and mtime it was given after being written
+        let to_path = link_root.append(&path);
+        corrupt(&to_path);
+        fs::set_mode(&to_path, &Mode::from(0o600)).unwrap();
+        fs::set_mtime(&to_path, &Mtime::from(1667000000, 0)).unwrap();
+        verify(&link_root, &remote_root, &path, &expected).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(to_path.to_path_buf()).unwrap(),
+            content
+        );
+        assert_eq!(fs::get_mode(&to_path).unwrap(), Mode::from(0o600));
+        assert_eq!(fs::get_mtime(&to_path).unwrap(), Mtime::from(1667000000, 0));
+
+        // If the right content can't be obtained, the mismatch fails the sync
+        //	instead of going unnoticed
+        corrupt(&to_path);
+        corrupt(&remote_root.append(&path));
+        let error = verify(&link_root, &remote_root, &path, &expected).unwrap_err();
+        assert!(format!("{error:#}").contains("could not query again corrupted file"));
+        assert_ne!(
+            std::fs::read(to_path.to_path_buf()).unwrap(),
+            content.as_bytes()
+        );
+    }
+
+    fn pruned_dirs(test_path: &AbstPath) {
+        let dirs = ["emptied", "emptied/nested", "excluded", "excluded/nested"];
+        for dir in dirs {
+            fs::create_dir(&test_path.append(&AbstPath::from(dir))).unwrap();
+        }
+        write(&test_path.add_last("kept.txt"), "kept");
+        write(
+            &test_path.append(&AbstPath::from("emptied/nested/only.txt")),
+            "only",
+        );
+        write(
+            &test_path.append(&AbstPath::from("excluded/nested/only.txt")),
+            "only",
+        );
+        write(
+            &test_path.append(&AbstPath::from("excluded/nested/debug.log")),
+            "excluded",
+        );
+        let removed = vec![
+            AbstPath::from("emptied/nested/only.txt"),
+            AbstPath::from("excluded/nested/only.txt"),
+        ];
+        let mut actions = Actions::new();
+        for path in &removed {
+            actions.push(path.clone(), Action::RemoveFile);
+        }
+        apply_actions(test_path, actions, test_path, None, 0, Durability::Unsynced).unwrap();
+        prune_empty_dirs(test_path, &removed, 0).unwrap();
+
+        // The directory emptied by the removal is pruned together with its parent,
+        //	while the one still holding an excluded file is kept
+        assert!(!test_path.add_last("emptied").exists());
+        assert!(test_path
+            .append(&AbstPath::from("excluded/nested/debug.log"))
+            .exists());
+        assert!(test_path.add_last("kept.txt").exists());
+    }
+
+    fn invalid_names(link_root: &AbstPath) {
+        fs::create_dir(&link_root.add_last("dir")).unwrap();
+        for name in ["con.txt", "what?.txt", "normal.txt", "dir/fine.txt"] {
+            write(&link_root.append(&AbstPath::from(name)), name);
+        }
+        let mut config = ProcessConfig::for_link(link_root);
+
+        // Syncing to Windows aborts before anything else, listing exactly the
+        //	offending names
+        config.flags.target_os = Some(TargetOs::Windows);
+        let mut state = ProcessState::init_state();
+        let error = format!("{:#}", get_local_delta(&config, &mut state).unwrap_err());
+        assert!(error.contains("2 objects can't be created on Windows"));
+        assert!(error.contains("con.txt"));
+        assert!(error.contains("what?.txt"));
+        assert!(!error.contains("normal.txt"));
+        assert!(!error.contains("fine.txt"));
+        assert!(state.local_delta.is_none());
+
+        // The same names are fine for any other target
+        for target_os in [Some(TargetOs::Unix), None] {
+            config.flags.target_os = target_os;
+            let mut state = ProcessState::init_state();
+            get_local_delta(&config, &mut state).unwrap();
+            assert_eq!(state.new_tree, Some(tree(link_root)));
+            assert!(!state.local_delta.unwrap().is_empty());
+        }
+    }
+
+    fn cli_excludes(link_root: &AbstPath) {
+        let link_config = LinkConfig::from(LinkType::Bijection, AbstPath::empty(), vec![]);
+        link_config.save(link_root).unwrap();
+        let config_path = link_root.add_last(".bbup").add_last("config.toml");
+        let saved_config = std::fs::read(config_path.to_path_buf()).unwrap();
+        for dir in ["node_modules", "src"] {
+            fs::create_dir(&link_root.add_last(dir)).unwrap();
+        }
+        write(
+            &link_root.append(&AbstPath::from("node_modules/dep.js")),
+            "dep",
+        );
+        write(&link_root.append(&AbstPath::from("src/main.rs")), "main");
+        let mut state = ProcessState::init_state();
+        state.last_known_fstree = tree(link_root);
+
+        // Some changes both in and out of the directory excluded for this sync
+        write(
+            &link_root.append(&AbstPath::from("node_modules/new.js")),
+            "new",
+        );
+        fs::remove_file(&link_root.append(&AbstPath::from("node_modules/dep.js"))).unwrap();
+        write(&link_root.append(&AbstPath::from("src/lib.rs")), "lib");
+
+        let mut config = ProcessConfig::for_link(link_root);
+        config.exclude_list = link_config
+            .sync_exclude_list(
+                link_root,
+                &vec![],
+                &vec![String::from("^\\./node_modules/")],
+            )
+            .unwrap();
+        get_local_delta(&config, &mut state).unwrap();
+
+        // The excluded directory is neither removed nor changed, and the link
+        //	config doesn't know about the pattern
+        let Delta(local_delta) = state.local_delta.unwrap();
+        assert_eq!(local_delta.keys().collect::<Vec<&String>>(), vec!["src"]);
+        assert_eq!(
+            std::fs::read(config_path.to_path_buf()).unwrap(),
+            saved_config
+        );
+        assert!(LinkConfig::load(link_root).unwrap().exclude_list.is_empty());
+
+        // An invalid pattern is rejected as such
+        match link_config.sync_exclude_list(link_root, &vec![], &vec![String::from("(")]) {
+            Ok(_) => panic!("invalid pattern accepted"),
+            Err(error) => {
+                assert!(format!("{error:#}").contains("invalid pattern given to --exclude"))
+            }
+        }
+    }
+
+    fn parallel_queries(test_path: &AbstPath) {
+        let remote_root = test_path.add_last("remote");
+        let remote_tree = remote(&remote_root);
+        let delta = get_delta(&FSTree::empty(), &remote_tree);
+
+        // Querying the objects in batches, smaller or larger than the update,
+        //	gives the same link as querying them one at a time
+        let mut trees = Vec::new();
+        for parallel in [1, 4, 64] {
+            let link_root = scenario(test_path, &format!("parallel-{parallel}"));
+            stream(
+                &link_root,
+                &remote_root,
+                delta.to_actions(),
+                false,
+                parallel,
+            )
+            .unwrap();
+            trees.push(tree(&link_root));
+        }
+        for tree in trees {
+            assert_eq!(tree, remote_tree);
+        }
+    }
+
+    fn safe_delete(test_path: &AbstPath) {
+        let exclude_list = ExcludeList::from(&vec![]).unwrap();
+        write(&test_path.add_last("modified.txt"), "synced content");
+        write(&test_path.add_last("unchanged.txt"), "synced content");
+        fs::create_dir(&test_path.add_last("dir")).unwrap();
+        write(
+            &test_path.add_last("dir").add_last("file.txt"),
+            "synced content",
+        );
+        let known = generate_fstree(test_path, &exclude_list).unwrap();
+
+        // Modified out of band after the last sync
+        write(&test_path.add_last("modified.txt"), "newer content");
+        write(
+            &test_path.add_last("dir").add_last("new.txt"),
+            "new content",
+        );
+
+        let mut actions = Actions::new();
+        for name in ["modified.txt", "unchanged.txt", "missing.txt"] {
+            actions.push(AbstPath::from(name), Action::RemoveFile);
+        }
+        actions.push(AbstPath::from("dir"), Action::RemoveDir);
+        actions.push(AbstPath::from("added"), Action::AddDir);
+        let (checked, kept) =
+            keep_changed_removals(test_path, actions, &known, &exclude_list).unwrap();
+
+        let checked: Vec<(AbstPath, Action)> = checked.into_iter().collect();
+        assert_eq!(
+            checked,
+            vec![
+                (AbstPath::from("unchanged.txt"), Action::RemoveFile),
+                (AbstPath::from("missing.txt"), Action::RemoveFile),
+                (AbstPath::from("added"), Action::AddDir),
+            ]
+        );
+        assert_eq!(
+            kept,
+            vec![AbstPath::from("modified.txt"), AbstPath::from("dir"),]
+        );
+
+        // An object that is not there anymore counts as unchanged
+        fs::remove_file(&test_path.add_last("unchanged.txt")).unwrap();
+        let mut actions = Actions::new();
+        actions.push(AbstPath::from("unchanged.txt"), Action::RemoveFile);
+        let (checked, kept) =
+            keep_changed_removals(test_path, actions, &known, &exclude_list).unwrap();
+        assert!(!checked.is_empty());
+        assert!(kept.is_empty());
+    }
+}
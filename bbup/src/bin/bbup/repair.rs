@@ -0,0 +1,114 @@
+use super::{protocol, LastSync, LinkConfig, LinkLock, ProcessState};
+
+use abst_fs::AbstPath;
+use fs_vcs::Commit;
+
+use anyhow::{Context, Result};
+use log::warn;
+
+/// Rebuild the stored tree of the link from the current content of the link,
+/// trusting the local state as the baseline. No file of the link is touched,
+/// but the local changes made since the last sync become part of the baseline
+/// and will not be pushed. The last known commit is kept if it can still be
+/// read, otherwise the link goes back to the base commit and the next sync
+/// pulls the whole endpoint again.
+/// The last sync can't be undone anymore, as its state refers to the old tree
+pub fn repair(cwd: &AbstPath, default_excludes: &Vec<String>) -> Result<()> {
+    let link_config = LinkConfig::load(cwd)?;
+    let _lock = LinkLock::acquire(cwd)?;
+    let exclude_list = link_config.exclude_list(cwd, default_excludes)?;
+
+    let last_known_commit = match ProcessState::load_last_known_commit(cwd) {
+        Ok(commit_id) => commit_id,
+        Err(err) => {
+            warn!("{err:#}\nthe link will be synced again from the base commit");
+            Commit::base_commit().commit_id
+        }
+    };
+    let (tree, _) = protocol::generate_link_tree(cwd, &exclude_list, &link_config.dereference)
+        .context("could not generate current fstree")?;
+    ProcessState::from(last_known_commit, tree).save(cwd)?;
+    if LastSync::exists(cwd) {
+        LastSync::clear(cwd)?;
+    }
+
+    println!("stored tree of link [{cwd}] rebuilt from its current content");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::repair;
+    use crate::{protocol, LastSync, LinkConfig, LinkType, ProcessState};
+    use abst_fs::{self as fs, AbstPath};
+    use fs_vcs::{Commit, CommitID, FSTree};
+
+    #[test]
+    fn test() {
+        let link_root = AbstPath::from("/tmp/bbup-test-repair");
+        assert!(!link_root.exists());
+        fs::create_dir(&link_root).unwrap();
+
+        let result = std::panic::catch_unwind(|| corrupted_tree(&link_root));
+        fs::remove_dir_all(&link_root).unwrap();
+        assert!(result.is_ok());
+    }
+
+    fn write(path: &AbstPath, content: &str) {
+        let mut file = fs::create_file(path).unwrap();
+        std::io::Write::write_all(&mut file, content.as_bytes()).unwrap();
+    }
+
+    fn corrupted_tree(link_root: &AbstPath) {
+        let link_config = LinkConfig::from(
+            LinkType::Bijection,
+            AbstPath::from("endpoint"),
+            vec![String::from("\\.log$")],
+        );
+        link_config.save(link_root).unwrap();
+        fs::create_dir(&link_root.add_last("dir")).unwrap();
+        write(&link_root.add_last("file.txt"), "file");
+        write(&link_root.add_last("dir").add_last("nested.txt"), "nested");
+        write(&link_root.add_last("dir").add_last("debug.log"), "excluded");
+        let exclude_list = link_config.exclude_list(link_root, &vec![]).unwrap();
+        let fresh = || {
+            protocol::generate_link_tree(link_root, &exclude_list, &[])
+                .unwrap()
+                .0
+        };
+
+        // The stored tree gets corrupted, while the last known commit is fine
+        let commit_id = CommitID::gen_valid();
+        ProcessState::from(commit_id.clone(), FSTree::empty())
+            .save(link_root)
+            .unwrap();
+        let stored_tree = link_root.add_last(".bbup").add_last("old-fstree.bin");
+        write(&stored_tree, "not a tree");
+        assert!(ProcessState::load(link_root).is_err());
+
+        repair(link_root, &vec![]).unwrap();
+        let state = ProcessState::load(link_root).unwrap();
+        assert_eq!(state.last_known_fstree, fresh());
+        assert_eq!(state.last_known_commit, commit_id);
+        assert!(state
+            .last_known_fstree
+            .get_at(&AbstPath::from("dir/debug.log"))
+            .is_none());
+        assert!(link_root.add_last("dir").add_last("debug.log").exists());
+        assert!(!LastSync::exists(link_root));
+
+        // Without a readable last known commit either, the link goes back to the
+        //	base commit
+        fs::remove_file(&stored_tree).unwrap();
+        write(
+            &link_root
+                .add_last(".bbup")
+                .add_last("last-known-commit.bin"),
+            "",
+        );
+        repair(link_root, &vec![]).unwrap();
+        let state = ProcessState::load(link_root).unwrap();
+        assert_eq!(state.last_known_fstree, fresh());
+        assert_eq!(state.last_known_commit, Commit::base_commit().commit_id);
+    }
+}
@@ -32,6 +32,14 @@ pub fn setup(home_dir: &AbstPath, opt_local_port: Option<u16>, opt_server_port:
         server_port,
         host_name,
         host_address,
+        known_hosts: None,
+        strict_host_key_checking: None,
+        identity_file: None,
+        ssh_port: None,
+        proxy_jump: None,
+        author: None,
+        temp_dir: None,
+        default_excludes: Vec::new(),
     };
     ClientConfig::from(settings, Vec::new()).save(home_dir)?;
 
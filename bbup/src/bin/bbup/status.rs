@@ -0,0 +1,129 @@
+use super::{protocol, ActionsReport, LinkConfig, ProcessState};
+
+use abst_fs::{AbstPath, MtimePrecision};
+use fs_vcs::DeltaOptions;
+
+use anyhow::{Context, Result};
+
+/// Print the local changes that would be pushed by a sync, without connecting
+/// to the server. If `json` is set, the changes are printed as JSON. Mtimes are
+/// compared up to the given precision
+pub fn status(
+    cwd: &AbstPath,
+    default_excludes: &Vec<String>,
+    json: bool,
+    mtime_precision: MtimePrecision,
+) -> Result<()> {
+    println!(
+        "{}",
+        status_report(cwd, default_excludes, json, mtime_precision)?
+    );
+    Ok(())
+}
+
+/// What `status` prints
+fn status_report(
+    cwd: &AbstPath,
+    default_excludes: &Vec<String>,
+    json: bool,
+    mtime_precision: MtimePrecision,
+) -> Result<String> {
+    let link_config = LinkConfig::load(cwd)?;
+    let exclude_list = link_config.exclude_list(cwd, default_excludes)?;
+    let state = ProcessState::load(cwd)?;
+
+    let (new_tree, skipped) =
+        protocol::generate_link_tree(cwd, &exclude_list, &link_config.dereference)
+            .context("could not generate current fstree")?;
+    protocol::warn_skipped(&skipped);
+    let local_delta = protocol::local_changes(
+        &state.last_known_fstree,
+        &new_tree,
+        &exclude_list,
+        false,
+        &DeltaOptions {
+            precision: mtime_precision,
+            ignore_dir_mtimes: link_config.ignore_dir_mtimes,
+        },
+    )?;
+
+    if json {
+        let report = ActionsReport {
+            stats: local_delta.stats(),
+            actions: local_delta.to_actions(),
+        };
+        serde_json::to_string_pretty(&report).context("could not serialize status")
+    } else if local_delta.is_empty() {
+        Ok(String::from("up to date"))
+    } else {
+        Ok(format!(
+            "local changes:\n{}\n{}",
+            local_delta.stats(),
+            local_delta.to_actions()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::status_report;
+    use crate::{LinkConfig, LinkType, ProcessState};
+    use abst_fs::{self as fs, AbstPath, MtimePrecision};
+    use fs_vcs::{generate_fstree, ExcludeList};
+
+    #[test]
+    fn test() {
+        let link_root = AbstPath::from("/tmp/bbup-test-status");
+        assert!(!link_root.exists());
+        fs::create_dir(&link_root).unwrap();
+
+        let result = std::panic::catch_unwind(|| status(&link_root));
+        fs::remove_dir_all(&link_root).unwrap();
+        assert!(result.is_ok());
+    }
+
+    fn write(path: &AbstPath, content: &str) {
+        let mut file = fs::create_file(path).unwrap();
+        std::io::Write::write_all(&mut file, content.as_bytes()).unwrap();
+    }
+
+    fn status(link_root: &AbstPath) {
+        let excludes = vec![String::from("\\.log$")];
+        LinkConfig::from(LinkType::Bijection, AbstPath::empty(), excludes.clone())
+            .save(link_root)
+            .unwrap();
+        write(&link_root.add_last("synced.txt"), "synced");
+        let mut state = ProcessState::init_state();
+        state.last_known_fstree =
+            generate_fstree(link_root, &ExcludeList::from(&excludes).unwrap()).unwrap();
+        state.save(link_root).unwrap();
+        let report = |json| status_report(link_root, &vec![], json, MtimePrecision::Full).unwrap();
+
+        // Nothing changed since the last sync, excluded objects don't count
+        write(&link_root.add_last("debug.log"), "excluded");
+        assert_eq!(report(false), "up to date");
+
+        // Only the objects that are not excluded show up
+        write(&link_root.add_last("new.txt"), "new");
+        fs::create_dir(&link_root.add_last("dir")).unwrap();
+        write(&link_root.add_last("dir").add_last("trace.log"), "excluded");
+        for json in [false, true] {
+            let report = report(json);
+            assert!(report.contains("new.txt"));
+            assert!(report.contains("dir"));
+            assert!(!report.contains("debug.log"));
+            assert!(!report.contains("trace.log"));
+        }
+
+        // The client's default excludes apply as well
+        let report = status_report(
+            link_root,
+            &vec![String::from("^\\./new\\.txt$")],
+            false,
+            MtimePrecision::Full,
+        )
+        .unwrap();
+        assert!(!report.contains("new.txt"));
+        assert!(report.contains("dir"));
+    }
+}
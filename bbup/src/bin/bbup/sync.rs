@@ -1,79 +1,315 @@
-use super::{protocol, ProcessConfig, ProcessState};
+use super::{cancel, protocol, LinkLock, LinkType, ProcessConfig, ProcessState};
 
 use tokio::net::TcpStream;
 
-use bbup::com::{BbupCom, JobType};
+use abst_fs::AbstPath;
+use bbup::com::{BbupCom, JobType, JsonLinesReporter, ProgressReporter, Traffic};
+use fs_vcs::human_size;
 use ssh_tunnel::SshTunnel;
 
 use anyhow::{bail, Context, Result};
+use log::{error, info};
 
-pub async fn process_link(config: ProcessConfig) -> Result<()> {
-    if config.flags.verbose {
-        println!("Synchronizing link: [{}]", config.link_root);
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Failure while establishing the connection with the server
+enum ConnectError {
+    /// Connection failures that might resolve by themselves, worth retrying
+    Transient(anyhow::Error),
+    /// Failures that retrying would not fix, such as the server refusing the
+    /// conversation
+    Fatal(anyhow::Error),
+}
+
+/// Reporter writing the events of the actions applied to `events` (a file or a
+/// named pipe, appended to, or stdout if '-'), forwarding every event to `inner`
+fn event_reporter(
+    events: &str,
+    inner: Option<Arc<dyn ProgressReporter>>,
+) -> Result<Arc<dyn ProgressReporter>> {
+    if events == "-" {
+        return Ok(Arc::new(JsonLinesReporter::new(std::io::stdout(), inner)));
     }
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(events)
+        .with_context(|| format!("could not open events file\npath: {events}"))?;
+    Ok(Arc::new(JsonLinesReporter::new(file, inner)))
+}
 
-    let process = {
-        let mut tunnel = SshTunnel::to(
-            config.connection.local_port,
-            config.connection.server_port,
-            config.connection.host_name.clone(),
-            config.connection.host_address.clone(),
-        )?;
-
-        if config.flags.verbose {
-            println!("ssh tunnel PID: {}", tunnel.pid());
-        }
+/// Open the ssh tunnel, connect to the server through it and await its green
+/// light to procede
+async fn connect(config: &ProcessConfig) -> Result<(SshTunnel, BbupCom), ConnectError> {
+    let mut tunnel = SshTunnel::to(
+        config.connection.local_port,
+        config.connection.server_port,
+        config.connection.host_name.clone(),
+        config.connection.host_address.clone(),
+        &config.connection.ssh_options,
+    )
+    .context("could not start ssh tunnel")
+    .map_err(ConnectError::Fatal)?;
 
-        tunnel.wait_for_ready()?;
+    bbup::logger::set_connection_pid(tunnel.pid());
+    info!("ssh tunnel PID: {}", tunnel.pid());
 
-        // Start connection
-        let socket = TcpStream::connect(format!("127.0.0.1:{}", config.connection.local_port))
-            .await
-            .context("could not connect to server")?;
-        let mut com = BbupCom::from(socket, config.flags.progress);
+    tunnel
+        .wait_for_ready()
+        .context("could not establish ssh tunnel")
+        .map_err(ConnectError::Transient)?;
 
-        let conversation_result: Result<()> = {
-            // Await green light to procede
-            com.check_ok()
-                .await
-                .context("could not get green light from server to procede with conversation")?;
+    // Start connection
+    let socket = TcpStream::connect(format!("127.0.0.1:{}", config.connection.local_port))
+        .await
+        .context("could not connect to server")
+        .map_err(ConnectError::Transient)?;
+    let mut com = BbupCom::from(
+        socket,
+        config.flags.progress,
+        config.flags.limit_rate,
+        config.flags.chunk_size,
+    );
+    if let Some(events) = &config.flags.events {
+        let reporter = event_reporter(events, com.reporter.take()).map_err(ConnectError::Fatal)?;
+        com.set_reporter(reporter);
+    }
+    let read_timeout = config.flags.read_timeout;
+    com.set_read_timeout((read_timeout > 0).then_some(Duration::from_secs(read_timeout)));
 
-            com.send_struct(&config.endpoint).await?;
+    // Await green light to procede
+    match com.check_ok().await {
+        Ok(()) => {}
+        // The server answered, but refused the conversation
+        Err(err @ bbup::com::Error::GenericError { .. }) => {
+            return Err(ConnectError::Fatal(anyhow::Error::new(err).context(
+                "could not get green light from server to procede with conversation",
+            )))
+        }
+        Err(err) => {
+            return Err(ConnectError::Transient(anyhow::Error::new(err).context(
+                "could not get green light from server to procede with conversation",
+            )))
+        }
+    }
+
+    match com.request_version().await {
+        Ok(()) => Ok((tunnel, com)),
+        // Retrying would not make the versions compatible
+        Err(err @ bbup::com::Error::VersionMismatch { .. }) => Err(ConnectError::Fatal(
+            anyhow::Error::new(err).context("server is not compatible with this client"),
+        )),
+        Err(err) => Err(ConnectError::Transient(
+            anyhow::Error::new(err).context("could not negotiate protocol version with server"),
+        )),
+    }
+}
 
-            let mut state = ProcessState::load(&config.link_root)?;
+/// Two connected ends of a conversation on localhost, as the client and the
+/// server have them once the green light is given
+#[cfg(test)]
+pub async fn loopback() -> (BbupCom, BbupCom) {
+    let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
+        .await
+        .unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (client, server) = tokio::join!(TcpStream::connect(("127.0.0.1", port)), listener.accept());
+    (
+        BbupCom::from(client.unwrap(), false, 0, None),
+        BbupCom::from(server.unwrap().0, false, 0, None),
+    )
+}
 
-            {
-                // GET DELTA
-                protocol::get_local_delta(&config, &mut state)?;
+/// Call `attempt` until it succeeds, up to `max_attempts` times, waiting an
+/// exponentially increasing delay (starting from `base_delay`) between attempts.
+/// Fatal errors are returned immediately
+async fn retry_with_backoff<T, F, Fut>(
+    max_attempts: u32,
+    base_delay: Duration,
+    mut attempt: F,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, ConnectError>>,
+{
+    let mut delay = base_delay;
+    let mut attempt_number = 1;
+    loop {
+        match attempt().await {
+            Ok(val) => return Ok(val),
+            Err(ConnectError::Fatal(err)) => return Err(err),
+            Err(ConnectError::Transient(err)) if attempt_number >= max_attempts => {
+                return Err(err.context(format!("giving up after {attempt_number} attempts")))
             }
+            Err(ConnectError::Transient(err)) => {
+                info!(
+                    "connection attempt {attempt_number}/{max_attempts} failed, retrying in {}ms\n{:#}",
+                    delay.as_millis(),
+                    err
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt_number += 1;
+            }
+        }
+    }
+}
+
+async fn connect_with_backoff(config: &ProcessConfig) -> Result<(SshTunnel, BbupCom)> {
+    retry_with_backoff(
+        config.flags.max_attempts,
+        Duration::from_millis(config.flags.retry_delay),
+        || connect(config),
+    )
+    .await
+}
+
+/// Summary of the files and bytes exchanged with the server in `elapsed`, with
+/// the resulting throughput
+fn traffic_summary(traffic: &Traffic, elapsed: Duration) -> String {
+    let bytes = traffic.bytes_sent + traffic.bytes_received;
+    // Avoid dividing by zero on conversations too fast to be measured
+    let throughput = bytes as f64 / elapsed.as_secs_f64().max(1e-9);
+    format!(
+        "{} files sent, {} files received, {} sent, {} received in {:.2}s ({}/s)",
+        traffic.files_sent,
+        traffic.files_received,
+        human_size(traffic.bytes_sent),
+        human_size(traffic.bytes_received),
+        elapsed.as_secs_f64(),
+        human_size(throughput as u64)
+    )
+}
+
+/// The jobs of the conversation with the server, once the endpoint is sent:
+/// pull the update and apply it, then push the local changes (or just preview
+/// both during a dry run)
+async fn run_jobs(config: &ProcessConfig, com: &mut BbupCom) -> Result<()> {
+    let mut state = ProcessState::load(&config.link_root)?;
+
+    {
+        // GET DELTA
+        protocol::get_local_delta(config, &mut state)?;
+    }
+    cancel::check()?;
+
+    // Block injective links only push their changes to the endpoint
+    if config.link_type != LinkType::BlockInjection {
+        // PULL
+        com.send_struct(JobType::Pull).await?;
+        protocol::pull_update_delta(config, &mut state, com).await?;
+        if config.flags.dry_run {
+            // Nothing gets downloaded during a dry run
+            com.query_files(Vec::new(), &config.link_root, None, 1)
+                .await?;
+        } else {
+            // protocol::check_for_conflicts(&mut state).await?;
+            // protocol::download_update(config, &mut state, com).await?;
+            // protocol::apply_update(config, &mut state).await?;
+            protocol::apply_update_or_get_conflicts(config, &mut state, com).await?;
+        }
+    }
+
+    // Nothing has been applied yet, or the pulled update has been applied
+    //	completely, so the sync can stop here
+    cancel::check()?;
+
+    if config.flags.dry_run {
+        // DRY RUN
+        protocol::preview_actions(config, &state)?;
+    } else {
+        // PUSH
+        let block = config.link_type == LinkType::BlockInjection;
+        match (config.flags.add_only, block) {
+            (true, block) => com.send_struct(JobType::AddOnlyPush { block }).await?,
+            (false, true) => com.send_struct(JobType::BlockPush).await?,
+            (false, false) => com.send_struct(JobType::Push).await?,
+        }
+        protocol::upload_changes(config, &mut state, com).await?;
+    }
+
+    // Terminate conversation with server
+    com.send_struct(JobType::Quit).await?;
+
+    Ok(())
+}
+
+pub async fn process_link(config: ProcessConfig) -> Result<()> {
+    bbup::logger::set_endpoint(&config.endpoint);
+    info!("Synchronizing link: [{}]", config.link_root);
+    if config.flags.since.is_some() && config.link_type == LinkType::BlockInjection {
+        bail!(
+            "Failed to sync link [{}]\n--since is not supported by block injective links, as they never pull",
+            config.link_root
+        );
+    }
+
+    if let Some(subpath) = &config.flags.subpath {
+        if !subpath.is_confined() {
+            bail!(
+                "Failed to sync link [{}]\nthe subpath to sync must be inside the link\nsubpath: {}",
+                config.link_root,
+                subpath
+            );
+        }
+    }
+    if let Some(only) = &config.flags.only {
+        if !only.is_confined() {
+            bail!(
+                "Failed to sync link [{}]\nthe path given to --only must be inside the link\nonly: {}",
+                config.link_root,
+                only
+            );
+        }
+    }
+
+    let _lock = LinkLock::acquire(&config.link_root)?;
+    cancel::install_handler();
+    let start = Instant::now();
 
-            {
-                // PULL
-                com.send_struct(JobType::Pull).await?;
-                protocol::pull_update_delta(&config, &mut state, &mut com).await?;
-                // protocol::check_for_conflicts(&mut state).await?;
-                // protocol::download_update(&config, &mut state, &mut com).await?;
-                // protocol::apply_update(&config, &mut state).await?;
-                protocol::apply_update_or_get_conflicts(&config, &mut state, &mut com).await?;
+    let process = {
+        // The tunnel is kept alive for the whole conversation
+        let (_tunnel, mut com) = connect_with_backoff(&config).await?;
+
+        let conversation_result: Result<()> = {
+            if config.link_type == LinkType::BlockInjection && com.protocol_version < 2 {
+                bail!(
+                    "Failed to sync link [{}]\nthe server is too old to support BlockInjection links",
+                    config.link_root
+                );
             }
+            if config.flags.add_only && com.protocol_version < 5 {
+                bail!(
+                    "Failed to sync link [{}]\nthe server is too old to support --add-only",
+                    config.link_root
+                );
+            }
+
+            com.request_compression(config.flags.compress).await?;
 
-            {
-                // PUSH
-                com.send_struct(JobType::Push).await?;
-                protocol::upload_changes(&config, &mut state, &mut com).await?;
+            // Injective links talk to the server about the whole archive, so that
+            //	their endpoint is just a subpath of it: the paths of the objects
+            //	they exchange are prefixed with the endpoint
+            match config.link_type {
+                LinkType::Injection => {
+                    com.send_struct(AbstPath::empty()).await?;
+                    com.prefix = config.endpoint.clone();
+                }
+                _ => com.send_struct(&config.endpoint).await?,
             }
 
-            // Terminate conversation with server
-            com.send_struct(JobType::Quit).await?;
+            run_jobs(&config, &mut com).await?;
 
             Ok(())
         };
 
         match conversation_result {
-            Ok(()) => Ok(()),
+            Ok(()) => Ok(com.traffic()),
             Err(error) => {
                 if let Err(err) = com.send_error(1, "error propagated from client").await {
-                    println!("Could not propagate error to server, because {:#?}", err)
+                    error!("Could not propagate error to server, because {:#?}", err)
                 }
                 Err(error)
             }
@@ -81,10 +317,9 @@ pub async fn process_link(config: ProcessConfig) -> Result<()> {
     };
 
     match process {
-        Ok(()) => {
-            if config.flags.verbose {
-                println!("Link correctly synchronized: [{}] ", config.link_root);
-            }
+        Ok(traffic) => {
+            info!("{}", traffic_summary(&traffic, start.elapsed()));
+            info!("Link correctly synchronized: [{}] ", config.link_root);
 
             Ok(())
         }
@@ -93,3 +328,158 @@ pub async fn process_link(config: ProcessConfig) -> Result<()> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{loopback, process_link, retry_with_backoff, run_jobs, ConnectError};
+    use crate::{cancel, ProcessConfig, ProcessState};
+
+    use abst_fs::{self as fs, AbstPath};
+    use bbup::com::JobType;
+    use fs_vcs::{generate_fstree, get_delta, CommitID, ExcludeList, FSTree};
+
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            backoff().await;
+            fatal().await;
+            give_up().await;
+            unconfined().await;
+        });
+
+        let test_path = AbstPath::from("/tmp/bbup-test-sync");
+        assert!(!test_path.exists());
+        fs::create_dir(&test_path).unwrap();
+
+        let result = std::panic::catch_unwind(|| dry_run(&test_path));
+        fs::remove_dir_all(&test_path).unwrap();
+        assert!(result.is_ok());
+    }
+
+    /// Attempt that fails with a transient error the first `failures` times,
+    /// and then succeeds returning the number of the attempt
+    fn flaky(failures: u32) -> impl FnMut() -> std::future::Ready<Result<u32, ConnectError>> {
+        let mut attempts = 0;
+        move || {
+            attempts += 1;
+            std::future::ready(match attempts > failures {
+                true => Ok(attempts),
+                false => Err(ConnectError::Transient(anyhow::anyhow!(
+                    "connection refused"
+                ))),
+            })
+        }
+    }
+
+    async fn backoff() {
+        // The connection fails twice, waiting 10ms and then 20ms before trying
+        //	again, and then succeeds
+        let start = Instant::now();
+        let attempt = retry_with_backoff(5, Duration::from_millis(10), flaky(2))
+            .await
+            .unwrap();
+        assert_eq!(attempt, 3);
+        assert!(start.elapsed() >= Duration::from_millis(30));
+    }
+
+    async fn fatal() {
+        // Fatal errors are never retried
+        let mut attempts = 0;
+        let result: anyhow::Result<()> = retry_with_backoff(5, Duration::from_millis(10), || {
+            attempts += 1;
+            std::future::ready(Err(ConnectError::Fatal(anyhow::anyhow!(
+                "version mismatch"
+            ))))
+        })
+        .await;
+        assert_eq!(attempts, 1);
+        assert_eq!(result.unwrap_err().to_string(), "version mismatch");
+    }
+
+    async fn give_up() {
+        // Transient errors are retried only up to the maximum number of attempts
+        let error = retry_with_backoff(3, Duration::from_millis(1), flaky(5))
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "giving up after 3 attempts");
+        assert_eq!(error.root_cause().to_string(), "connection refused");
+    }
+
+    async fn unconfined() {
+        // Paths outside of the link are refused before connecting to the server
+        let mut config = ProcessConfig::for_link(&AbstPath::from("/tmp/bbup-test-sync-link"));
+        config.flags.only = Some(AbstPath::from("dir/../../outside").normalize());
+        let error = process_link(config).await.unwrap_err().to_string();
+        assert!(error.contains("the path given to --only must be inside the link"));
+    }
+
+    fn write(path: &AbstPath, content: &str) {
+        let mut file = fs::create_file(path).unwrap();
+        std::io::Write::write_all(&mut file, content.as_bytes()).unwrap();
+    }
+
+    fn tree(path: &AbstPath) -> FSTree {
+        generate_fstree(path, &ExcludeList::from(&vec![]).unwrap()).unwrap()
+    }
+
+    /// Every file under `path`, state of the link included, with its content
+    fn snapshot(path: &PathBuf) -> Vec<(PathBuf, Vec<u8>)> {
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(path).unwrap() {
+            let path = entry.unwrap().path();
+            match path.is_dir() {
+                true => files.extend(snapshot(&path)),
+                false => files.push((path.clone(), std::fs::read(&path).unwrap())),
+            }
+        }
+        files.sort();
+        files
+    }
+
+    fn dry_run(test_path: &AbstPath) {
+        let remote_root = test_path.add_last("remote");
+        fs::create_dir(&remote_root).unwrap();
+        write(&remote_root.add_last("pulled.txt"), "pulled");
+        let update = get_delta(&FSTree::empty(), &tree(&remote_root));
+
+        // The link has changes of its own since the last sync
+        let link_root = test_path.add_last("link");
+        fs::create_dir(&link_root).unwrap();
+        write(&link_root.add_last("synced.txt"), "synced");
+        let mut state = ProcessState::init_state();
+        state.last_known_fstree = tree(&link_root);
+        state.save(&link_root).unwrap();
+        write(&link_root.add_last("pushed.txt"), "pushed");
+
+        let pre_tree = tree(&link_root);
+        let pre_files = snapshot(&link_root.to_path_buf());
+        let mut config = ProcessConfig::for_link(&link_root);
+        config.flags.dry_run = true;
+
+        // The server has an update to pull, but nothing gets downloaded nor
+        //	pushed, and the conversation ends right after the pull
+        let _lock = cancel::lock();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let (mut client, mut server) = loopback().await;
+            let (synced, _) = tokio::join!(run_jobs(&config, &mut client), async {
+                assert_eq!(server.get_struct::<JobType>().await.unwrap(), JobType::Pull);
+                let _: CommitID = server.get_struct().await.unwrap();
+                server.send_struct(&update).await.unwrap();
+                server.send_struct(CommitID::gen_valid()).await.unwrap();
+                server.supply_files(&[], &remote_root).await.unwrap();
+                assert_eq!(server.get_struct::<JobType>().await.unwrap(), JobType::Quit);
+            });
+            synced.unwrap();
+        });
+
+        // Neither the link nor its state have changed
+        assert_eq!(tree(&link_root), pre_tree);
+        assert_eq!(snapshot(&link_root.to_path_buf()), pre_files);
+        assert!(!link_root.add_last("pulled.txt").exists());
+    }
+}
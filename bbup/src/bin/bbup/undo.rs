@@ -0,0 +1,160 @@
+use super::{protocol, LastSync, LinkConfig, LinkLock, ProcessState};
+
+use abst_fs::{AbstPath, Durability};
+use fs_vcs::get_delta;
+
+use anyhow::{Context, Result};
+
+/// Revert the changes applied to the link by the last sync, restoring the replaced
+/// and removed objects from the backup of the sync. The link state goes back to the
+/// one before the sync, so the next sync pulls the same update again. Operations
+/// on the file system failing with a transient error are retried at most
+/// `io_retries` times
+pub fn undo(cwd: &AbstPath, default_excludes: &Vec<String>, io_retries: u32) -> Result<()> {
+    if !LastSync::exists(cwd) {
+        anyhow::bail!("there is no sync to undo for link [{cwd}]");
+    }
+    let link_config = LinkConfig::load(cwd)?;
+    let _lock = LinkLock::acquire(cwd)?;
+    let exclude_list = link_config.exclude_list(cwd, default_excludes)?;
+    let last_sync = LastSync::load(cwd)?;
+
+    // Undoing is safe only if nothing changed since the sync
+    let (current_tree, _) =
+        protocol::generate_link_tree(cwd, &exclude_list, &link_config.dereference)
+            .context("could not generate current fstree")?;
+    let changes = get_delta(&last_sync.post_tree, &current_tree);
+    if !changes.is_empty() {
+        anyhow::bail!(
+            "the link has changed since the last sync, refusing to undo it\nchanges:\n{}",
+            changes.stats()
+        );
+    }
+    let mut reverted_tree = current_tree;
+    reverted_tree
+        .undo_delta(&last_sync.applied)
+        .context("could not revert the tree of the link")?;
+
+    protocol::apply_actions(
+        cwd,
+        last_sync.applied.invert().to_actions(),
+        &LastSync::backup_path(cwd),
+        None,
+        io_retries,
+        Durability::Synced,
+    )
+    .context("could not undo the actions of the last sync")?;
+
+    let (undone_tree, _) =
+        protocol::generate_link_tree(cwd, &exclude_list, &link_config.dereference)
+            .context("could not generate undone fstree")?;
+    if !get_delta(&reverted_tree, &undone_tree).is_empty() {
+        anyhow::bail!("undoing the last sync left the link in an unexpected state");
+    }
+
+    ProcessState::from(last_sync.previous_commit, last_sync.previous_fstree).save(cwd)?;
+    LastSync::clear(cwd)?;
+
+    println!("last sync undone");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::undo;
+    use crate::{
+        cancel, protocol, sync::loopback, LastSync, LinkConfig, LinkType, ProcessConfig,
+        ProcessState,
+    };
+    use abst_fs::{self as fs, AbstPath, Mtime};
+    use fs_vcs::{generate_fstree, get_delta, CommitID, Delta, ExcludeList, FSTree};
+
+    #[test]
+    fn test() {
+        let test_path = AbstPath::from("/tmp/bbup-test-undo");
+        assert!(!test_path.exists());
+        fs::create_dir(&test_path).unwrap();
+
+        let result = std::panic::catch_unwind(|| undo_sync(&test_path));
+        fs::remove_dir_all(&test_path).unwrap();
+        assert!(result.is_ok());
+    }
+
+    fn write(path: &AbstPath, content: &str, mtime: i64) {
+        let mut file = fs::create_file(path).unwrap();
+        std::io::Write::write_all(&mut file, content.as_bytes()).unwrap();
+        fs::set_mtime(path, &Mtime::from(mtime, 0)).unwrap();
+    }
+
+    fn tree(path: &AbstPath) -> FSTree {
+        generate_fstree(path, &ExcludeList::from(&vec![]).unwrap()).unwrap()
+    }
+
+    fn undo_sync(test_path: &AbstPath) {
+        // The sync replaces a file, removes another one and adds a new one
+        let link_root = test_path.add_last("link");
+        fs::create_dir(&link_root).unwrap();
+        write(
+            &link_root.add_last("replaced.txt"),
+            "old content",
+            1667000000,
+        );
+        write(&link_root.add_last("removed.txt"), "removed", 1667000100);
+        let pre_tree = tree(&link_root);
+        let pre_commit = CommitID::gen_valid();
+        LinkConfig::from(LinkType::Bijection, AbstPath::empty(), vec![])
+            .save(&link_root)
+            .unwrap();
+        ProcessState::from(pre_commit.clone(), pre_tree.clone())
+            .save(&link_root)
+            .unwrap();
+
+        let remote_root = test_path.add_last("remote");
+        fs::create_dir(&remote_root).unwrap();
+        write(
+            &remote_root.add_last("replaced.txt"),
+            "new content",
+            1667000200,
+        );
+        write(&remote_root.add_last("added.txt"), "added", 1667000300);
+        let update = get_delta(&pre_tree, &tree(&remote_root));
+        let queryable = [AbstPath::from("replaced.txt"), AbstPath::from("added.txt")];
+
+        let config = ProcessConfig::for_link(&link_root);
+        let mut state = ProcessState::from(pre_commit.clone(), pre_tree.clone());
+        state.new_tree = Some(pre_tree.clone());
+        state.local_delta = Some(Delta::empty());
+        state.update = Some((CommitID::gen_valid(), update));
+
+        let _lock = cancel::lock();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let (mut client, mut server) = loopback().await;
+            let (applied, supplied) = tokio::join!(
+                protocol::apply_update_or_get_conflicts(&config, &mut state, &mut client),
+                server.supply_files(&queryable, &remote_root)
+            );
+            supplied.unwrap();
+            applied.unwrap();
+        });
+        assert_eq!(tree(&link_root), tree(&remote_root));
+        assert!(LastSync::exists(&link_root));
+
+        // Undoing the sync restores the content and the mtimes of the link, and
+        //	the state it had before the sync
+        undo(&link_root, &vec![], 0).unwrap();
+        assert_eq!(tree(&link_root), pre_tree);
+        assert_eq!(
+            std::fs::read(link_root.add_last("replaced.txt").to_path_buf()).unwrap(),
+            b"old content"
+        );
+        assert!(!link_root.add_last("added.txt").exists());
+        let state = ProcessState::load(&link_root).unwrap();
+        assert_eq!(state.last_known_commit, pre_commit);
+        assert_eq!(state.last_known_fstree, pre_tree);
+        assert!(!LastSync::exists(&link_root));
+
+        // There is nothing left to undo
+        assert!(undo(&link_root, &vec![], 0).is_err());
+    }
+}
@@ -2,6 +2,14 @@ use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 use abst_fs::AbstPath;
+use hasher::{Hash, Signature};
+
+use std::sync::Arc;
+
+use super::{
+    Counted, ProgressBarReporter, ProgressReporter, RateLimiter, TimeoutReader, Traffic,
+    PROTOCOL_VERSION,
+};
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -10,6 +18,9 @@ pub enum Error {
 
     #[error("Comunications Error: some error occurred.\nSource: {src}\nError: {err}")]
     GenericError { src: String, err: String },
+
+    #[error("Comunications Error: incompatible protocol versions.\nThis party speaks version {local}, the other party speaks version {remote}")]
+    VersionMismatch { local: u32, remote: u32 },
 }
 
 pub fn inerr<S: std::string::ToString, E: std::error::Error>(src: S) -> impl Fn(E) -> Error {
@@ -35,15 +46,73 @@ pub fn error_context<S: std::string::ToString, T: std::string::ToString>(
 }
 
 pub struct BbupCom {
-    pub tx: tokio::net::tcp::OwnedWriteHalf,
-    pub rx: tokio::net::tcp::OwnedReadHalf,
+    pub tx: Counted<tokio::net::tcp::OwnedWriteHalf>,
+    pub rx: TimeoutReader<Counted<tokio::net::tcp::OwnedReadHalf>>,
 
-    pub progress: bool,
+    pub reporter: Option<Arc<dyn ProgressReporter>>,
+    pub limiter: Option<RateLimiter>,
+    pub compress: bool,
+    pub chunk_size: Option<usize>,
+    /// Protocol version agreed with the other party, see `request_version`
+    pub protocol_version: u32,
+    /// Path that the other party sees the queried objects under: it is added to
+    /// the paths of the queries sent, and stripped from the ones received
+    pub prefix: AbstPath,
+    /// Number of files completely sent and received so far
+    pub files_sent: u64,
+    pub files_received: u64,
 }
 impl BbupCom {
-    pub fn from(socket: tokio::net::TcpStream, progress: bool) -> BbupCom {
+    /// Wrap a socket, optionally limiting file transfers to `limit_rate` bytes per
+    /// second (a limit of 0 means unlimited). If `progress` is set, the progress of
+    /// file transfers is shown on stdout. File content is transferred in chunks of
+    /// `chunk_size` bytes, or of a default size if None
+    pub fn from(
+        socket: tokio::net::TcpStream,
+        progress: bool,
+        limit_rate: u64,
+        chunk_size: Option<usize>,
+    ) -> BbupCom {
         let (rx, tx) = socket.into_split();
-        BbupCom { tx, rx, progress }
+        let reporter: Option<Arc<dyn ProgressReporter>> = match progress {
+            true => Some(Arc::new(ProgressBarReporter::default())),
+            false => None,
+        };
+        BbupCom {
+            tx: Counted::new(tx),
+            rx: TimeoutReader::new(Counted::new(rx)),
+            reporter,
+            limiter: RateLimiter::new(limit_rate),
+            compress: false,
+            chunk_size,
+            protocol_version: PROTOCOL_VERSION,
+            prefix: AbstPath::empty(),
+            files_sent: 0,
+            files_received: 0,
+        }
+    }
+
+    /// Send the progress events of file transfers to `reporter`, replacing the
+    /// current reporter (if any)
+    pub fn set_reporter(&mut self, reporter: Arc<dyn ProgressReporter>) {
+        self.reporter = Some(reporter);
+    }
+
+    /// Fail reads with a timeout error if the other party sends nothing for longer
+    /// than `timeout`, instead of waiting for it forever. The deadline is reset
+    /// every time some data is received. None disables the timeout
+    pub fn set_read_timeout(&mut self, timeout: Option<std::time::Duration>) {
+        self.rx.set_timeout(timeout);
+    }
+
+    /// Files and bytes exchanged with the other party so far
+    pub fn traffic(&self) -> Traffic {
+        Traffic {
+            files_sent: self.files_sent,
+            files_received: self.files_received,
+            bytes_sent: self.tx.bytes(),
+            bytes_received: self.rx.get_ref().bytes(),
+        }
     }
 }
 
@@ -52,12 +121,52 @@ pub enum JobType {
     Pull,
     Push,
     Quit,
+    /// Push confined to the endpoint, which is reserved to the client as a block.
+    /// Requires protocol version 2
+    BlockPush,
+    /// Push that only adds new objects, rejected if it would delete or overwrite
+    /// anything. If `block` is set, it is also confined to the endpoint as a
+    /// `BlockPush`. Requires protocol version 5
+    AddOnlyPush {
+        block: bool,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Query {
     Object(Queryable, AbstPath),
     Stop,
+    /// Several objects queried at once, sent back to back by the other party.
+    /// Requires protocol version 4
+    Batch(Vec<BatchQuery>),
+}
+
+/// Maximum number of objects queried in a single batch
+pub const MAX_BATCH_SIZE: u64 = 64;
+
+/// Object queried as part of a batch. Files come with the resume offer that
+/// would otherwise be sent right before receiving them
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum BatchQuery {
+    File(AbstPath, ResumeOffer),
+    SymLink(AbstPath),
+}
+
+/// Sent by the receiver of a file before the transfer, to tell the sender how much
+/// of the file it already has from a previous interrupted transfer. If the receiver
+/// has an older version of the file, its signature is sent so that only a delta
+/// against it is transferred
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResumeOffer {
+    pub offset: u64,
+    pub prefix_hash: Hash,
+    pub signature: Option<Signature>,
+}
+
+/// Path of the partial file where the content of the file at path is received
+pub fn partial_path(path: &AbstPath) -> Option<AbstPath> {
+    let name = path.file_name()?;
+    Some(path.parent()?.add_last(format!(".{name}.bbup-partial")))
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -65,3 +174,49 @@ pub enum Queryable {
     File,
     SymLink,
 }
+
+/// Two parties connected to each other through the loopback interface
+#[cfg(test)]
+pub(super) async fn loopback() -> (BbupCom, BbupCom) {
+    let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
+        .await
+        .unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (client, server) = tokio::join!(
+        tokio::net::TcpStream::connect(("127.0.0.1", port)),
+        listener.accept()
+    );
+    (
+        BbupCom::from(client.unwrap(), false, 0, None),
+        BbupCom::from(server.unwrap().0, false, 0, None),
+    )
+}
+
+/// Write `files` (name and content) under `source`, and have `client` query all
+/// of them to `server`, `batch_size` at a time, saving them under `dest`
+#[cfg(test)]
+pub(super) async fn transfer(
+    client: &mut BbupCom,
+    server: &mut BbupCom,
+    source: &AbstPath,
+    dest: &AbstPath,
+    files: &[(&str, &[u8])],
+    batch_size: usize,
+) {
+    let mut queries = Vec::new();
+    for (name, content) in files {
+        std::fs::write(source.add_last(name).to_path_buf(), content).unwrap();
+        queries.push((
+            Queryable::File,
+            AbstPath::from(name),
+            hasher::hash_bytes(content),
+        ));
+    }
+    let queryable: Vec<AbstPath> = files.iter().map(|(name, _)| AbstPath::from(name)).collect();
+    let (queried, supplied) = tokio::join!(
+        client.query_files(queries, dest, None, batch_size),
+        server.supply_files(&queryable, source)
+    );
+    queried.unwrap();
+    supplied.unwrap();
+}
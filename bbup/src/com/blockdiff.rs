@@ -0,0 +1,168 @@
+use abst_fs::{self as fs, AbstPath};
+use hasher::{DeltaEncoder, DeltaOp, Hasher, Signature};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::{
+    bbupcom::{error_context, generr, inerr, Error},
+    get::HashingWriter,
+    BbupCom,
+};
+
+/// Files smaller than this are always transferred whole
+const MIN_DELTA_SIZE: u64 = 64 * 1024;
+
+/// Maximum size of a literal accepted from the other party
+const MAX_LITERAL_SIZE: usize = 16 * 1024 * 1024;
+
+const END: u8 = 0;
+const COPY: u8 = 1;
+const LITERAL: u8 = 2;
+
+/// Signature of the file at basis, if it is worth transferring a delta against it
+pub(super) fn basis_signature(basis: &AbstPath) -> Result<Option<Signature>, Error> {
+    let errctx = error_context(format!("could not get signature of basis at path {basis}"));
+    if basis.object_type() != Some(fs::ObjectType::File) {
+        return Ok(None);
+    }
+    let file = fs::read_file(basis).map_err(inerr(errctx("open basis")))?;
+    let len = file
+        .metadata()
+        .map_err(inerr(errctx("read basis metadata")))?
+        .len();
+    if len < MIN_DELTA_SIZE {
+        return Ok(None);
+    }
+    let signature = hasher::signature(std::io::BufReader::new(file), hasher::block_size_for(len))
+        .map_err(inerr(errctx("compute signature of basis")))?;
+    Ok(Some(signature))
+}
+
+impl BbupCom {
+    /// Send the content of the file at path as a delta against the basis of the
+    /// other party, described by its signature. Only the blocks missing from the
+    /// basis are actually sent
+    pub(super) async fn send_delta(
+        &mut self,
+        path: &AbstPath,
+        signature: &Signature,
+    ) -> Result<(), Error> {
+        let errctx = error_context(format!("could not send delta of file at path {path}"));
+        let file = fs::read_file(path).map_err(inerr(errctx("open the file")))?;
+        let mut encoder = DeltaEncoder::new(std::io::BufReader::new(file), signature);
+        while let Some(op) = encoder
+            .next_op()
+            .map_err(inerr(errctx("compute next operation of delta")))?
+        {
+            match op {
+                DeltaOp::Copy(index) => {
+                    self.tx
+                        .write_u8(COPY)
+                        .await
+                        .map_err(inerr(errctx("send copy operation")))?;
+                    self.tx
+                        .write_u64(index)
+                        .await
+                        .map_err(inerr(errctx("send index of copied block")))?;
+                }
+                DeltaOp::Literal(content) => {
+                    if let Some(limiter) = &mut self.limiter {
+                        limiter.consume(content.len()).await;
+                    }
+                    self.tx
+                        .write_u8(LITERAL)
+                        .await
+                        .map_err(inerr(errctx("send literal operation")))?;
+                    self.tx
+                        .write_u32(content.len() as u32)
+                        .await
+                        .map_err(inerr(errctx("send length of literal")))?;
+                    self.tx
+                        .write_all(&content)
+                        .await
+                        .map_err(inerr(errctx("send literal content")))?;
+                }
+            }
+        }
+        self.tx
+            .write_u8(END)
+            .await
+            .map_err(inerr(errctx("send end of delta")))?;
+        self.tx.flush().await.map_err(inerr(errctx("flush data")))?;
+        Ok(())
+    }
+
+    /// Receive a delta sent by `send_delta` and reconstruct the new content into
+    /// the file at path, copying the unchanged blocks from the file at basis. The
+    /// content written is fed to `hasher`. Returns the number of bytes written
+    pub(super) async fn get_delta_to(
+        &mut self,
+        path: &AbstPath,
+        basis: &AbstPath,
+        block_size: u32,
+        hasher: &mut Hasher,
+    ) -> Result<u64, Error> {
+        let errmsg = format!("could not get delta to path {path}");
+        let errctx = error_context(errmsg.clone());
+        let mut basis = fs::read_file(basis).map_err(inerr(errctx("open basis")))?;
+        let mut file = HashingWriter::new(
+            std::io::BufWriter::new(
+                fs::create_file(path).map_err(inerr(errctx("create file to save content")))?,
+            ),
+            hasher,
+        );
+        let mut total = 0u64;
+        let mut literal = Vec::new();
+        loop {
+            let op = match self
+                .rx
+                .read_u8()
+                .await
+                .map_err(inerr(errctx("get delta operation")))?
+            {
+                END => break,
+                COPY => DeltaOp::Copy(
+                    self.rx
+                        .read_u64()
+                        .await
+                        .map_err(inerr(errctx("get index of copied block")))?,
+                ),
+                LITERAL => {
+                    let len = self
+                        .rx
+                        .read_u32()
+                        .await
+                        .map_err(inerr(errctx("get length of literal")))?
+                        as usize;
+                    if len > MAX_LITERAL_SIZE {
+                        return Err(generr(errmsg, format!("literal too big ({len} bytes)")));
+                    }
+                    if let Some(limiter) = &mut self.limiter {
+                        limiter.consume(len).await;
+                    }
+                    literal.resize(len, 0);
+                    self.rx
+                        .read_exact(&mut literal)
+                        .await
+                        .map_err(inerr(errctx("get literal content")))?;
+                    DeltaOp::Literal(std::mem::take(&mut literal))
+                }
+                tag => {
+                    return Err(generr(errmsg, format!("unknown delta operation {tag}")));
+                }
+            };
+            total += hasher::apply_op(&mut basis, block_size, &op, &mut file)
+                .map_err(inerr(errctx("apply delta operation")))?;
+            if let DeltaOp::Literal(content) = op {
+                literal = content;
+            }
+        }
+        let file = file
+            .into_inner()
+            .into_inner()
+            .map_err(inerr(errctx("flush file content to file")))?;
+        file.sync_all()
+            .map_err(inerr(errctx("sync file content to disk")))?;
+        Ok(total)
+    }
+}
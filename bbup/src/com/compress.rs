@@ -0,0 +1,202 @@
+use std::io::Write;
+
+use flate2::{write::ZlibDecoder, write::ZlibEncoder, Compression};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::{
+    bbupcom::{error_context, inerr, Error},
+    BbupCom, RateLimiter,
+};
+
+/// Default size of the chunks of uncompressed content fed to the encoder
+const CHUNK_SIZE: usize = 64 * 1024;
+
+impl BbupCom {
+    /// Ask the other party to compress file transfers (if `wanted`) and wait for its
+    /// answer. Compression is enabled only if both parties agree to it
+    pub async fn request_compression(&mut self, wanted: bool) -> Result<(), Error> {
+        let errctx = error_context("could not negotiate compression");
+        self.send_struct(wanted)
+            .await
+            .map_err(inerr(errctx("send compression request")))?;
+        self.compress = self
+            .get_struct()
+            .await
+            .map_err(inerr(errctx("get compression answer")))?;
+        Ok(())
+    }
+
+    /// Answer the compression request of the other party, enabling compression only
+    /// if it has been requested and it is `allowed`
+    pub async fn answer_compression(&mut self, allowed: bool) -> Result<(), Error> {
+        let errctx = error_context("could not negotiate compression");
+        let requested: bool = self
+            .get_struct()
+            .await
+            .map_err(inerr(errctx("get compression request")))?;
+        self.compress = requested && allowed;
+        self.send_struct(self.compress)
+            .await
+            .map_err(inerr(errctx("send compression answer")))?;
+        Ok(())
+    }
+}
+
+/// Write a frame of compressed content, preceded by its length
+async fn write_frame<W>(
+    writer: &mut W,
+    frame: &[u8],
+    limiter: &mut Option<RateLimiter>,
+) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    if let Some(limiter) = limiter {
+        limiter.consume(frame.len()).await;
+    }
+    writer.write_u32(frame.len() as u32).await?;
+    writer.write_all(frame).await
+}
+
+/// Compress the whole content of reader, in chunks of `chunk_size` bytes (or of a
+/// default size if None), and send it into writer as a sequence of frames,
+/// terminated by an empty frame. Returns the number of uncompressed bytes read
+pub async fn copy_compressed<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    limiter: &mut Option<RateLimiter>,
+    chunk_size: Option<usize>,
+) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    let mut buffer = vec![0u8; chunk_size.unwrap_or(CHUNK_SIZE)];
+    let mut total = 0u64;
+    loop {
+        let bytes = reader.read(&mut buffer).await?;
+        if bytes == 0 {
+            break;
+        }
+        total += bytes as u64;
+
+        encoder.write_all(&buffer[..bytes])?;
+        encoder.flush()?;
+        let frame = std::mem::take(encoder.get_mut());
+        if !frame.is_empty() {
+            write_frame(writer, &frame, limiter).await?;
+        }
+    }
+    let frame = encoder.finish()?;
+    if !frame.is_empty() {
+        write_frame(writer, &frame, limiter).await?;
+    }
+    writer.write_u32(0).await?;
+    Ok(total)
+}
+
+/// Receive from reader a sequence of frames sent by `copy_compressed` and write
+/// the decompressed content into writer. Returns the number of uncompressed bytes
+/// written
+pub async fn copy_decompressed<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    limiter: &mut Option<RateLimiter>,
+) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut decoder = ZlibDecoder::new(Vec::new());
+    let mut frame = Vec::new();
+    let mut total = 0u64;
+    loop {
+        let len = reader.read_u32().await? as usize;
+        if len == 0 {
+            break;
+        }
+        if let Some(limiter) = limiter {
+            limiter.consume(len).await;
+        }
+        frame.resize(len, 0);
+        reader.read_exact(&mut frame).await?;
+
+        decoder.write_all(&frame)?;
+        let content = std::mem::take(decoder.get_mut());
+        writer.write_all(&content).await?;
+        total += content.len() as u64;
+    }
+    let content = decoder.finish()?;
+    writer.write_all(&content).await?;
+    total += content.len() as u64;
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::bbupcom::loopback, copy_compressed, copy_decompressed};
+
+    #[test]
+    fn test() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            round_trip().await;
+            negotiation().await;
+        });
+    }
+
+    async fn round_trip() {
+        // Compressible content spanning several chunks, and some that is not
+        let mut content = b"some very repetitive content ".repeat(4096);
+        content.extend((0..50000u32).map(|n| (n.wrapping_mul(2654435761) >> 24) as u8));
+
+        for chunk_size in [None, Some(512), Some(1 << 20)] {
+            let mut reader: &[u8] = &content;
+            let mut frames: Vec<u8> = Vec::new();
+            let read = copy_compressed(&mut reader, &mut frames, &mut None, chunk_size)
+                .await
+                .unwrap();
+            assert_eq!(read, content.len() as u64);
+            assert!(frames.len() < content.len());
+
+            let mut reader: &[u8] = &frames;
+            let mut output: Vec<u8> = Vec::new();
+            let written = copy_decompressed(&mut reader, &mut output, &mut None)
+                .await
+                .unwrap();
+            assert_eq!(written, content.len() as u64);
+            assert_eq!(output, content);
+            // Every frame has been consumed, up to the terminating one
+            assert!(reader.is_empty());
+        }
+
+        // Empty content is just the terminating frame and the end of the stream
+        let mut reader: &[u8] = &[];
+        let mut frames: Vec<u8> = Vec::new();
+        copy_compressed(&mut reader, &mut frames, &mut None, None)
+            .await
+            .unwrap();
+        let mut reader: &[u8] = &frames;
+        let mut output: Vec<u8> = Vec::new();
+        copy_decompressed(&mut reader, &mut output, &mut None)
+            .await
+            .unwrap();
+        assert!(output.is_empty());
+    }
+
+    async fn negotiation() {
+        for (wanted, allowed) in [(true, true), (true, false), (false, true), (false, false)] {
+            let (mut client, mut server) = loopback().await;
+            let (requested, answered) = tokio::join!(
+                client.request_compression(wanted),
+                server.answer_compression(allowed)
+            );
+            requested.unwrap();
+            answered.unwrap();
+            // Both sides agree, and compress only if both want to
+            assert_eq!(client.compress, wanted && allowed);
+            assert_eq!(server.compress, wanted && allowed);
+        }
+    }
+}
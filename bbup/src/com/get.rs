@@ -1,15 +1,151 @@
 use abst_fs::{self as fs, AbstPath, Endpoint};
 
-use hasher::Hash;
+use hasher::{Hash, Hasher};
 
 use serde::de::DeserializeOwned;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::pin::Pin;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use super::{
-    bbupcom::{error_context, generr, inerr, Error, Query},
-    BbupCom, ProgressReader, Queryable,
+    bbupcom::{error_context, generr, inerr, partial_path, BatchQuery, Error, Query, ResumeOffer},
+    blockdiff, compress, throttle, BbupCom, ProgressReader, Queryable,
 };
 
+/// Offer to send to the other party before receiving the file at path: the
+/// partial file left by a previous interrupted transfer, if any, or the
+/// signature of `basis` otherwise
+fn resume_offer(path: &AbstPath, basis: Option<&AbstPath>) -> Result<ResumeOffer, Error> {
+    let errmsg = format!("could not prepare resume offer for file at path {path}");
+    let errctx = error_context(errmsg.clone());
+
+    let partial_path = partial_path(path)
+        .ok_or_else(|| generr(errmsg.clone(), "path has no parent or no file name"))?;
+    let (offset, prefix_hash) = match partial_path.object_type() {
+        Some(fs::ObjectType::File) => {
+            let partial =
+                fs::read_file(&partial_path).map_err(inerr(errctx("open partial file")))?;
+            let offset = partial
+                .metadata()
+                .map_err(inerr(errctx("read partial file metadata")))?
+                .len();
+            let prefix_hash =
+                hasher::hash_stream(partial).map_err(inerr(errctx("hash partial file content")))?;
+            (offset, prefix_hash)
+        }
+        _ => (0, hasher::hash_bytes([])),
+    };
+    let signature = match (offset, basis) {
+        (0, Some(basis)) => {
+            blockdiff::basis_signature(basis).map_err(inerr(errctx("get signature of basis")))?
+        }
+        _ => None,
+    };
+    Ok(ResumeOffer {
+        offset,
+        prefix_hash,
+        signature,
+    })
+}
+
+/// Writer passing everything written to it on to `hasher`, so that the hash of a
+/// received file is computed while its content streams in
+pub(super) struct HashingWriter<'a, W> {
+    writer: W,
+    hasher: &'a mut Hasher,
+}
+impl<'a, W> HashingWriter<'a, W> {
+    pub fn new(writer: W, hasher: &'a mut Hasher) -> HashingWriter<'a, W> {
+        HashingWriter { writer, hasher }
+    }
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+impl<W: std::io::Write> std::io::Write for HashingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+impl<W: AsyncWrite + Unpin> AsyncWrite for HashingWriter<'_, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        match Pin::new(&mut this.writer).poll_write(cx, buf) {
+            std::task::Poll::Ready(Ok(written)) => {
+                this.hasher.update(&buf[..written]);
+                std::task::Poll::Ready(Ok(written))
+            }
+            other => other,
+        }
+    }
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        Pin::new(&mut self.writer).poll_flush(cx)
+    }
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        Pin::new(&mut self.writer).poll_shutdown(cx)
+    }
+}
+
+/// Check that the content received for the file at path, whose hash is
+/// `received`, matches the hash it was queried with. A mismatching content is
+/// discarded together with its partial file, so that it is not resumed from
+fn check_received_file(
+    path: &AbstPath,
+    partial_path: &AbstPath,
+    received: Hash,
+    hash: &Hash,
+) -> Result<(), Error> {
+    let errmsg = String::from("could not query files and symlinks");
+    let errctx = error_context(errmsg.clone());
+    if received != *hash {
+        fs::remove_file(partial_path).map_err(inerr(errctx(format!(
+            "remove mismatching partial file of path {path}"
+        ))))?;
+        return Err(generr(
+            errmsg,
+            format!("hash of the file recieved (at path {path}) does not match the hash given"),
+        ));
+    }
+    Ok(())
+}
+
+/// Check that the endpoint received for the symlink at path matches the hash it
+/// was queried with, and create the symlink
+fn save_received_symlink(path: &AbstPath, endpoint: Endpoint, hash: &Hash) -> Result<(), Error> {
+    let errmsg = String::from("could not query files and symlinks");
+    let errctx = error_context(errmsg.clone());
+    if *hash != hasher::hash_bytes(endpoint.as_bytes()) {
+        return Err(generr(
+            errmsg,
+            format!("hash of the symlink recieved (at path {path}) does not match the hash given"),
+        ));
+    }
+    // A previous interrupted transfer might have left a stale object
+    match path.object_type() {
+        Some(fs::ObjectType::SymLink) => fs::remove_symlink(path),
+        Some(fs::ObjectType::File) => fs::remove_file(path),
+        _ => Ok(()),
+    }
+    .map_err(inerr(errctx(format!("remove stale object at path {path}"))))?;
+    fs::create_symlink(path, endpoint).map_err(inerr(errctx(format!(
+        "create queried symlink at path {path}"
+    ))))
+}
+
 impl BbupCom {
     pub async fn check_ok(&mut self) -> Result<(), Error> {
         let errmsg = "check for ok status".to_string();
@@ -56,7 +192,27 @@ impl BbupCom {
         Ok(buffer)
     }
 
-    pub async fn get_struct<'a, S>(&mut self) -> Result<S, Error>
+    /// Same as `get_struct`, but without confirming to the other party that the
+    /// struct arrived, so that it can keep sending without waiting
+    async fn get_struct_unacked<S>(&mut self) -> Result<S, Error>
+    where
+        S: std::marker::Send + std::marker::Sync + DeserializeOwned,
+    {
+        let errctx = error_context(format!(
+            "could not get struct of type {}",
+            std::any::type_name::<S>()
+        ));
+        self.check_ok()
+            .await
+            .map_err(inerr(errctx("get ok status")))?;
+        let buffer = self
+            .get_block()
+            .await
+            .map_err(inerr(errctx("get serialized struct")))?;
+        bincode::deserialize::<S>(&buffer[..]).map_err(inerr(errctx("deserialize block")))
+    }
+
+    pub async fn get_struct<S>(&mut self) -> Result<S, Error>
     where
         S: std::marker::Send + std::marker::Sync + DeserializeOwned,
     {
@@ -89,99 +245,492 @@ impl BbupCom {
         }
     }
 
-    pub async fn get_file_to(&mut self, path: &AbstPath) -> Result<(), Error> {
+    /// Receive a file and save it at path. The content is first written to a partial
+    /// file next to path, which is moved in place only once the transfer completes.
+    /// If a partial file from a previous interrupted transfer exists, the other party
+    /// is asked to resume the transfer from where it stopped. Otherwise, if `basis`
+    /// is an older version of the file, only the blocks that changed are transferred.
+    /// The content is hashed as it is received, and the file is moved in place only
+    /// if it matches `hash`
+    pub async fn get_file_to(
+        &mut self,
+        path: &AbstPath,
+        hash: &Hash,
+        basis: Option<&AbstPath>,
+    ) -> Result<(), Error> {
+        let errctx = error_context(format!("could not get file to path {path}"));
+
+        let offer = resume_offer(path, basis)?;
+        self.send_struct(&offer)
+            .await
+            .map_err(inerr(errctx("send resume offer")))?;
+        self.receive_file(path, hash, basis, &offer).await
+    }
+
+    /// Receive the content of a file and save it at path, once the resume offer
+    /// for it has been sent to the other party
+    async fn receive_file(
+        &mut self,
+        path: &AbstPath,
+        hash: &Hash,
+        basis: Option<&AbstPath>,
+        offer: &ResumeOffer,
+    ) -> Result<(), Error> {
         let errmsg = format!("could not get file to path {path}");
         let errctx = error_context(errmsg.clone());
+
+        let partial_path = partial_path(path)
+            .ok_or_else(|| generr(errmsg.clone(), "path has no parent or no file name"))?;
+        let block_size = offer
+            .signature
+            .as_ref()
+            .map(|signature| signature.block_size);
+
         self.check_ok()
             .await
             .map_err(inerr(errctx("get ok status")))?;
-        let mut file = fs::async_create_file(path)
+        let start = self
+            .rx
+            .read_u64()
             .await
-            .map_err(inerr(errctx("async create file to save content")))?;
+            .map_err(inerr(errctx("get resume offset")))?;
         let len = self
             .rx
             .read_u64()
             .await
             .map_err(inerr(errctx("get file length")))?;
 
-        if self.progress {
-            let name = match path.file_name() {
-                Some(val) => val,
-                None => String::from("[invalid filename]"),
-            };
-            let pw = ProgressReader::new(&mut self.rx, len, &name);
-            let mut handle = pw.take(len);
-            tokio::io::copy(&mut handle, &mut file)
+        let mut hasher = Hasher::new();
+        if let (Some(block_size), Some(basis)) = (block_size, basis) {
+            let copied = self
+                .get_delta_to(&partial_path, basis, block_size, &mut hasher)
                 .await
-                .map_err(inerr(errctx("copy progress reader to file content")))?;
+                .map_err(inerr(errctx("get delta of file content")))?;
+            if copied != len {
+                return Err(generr(
+                    errmsg,
+                    format!("delta reconstructed {copied} bytes out of {len}"),
+                ));
+            }
+            check_received_file(path, &partial_path, hasher.finalize(), hash)?;
+            fs::rename_file(&partial_path, path)
+                .map_err(inerr(errctx("move complete file in place")))?;
+            self.files_received += 1;
+            return Ok(());
+        }
+
+        let mut file = match start {
+            0 => fs::async_create_file(&partial_path)
+                .await
+                .map_err(inerr(errctx("async create file to save content")))?,
+            _ => {
+                // The content received by the interrupted transfer is hashed
+                //	first, as the rest is appended to it
+                let partial = fs::read_file(&partial_path)
+                    .map_err(inerr(errctx("open partial file to hash its content")))?;
+                std::io::copy(&mut std::io::Read::take(partial, start), &mut hasher)
+                    .map_err(inerr(errctx("hash partial file content")))?;
+                fs::async_append_file(&partial_path)
+                    .await
+                    .map_err(inerr(errctx("async open partial file to resume")))?
+            }
+        };
+        let mut writer = HashingWriter::new(&mut file, &mut hasher);
+
+        // Compressed content is delimited by its frames rather than by its length
+        let compress = self.compress;
+        let chunk_size = self.chunk_size;
+        let limit = match compress {
+            true => u64::MAX,
+            false => len,
+        };
+        let copy_result = if let Some(reporter) = &self.reporter {
+            let pw = ProgressReader::new(&mut self.rx, len, path, reporter.clone());
+            let mut handle = pw.take(limit);
+            let result = match compress {
+                true => {
+                    compress::copy_decompressed(&mut handle, &mut writer, &mut self.limiter).await
+                }
+                false => {
+                    throttle::copy(&mut handle, &mut writer, &mut self.limiter, chunk_size).await
+                }
+            };
 
             handle.into_inner().finish().await;
+            result.map_err(inerr(errctx("copy progress reader to file content")))
         } else {
-            let mut handle = (&mut self.rx).take(len);
-            tokio::io::copy(&mut handle, &mut file)
-                .await
-                .map_err(inerr(errctx("copy rx to file content")))?;
-        }
+            let mut handle = (&mut self.rx).take(limit);
+            match compress {
+                true => {
+                    compress::copy_decompressed(&mut handle, &mut writer, &mut self.limiter).await
+                }
+                false => {
+                    throttle::copy(&mut handle, &mut writer, &mut self.limiter, chunk_size).await
+                }
+            }
+            .map_err(inerr(errctx("copy rx to file content")))
+        };
+        // Whatever has been received so far is kept in the partial file, so that
+        //	the transfer can be resumed
         file.flush()
             .await
             .map_err(inerr(errctx("flush file content to file")))?;
+        let copied = copy_result?;
+        if copied < len {
+            return Err(generr(
+                errmsg,
+                format!("connection closed after {copied} bytes out of {len}"),
+            ));
+        }
+        file.sync_all()
+            .await
+            .map_err(inerr(errctx("sync file content to disk")))?;
+
+        check_received_file(path, &partial_path, hasher.finalize(), hash)?;
+        fs::rename_file(&partial_path, path)
+            .map_err(inerr(errctx("move complete file in place")))?;
+        self.files_received += 1;
         Ok(())
     }
 
+    /// Query the files and symlinks to the other party, saving them under endpoint.
+    /// If `basis` is given, the files already existing at the same relative path
+    /// under it are used as older versions of the queried files, so that only what
+    /// changed is transferred. Objects are queried in batches of `batch_size` if
+    /// the other party supports it, or one at a time otherwise
     pub async fn query_files(
         &mut self,
         queries: Vec<(Queryable, AbstPath, Hash)>,
         endpoint: &AbstPath,
+        basis: Option<&AbstPath>,
+        batch_size: usize,
+    ) -> Result<(), Error> {
+        if batch_size > 1 && self.protocol_version >= 4 {
+            for batch in queries.chunks(batch_size) {
+                self.query_batch(batch, endpoint, basis).await?;
+            }
+        } else {
+            for (querable, rel_path, hash) in queries {
+                self.query_object(querable, &rel_path, &hash, endpoint, basis)
+                    .await?;
+            }
+        }
+        self.end_queries().await
+    }
+
+    /// Query a single file or symlink to the other party, saving it under endpoint
+    /// and checking that its hash matches `hash`. More objects can be queried
+    /// afterwards, until `end_queries` is called
+    pub async fn query_object(
+        &mut self,
+        querable: Queryable,
+        rel_path: &AbstPath,
+        hash: &Hash,
+        endpoint: &AbstPath,
+        basis: Option<&AbstPath>,
     ) -> Result<(), Error> {
         let errmsg = String::from("could not query files and symlinks");
         let errctx = error_context(errmsg.clone());
-        for (querable, rel_path, hash) in queries {
-            let path = endpoint.append(&rel_path);
-            match querable {
-                Queryable::File => {
-                    self.send_struct(Query::Object(Queryable::File, rel_path.clone()))
-                        .await
-                        .map_err(inerr(errctx(format!("ask query for file at path {path}"))))?;
+        let path = endpoint.append(rel_path);
+        match querable {
+            Queryable::File => {
+                self.send_struct(Query::Object(Queryable::File, self.prefix.append(rel_path)))
+                    .await
+                    .map_err(inerr(errctx(format!("ask query for file at path {path}"))))?;
 
-                    self.get_file_to(&path)
-                        .await
-                        .map_err(inerr(errctx(format!("query file at path {path}"))))?;
+                let basis_path = basis.map(|basis| basis.append(rel_path));
+                self.get_file_to(&path, hash, basis_path.as_ref())
+                    .await
+                    .map_err(inerr(errctx(format!("query file at path {path}"))))?;
+            }
+            Queryable::SymLink => {
+                self.send_struct(Query::Object(
+                    Queryable::SymLink,
+                    self.prefix.append(rel_path),
+                ))
+                .await
+                .map_err(inerr(errctx(format!(
+                    "ask query for symlink at path {path}"
+                ))))?;
 
-                    let file = fs::read_file(&path).map_err(inerr(errctx(format!(
-                        "open file to check hash at path {path}"
-                    ))))?;
+                let endpoint: Endpoint = self.get_struct().await.map_err(inerr(errctx(
+                    format!("query symlink's endpoint at path {path}"),
+                )))?;
+                save_received_symlink(&path, endpoint, hash)?;
+            }
+        }
+        Ok(())
+    }
 
-                    if hash
-                        != hasher::hash_stream(file)
-                            .map_err(inerr(errctx(format!("hash file content at path {path}"))))?
-                    {
-                        return Err(generr(errmsg, format!("hash of the file recieved (at path {path}) does not match the hash given")));
-                    }
+    /// Same as querying each object with `query_object`, but in a single round
+    /// trip: the queries are sent all at once, together with the resume offers of
+    /// the files, and the other party sends all the objects back to back.
+    /// Requires protocol version 4
+    pub async fn query_batch(
+        &mut self,
+        queries: &[(Queryable, AbstPath, Hash)],
+        endpoint: &AbstPath,
+        basis: Option<&AbstPath>,
+    ) -> Result<(), Error> {
+        let errctx = error_context("could not query batch of files and symlinks");
+        let basis_path = |rel_path: &AbstPath| basis.map(|basis| basis.append(rel_path));
+        let mut batch = Vec::new();
+        for (querable, rel_path, _) in queries {
+            batch.push(match querable {
+                Queryable::File => {
+                    let path = endpoint.append(rel_path);
+                    let offer = resume_offer(&path, basis_path(rel_path).as_ref())?;
+                    BatchQuery::File(self.prefix.append(rel_path), offer)
                 }
-                Queryable::SymLink => {
-                    self.send_struct(Query::Object(Queryable::SymLink, rel_path.clone()))
-                        .await
-                        .map_err(inerr(errctx(format!(
-                            "ask query for symlink at path {path}"
-                        ))))?;
-
-                    let endpoint: Endpoint = self.get_struct().await.map_err(inerr(errctx(
-                        format!("query symlink's endpoint at path {path}"),
-                    )))?;
+                Queryable::SymLink => BatchQuery::SymLink(self.prefix.append(rel_path)),
+            });
+        }
+        self.send_struct(Query::Batch(batch.clone()))
+            .await
+            .map_err(inerr(errctx("ask query for batch".to_string())))?;
 
-                    if hash != hasher::hash_bytes(endpoint.as_bytes()) {
-                        return Err(generr(errmsg, format!("hash of the symlink recieved (at path {path}) does not match the hash given")));
-                    }
-                    fs::create_symlink(&path, endpoint).map_err(inerr(errctx(format!(
-                        "create queried symlink at path {path}"
-                    ))))?;
+        for (query, (_, rel_path, hash)) in batch.iter().zip(queries) {
+            let path = endpoint.append(rel_path);
+            match query {
+                BatchQuery::File(_, offer) => {
+                    self.receive_file(&path, hash, basis_path(rel_path).as_ref(), offer)
+                        .await
+                        .map_err(inerr(errctx(format!("query file at path {path}"))))?;
+                }
+                BatchQuery::SymLink(_) => {
+                    let endpoint: Endpoint =
+                        self.get_struct_unacked()
+                            .await
+                            .map_err(inerr(errctx(format!(
+                                "query symlink's endpoint at path {path}"
+                            ))))?;
+                    save_received_symlink(&path, endpoint, hash)?;
                 }
             }
         }
+        Ok(())
+    }
+
+    /// Tell the other party that no more objects will be queried
+    pub async fn end_queries(&mut self) -> Result<(), Error> {
+        let errctx = error_context("could not end queries");
         self.send_struct(Query::Stop)
             .await
-            .map_err(inerr(errctx("send query stop signal".to_string())))?;
+            .map_err(inerr(errctx("send query stop signal")))
+    }
+}
 
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::super::bbupcom::{loopback, partial_path, transfer};
+    use super::super::Queryable;
+    use abst_fs::AbstPath;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test() {
+        let path = PathBuf::from("/tmp/bbup-test-bbup-get");
+        assert!(!path.exists());
+        std::fs::create_dir(&path).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            let path = AbstPath::from(&path);
+            large_chunks(&path);
+            prefixed_queries(&path);
+            checked_hashes(&path);
+        });
+        std::fs::remove_dir_all(&path).unwrap();
+        assert!(result.is_ok())
+    }
+
+    fn large_chunks(path: &AbstPath) {
+        // A few MB that don't compress well, so that frames are as big as chunks
+        let content: Vec<u8> = (0..6 * 1024 * 1024u32)
+            .map(|n| (n.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        for (index, (chunk_size, compress)) in [(4 << 20, false), (4 << 20, true), (512, false)]
+            .into_iter()
+            .enumerate()
+        {
+            let source = path.add_last(format!("source-{index}"));
+            let dest = path.add_last(format!("dest-{index}"));
+            std::fs::create_dir(source.to_path_buf()).unwrap();
+            std::fs::create_dir(dest.to_path_buf()).unwrap();
+
+            runtime.block_on(async {
+                let (mut client, mut server) = loopback().await;
+                for com in [&mut client, &mut server] {
+                    com.chunk_size = Some(chunk_size);
+                    com.compress = compress;
+                }
+                transfer(
+                    &mut client,
+                    &mut server,
+                    &source,
+                    &dest,
+                    &[("large", &content)],
+                    1,
+                )
+                .await;
+            });
+
+            let received = std::fs::read(dest.add_last("large").to_path_buf()).unwrap();
+            assert_eq!(received.len(), content.len());
+            assert_eq!(hasher::hash_bytes(&received), hasher::hash_bytes(&content));
+        }
+    }
+
+    fn prefixed_queries(path: &AbstPath) {
+        // A link whose endpoint is a subpath of the archive, talking to the server
+        //	about the whole archive
+        let archive = path.add_last("archive");
+        let endpoint = AbstPath::from("backups/desktop");
+        let link = path.add_last("link");
+        std::fs::create_dir_all(archive.append(&endpoint).to_path_buf()).unwrap();
+        std::fs::create_dir(link.to_path_buf()).unwrap();
+        std::fs::write(archive.add_last("unrelated").to_path_buf(), "unrelated").unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+
+        for batch_size in [1, 4] {
+            let pulled = format!("pulled-{batch_size}");
+            let pushed = format!("pushed-{batch_size}");
+            std::fs::write(
+                archive.append(&endpoint).add_last(&pulled).to_path_buf(),
+                "down",
+            )
+            .unwrap();
+            std::fs::write(link.add_last(&pushed).to_path_buf(), "up").unwrap();
+
+            runtime.block_on(async {
+                let (mut client, mut server) = loopback().await;
+                client.prefix = endpoint.clone();
+
+                // The link queries its own paths, the server supplies them under
+                //	the endpoint
+                let queryable = [endpoint.add_last(&pulled)];
+                let (queried, supplied) = tokio::join!(
+                    client.query_files(
+                        vec![(
+                            Queryable::File,
+                            AbstPath::from(&pulled),
+                            hasher::hash_bytes("down")
+                        )],
+                        &link,
+                        None,
+                        batch_size
+                    ),
+                    server.supply_files(&queryable, &archive)
+                );
+                queried.unwrap();
+                supplied.unwrap();
+
+                // The server queries the paths under the endpoint, the link
+                //	supplies its own
+                let queryable = [AbstPath::from(&pushed)];
+                let (queried, supplied) = tokio::join!(
+                    server.query_files(
+                        vec![(
+                            Queryable::File,
+                            endpoint.add_last(&pushed),
+                            hasher::hash_bytes("up")
+                        )],
+                        &archive,
+                        None,
+                        batch_size
+                    ),
+                    client.supply_files(&queryable, &link)
+                );
+                queried.unwrap();
+                supplied.unwrap();
+
+                // Nothing outside of the endpoint can be queried from the link
+                let queryable = [AbstPath::from("unrelated")];
+                let (queried, supplied) = tokio::join!(
+                    server.query_files(
+                        vec![(
+                            Queryable::File,
+                            AbstPath::from("unrelated"),
+                            hasher::hash_bytes("unrelated")
+                        )],
+                        &archive,
+                        None,
+                        batch_size
+                    ),
+                    client.supply_files(&queryable, &link)
+                );
+                assert!(queried.is_err());
+                assert!(supplied.is_err());
+            });
+
+            let read = |path: AbstPath| std::fs::read_to_string(path.to_path_buf()).unwrap();
+            assert_eq!(read(link.add_last(&pulled)), "down");
+            assert_eq!(read(archive.append(&endpoint).add_last(&pushed)), "up");
+        }
+    }
+
+    fn checked_hashes(path: &AbstPath) {
+        // Large enough for the changed blocks only to be sent when there is a basis
+        let content: Vec<u8> = (0..256 * 1024u32)
+            .map(|n| (n.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        let mut older = content.clone();
+        older[100_000..100_100].fill(0);
+        let source = path.add_last("checked-source");
+        let basis = path.add_last("checked-basis");
+        std::fs::create_dir(source.to_path_buf()).unwrap();
+        std::fs::create_dir(basis.to_path_buf()).unwrap();
+        std::fs::write(source.add_last("file").to_path_buf(), &content).unwrap();
+        std::fs::write(basis.add_last("file").to_path_buf(), &older).unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+
+        for (index, (compress, batch_size, with_basis)) in [
+            (false, 1, false),
+            (true, 1, false),
+            (false, 4, false),
+            (false, 1, true),
+            (false, 4, true),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let dest = path.add_last(format!("checked-dest-{index}"));
+            std::fs::create_dir(dest.to_path_buf()).unwrap();
+            let file = dest.add_last("file");
+            let query = |hash| {
+                let (source, dest, basis) = (source.clone(), dest.clone(), basis.clone());
+                runtime.block_on(async move {
+                    let (mut client, mut server) = loopback().await;
+                    for com in [&mut client, &mut server] {
+                        com.compress = compress;
+                    }
+                    let queryable = [AbstPath::from("file")];
+                    // The client is dropped as soon as it is done, so that the
+                    //	other party stops waiting for queries even if it failed
+                    let (queried, _) = tokio::join!(
+                        async move {
+                            client
+                                .query_files(
+                                    vec![(Queryable::File, AbstPath::from("file"), hash)],
+                                    &dest,
+                                    with_basis.then_some(&basis),
+                                    batch_size,
+                                )
+                                .await
+                        },
+                        server.supply_files(&queryable, &source)
+                    );
+                    queried
+                })
+            };
+
+            // Content not matching the hash it was queried with is discarded
+            assert!(query(hasher::hash_bytes("something else")).is_err());
+            assert!(!file.exists());
+            assert!(!partial_path(&file).unwrap().exists());
+
+            query(hasher::hash_bytes(&content)).unwrap();
+            assert_eq!(std::fs::read(file.to_path_buf()).unwrap(), content);
+        }
     }
 }
@@ -1,6 +1,21 @@
 mod bbupcom;
+mod blockdiff;
+mod compress;
 mod get;
 mod progress;
 mod send;
-pub use bbupcom::{BbupCom, JobType, Queryable};
+mod throttle;
+mod timeout;
+mod traffic;
+mod version;
+pub use bbupcom::{partial_path, BbupCom, Error, JobType, Queryable, MAX_BATCH_SIZE};
+pub use progress::{
+    percent, Direction, JsonLinesReporter, OverallProgress, ProgressBarReporter, ProgressEvent,
+    ProgressReporter,
+};
 use progress::{ProgressReader, ProgressWriter};
+use throttle::RateLimiter;
+pub use throttle::{parse_chunk_size, parse_rate, parse_size};
+pub use timeout::TimeoutReader;
+pub use traffic::{Counted, Traffic};
+pub use version::PROTOCOL_VERSION;
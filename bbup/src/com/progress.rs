@@ -1,53 +1,312 @@
 use std::{
+    io::Write,
     pin::Pin,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 
+use abst_fs::AbstPath;
+use fs_vcs::METADATA_WORK;
 use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
 use tokio::io::{AsyncRead, AsyncWrite};
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+impl std::fmt::Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Direction::Upload => write!(f, "upload"),
+            Direction::Download => write!(f, "download"),
+        }
+    }
+}
+
+/// Events emitted during the transfer of a file. `total` and `done` count the
+/// bytes going through the connection, except for `Overall` and `ActionDone`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProgressEvent {
+    /// Progress of the application of a whole list of actions, in units of work
+    /// (see `fs_vcs::Actions::work`)
+    Overall {
+        done: u64,
+        total: u64,
+    },
+    FileStarted {
+        path: AbstPath,
+        direction: Direction,
+        total: u64,
+    },
+    Progress {
+        path: AbstPath,
+        done: u64,
+    },
+    FileDone {
+        path: AbstPath,
+    },
+    /// An action of the list was applied, writing `bytes` of file content, in
+    /// `duration` (including the transfer of the object, if any)
+    ActionDone {
+        path: AbstPath,
+        kind: &'static str,
+        bytes: u64,
+        duration: Duration,
+    },
+}
+
+/// Consumer of the progress events of file transfers
+pub trait ProgressReporter: Send + Sync {
+    fn report(&self, event: ProgressEvent);
+}
+
+/// Percentage of the work done, 100 if there is no work at all
+pub fn percent(done: u64, total: u64) -> u64 {
+    match total {
+        0 => 100,
+        total => (done.min(total) as u128 * 100 / total as u128) as u64,
+    }
+}
+
+/// Tracker of the overall progress of applying a list of actions, whose work is
+/// known before any of them is applied. Reports `ProgressEvent::Overall` to the
+/// reporter, if any
+pub struct OverallProgress {
+    reporter: Option<Arc<dyn ProgressReporter>>,
+    work: Vec<u64>,
+    done: u64,
+    total: u64,
+}
+impl OverallProgress {
+    /// Start tracking the actions, the i-th of which takes `work[i]` units of work
+    pub fn start(reporter: Option<Arc<dyn ProgressReporter>>, work: Vec<u64>) -> OverallProgress {
+        let total = work.iter().sum();
+        let progress = OverallProgress {
+            reporter,
+            work,
+            done: 0,
+            total,
+        };
+        progress.report();
+        progress
+    }
+    /// Mark the action with the given index as applied
+    pub fn complete(&mut self, index: usize) {
+        self.done += self.work.get(index).copied().unwrap_or(0);
+        self.report();
+    }
+    /// Mark the action with the given index as applied, reporting it as a
+    /// `ProgressEvent::ActionDone` before the overall progress. The bytes written
+    /// are the work of the action beyond its `METADATA_WORK`
+    pub fn complete_action(
+        &mut self,
+        index: usize,
+        path: &AbstPath,
+        kind: &'static str,
+        duration: Duration,
+    ) {
+        if let Some(reporter) = &self.reporter {
+            let work = self.work.get(index).copied().unwrap_or(0);
+            reporter.report(ProgressEvent::ActionDone {
+                path: path.clone(),
+                kind,
+                bytes: work.saturating_sub(METADATA_WORK),
+                duration,
+            });
+        }
+        self.complete(index);
+    }
+    pub fn percent(&self) -> u64 {
+        percent(self.done, self.total)
+    }
+    fn report(&self) {
+        if let Some(reporter) = &self.reporter {
+            reporter.report(ProgressEvent::Overall {
+                done: self.done,
+                total: self.total,
+            });
+        }
+    }
+}
+
+/// Reporter showing a progress bar on stdout for each transfer, prefixed by the
+/// overall progress when it is known
+#[derive(Default)]
+pub struct ProgressBarReporter {
+    pb: Mutex<Option<ProgressBar>>,
+    overall: Mutex<Option<u64>>,
+}
+fn overall_prefix(overall: Option<u64>) -> String {
+    match overall {
+        Some(percent) => format!("[{percent:>3}%] "),
+        None => String::new(),
+    }
+}
 fn pb_style_from(direction: &str, name: &str) -> ProgressStyle {
-    let style_path = String::from("[")
+    let style_path = String::from("{prefix}[")
         + direction
         + "]\t"
         + name
         + "\t\t{bytes}\t{percent}%\t{bytes_per_sec}\t{elapsed_precise}";
     ProgressStyle::default_bar().template(style_path.as_str())
 }
-async fn update_progressbar(pb: Arc<Mutex<ProgressBar>>, bytes: Arc<Mutex<u64>>) {
-    loop {
-        pb.lock().unwrap().set_position(*bytes.lock().unwrap());
-        tokio::time::sleep(tokio::time::Duration::from_millis(45)).await;
+impl ProgressReporter for ProgressBarReporter {
+    fn report(&self, event: ProgressEvent) {
+        let mut pb = self.pb.lock().unwrap();
+        let mut overall = self.overall.lock().unwrap();
+        match event {
+            ProgressEvent::Overall { done, total } => {
+                *overall = Some(percent(done, total));
+                if let Some(bar) = pb.as_ref() {
+                    bar.set_prefix(overall_prefix(*overall));
+                }
+            }
+            ProgressEvent::FileStarted {
+                path,
+                direction,
+                total,
+            } => {
+                let name = match path.file_name() {
+                    Some(val) => val,
+                    None => String::from("[invalid filename]"),
+                };
+                let bar = ProgressBar::new(total);
+                bar.set_style(pb_style_from(&direction.to_string(), &name));
+                bar.set_prefix(overall_prefix(*overall));
+                *pb = Some(bar);
+            }
+            ProgressEvent::Progress { done, .. } => {
+                if let Some(bar) = pb.as_ref() {
+                    bar.set_position(done);
+                }
+            }
+            ProgressEvent::FileDone { .. } => {
+                if let Some(bar) = pb.take() {
+                    bar.finish();
+                }
+            }
+            ProgressEvent::ActionDone { .. } => {}
+        }
     }
 }
 
-pub struct ProgressWriter<'a, W: AsyncWrite + Unpin + Sync + Send> {
-    pb_task_handle: tokio::task::JoinHandle<()>,
-    pb: Arc<Mutex<ProgressBar>>,
-    bytes_written: Arc<Mutex<u64>>,
-    pub writer: &'a mut W,
+/// Line written by `JsonLinesReporter` for each action applied
+#[derive(Serialize)]
+struct ActionLine<'a> {
+    path: String,
+    kind: &'a str,
+    bytes: u64,
+    /// Seconds
+    duration: f64,
 }
-impl<'a, W: AsyncWrite + Unpin + Sync + Send> ProgressWriter<'a, W> {
-    pub fn new(writer: &'a mut W, len: u64, name: &str) -> ProgressWriter<'a, W> {
-        let pb = ProgressBar::new(len);
-        pb.set_style(pb_style_from("upload", name));
 
-        let pb = Arc::new(Mutex::new(pb));
-        let bytes_written = Arc::new(Mutex::new(0u64));
+/// Reporter writing one JSON object per line to `writer` for each action
+/// applied (see `ProgressEvent::ActionDone`), as soon as it is applied, so that
+/// the sync can be followed from another program. Every event is forwarded to
+/// `inner`, if any
+pub struct JsonLinesReporter<W: Write + Send> {
+    writer: Mutex<W>,
+    inner: Option<Arc<dyn ProgressReporter>>,
+}
+impl<W: Write + Send> JsonLinesReporter<W> {
+    pub fn new(writer: W, inner: Option<Arc<dyn ProgressReporter>>) -> JsonLinesReporter<W> {
+        JsonLinesReporter {
+            writer: Mutex::new(writer),
+            inner,
+        }
+    }
+    pub fn into_writer(self) -> W {
+        self.writer.into_inner().unwrap()
+    }
+}
+impl<W: Write + Send> ProgressReporter for JsonLinesReporter<W> {
+    fn report(&self, event: ProgressEvent) {
+        if let ProgressEvent::ActionDone {
+            path,
+            kind,
+            bytes,
+            duration,
+        } = &event
+        {
+            let line = ActionLine {
+                path: path.to_string(),
+                kind,
+                bytes: *bytes,
+                duration: duration.as_secs_f64(),
+            };
+            // Reporting is best effort: a reader that went away must not stop
+            // the sync
+            if let Ok(line) = serde_json::to_string(&line) {
+                let mut writer = self.writer.lock().unwrap();
+                let _ = writeln!(writer, "{line}").and_then(|()| writer.flush());
+            }
+        }
+        if let Some(inner) = &self.inner {
+            inner.report(event);
+        }
+    }
+}
 
-        let pb_task_handle = tokio::spawn(update_progressbar(pb.clone(), bytes_written.clone()));
+/// State shared by `ProgressWriter` and `ProgressReader`
+struct Tracker {
+    reporter: Arc<dyn ProgressReporter>,
+    path: AbstPath,
+    done: u64,
+}
+impl Tracker {
+    fn start(
+        reporter: Arc<dyn ProgressReporter>,
+        path: &AbstPath,
+        direction: Direction,
+        total: u64,
+    ) -> Tracker {
+        reporter.report(ProgressEvent::FileStarted {
+            path: path.clone(),
+            direction,
+            total,
+        });
+        Tracker {
+            reporter,
+            path: path.clone(),
+            done: 0,
+        }
+    }
+    fn advance(&mut self, bytes: u64) {
+        if bytes > 0 {
+            self.done += bytes;
+            self.reporter.report(ProgressEvent::Progress {
+                path: self.path.clone(),
+                done: self.done,
+            });
+        }
+    }
+    fn finish(self) {
+        self.reporter
+            .report(ProgressEvent::FileDone { path: self.path });
+    }
+}
 
+pub struct ProgressWriter<'a, W: AsyncWrite + Unpin + Sync + Send> {
+    tracker: Tracker,
+    pub writer: &'a mut W,
+}
+impl<'a, W: AsyncWrite + Unpin + Sync + Send> ProgressWriter<'a, W> {
+    pub fn new(
+        writer: &'a mut W,
+        len: u64,
+        path: &AbstPath,
+        reporter: Arc<dyn ProgressReporter>,
+    ) -> ProgressWriter<'a, W> {
         ProgressWriter {
-            pb_task_handle,
-            pb,
-            bytes_written,
+            tracker: Tracker::start(reporter, path, Direction::Upload, len),
             writer,
         }
     }
 
     pub async fn finish(self) {
-        self.pb.lock().unwrap().finish();
-        self.pb_task_handle.abort();
+        self.tracker.finish();
     }
 }
 impl<'a, W: AsyncWrite + Unpin + Sync + Send> AsyncWrite for ProgressWriter<'a, W> {
@@ -59,7 +318,7 @@ impl<'a, W: AsyncWrite + Unpin + Sync + Send> AsyncWrite for ProgressWriter<'a,
         match Pin::new(&mut self.writer).poll_write(cx, buf) {
             std::task::Poll::Ready(writer) => match writer {
                 Ok(bytes) => {
-                    *self.bytes_written.lock().unwrap() += bytes as u64;
+                    self.tracker.advance(bytes as u64);
                     std::task::Poll::Ready(Ok(bytes))
                 }
                 Err(err) => std::task::Poll::Ready(Err(err)),
@@ -82,32 +341,24 @@ impl<'a, W: AsyncWrite + Unpin + Sync + Send> AsyncWrite for ProgressWriter<'a,
 }
 
 pub struct ProgressReader<'a, W: AsyncRead + Unpin + Sync + Send> {
-    pb_task_handle: tokio::task::JoinHandle<()>,
-    pb: Arc<Mutex<ProgressBar>>,
-    bytes_read: Arc<Mutex<u64>>,
+    tracker: Tracker,
     pub reader: &'a mut W,
 }
 impl<'a, R: AsyncRead + Unpin + Sync + Send> ProgressReader<'a, R> {
-    pub fn new(reader: &'a mut R, len: u64, name: &str) -> ProgressReader<'a, R> {
-        let pb = ProgressBar::new(len);
-        pb.set_style(pb_style_from("download", name));
-
-        let pb = Arc::new(Mutex::new(pb));
-        let bytes_read = Arc::new(Mutex::new(0u64));
-
-        let pb_task_handle = tokio::spawn(update_progressbar(pb.clone(), bytes_read.clone()));
-
+    pub fn new(
+        reader: &'a mut R,
+        len: u64,
+        path: &AbstPath,
+        reporter: Arc<dyn ProgressReporter>,
+    ) -> ProgressReader<'a, R> {
         ProgressReader {
-            pb_task_handle,
-            pb,
-            bytes_read,
+            tracker: Tracker::start(reporter, path, Direction::Download, len),
             reader,
         }
     }
 
     pub async fn finish(self) {
-        self.pb.lock().unwrap().finish();
-        self.pb_task_handle.abort();
+        self.tracker.finish();
     }
 }
 impl<'a, R: AsyncRead + Unpin + Sync + Send> AsyncRead for ProgressReader<'a, R> {
@@ -116,10 +367,12 @@ impl<'a, R: AsyncRead + Unpin + Sync + Send> AsyncRead for ProgressReader<'a, R>
         cx: &mut std::task::Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> std::task::Poll<std::io::Result<()>> {
+        let filled_before = buf.filled().len();
         match Pin::new(&mut self.reader).poll_read(cx, buf) {
             std::task::Poll::Ready(reader) => match reader {
                 Ok(()) => {
-                    *self.bytes_read.lock().unwrap() += buf.filled().len() as u64;
+                    let bytes = buf.filled().len() - filled_before;
+                    self.tracker.advance(bytes as u64);
                     std::task::Poll::Ready(Ok(()))
                 }
                 Err(err) => std::task::Poll::Ready(Err(err)),
@@ -128,3 +381,213 @@ impl<'a, R: AsyncRead + Unpin + Sync + Send> AsyncRead for ProgressReader<'a, R>
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::bbupcom::{loopback, transfer};
+    use super::{
+        percent, Direction, JsonLinesReporter, OverallProgress, ProgressEvent, ProgressReporter,
+    };
+    use abst_fs::{AbstPath, Mode, Mtime};
+    use fs_vcs::{Action, Actions, METADATA_WORK};
+    use std::{
+        path::PathBuf,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    /// Reporter keeping the overall percentages it is given
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<u64>>);
+    impl ProgressReporter for Recorder {
+        fn report(&self, event: ProgressEvent) {
+            if let ProgressEvent::Overall { done, total } = event {
+                self.0.lock().unwrap().push(percent(done, total));
+            }
+        }
+    }
+
+    /// Reporter keeping every event it is given
+    #[derive(Default)]
+    struct Sink(Mutex<Vec<ProgressEvent>>);
+    impl ProgressReporter for Sink {
+        fn report(&self, event: ProgressEvent) {
+            self.0.lock().unwrap().push(event);
+        }
+    }
+
+    #[test]
+    fn test() {
+        overall();
+        action_events();
+
+        let path = PathBuf::from("/tmp/bbup-test-bbup-progress");
+        assert!(!path.exists());
+        std::fs::create_dir(&path).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            let path = AbstPath::from(&path);
+            transfer_events(&path);
+        });
+        std::fs::remove_dir_all(&path).unwrap();
+        assert!(result.is_ok())
+    }
+
+    fn overall() {
+        let recorder = Arc::new(Recorder::default());
+        let work = vec![4096, 10000 + 4096, 4096, 0, 1 + 4096];
+        let mut progress = OverallProgress::start(Some(recorder.clone()), work.clone());
+        for index in 0..work.len() {
+            progress.complete(index);
+        }
+        let percents = recorder.0.lock().unwrap().clone();
+        assert_eq!(percents.len(), work.len() + 1);
+        assert_eq!(percents.first(), Some(&0));
+        assert!(percents.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(percents[..percents.len() - 1].iter().all(|p| *p < 100));
+        assert_eq!(percents.last(), Some(&100));
+        assert_eq!(progress.percent(), 100);
+
+        // Nothing to do is all done, and nothing is reported without a reporter
+        assert_eq!(OverallProgress::start(None, Vec::new()).percent(), 100);
+        assert_eq!(percent(5, 10), 50);
+        assert_eq!(percent(u64::MAX, u64::MAX), 100);
+    }
+
+    fn action_events() {
+        let mut actions = Actions::new();
+        actions.push(AbstPath::from("old"), Action::RemoveFile);
+        actions.push(AbstPath::from("dir"), Action::AddDir);
+        actions.push(
+            AbstPath::from("dir/file"),
+            Action::AddFile(
+                Mtime::from(1667000000, 0),
+                hasher::hash_bytes("content"),
+                Mode::from(0o644),
+            ),
+        );
+        let actions: Vec<(AbstPath, Action)> = actions.into_iter().collect();
+        let work = vec![METADATA_WORK, METADATA_WORK, 7 + METADATA_WORK];
+
+        let sink = Arc::new(Sink::default());
+        let reporter = Arc::new(JsonLinesReporter::new(Vec::new(), Some(sink.clone())));
+        let mut progress = OverallProgress::start(Some(reporter.clone()), work);
+        for (index, (path, action)) in actions.iter().enumerate() {
+            progress.complete_action(index, path, action.kind(), Duration::from_millis(500));
+        }
+
+        // One event per action, in order, each followed by the overall progress
+        let events = sink.0.lock().unwrap().clone();
+        let done: Vec<(AbstPath, &str, u64)> = events
+            .iter()
+            .filter_map(|event| match event {
+                ProgressEvent::ActionDone {
+                    path, kind, bytes, ..
+                } => Some((path.clone(), *kind, *bytes)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            done,
+            vec![
+                (AbstPath::from("old"), "remove-file", 0),
+                (AbstPath::from("dir"), "add-dir", 0),
+                (AbstPath::from("dir/file"), "add-file", 7),
+            ]
+        );
+        assert_eq!(events.len(), 1 + 2 * actions.len());
+        assert!(matches!(
+            events.last(),
+            Some(ProgressEvent::Overall { done, total }) if done == total
+        ));
+
+        // The same events as JSON lines, one per action
+        drop(progress);
+        let reporter = Arc::try_unwrap(reporter).ok().unwrap();
+        let output = String::from_utf8(reporter.into_writer()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                r#"{"path":"old","kind":"remove-file","bytes":0,"duration":0.5}"#,
+                r#"{"path":"dir","kind":"add-dir","bytes":0,"duration":0.5}"#,
+                r#"{"path":"dir/file","kind":"add-file","bytes":7,"duration":0.5}"#,
+            ]
+        );
+    }
+
+    /// Check that `events` are exactly the events of the transfers of `files`
+    /// (path and length) one after the other: each file is started, advances up
+    /// to its length and is done before the next one starts
+    fn check_transfers(events: &[ProgressEvent], direction: Direction, files: &[(AbstPath, u64)]) {
+        let mut events = events.iter().peekable();
+        for (path, len) in files {
+            assert_eq!(
+                events.next(),
+                Some(&ProgressEvent::FileStarted {
+                    path: path.clone(),
+                    direction,
+                    total: *len,
+                })
+            );
+            let mut last = 0;
+            while let Some(ProgressEvent::Progress { path: p, done }) = events.peek() {
+                assert_eq!(p, path);
+                assert!(*done > last);
+                last = *done;
+                events.next();
+            }
+            assert_eq!(last, *len);
+            assert_eq!(
+                events.next(),
+                Some(&ProgressEvent::FileDone { path: path.clone() })
+            );
+        }
+        assert_eq!(events.next(), None);
+    }
+
+    fn transfer_events(path: &AbstPath) {
+        let source = path.add_last("source");
+        let dest = path.add_last("dest");
+        std::fs::create_dir(source.to_path_buf()).unwrap();
+        std::fs::create_dir(dest.to_path_buf()).unwrap();
+        let first = vec![1u8; 100000];
+        let second = vec![2u8; 5000];
+
+        let downloads = Arc::new(Sink::default());
+        let uploads = Arc::new(Sink::default());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let (mut client, mut server) = loopback().await;
+            client.set_reporter(downloads.clone());
+            server.set_reporter(uploads.clone());
+            transfer(
+                &mut client,
+                &mut server,
+                &source,
+                &dest,
+                &[("first", &first), ("second", &second)],
+                1,
+            )
+            .await;
+        });
+
+        let lens = [first.len() as u64, second.len() as u64];
+        let files = |root: &AbstPath| -> Vec<(AbstPath, u64)> {
+            vec![
+                (root.add_last("first"), lens[0]),
+                (root.add_last("second"), lens[1]),
+            ]
+        };
+        check_transfers(
+            &downloads.0.lock().unwrap(),
+            Direction::Download,
+            &files(&dest),
+        );
+        check_transfers(
+            &uploads.0.lock().unwrap(),
+            Direction::Upload,
+            &files(&source),
+        );
+    }
+}
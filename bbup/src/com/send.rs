@@ -1,13 +1,13 @@
 use serde::Serialize;
 
 use super::{
-    bbupcom::{error_context, generr, inerr, Error, Query, Queryable},
-    BbupCom, ProgressWriter,
+    bbupcom::{error_context, generr, inerr, BatchQuery, Error, Query, Queryable, ResumeOffer},
+    compress, throttle, BbupCom, ProgressWriter,
 };
 
 use abst_fs::{self as fs, AbstPath};
 
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
 
 impl BbupCom {
     async fn send_status(&mut self, status: u8) -> Result<(), Error> {
@@ -80,44 +80,177 @@ impl BbupCom {
         Ok(())
     }
 
+    /// Same as `send_struct`, but then wait for the other party to `confirm` that
+    /// it has acted upon the struct. The confirmation of `send_struct` only says
+    /// that the struct arrived, while the other party might still lose it
+    pub async fn send_struct_confirmed<C>(&mut self, content: C) -> Result<(), Error>
+    where
+        C: std::marker::Send + std::marker::Sync + Serialize,
+    {
+        let errctx = error_context(format!(
+            "could not send struct of type {} with confirmation",
+            std::any::type_name::<C>()
+        ));
+        self.send_struct(content)
+            .await
+            .map_err(inerr(errctx("send struct")))?;
+        self.check_ok()
+            .await
+            .map_err(inerr(errctx("get confirmation")))
+    }
+
+    /// Confirm to the other party that the struct it sent with
+    /// `send_struct_confirmed` has been acted upon
+    pub async fn confirm(&mut self) -> Result<(), Error> {
+        self.send_ok().await
+    }
+
+    /// Same as `send_struct`, but without waiting for the other party to confirm
+    /// that the struct arrived
+    async fn send_struct_unacked<C>(&mut self, content: C) -> Result<(), Error>
+    where
+        C: std::marker::Send + std::marker::Sync + Serialize,
+    {
+        let errctx = error_context(format!(
+            "could not send struct of type {}",
+            std::any::type_name::<C>()
+        ));
+        self.send_ok().await.map_err(inerr(errctx("send ok")))?;
+        self.send_block(bincode::serialize(&content).map_err(inerr("serialize struct"))?)
+            .await
+            .map_err(inerr("send serialized struct"))
+    }
+
     pub async fn send_file_from(&mut self, path: &AbstPath) -> Result<(), Error> {
+        let errctx = error_context(format!("could not send file at path {path}"));
+        let offer: ResumeOffer = self
+            .get_struct()
+            .await
+            .map_err(inerr(errctx("get resume offer")))?;
+        self.send_file_with_offer(path, offer).await
+    }
+
+    /// Send the file at path, once the resume offer of the receiver is known
+    async fn send_file_with_offer(
+        &mut self,
+        path: &AbstPath,
+        offer: ResumeOffer,
+    ) -> Result<(), Error> {
         let errctx = error_context(format!("could not send file at path {path}"));
         let mut file = fs::async_read_file(path)
             .await
             .map_err(inerr(errctx("async read the file")))?;
-
-        self.send_ok().await?;
-
         let len = file
             .metadata()
             .await
             .map_err(inerr(errctx("read file metadata to retrieve file length")))?
             .len();
+
+        // Resume from the offset proposed by the receiver only if the content it
+        //	already has matches the beginning of the file
+        let start = if offer.offset > 0 && offer.offset <= len {
+            let prefix = fs::read_file(path)
+                .map_err(inerr(errctx("read the file to check resume offer")))?;
+            let prefix_hash = hasher::hash_stream(std::io::Read::take(prefix, offer.offset))
+                .map_err(inerr(errctx("hash the beginning of the file")))?;
+            match prefix_hash == offer.prefix_hash {
+                true => offer.offset,
+                false => 0,
+            }
+        } else {
+            0
+        };
+        file.seek(std::io::SeekFrom::Start(start))
+            .await
+            .map_err(inerr(errctx("seek resume offset")))?;
+
+        self.send_ok().await?;
+
+        // The receiver has an older version of the file, send only what changed
+        if let (0, Some(signature)) = (start, &offer.signature) {
+            self.tx
+                .write_u64(0)
+                .await
+                .map_err(inerr(errctx("send resume offset")))?;
+            self.tx
+                .write_u64(len)
+                .await
+                .map_err(inerr(errctx("send length of file")))?;
+            self.send_delta(path, signature)
+                .await
+                .map_err(inerr(errctx("send delta of file content")))?;
+            self.files_sent += 1;
+            return Ok(());
+        }
+
+        self.tx
+            .write_u64(start)
+            .await
+            .map_err(inerr(errctx("send resume offset")))?;
         self.tx
-            .write_u64(len)
+            .write_u64(len - start)
             .await
             .map_err(inerr(errctx("send length of file")))?;
 
-        if self.progress {
-            let name = match path.file_name() {
-                Some(val) => val,
-                None => String::from("[invalid filename]"),
-            };
-            let mut pw = ProgressWriter::new(&mut self.tx, len, &name);
-            tokio::io::copy(&mut file, &mut pw)
-                .await
-                .map_err(inerr(errctx("copy file content into progress writer")))?;
+        let chunk_size = self.chunk_size;
+        if let Some(reporter) = &self.reporter {
+            let mut pw = ProgressWriter::new(&mut self.tx, len - start, path, reporter.clone());
+            match self.compress {
+                true => {
+                    compress::copy_compressed(&mut file, &mut pw, &mut self.limiter, chunk_size)
+                        .await
+                }
+                false => throttle::copy(&mut file, &mut pw, &mut self.limiter, chunk_size).await,
+            }
+            .map_err(inerr(errctx("copy file content into progress writer")))?;
 
             pw.finish().await;
         } else {
-            tokio::io::copy(&mut file, &mut self.tx)
-                .await
-                .map_err(inerr(errctx("copy file content into tx")))?;
+            match self.compress {
+                true => {
+                    compress::copy_compressed(
+                        &mut file,
+                        &mut self.tx,
+                        &mut self.limiter,
+                        chunk_size,
+                    )
+                    .await
+                }
+                false => {
+                    throttle::copy(&mut file, &mut self.tx, &mut self.limiter, chunk_size).await
+                }
+            }
+            .map_err(inerr(errctx("copy file content into tx")))?;
         }
 
+        self.files_sent += 1;
         Ok(())
     }
 
+    /// Get the queried path without the prefix, failing and telling the other
+    /// party as well if it is not among the queryable ones
+    async fn check_queryable(
+        &mut self,
+        queryable: &[AbstPath],
+        rel_path: &AbstPath,
+    ) -> Result<AbstPath, Error> {
+        let errmsg = String::from("could not supply files and symlinks");
+        let errctx = error_context(errmsg.clone());
+        let unprefixed = rel_path.relative_to(&self.prefix);
+        if !queryable.iter().any(|qp| Some(qp) == unprefixed.as_ref()) {
+            self.send_error(1, "quered file at path not allowed")
+                .await
+                .map_err(inerr(errctx(format!(
+                    "propagate not allowed path at {rel_path}"
+                ))))?;
+            return Err(generr(
+                errmsg,
+                format!("other party tried to query a non queryable path at {rel_path}"),
+            ));
+        }
+        Ok(unprefixed.unwrap_or_else(AbstPath::empty))
+    }
+
     pub async fn supply_files(
         &mut self,
         queryable: &[AbstPath],
@@ -132,19 +265,7 @@ impl BbupCom {
                 .map_err(inerr(errctx("get query".to_string())))?;
             match query {
                 Query::Object(qb, rel_path) => {
-                    if !queryable.iter().any(|qp| qp.eq(&rel_path)) {
-                        self.send_error(1, "quered file at path not allowed")
-                            .await
-                            .map_err(inerr(errctx(format!(
-                                "propagate not allowed path at {rel_path}"
-                            ))))?;
-                        return Err(generr(
-                            errmsg,
-                            format!(
-                                "other party tried to query a non queryable path at {rel_path}"
-                            ),
-                        ));
-                    }
+                    let rel_path = self.check_queryable(queryable, &rel_path).await?;
 
                     match qb {
                         Queryable::File => {
@@ -168,6 +289,40 @@ impl BbupCom {
                         }
                     }
                 }
+                Query::Batch(batch) => {
+                    let mut rel_paths = Vec::new();
+                    for query in &batch {
+                        let (BatchQuery::File(rel_path, _) | BatchQuery::SymLink(rel_path)) = query;
+                        rel_paths.push(self.check_queryable(queryable, rel_path).await?);
+                    }
+
+                    // The objects are sent back to back, without waiting for the
+                    //	other party in between
+                    for (query, rel_path) in batch.into_iter().zip(rel_paths) {
+                        match query {
+                            BatchQuery::File(_, offer) => {
+                                let path = source.append(&rel_path);
+                                self.send_file_with_offer(&path, offer)
+                                    .await
+                                    .map_err(inerr(errctx(format!(
+                                        "send quered file at path {path}"
+                                    ))))?;
+                            }
+                            BatchQuery::SymLink(_) => {
+                                let path = source.append(&rel_path);
+                                let symlink_endpoint =
+                                    fs::read_link(&path).map_err(inerr(errctx(format!(
+                                        "get endpoint of quered symlink at path {path}"
+                                    ))))?;
+                                self.send_struct_unacked(symlink_endpoint)
+                                    .await
+                                    .map_err(inerr(errctx(format!(
+                                        "send endpoint of quered symlink at path {path}"
+                                    ))))?;
+                            }
+                        }
+                    }
+                }
                 Query::Stop => break,
             }
         }
@@ -0,0 +1,199 @@
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{Duration, Instant};
+
+/// Size of the chunks in which file content is copied when throttled
+const CHUNK_SIZE: u64 = 8 * 1024;
+
+/// Bounds of the chunk size that can be set for file transfers
+const MIN_CHUNK_SIZE: usize = 512;
+const MAX_CHUNK_SIZE: usize = 64 * 1024 * 1024;
+
+/// Parse an amount of bytes, optionally followed by a K, M or G suffix
+fn parse_bytes(value: &str, what: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, multiplier) = match value.chars().last() {
+        Some('k') | Some('K') => (&value[..value.len() - 1], 1024),
+        Some('m') | Some('M') => (&value[..value.len() - 1], 1024 * 1024),
+        Some('g') | Some('G') => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+    let number = number
+        .parse::<u64>()
+        .map_err(|err| format!("invalid {what} '{value}': {err}"))?;
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("invalid {what} '{value}': value too big"))
+}
+
+/// Parse a rate in bytes per second, optionally followed by a K, M or G suffix
+/// (as in `500K` or `2M`). A rate of 0 means unlimited
+pub fn parse_rate(rate: &str) -> Result<u64, String> {
+    parse_bytes(rate, "rate")
+}
+
+/// Parse a size in bytes, optionally followed by a K, M or G suffix (as in `64K`)
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    parse_bytes(size, "size")
+}
+
+/// Parse the size of the chunks in which file content is transferred, optionally
+/// followed by a K, M or G suffix (as in `256K` or `4M`). The size must be between
+/// 512 bytes and 64M
+pub fn parse_chunk_size(size: &str) -> Result<usize, String> {
+    let bytes = parse_bytes(size, "chunk size")?;
+    match usize::try_from(bytes) {
+        Ok(bytes) if (MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&bytes) => Ok(bytes),
+        _ => Err(format!(
+            "invalid chunk size '{}': must be between {MIN_CHUNK_SIZE} and {MAX_CHUNK_SIZE} bytes",
+            size.trim()
+        )),
+    }
+}
+
+/// Token bucket limiting the number of bytes per second transferred through a
+/// connection
+pub struct RateLimiter {
+    rate: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+impl RateLimiter {
+    /// Create a rate limiter of `rate` bytes per second, or None if the rate is 0
+    /// (which means unlimited)
+    pub fn new(rate: u64) -> Option<RateLimiter> {
+        match rate {
+            0 => None,
+            rate => Some(RateLimiter {
+                rate,
+                tokens: 0.,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    fn chunk_size(&self, chunk_size: Option<usize>) -> usize {
+        match chunk_size {
+            Some(size) => (self.rate as usize).min(size),
+            None => self.rate.min(CHUNK_SIZE) as usize,
+        }
+    }
+
+    /// Consume `bytes` tokens, waiting until enough tokens are available
+    pub(super) async fn consume(&mut self, bytes: usize) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        // Allow bursts of at most one second worth of transfer
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.last_refill = now;
+
+        self.tokens -= bytes as f64;
+        if self.tokens < 0. {
+            let wait = -self.tokens / self.rate as f64;
+            tokio::time::sleep(Duration::from_secs_f64(wait)).await;
+        }
+    }
+}
+
+/// Copy the whole content of reader into writer in chunks of `chunk_size` bytes
+/// (or of a default size if None), throttling the transfer if a rate limiter is
+/// given
+pub async fn copy<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    limiter: &mut Option<RateLimiter>,
+    chunk_size: Option<usize>,
+) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let size = match (&limiter, chunk_size) {
+        (None, None) => return tokio::io::copy(reader, writer).await,
+        (None, Some(size)) => size,
+        (Some(limiter), chunk_size) => limiter.chunk_size(chunk_size),
+    };
+    let mut buffer = vec![0u8; size];
+    let mut total = 0u64;
+    loop {
+        let bytes = reader.read(&mut buffer).await?;
+        if bytes == 0 {
+            break;
+        }
+        if let Some(limiter) = limiter {
+            limiter.consume(bytes).await;
+        }
+        writer.write_all(&buffer[..bytes]).await?;
+        total += bytes as u64;
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{copy, parse_chunk_size, parse_rate, RateLimiter};
+    use tokio::time::{Duration, Instant};
+
+    #[test]
+    fn test() {
+        rates();
+        limiter();
+    }
+
+    fn rates() {
+        assert_eq!(parse_rate("0"), Ok(0));
+        assert_eq!(parse_rate("1500"), Ok(1500));
+        assert_eq!(parse_rate(" 500K "), Ok(500 * 1024));
+        assert_eq!(parse_rate("500k"), Ok(500 * 1024));
+        assert_eq!(parse_rate("2M"), Ok(2 * 1024 * 1024));
+        assert_eq!(parse_rate("1g"), Ok(1024 * 1024 * 1024));
+        for invalid in [
+            "",
+            "K",
+            "abc",
+            "12KB",
+            "1.5M",
+            "-1",
+            "99999999999999999999G",
+        ] {
+            assert!(parse_rate(invalid).is_err(), "'{invalid}' is a valid rate");
+        }
+        // Values overflowing once multiplied are rejected too
+        assert_eq!(parse_rate("16777215K"), Ok(16777215 * 1024));
+        assert!(parse_rate("17179869184G").is_err());
+
+        assert_eq!(parse_chunk_size("64K"), Ok(64 * 1024));
+        assert!(parse_chunk_size("511").is_err());
+        assert!(parse_chunk_size("65M").is_err());
+    }
+
+    fn limiter() {
+        assert!(RateLimiter::new(0).is_none());
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            // The bucket starts empty, so 3000 bytes at 2000 bytes per second
+            // take at least a second and a half
+            let content = vec![7u8; 3000];
+            let mut reader: &[u8] = &content;
+            let mut writer: Vec<u8> = Vec::new();
+            let mut limiter = RateLimiter::new(2000);
+            let start = Instant::now();
+            let copied = copy(&mut reader, &mut writer, &mut limiter, None)
+                .await
+                .unwrap();
+            assert!(start.elapsed() >= Duration::from_millis(1500));
+            assert_eq!(copied, 3000);
+            assert_eq!(writer, content);
+
+            // Without a limiter nothing waits
+            let mut reader: &[u8] = &content;
+            let mut writer: Vec<u8> = Vec::new();
+            let start = Instant::now();
+            copy(&mut reader, &mut writer, &mut None, Some(512))
+                .await
+                .unwrap();
+            assert!(start.elapsed() < Duration::from_millis(500));
+            assert_eq!(writer, content);
+        });
+    }
+}
@@ -0,0 +1,129 @@
+use std::{future::Future, pin::Pin, task::Poll, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    time::Sleep,
+};
+
+/// Reader failing with a `TimedOut` error if no data is received for longer than
+/// the timeout. The deadline is reset every time some data is received, so long
+/// transfers never time out as long as they keep progressing
+pub struct TimeoutReader<R: AsyncRead + Unpin> {
+    reader: R,
+    timeout: Option<Duration>,
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+impl<R: AsyncRead + Unpin> TimeoutReader<R> {
+    pub fn new(reader: R) -> TimeoutReader<R> {
+        TimeoutReader {
+            reader,
+            timeout: None,
+            deadline: None,
+        }
+    }
+
+    /// Reader wrapped by this one
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    /// Set the timeout of reads, or disable it if None
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+        self.deadline = None;
+    }
+}
+impl<R: AsyncRead + Unpin> AsyncRead for TimeoutReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        match Pin::new(&mut this.reader).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                this.deadline = None;
+                Poll::Ready(result)
+            }
+            Poll::Pending => {
+                let timeout = match this.timeout {
+                    Some(timeout) => timeout,
+                    None => return Poll::Pending,
+                };
+                let deadline = this
+                    .deadline
+                    .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+                if deadline.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                this.deadline = None;
+                Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("the other party sent no data for {timeout:?}"),
+                )))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TimeoutReader;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            stalled().await;
+            progressing().await;
+        });
+    }
+
+    async fn stalled() {
+        let (mut writer, reader) = tokio::io::duplex(64);
+        let mut reader = TimeoutReader::new(reader);
+        reader.set_timeout(Some(Duration::from_millis(200)));
+
+        writer.write_all(b"some").await.unwrap();
+        let mut buffer = [0u8; 8];
+        reader.read_exact(&mut buffer[..4]).await.unwrap();
+        // The peer is still connected but sends nothing more
+        let err = reader.read_exact(&mut buffer).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        drop(writer);
+    }
+
+    async fn progressing() {
+        let (mut writer, reader) = tokio::io::duplex(64);
+        let mut reader = TimeoutReader::new(reader);
+        reader.set_timeout(Some(Duration::from_millis(200)));
+
+        // Each byte arrives well within the timeout, while the whole transfer
+        // takes several times as long
+        let sender = tokio::spawn(async move {
+            for byte in 0..10u8 {
+                tokio::time::sleep(Duration::from_millis(80)).await;
+                writer.write_all(&[byte]).await.unwrap();
+            }
+        });
+        let mut buffer = [0u8; 10];
+        reader.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(buffer, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        sender.await.unwrap();
+
+        // Without a timeout, reads wait for as long as it takes
+        let (mut writer, reader) = tokio::io::duplex(64);
+        let mut reader = TimeoutReader::new(reader);
+        reader.set_timeout(None);
+        let sender = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            writer.write_all(b"late").await.unwrap();
+        });
+        let mut buffer = [0u8; 4];
+        reader.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"late");
+        sender.await.unwrap();
+    }
+}
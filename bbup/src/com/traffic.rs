@@ -0,0 +1,129 @@
+use std::pin::Pin;
+use std::task::Poll;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Amount of data exchanged with the other party during a conversation. Bytes
+/// are counted as they go through the socket, so they include the messages of
+/// the protocol and are counted after compression
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Traffic {
+    pub files_sent: u64,
+    pub files_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/// Reader or writer counting the bytes that go through it
+pub struct Counted<T> {
+    inner: T,
+    bytes: u64,
+}
+impl<T> Counted<T> {
+    pub fn new(inner: T) -> Counted<T> {
+        Counted { inner, bytes: 0 }
+    }
+
+    /// Number of bytes read or written so far
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+}
+impl<R: AsyncRead + Unpin> AsyncRead for Counted<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled_before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            self.bytes += (buf.filled().len() - filled_before) as u64;
+        }
+        result
+    }
+}
+impl<W: AsyncWrite + Unpin> AsyncWrite for Counted<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(bytes)) = result {
+            self.bytes += bytes as u64;
+        }
+        result
+    }
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::bbupcom::{loopback, transfer};
+    use abst_fs::AbstPath;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test() {
+        let path = PathBuf::from("/tmp/bbup-test-bbup-traffic");
+        assert!(!path.exists());
+        std::fs::create_dir(&path).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            let path = AbstPath::from(&path);
+            counters(&path);
+        });
+        std::fs::remove_dir_all(&path).unwrap();
+        assert!(result.is_ok())
+    }
+
+    fn counters(path: &AbstPath) {
+        let source = path.add_last("source");
+        let dest = path.add_last("dest");
+        std::fs::create_dir(source.to_path_buf()).unwrap();
+        std::fs::create_dir(dest.to_path_buf()).unwrap();
+        let first = vec![1u8; 30000];
+        let second = vec![2u8; 12345];
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (client, server) = runtime.block_on(async {
+            let (mut client, mut server) = loopback().await;
+            assert_eq!(client.traffic(), Default::default());
+            transfer(
+                &mut client,
+                &mut server,
+                &source,
+                &dest,
+                &[("first", &first), ("second", &second)],
+                1,
+            )
+            .await;
+            (client.traffic(), server.traffic())
+        });
+
+        assert_eq!(client.files_received, 2);
+        assert_eq!(client.files_sent, 0);
+        assert_eq!(server.files_sent, 2);
+        assert_eq!(server.files_received, 0);
+        // Every byte sent by a party is received by the other
+        assert_eq!(client.bytes_sent, server.bytes_received);
+        assert_eq!(server.bytes_sent, client.bytes_received);
+        // The content of the files goes one way, only queries go the other way
+        let content = (first.len() + second.len()) as u64;
+        assert!(server.bytes_sent > content);
+        assert!(server.bytes_sent < content + 1024);
+        assert!(client.bytes_sent < 1024);
+    }
+}
@@ -0,0 +1,131 @@
+use super::{
+    bbupcom::{error_context, inerr, Error},
+    BbupCom,
+};
+
+/// Version of the protocol spoken by this build. It must be increased whenever a
+/// change to the conversation is not understood by the previous version
+//	1: initial version
+//	2: JobType::BlockPush
+//	3: commit metadata sent with pushes
+//	4: Query::Batch, and batch size sent with pushes
+//	5: JobType::AddOnlyPush
+//	6: commit id of pushes confirmed by the client
+pub const PROTOCOL_VERSION: u32 = 6;
+
+/// Parties whose protocol versions differ by at most one can talk to each other:
+/// the newer one is responsible for speaking the protocol of the older one
+fn check_compatible(local: u32, remote: u32) -> Result<u32, Error> {
+    match local.abs_diff(remote) <= 1 {
+        true => Ok(local.min(remote)),
+        false => Err(Error::VersionMismatch { local, remote }),
+    }
+}
+
+impl BbupCom {
+    /// Send the protocol version of this party to the other party and wait for
+    /// its version. Fails with [`Error::VersionMismatch`] if the versions are not
+    /// compatible, otherwise the conversation continues with the older of the two
+    pub async fn request_version(&mut self) -> Result<(), Error> {
+        self.request_version_as(PROTOCOL_VERSION).await
+    }
+    async fn request_version_as(&mut self, local: u32) -> Result<(), Error> {
+        let errctx = error_context("could not negotiate protocol version");
+        self.send_struct(local)
+            .await
+            .map_err(inerr(errctx("send protocol version")))?;
+        let remote: u32 = self
+            .get_struct()
+            .await
+            .map_err(inerr(errctx("get protocol version of the other party")))?;
+        self.protocol_version = check_compatible(local, remote)?;
+        Ok(())
+    }
+
+    /// Answer the protocol version request of the other party with the version of
+    /// this party. Fails with [`Error::VersionMismatch`] if the versions are not
+    /// compatible, otherwise the conversation continues with the older of the two
+    pub async fn answer_version(&mut self) -> Result<(), Error> {
+        self.answer_version_as(PROTOCOL_VERSION).await
+    }
+    async fn answer_version_as(&mut self, local: u32) -> Result<(), Error> {
+        let errctx = error_context("could not negotiate protocol version");
+        let remote: u32 = self
+            .get_struct()
+            .await
+            .map_err(inerr(errctx("get protocol version of the other party")))?;
+        self.send_struct(local)
+            .await
+            .map_err(inerr(errctx("send protocol version")))?;
+        self.protocol_version = check_compatible(local, remote)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::bbupcom::loopback, Error, PROTOCOL_VERSION};
+
+    #[test]
+    fn test() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            same_version().await;
+            skew().await;
+            mismatch().await;
+        });
+    }
+
+    /// Negotiate the protocol version between a client speaking version `client`
+    /// and a server speaking version `server`
+    async fn negotiate(
+        client: u32,
+        server: u32,
+    ) -> ((Result<(), Error>, u32), (Result<(), Error>, u32)) {
+        let (mut client_com, mut server_com) = loopback().await;
+        let (requested, answered) = tokio::join!(
+            client_com.request_version_as(client),
+            server_com.answer_version_as(server)
+        );
+        (
+            (requested, client_com.protocol_version),
+            (answered, server_com.protocol_version),
+        )
+    }
+
+    async fn same_version() {
+        let (mut client, mut server) = loopback().await;
+        let (requested, answered) = tokio::join!(client.request_version(), server.answer_version());
+        requested.unwrap();
+        answered.unwrap();
+        assert_eq!(client.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(server.protocol_version, PROTOCOL_VERSION);
+    }
+
+    async fn skew() {
+        // Whichever side is newer, both speak the older version
+        for (client, server) in [(6, 5), (5, 6)] {
+            let ((requested, client_version), (answered, server_version)) =
+                negotiate(client, server).await;
+            requested.unwrap();
+            answered.unwrap();
+            assert_eq!(client_version, 5);
+            assert_eq!(server_version, 5);
+        }
+    }
+
+    async fn mismatch() {
+        let ((requested, _), (answered, _)) = negotiate(6, 4).await;
+        for (result, local, remote) in [(requested, 6, 4), (answered, 4, 6)] {
+            let err = result.unwrap_err();
+            assert!(matches!(
+                err,
+                Error::VersionMismatch { local: l, remote: r } if l == local && r == remote
+            ));
+            // The message names both versions
+            let message = err.to_string();
+            assert!(message.contains(&format!("version {local}")));
+            assert!(message.contains(&format!("version {remote}")));
+        }
+    }
+}
@@ -1 +1,2 @@
 pub mod com;
+pub mod logger;
@@ -0,0 +1,207 @@
+use abst_fs::AbstPath;
+
+use anyhow::{Context, Result};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use std::fs::File;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Environment variable selecting the log level (error, warn, info, debug,
+/// trace or off), with precedence over `--verbose`
+pub const LEVEL_VAR: &str = "BBUP_LOG";
+
+/// Fields attached to every line of the log file
+struct LogContext {
+    endpoint: Option<String>,
+    pid: Option<u32>,
+}
+static CONTEXT: Mutex<LogContext> = Mutex::new(LogContext {
+    endpoint: None,
+    pid: None,
+});
+
+/// Set the endpoint reported in the log file
+pub fn set_endpoint(endpoint: &AbstPath) {
+    if let Ok(mut context) = CONTEXT.lock() {
+        context.endpoint = Some(endpoint.to_string());
+    }
+}
+
+/// Set the PID of the process handling the connection reported in the log file
+/// (the ssh tunnel on the client). Defaults to the PID of the current process
+pub fn set_connection_pid(pid: u32) {
+    if let Ok(mut context) = CONTEXT.lock() {
+        context.pid = Some(pid);
+    }
+}
+
+/// Logger printing the messages as they are, info and lower levels to stdout and
+/// warnings and errors to stderr. If a log file is given, each message is also
+/// appended to it as a structured line with timestamp, level, endpoint and PID
+pub struct Logger {
+    level: LevelFilter,
+    file: Option<Mutex<File>>,
+}
+impl Logger {
+    pub fn new(level: LevelFilter, file: Option<File>) -> Logger {
+        Logger {
+            level,
+            file: file.map(Mutex::new),
+        }
+    }
+
+    fn write_line(&self, record: &Record) {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return,
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let (endpoint, pid) = match CONTEXT.lock() {
+            Ok(context) => (context.endpoint.clone(), context.pid),
+            Err(_) => (None, None),
+        };
+        let line = format!(
+            "ts={:.3} level={} endpoint={} pid={} msg={:?}\n",
+            timestamp,
+            record.level().as_str().to_lowercase(),
+            endpoint.as_deref().unwrap_or("-"),
+            pid.unwrap_or_else(std::process::id),
+            record.args().to_string()
+        );
+        // Logging must never make the operation fail
+        if let Ok(mut file) = file.lock() {
+            let _ = file.write_all(line.as_bytes());
+        }
+    }
+}
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match record.level() {
+            Level::Error => eprintln!("error: {}", record.args()),
+            Level::Warn => eprintln!("warning: {}", record.args()),
+            _ => println!("{}", record.args()),
+        }
+        self.write_line(record);
+    }
+
+    fn flush(&self) {
+        if let Some(Ok(mut file)) = self.file.as_ref().map(|file| file.lock()) {
+            let _ = file.flush();
+        }
+    }
+}
+
+/// Level of the log: the one in `BBUP_LOG` if set, otherwise info if `verbose`
+/// and warn if not
+pub fn level_from_env(verbose: bool) -> Result<LevelFilter> {
+    match std::env::var(LEVEL_VAR) {
+        Ok(val) => LevelFilter::from_str(&val)
+            .with_context(|| format!("invalid log level in {LEVEL_VAR}: {val}")),
+        Err(_) => Ok(match verbose {
+            true => LevelFilter::Info,
+            false => LevelFilter::Warn,
+        }),
+    }
+}
+
+/// Install the logger, at the level given by `level_from_env`, appending to
+/// `log_file` if given
+pub fn init(verbose: bool, log_file: Option<&AbstPath>) -> Result<()> {
+    let level = level_from_env(verbose)?;
+    let file = match log_file {
+        Some(path) => Some(
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path.to_path_buf())
+                .with_context(|| format!("could not open log file\npath: {path}"))?,
+        ),
+        None => None,
+    };
+    log::set_boxed_logger(Box::new(Logger::new(level, file)))
+        .context("could not install logger")?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{set_connection_pid, set_endpoint, Logger};
+    use abst_fs::AbstPath;
+    use log::{Level, LevelFilter, Log, Record};
+    use std::path::PathBuf;
+
+    fn emit(logger: &Logger) {
+        logger.log(
+            &Record::builder()
+                .level(Level::Info)
+                .args(format_args!("pulling from server..."))
+                .build(),
+        );
+        logger.log(
+            &Record::builder()
+                .level(Level::Debug)
+                .args(format_args!("apply action: add file\npath: a/b"))
+                .build(),
+        );
+        logger.flush();
+    }
+
+    #[test]
+    fn test() {
+        let path_bf = PathBuf::from("/tmp/bbup-test-bbup-logger");
+        assert!(!path_bf.exists());
+        std::fs::create_dir(&path_bf).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            set_endpoint(&AbstPath::from("docs/notes"));
+            set_connection_pid(42);
+
+            let quiet_path = path_bf.join("quiet.log");
+            let quiet = Logger::new(
+                LevelFilter::Info,
+                Some(std::fs::File::create(&quiet_path).unwrap()),
+            );
+            emit(&quiet);
+            let quiet_log = std::fs::read_to_string(&quiet_path).unwrap();
+            assert_eq!(quiet_log.lines().count(), 1);
+            assert!(quiet_log.contains("level=info"));
+            assert!(quiet_log.contains("endpoint=docs/notes pid=42"));
+            assert!(quiet_log.contains("msg=\"pulling from server...\""));
+            assert!(!quiet_log.contains("apply action"));
+
+            let debug_path = path_bf.join("debug.log");
+            let debug = Logger::new(
+                LevelFilter::Debug,
+                Some(std::fs::File::create(&debug_path).unwrap()),
+            );
+            emit(&debug);
+            let debug_log = std::fs::read_to_string(&debug_path).unwrap();
+            let lines: Vec<&str> = debug_log.lines().collect();
+            assert_eq!(lines.len(), 2);
+            assert!(lines[1].contains("level=debug"));
+            // Multi-line messages are kept on a single line
+            assert!(lines[1].contains("msg=\"apply action: add file\\npath: a/b\""));
+
+            // Without a log file nothing is written, and nothing fails
+            emit(&Logger::new(LevelFilter::Debug, None));
+        });
+
+        std::fs::remove_dir_all(&path_bf).unwrap();
+
+        assert!(result.is_ok())
+    }
+}
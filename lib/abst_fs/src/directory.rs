@@ -103,6 +103,30 @@ pub fn remove_dir_all(path: &AbstPath) -> Result<(), Error> {
     std::fs::remove_dir_all(path.to_path_buf()).map_err(inerr(errctx("remove directory")))
 }
 
+/// Attempts to move a directory (together with its content) from a specified position
+/// to a specified position, creating the necessary subdirectories if needed
+pub fn rename_dir(from: &AbstPath, to: &AbstPath) -> Result<(), Error> {
+    let errmsg = format!("could not move directory from path {from}, to path {to}");
+    let errctx = error_context(errmsg.clone());
+    match from.object_type() {
+        Some(ObjectType::Dir) => { /* ... */ }
+        None => {
+            return Err(wrgobj(
+                errmsg + "\nDirectory doesn't exist",
+                "object doesn't exist",
+            ));
+        }
+        _ => {
+            return Err(wrgobj(
+                errmsg + "\nPath is not a dir",
+                "object is not a directory",
+            ));
+        }
+    }
+    ensure_parent(to).map_err(inerr(errctx("ensure parent directory")))?;
+    std::fs::rename(from.to_path_buf(), to.to_path_buf()).map_err(inerr(errctx("rename directory")))
+}
+
 /// Ensures that at path there exists an empty directory.
 /// It creates the directory if it doesn't exists and it removes all the content
 /// if it does
@@ -128,7 +152,7 @@ pub fn make_clean_dir(path: &AbstPath) -> Result<(), Error> {
 mod tests {
     use super::{
         create_dir, ensure_parent, list_dir_content, make_clean_dir, remove_dir, remove_dir_all,
-        AbstPath, ObjectType,
+        rename_dir, AbstPath, ObjectType,
     };
     use std::path::PathBuf;
 
@@ -236,6 +260,21 @@ mod tests {
             std::fs::File::create(file.to_path_buf()).unwrap();
             assert!(remove_dir(&file).is_err());
 
+            // rename_dir
+            let (moved, _) = dir.safe_add_last("moved").safe_add_last("dir");
+            create_dir(&parent).unwrap();
+            std::fs::File::create(parent.add_last("file.txt").to_path_buf()).unwrap();
+            rename_dir(&parent, &moved).unwrap();
+            assert!(!parent.exists());
+            assert_eq!(moved.object_type(), Some(ObjectType::Dir));
+            assert_eq!(
+                moved.add_last("file.txt").object_type(),
+                Some(ObjectType::File)
+            );
+            assert!(rename_dir(&parent, &moved).is_err());
+            assert!(rename_dir(&file, &parent).is_err());
+            remove_dir_all(&moved).unwrap();
+
             // remove_dir_all
             assert!(remove_dir_all(&file).is_err());
             assert!(dir.0.exists());
@@ -0,0 +1,82 @@
+use super::{error_context, inerr, AbstPath, Error};
+
+/// Whether a write has to reach the disk before returning. `Synced` writes
+/// survive a power loss as soon as they return, at the cost of waiting for the
+/// disk, so they are meant for the data that a commit relies on. `Unsynced`
+/// writes are left to the caches of the operating system
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Durability {
+    #[default]
+    Synced,
+    Unsynced,
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Number of syncs performed by the current thread, to check which writes
+    ///	wait for the disk
+    pub static SYNCS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Flush the content and metadata of the file at path to the disk
+pub fn sync_file(path: &AbstPath) -> Result<(), Error> {
+    let errctx = error_context(format!("could not sync file at path {path}"));
+    std::fs::File::open(path.to_path_buf())
+        .map_err(inerr(errctx("open file")))?
+        .sync_all()
+        .map_err(inerr(errctx("sync file to disk")))?;
+    #[cfg(test)]
+    SYNCS.with(|syncs| syncs.set(syncs.get() + 1));
+    Ok(())
+}
+
+/// Flush the entries of the directory at path to the disk, so that the objects
+/// created, renamed or removed in it persist. The empty path is the current
+/// directory, as the parent of a relative path with a single component
+pub fn sync_dir(path: &AbstPath) -> Result<(), Error> {
+    let errctx = error_context(format!("could not sync directory at path {path}"));
+    let dir = match path.is_empty() {
+        true => std::path::PathBuf::from("."),
+        false => path.to_path_buf(),
+    };
+    std::fs::File::open(dir)
+        .map_err(inerr(errctx("open directory")))?
+        .sync_all()
+        .map_err(inerr(errctx("sync directory to disk")))?;
+    #[cfg(test)]
+    SYNCS.with(|syncs| syncs.set(syncs.get() + 1));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sync_dir, sync_file, AbstPath, SYNCS};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test() {
+        let path_bf = PathBuf::from("/tmp/bbup-test-abst_fs-durability");
+        let path = AbstPath::from(&path_bf);
+        assert!(!path_bf.exists());
+        std::fs::create_dir(&path_bf).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            let file = path.add_last("file");
+            std::fs::write(file.to_path_buf(), "content").unwrap();
+
+            let before = SYNCS.with(|syncs| syncs.get());
+            sync_file(&file).unwrap();
+            sync_dir(&path).unwrap();
+            assert_eq!(SYNCS.with(|syncs| syncs.get()), before + 2);
+
+            // Missing objects can't be synced
+            assert!(sync_file(&path.add_last("missing")).is_err());
+            assert!(sync_dir(&path.add_last("missing")).is_err());
+            assert_eq!(SYNCS.with(|syncs| syncs.get()), before + 2);
+        });
+
+        std::fs::remove_dir_all(&path_bf).unwrap();
+
+        assert!(result.is_ok())
+    }
+}
@@ -1,5 +1,7 @@
 use super::{generr, inerr, AbstPath, Error};
 
+use std::str::FromStr;
+
 pub fn home_dir() -> Result<AbstPath, Error> {
     let home_dir = dirs::home_dir().ok_or_else(|| {
         generr(
@@ -15,9 +17,27 @@ pub fn cwd() -> Result<AbstPath, Error> {
     ))?))
 }
 
+/// Get the value of the environment variable `var` parsed as T, or None if the
+/// variable is not set. Fails if the variable is set but can't be parsed
+pub fn env_var<T>(var: &str) -> Result<Option<T>, Error>
+where
+    T: FromStr,
+    T::Err: std::string::ToString,
+{
+    match std::env::var(var) {
+        Ok(value) => value.parse().map(Some).map_err(|err: T::Err| {
+            generr(format!("could not parse environment variable {var}"), err)
+        }),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(err) => Err(inerr(format!("could not read environment variable {var}"))(
+            err,
+        )),
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{cwd, home_dir};
+    use super::{cwd, env_var, home_dir};
 
     #[test]
     fn test() {
@@ -27,5 +47,22 @@ mod tests {
         // dirs: https://docs.rs/dirs/latest/dirs/
         println!("home directory:            {}", home_dir().unwrap());
         println!("current working directory: {}", cwd().unwrap());
+
+        env_vars();
+    }
+
+    fn env_vars() {
+        let var = "BBUP_TEST_ABST_FS_ENV_VAR";
+        assert_eq!(env_var::<u16>(var), Ok(None));
+
+        std::env::set_var(var, "8080");
+        assert_eq!(env_var::<u16>(var), Ok(Some(8080)));
+        assert_eq!(env_var::<String>(var), Ok(Some("8080".to_string())));
+
+        std::env::set_var(var, "not a port");
+        assert!(env_var::<u16>(var).is_err());
+
+        std::env::remove_var(var);
+        assert_eq!(env_var::<u16>(var), Ok(None));
     }
 }
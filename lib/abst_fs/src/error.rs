@@ -11,12 +11,35 @@ pub enum Error {
     OperationOnWrongObject { src: String, err: String },
 
     #[error("Abstract File System Error: inner error occurred.\nSource: {src}\n{err}")]
-    Inner { src: String, err: String },
+    Inner {
+        src: String,
+        err: String,
+        kind: Option<std::io::ErrorKind>,
+    },
 
     #[error("Abstract File System Error: some error occurred.\nSource: {src}\nError: {err}")]
     Generic { src: String, err: String },
 }
 
+impl Error {
+    /// Kind of the IO error that ultimately caused this error, if any
+    pub fn kind(&self) -> Option<std::io::ErrorKind> {
+        match self {
+            Error::Inner { kind, .. } => *kind,
+            _ => None,
+        }
+    }
+
+    /// Whether the error is caused by a momentary condition, so that the failed
+    /// operation might succeed if tried again
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self.kind(),
+            Some(std::io::ErrorKind::Interrupted | std::io::ErrorKind::WouldBlock)
+        )
+    }
+}
+
 pub fn unkext(path: &AbstPath) -> Error {
     Error::UnknownExtension {
         path: path.to_string(),
@@ -28,11 +51,24 @@ pub fn wrgobj<S: std::string::ToString, E: std::string::ToString>(src: S, err: E
         err: err.to_string(),
     }
 }
-pub fn inerr<S: std::string::ToString, E: std::error::Error>(src: S) -> impl Fn(E) -> Error {
+pub fn inerr<S: std::string::ToString, E: std::error::Error + 'static>(
+    src: S,
+) -> impl Fn(E) -> Error {
     move |err: E| -> Error {
+        // Keep track of the kind of the underlying IO error, if any
+        let any = &err as &dyn std::any::Any;
+        let kind = match (
+            any.downcast_ref::<std::io::Error>(),
+            any.downcast_ref::<Error>(),
+        ) {
+            (Some(err), _) => Some(err.kind()),
+            (None, Some(err)) => err.kind(),
+            (None, None) => None,
+        };
         Error::Inner {
             src: src.to_string(),
             err: err.to_string(),
+            kind,
         }
     }
 }
@@ -68,10 +104,22 @@ mod tests {
             inerr("source")(wrgobj_error.clone()),
             Error::Inner {
                 src: String::from("source"),
-                err: wrgobj_error.to_string()
+                err: wrgobj_error.to_string(),
+                kind: None,
             }
         );
 
+        // The kind of IO errors is kept through nested errors
+        let io_error = std::io::Error::from(std::io::ErrorKind::Interrupted);
+        let inner_error = inerr("inner source")(io_error);
+        assert_eq!(inner_error.kind(), Some(std::io::ErrorKind::Interrupted));
+        let outer_error = inerr("outer source")(inner_error);
+        assert_eq!(outer_error.kind(), Some(std::io::ErrorKind::Interrupted));
+        assert!(outer_error.is_transient());
+        let io_error = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        assert!(!inerr("source")(io_error).is_transient());
+        assert!(!wrgobj("source", "error").is_transient());
+
         assert_eq!(
             generr("source", "error"),
             Error::Generic {
@@ -1,4 +1,7 @@
-use super::{ensure_parent, error_context, inerr, wrgobj, AbstPath, Error, ObjectType};
+use super::{
+    ensure_parent, error_context, generr, inerr, sync_file, wrgobj, AbstPath, Durability, Error,
+    ObjectType,
+};
 
 /// Create a file (creating subpaths recursively if needed) and open it in write-only
 /// mode
@@ -17,6 +20,201 @@ pub async fn async_create_file(path: &AbstPath) -> Result<tokio::fs::File, Error
         .map_err(inerr(errctx("create file")))
 }
 
+/// Path of the temporary sibling used to atomically write the file at path
+fn atomic_temp_path(path: &AbstPath) -> Result<AbstPath, Error> {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => Ok(parent.add_last(format!(".{name}.bbup-tmp"))),
+        _ => Err(generr(
+            format!("could not get temporary path for file at path {path}"),
+            "path has no parent or no file name",
+        )),
+    }
+}
+
+/// Directory where the content to be moved under root is staged. If configured,
+/// it's the given path (relative to root, unless absolute), otherwise it's the
+/// hidden `.bbup/temp` directory inside root, which is on the same file system
+/// as the destinations so that moving the content in place is a plain rename
+pub fn resolve_temp_dir(root: &AbstPath, configured: Option<&AbstPath>) -> AbstPath {
+    match configured {
+        Some(path) if path.to_path_buf().is_absolute() => path.clone(),
+        Some(path) => root.append(path),
+        None => root.add_last(".bbup").add_last("temp"),
+    }
+}
+
+/// Move the file at from to path to, falling back to copying it and removing the
+/// original if the two paths are on different file systems. The copy is written
+/// to a temporary sibling of the destination and then moved in place, so that the
+/// destination is never left half written
+fn move_file(from: &AbstPath, to: &AbstPath) -> std::io::Result<()> {
+    match std::fs::rename(from.to_path_buf(), to.to_path_buf()) {
+        Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => copy_and_remove(from, to),
+        result => result,
+    }
+}
+fn copy_and_remove(from: &AbstPath, to: &AbstPath) -> std::io::Result<()> {
+    copy_in_place(from, to)?;
+    std::fs::remove_file(from.to_path_buf())
+}
+/// Copy the file through a temporary sibling of the destination, so that the
+/// destination never holds a partial copy
+fn copy_in_place(from: &AbstPath, to: &AbstPath) -> std::io::Result<()> {
+    let temp_path = atomic_temp_path(to).map_err(std::io::Error::other)?;
+    let copy = || -> std::io::Result<()> {
+        std::fs::copy(from.to_path_buf(), temp_path.to_path_buf())?;
+        std::fs::File::open(temp_path.to_path_buf())?.sync_all()?;
+        std::fs::rename(temp_path.to_path_buf(), to.to_path_buf())
+    };
+    if let Err(err) = copy() {
+        let _ = std::fs::remove_file(temp_path.to_path_buf());
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Handle to a file being written atomically. The content is written to a
+/// temporary sibling file, which replaces the actual file only when `persist` is
+/// called. If the handle is dropped before being persisted, the temporary file is
+/// removed and the actual file is left untouched
+pub struct AtomicFile {
+    file: std::fs::File,
+    temp_path: AbstPath,
+    path: AbstPath,
+    persisted: bool,
+}
+impl AtomicFile {
+    /// Flush the content written so far and move it in place of the actual file
+    pub fn persist(self) -> Result<(), Error> {
+        self.persist_with(Durability::Synced)
+    }
+    /// Same as `persist`, but syncing the content to the disk before moving it
+    /// in place only if asked by `durability`
+    pub fn persist_with(mut self, durability: Durability) -> Result<(), Error> {
+        use std::io::Write;
+
+        let errctx = error_context(format!("could not persist file at path {}", self.path));
+        self.file.flush().map_err(inerr(errctx("flush content")))?;
+        if durability == Durability::Synced {
+            sync_file(&self.temp_path).map_err(inerr(errctx("sync content to disk")))?;
+        }
+        std::fs::rename(self.temp_path.to_path_buf(), self.path.to_path_buf())
+            .map_err(inerr(errctx("move temporary file in place")))?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+impl std::io::Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = std::fs::remove_file(self.temp_path.to_path_buf());
+        }
+    }
+}
+
+/// Handle to a file being written atomically and asynchronously. Same as
+/// `AtomicFile`, but the content is written asynchronously
+pub struct AsyncAtomicFile {
+    file: tokio::fs::File,
+    temp_path: AbstPath,
+    path: AbstPath,
+    persisted: bool,
+}
+impl AsyncAtomicFile {
+    /// Flush the content written so far and move it in place of the actual file
+    pub async fn persist(mut self) -> Result<(), Error> {
+        use tokio::io::AsyncWriteExt;
+
+        let errctx = error_context(format!(
+            "could not async persist file at path {}",
+            self.path
+        ));
+        self.file
+            .flush()
+            .await
+            .map_err(inerr(errctx("flush content")))?;
+        self.file
+            .sync_all()
+            .await
+            .map_err(inerr(errctx("sync content to disk")))?;
+        tokio::fs::rename(self.temp_path.to_path_buf(), self.path.to_path_buf())
+            .await
+            .map_err(inerr(errctx("move temporary file in place")))?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+impl tokio::io::AsyncWrite for AsyncAtomicFile {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<Result<usize, std::io::Error>> {
+        std::pin::Pin::new(&mut self.get_mut().file).poll_write(cx, buf)
+    }
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), std::io::Error>> {
+        std::pin::Pin::new(&mut self.get_mut().file).poll_flush(cx)
+    }
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), std::io::Error>> {
+        std::pin::Pin::new(&mut self.get_mut().file).poll_shutdown(cx)
+    }
+}
+impl Drop for AsyncAtomicFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = std::fs::remove_file(self.temp_path.to_path_buf());
+        }
+    }
+}
+
+/// Create a temporary sibling of the file at path (creating subpaths recursively if
+/// needed) and open it in write-only mode. The content will be moved in place of the
+/// file at path only once the handle is persisted
+pub fn atomic_create_file(path: &AbstPath) -> Result<AtomicFile, Error> {
+    let errctx = error_context(format!("could not atomically create file at path {path}"));
+    let temp_path = atomic_temp_path(path)?;
+    let file = create_file(&temp_path).map_err(inerr(errctx("create temporary file")))?;
+    Ok(AtomicFile {
+        file,
+        temp_path,
+        path: path.clone(),
+        persisted: false,
+    })
+}
+/// Create a temporary sibling of the file at path (creating subpaths recursively if
+/// needed) and open it in write-only mode, giving an async handle for asynchronous
+/// writing. The content will be moved in place of the file at path only once the
+/// handle is persisted
+pub async fn async_atomic_create_file(path: &AbstPath) -> Result<AsyncAtomicFile, Error> {
+    let errctx = error_context(format!(
+        "could not async atomically create file at path {path}"
+    ));
+    let temp_path = atomic_temp_path(path)?;
+    let file = async_create_file(&temp_path)
+        .await
+        .map_err(inerr(errctx("create temporary file")))?;
+    Ok(AsyncAtomicFile {
+        file,
+        temp_path,
+        path: path.clone(),
+        persisted: false,
+    })
+}
+
 /// Attempts to open a file in read-only mode
 pub fn read_file(path: &AbstPath) -> Result<std::fs::File, Error> {
     let errmsg = format!("could not open file at path {path}");
@@ -38,6 +236,13 @@ pub fn read_file(path: &AbstPath) -> Result<std::fs::File, Error> {
     }
     std::fs::File::open(path.to_path_buf()).map_err(inerr(errctx("open file")))
 }
+/// Get the size in bytes of the content of a file
+pub fn get_file_size(path: &AbstPath) -> Result<u64, Error> {
+    let errctx = error_context(format!("could not get size of file at path {path}"));
+    let metadata =
+        std::fs::metadata(path.to_path_buf()).map_err(inerr(errctx("get metadata of file")))?;
+    Ok(metadata.len())
+}
 /// Attempts to open a file in read-only mode, giving an async handle to the content of
 /// the file for asynchronous reading
 pub async fn async_read_file(path: &AbstPath) -> Result<tokio::fs::File, Error> {
@@ -63,6 +268,33 @@ pub async fn async_read_file(path: &AbstPath) -> Result<tokio::fs::File, Error>
         .map_err(inerr(errctx("open file")))
 }
 
+/// Attempts to open an existing file in append mode, giving an async handle for
+/// asynchronous writing at the end of the file
+pub async fn async_append_file(path: &AbstPath) -> Result<tokio::fs::File, Error> {
+    let errmsg = format!("could not async open file in append mode at path {path}");
+    let errctx = error_context(errmsg.clone());
+    match path.object_type() {
+        Some(ObjectType::File) => { /* ... */ }
+        None => {
+            return Err(wrgobj(
+                errmsg + "\nFile doesn't exist",
+                "object doesn't exist",
+            ));
+        }
+        _ => {
+            return Err(wrgobj(
+                errmsg + "\nPath is not a file",
+                "object is not a file",
+            ));
+        }
+    }
+    tokio::fs::OpenOptions::new()
+        .append(true)
+        .open(path.to_path_buf())
+        .await
+        .map_err(inerr(errctx("open file")))
+}
+
 /// Attempts to remove a file. The inner process is the same as remove_symlink as they
 /// both invoke std::fs::remove_file, but it checks that the object at path is a file
 /// beforehand
@@ -87,9 +319,9 @@ pub fn remove_file(path: &AbstPath) -> Result<(), Error> {
     std::fs::remove_file(path.to_path_buf()).map_err(inerr(errctx("remove file")))
 }
 
-/// Attempts to move a file from a specified position to a specified position (does
-/// not copy, only attempts to move), creating the necessary subdirectories for the
-/// endpoint
+/// Attempts to move a file from a specified position to a specified position,
+/// creating the necessary subdirectories for the endpoint. If the two positions are
+/// on different file systems, the file is copied and the original is removed
 pub fn rename_file(from: &AbstPath, to: &AbstPath) -> Result<(), Error> {
     let errmsg = format!("could not move object from path {from}, to path {to}");
     let errctx = error_context(errmsg.clone());
@@ -109,14 +341,116 @@ pub fn rename_file(from: &AbstPath, to: &AbstPath) -> Result<(), Error> {
         }
     }
     ensure_parent(to).map_err(inerr(errctx("ensure parent directory")))?;
-    std::fs::rename(from.to_path_buf(), to.to_path_buf()).map_err(inerr(errctx("rename object")))
+    move_file(from, to).map_err(inerr(errctx("move object")))
+}
+
+/// Attempts to copy a file from a specified position to a specified position,
+/// creating the necessary subdirectories for the endpoint. Fails if an object
+/// already exists at the endpoint
+pub fn copy_file(from: &AbstPath, to: &AbstPath) -> Result<(), Error> {
+    let errmsg = format!("could not copy object from path {from}, to path {to}");
+    let errctx = error_context(errmsg.clone());
+    match from.object_type() {
+        Some(ObjectType::File) => { /* ... */ }
+        None => {
+            return Err(wrgobj(
+                errmsg + "\nFile doesn't exist",
+                "object doesn't exist",
+            ));
+        }
+        _ => {
+            return Err(wrgobj(
+                errmsg + "\nPath is not a file",
+                "object is not a file",
+            ));
+        }
+    }
+    if to.exists() {
+        return Err(wrgobj(
+            errmsg + "\nEndpoint already exists",
+            "object already exists",
+        ));
+    }
+    ensure_parent(to).map_err(inerr(errctx("ensure parent directory")))?;
+    copy_in_place(from, to).map_err(inerr(errctx("copy object")))
+}
+
+/// Attempts to create a hard link at path to the existing file at original,
+/// creating the necessary subdirectories for the link
+pub fn create_hardlink(original: &AbstPath, path: &AbstPath) -> Result<(), Error> {
+    let errmsg = format!("could not create hard link at path {path} to file at path {original}");
+    let errctx = error_context(errmsg.clone());
+    match original.object_type() {
+        Some(ObjectType::File) => { /* ... */ }
+        None => {
+            return Err(wrgobj(
+                errmsg + "\nFile doesn't exist",
+                "object doesn't exist",
+            ));
+        }
+        _ => {
+            return Err(wrgobj(
+                errmsg + "\nPath is not a file",
+                "object is not a file",
+            ));
+        }
+    }
+    ensure_parent(path).map_err(inerr(errctx("ensure parent directory")))?;
+    std::fs::hard_link(original.to_path_buf(), path.to_path_buf())
+        .map_err(inerr(errctx("create hard link")))
+}
+
+/// Number of hard links to the file at path, itself included. Always 1 on
+/// platforms where it can't be known
+pub fn hardlink_count(path: &AbstPath) -> Result<u64, Error> {
+    let errctx = error_context(format!("could not count hard links of file at path {path}"));
+    let metadata = std::fs::symlink_metadata(path.to_path_buf())
+        .map_err(inerr(errctx("retrieve metadata")))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Ok(metadata.nlink())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        Ok(1)
+    }
+}
+
+/// Atomically replace the file at path with a hard link to the file at original
+pub fn replace_with_hardlink(original: &AbstPath, path: &AbstPath) -> Result<(), Error> {
+    let errctx = error_context(format!(
+        "could not replace file at path {path} with hard link to file at path {original}"
+    ));
+    let temp_path = atomic_temp_path(path)?;
+    create_hardlink(original, &temp_path).map_err(inerr(errctx("create hard link")))?;
+    std::fs::rename(temp_path.to_path_buf(), path.to_path_buf()).map_err(|err| {
+        let _ = std::fs::remove_file(temp_path.to_path_buf());
+        inerr(errctx("move hard link in place"))(err)
+    })
+}
+
+/// Give the file at path its own copy of the content if it's shared with other
+/// hard links, so that changing its mode or mtime doesn't affect them. The mode
+/// and mtime of the file are kept
+pub fn unshare_file(path: &AbstPath) -> Result<(), Error> {
+    let errctx = error_context(format!("could not unshare file at path {path}"));
+    if hardlink_count(path)? <= 1 {
+        return Ok(());
+    }
+    let mtime = super::get_mtime(path).map_err(inerr(errctx("get mtime")))?;
+    copy_in_place(path, path).map_err(inerr(errctx("copy content")))?;
+    super::set_mtime(path, &mtime).map_err(inerr(errctx("set mtime")))
 }
 
 #[cfg(test)]
 mod tests {
     use super::{
-        async_create_file, async_read_file, create_file, read_file, remove_file, rename_file,
-        AbstPath,
+        async_append_file, async_atomic_create_file, async_create_file, async_read_file,
+        atomic_create_file, copy_and_remove, copy_file, create_file, create_hardlink,
+        get_file_size, hardlink_count, read_file, remove_file, rename_file, replace_with_hardlink,
+        resolve_temp_dir, unshare_file, AbstPath,
     };
     use std::path::PathBuf;
 
@@ -167,6 +501,9 @@ mod tests {
             reader.read_to_string(&mut buffer).unwrap();
             assert_eq!(buffer, String::from(dummy_content));
 
+            // get_file_size
+            assert_eq!(get_file_size(&file).unwrap(), dummy_content.len() as u64);
+
             let (dir, _) = path.safe_add_last("dir");
             std::fs::create_dir(dir.to_path_buf()).unwrap();
             assert!(read_file(&dir).is_err());
@@ -184,10 +521,137 @@ mod tests {
             std::os::unix::fs::symlink(".", symlink.to_path_buf()).unwrap();
             assert!(rename_file(&symlink, &file2).is_err());
 
+            // create_hardlink
+            let (hardlink, _) = path.safe_add_last("hardlink.txt");
+            create_hardlink(&file, &hardlink).unwrap();
+            let mut reader = BufReader::new(read_file(&hardlink).unwrap());
+            let mut buffer = String::new();
+            reader.read_to_string(&mut buffer).unwrap();
+            assert_eq!(buffer, String::from(dummy_content));
+            assert!(create_hardlink(&file, &hardlink).is_err());
+            assert!(create_hardlink(&non_existing_file, &file2).is_err());
+            assert!(create_hardlink(&dir, &file2).is_err());
+            assert_eq!(hardlink_count(&file).unwrap(), 2);
+
+            // replace_with_hardlink and unshare_file
+            let (other, _) = path.safe_add_last("other.txt");
+            std::fs::write(other.to_path_buf(), "other content").unwrap();
+            replace_with_hardlink(&file, &other).unwrap();
+            assert_eq!(hardlink_count(&file).unwrap(), 3);
+            assert_eq!(
+                std::fs::read_to_string(other.to_path_buf()).unwrap(),
+                dummy_content
+            );
+            let mtime = crate::get_mtime(&file).unwrap();
+            unshare_file(&other).unwrap();
+            assert_eq!(hardlink_count(&other).unwrap(), 1);
+            assert_eq!(hardlink_count(&file).unwrap(), 2);
+            assert_eq!(crate::get_mtime(&other).unwrap(), mtime);
+            assert_eq!(
+                std::fs::read_to_string(other.to_path_buf()).unwrap(),
+                dummy_content
+            );
+            remove_file(&other).unwrap();
+
+            // copy_file
+            let (copy, _) = path.safe_add_last("copies").safe_add_last("copy.txt");
+            copy_file(&file, &copy).unwrap();
+            assert_eq!(
+                std::fs::read_to_string(copy.to_path_buf()).unwrap(),
+                dummy_content
+            );
+            assert_eq!(
+                std::fs::read_to_string(file.to_path_buf()).unwrap(),
+                dummy_content
+            );
+            assert!(copy_file(&file, &copy).is_err());
+            assert!(copy_file(&non_existing_file, &file2).is_err());
+            assert!(copy_file(&dir, &file2).is_err());
+
+            // rename_file across file systems: the fallback is tested directly, as
+            //	the staging directory is not guaranteed to be on another file system
+            let (staged, _) = path.safe_add_last("staged.txt");
+            let (finalized, _) = path.safe_add_last("finalized").safe_add_last("file.txt");
+            let (finalized_temp, _) = path
+                .safe_add_last("finalized")
+                .safe_add_last(".file.txt.bbup-tmp");
+            std::fs::write(staged.to_path_buf(), dummy_content).unwrap();
+            std::fs::create_dir(finalized.parent().unwrap().to_path_buf()).unwrap();
+            copy_and_remove(&staged, &finalized).unwrap();
+            assert!(!staged.exists());
+            assert!(!finalized_temp.exists());
+            assert_eq!(
+                std::fs::read_to_string(finalized.to_path_buf()).unwrap(),
+                dummy_content
+            );
+            assert!(copy_and_remove(&staged, &finalized).is_err());
+            if std::path::Path::new("/dev/shm").is_dir() {
+                let staged = AbstPath::from("/dev/shm/bbup-test-abst_fs-file-staged.txt");
+                std::fs::write(staged.to_path_buf(), dummy_content).unwrap();
+                rename_file(&staged, &finalized).unwrap();
+                assert!(!staged.exists());
+                assert_eq!(
+                    std::fs::read_to_string(finalized.to_path_buf()).unwrap(),
+                    dummy_content
+                );
+            }
+
+            // resolve_temp_dir
+            let (root, _) = path.safe_add_last("root");
+            assert_eq!(
+                resolve_temp_dir(&root, None),
+                root.add_last(".bbup").add_last("temp")
+            );
+            assert_eq!(
+                resolve_temp_dir(&root, Some(&AbstPath::from("staging"))),
+                root.add_last("staging")
+            );
+            assert_eq!(
+                resolve_temp_dir(&root, Some(&AbstPath::from("/var/tmp/staging"))),
+                AbstPath::from("/var/tmp/staging")
+            );
+
             // remove_file
             remove_file(&file).unwrap();
             assert!(remove_file(&file).is_err());
             assert!(remove_file(&symlink).is_err());
+
+            // atomic_create_file
+            let (atomic, _) = path.safe_add_last("atomic.txt");
+            let (atomic_temp, _) = path.safe_add_last(".atomic.txt.bbup-tmp");
+            let mut writer = atomic_create_file(&atomic).unwrap();
+            writer.write_all(dummy_content.as_bytes()).unwrap();
+            assert!(atomic_temp.exists());
+            assert!(!atomic.exists());
+            writer.persist().unwrap();
+            assert!(!atomic_temp.exists());
+            let mut reader = BufReader::new(read_file(&atomic).unwrap());
+            let mut buffer = String::new();
+            reader.read_to_string(&mut buffer).unwrap();
+            assert_eq!(buffer, String::from(dummy_content));
+
+            // simulate an error mid-write: the handle gets dropped before
+            //	being persisted
+            let (interrupted, _) = path.safe_add_last("interrupted.txt");
+            let (interrupted_temp, _) = path.safe_add_last(".interrupted.txt.bbup-tmp");
+            let write_and_fail = || -> std::io::Result<()> {
+                let mut writer = atomic_create_file(&interrupted).unwrap();
+                writer.write_all(dummy_content.as_bytes())?;
+                Err(std::io::Error::other("simulated"))
+            };
+            assert!(write_and_fail().is_err());
+            assert!(!interrupted.exists());
+            assert!(!interrupted_temp.exists());
+
+            // an interrupted atomic write leaves a pre-existing file untouched
+            let mut writer = atomic_create_file(&atomic).unwrap();
+            writer.write_all(b"partial").unwrap();
+            drop(writer);
+            let mut reader = BufReader::new(read_file(&atomic).unwrap());
+            let mut buffer = String::new();
+            reader.read_to_string(&mut buffer).unwrap();
+            assert_eq!(buffer, String::from(dummy_content));
+            assert!(!atomic_temp.exists());
         });
         std::fs::remove_dir_all(&path.1).unwrap();
         assert!(result.is_ok())
@@ -229,6 +693,38 @@ mod tests {
             let (dir, _) = path.safe_add_last("dir");
             std::fs::create_dir(dir.to_path_buf()).unwrap();
             assert!(async_read_file(&dir).await.is_err());
+
+            // async_append_file
+            let mut writer = async_append_file(&file).await.unwrap();
+            writer.write_all(dummy_content.as_bytes()).await.unwrap();
+            writer.flush().await.unwrap();
+            let mut reader = BufReader::new(async_read_file(&file).await.unwrap());
+            let mut buffer = String::new();
+            reader.read_to_string(&mut buffer).await.unwrap();
+            assert_eq!(buffer, dummy_content.repeat(2));
+            assert!(async_append_file(&dir).await.is_err());
+
+            // async_atomic_create_file
+            let (atomic, _) = path.safe_add_last("atomic.txt");
+            let (atomic_temp, _) = path.safe_add_last(".atomic.txt.bbup-tmp");
+            let mut writer = async_atomic_create_file(&atomic).await.unwrap();
+            writer.write_all(dummy_content.as_bytes()).await.unwrap();
+            assert!(atomic_temp.exists());
+            assert!(!atomic.exists());
+            writer.persist().await.unwrap();
+            assert!(!atomic_temp.exists());
+            let mut reader = BufReader::new(async_read_file(&atomic).await.unwrap());
+            let mut buffer = String::new();
+            reader.read_to_string(&mut buffer).await.unwrap();
+            assert_eq!(buffer, String::from(dummy_content));
+
+            let (interrupted, _) = path.safe_add_last("interrupted.txt");
+            let (interrupted_temp, _) = path.safe_add_last(".interrupted.txt.bbup-tmp");
+            let mut writer = async_atomic_create_file(&interrupted).await.unwrap();
+            writer.write_all(dummy_content.as_bytes()).await.unwrap();
+            drop(writer);
+            assert!(!interrupted.exists());
+            assert!(!interrupted_temp.exists());
         });
 
         std::fs::remove_dir_all(&path.1).unwrap();
@@ -1,5 +1,6 @@
 mod error;
-use error::{error_context, generr, inerr, unkext, wrgobj, Error};
+pub use error::Error;
+use error::{error_context, generr, inerr, unkext, wrgobj};
 
 mod path;
 pub use path::{AbstPath, Endpoint, ObjectType};
@@ -7,22 +8,38 @@ pub use path::{AbstPath, Endpoint, ObjectType};
 mod directory;
 pub use directory::{
     create_dir, ensure_parent, list_dir_content, make_clean_dir, remove_dir, remove_dir_all,
+    rename_dir,
 };
 
+mod durability;
+pub use durability::{sync_dir, sync_file, Durability};
+
 mod file;
 pub use file::{
-    async_create_file, async_read_file, create_file, read_file, remove_file, rename_file,
+    async_append_file, async_atomic_create_file, async_create_file, async_read_file,
+    atomic_create_file, copy_file, create_file, create_hardlink, get_file_size, hardlink_count,
+    read_file, remove_file, rename_file, replace_with_hardlink, resolve_temp_dir, unshare_file,
+    AsyncAtomicFile, AtomicFile,
 };
 
+mod lock;
+pub use lock::{try_lock_file, FileLock};
+
 mod symlink;
 use symlink::ABST_OBJ_HEADER;
 pub use symlink::{create_symlink, read_link, remove_symlink, rename_symlink};
 
 mod mtime;
-pub use mtime::{get_mtime, set_mtime, Mtime};
+pub use mtime::{get_mtime, set_mtime, Mtime, MtimePrecision};
+
+mod mode;
+pub use mode::{get_mode, set_mode, Mode};
 
 mod saveload;
-pub use saveload::{load, save};
+pub use saveload::{load, load_as, save, save_as, save_as_with, save_with, Format};
 
 mod env;
-pub use env::{cwd, home_dir};
+pub use env::{cwd, env_var, home_dir};
+
+mod retry;
+pub use retry::retry_transient;
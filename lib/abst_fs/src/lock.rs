@@ -0,0 +1,66 @@
+use super::{ensure_parent, error_context, inerr, AbstPath, Error};
+
+/// Exclusive advisory lock on a file, held until the handle is dropped. The lock
+/// is released by the operating system when the file is closed, so it doesn't
+/// outlive the process holding it, even if the process crashes
+#[derive(Debug)]
+pub struct FileLock {
+    _file: std::fs::File,
+}
+
+/// Attempt to acquire an exclusive lock on the file at path (creating the file and
+/// its subpaths recursively if needed) without waiting for it. Returns None if the
+/// lock is already held by someone else
+pub fn try_lock_file(path: &AbstPath) -> Result<Option<FileLock>, Error> {
+    let errctx = error_context(format!("could not lock file at path {path}"));
+    ensure_parent(path).map_err(inerr(errctx("ensure parent directory")))?;
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path.to_path_buf())
+        .map_err(inerr(errctx("open lock file")))?;
+    match file.try_lock() {
+        Ok(()) => Ok(Some(FileLock { _file: file })),
+        Err(std::fs::TryLockError::WouldBlock) => Ok(None),
+        Err(std::fs::TryLockError::Error(err)) => Err(inerr(errctx("acquire lock"))(err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{try_lock_file, AbstPath};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test() {
+        let path_bf = PathBuf::from("/tmp/bbup-test-abst_fs-lock");
+        let path = AbstPath::from(&path_bf);
+        assert!(!path_bf.exists());
+        std::fs::create_dir(&path_bf).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            let lock_path = path.add_last("dir").add_last("lock");
+            let lock = try_lock_file(&lock_path).unwrap();
+            assert!(lock.is_some());
+            assert!(lock_path.exists());
+
+            // The lock is exclusive
+            assert!(try_lock_file(&lock_path).unwrap().is_none());
+
+            // The lock is released on drop, and the lock file being left behind
+            //	doesn't prevent it from being acquired again
+            drop(lock);
+            let lock = try_lock_file(&lock_path).unwrap();
+            assert!(lock.is_some());
+            assert!(try_lock_file(&lock_path).unwrap().is_none());
+
+            // Locking a directory is an error, not a held lock
+            assert!(try_lock_file(&path.add_last("dir")).is_err());
+        });
+
+        std::fs::remove_dir_all(&path_bf).unwrap();
+
+        assert!(result.is_ok())
+    }
+}
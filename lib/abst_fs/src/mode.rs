@@ -0,0 +1,132 @@
+use serde::{Deserialize, Serialize};
+
+use super::{error_context, inerr, AbstPath, Error};
+
+/// Unix permission bits of a file.
+///
+/// On platforms without unix permissions every file is considered to have the
+/// default mode, and setting a mode does nothing
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct Mode(u32);
+
+impl Mode {
+    const MASK: u32 = 0o7777;
+    const DEFAULT: Mode = Mode(0o644);
+
+    pub fn from(bits: u32) -> Mode {
+        Mode(bits & Mode::MASK)
+    }
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_be_bytes().to_vec()
+    }
+}
+impl Default for Mode {
+    fn default() -> Self {
+        Mode::DEFAULT
+    }
+}
+
+impl std::fmt::Display for Mode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04o}", self.0)
+    }
+}
+
+/// Get the permission bits of an object
+///
+/// Returns an error if the std::fs fails to retrieve metadata
+/// from the specified path, in which case it returns the
+/// wrapped error
+pub fn get_mode(path: &AbstPath) -> Result<Mode, Error> {
+    let errctx = error_context(format!("could not get mode from path {path}"));
+    let metadata = std::fs::symlink_metadata(path.to_path_buf())
+        .map_err(inerr(errctx("get metadata of object")))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        Ok(Mode::from(metadata.permissions().mode()))
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        Ok(Mode::DEFAULT)
+    }
+}
+
+/// Set the permission bits of an object
+///
+/// Returns an error if std::fs fails to set the permissions, in which case it
+/// returns the wrapped error
+pub fn set_mode(path: &AbstPath, mode: &Mode) -> Result<(), Error> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let errctx = error_context(format!("could not set mode at path {path}"));
+        std::fs::set_permissions(path.to_path_buf(), std::fs::Permissions::from_mode(mode.0))
+            .map_err(inerr(errctx("set permissions")))?;
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = (path, mode);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_mode, set_mode, AbstPath, Mode};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test() {
+        from();
+        to_string();
+        get_set_mode();
+    }
+
+    fn from() {
+        assert_eq!(Mode::from(0o755), Mode(0o755));
+        assert_eq!(Mode::from(0o100755), Mode(0o755));
+        assert_eq!(Mode::from(0o100755).bits(), 0o755);
+        assert_eq!(Mode::default(), Mode(0o644));
+        assert_ne!(Mode(0o644).to_bytes(), Mode(0o755).to_bytes());
+    }
+
+    fn to_string() {
+        assert_eq!(format!("{}", Mode(0o755)), "0755");
+        assert_eq!(format!("{}", Mode(0o4750)), "4750");
+    }
+
+    fn get_set_mode() {
+        let path_bf = PathBuf::from("/tmp/bbup-test-abst_fs-mode");
+        let path = AbstPath::from(&path_bf);
+        assert!(!path_bf.exists());
+
+        std::fs::create_dir(&path_bf).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            let file = path.add_last("file");
+            std::fs::File::create(file.to_path_buf()).unwrap();
+
+            set_mode(&file, &Mode(0o755)).unwrap();
+            assert_eq!(get_mode(&file).unwrap(), Mode(0o755));
+            set_mode(&file, &Mode(0o600)).unwrap();
+            assert_eq!(get_mode(&file).unwrap(), Mode(0o600));
+
+            let non_existing_object = path.add_last("non_existing_object");
+            assert!(get_mode(&non_existing_object).is_err());
+            assert!(set_mode(&non_existing_object, &Mode(0o644)).is_err());
+        });
+
+        std::fs::remove_dir_all(&path_bf).unwrap();
+
+        assert!(result.is_ok())
+    }
+}
@@ -2,9 +2,34 @@ use serde::{Deserialize, Serialize};
 
 use super::{error_context, inerr, AbstPath, Error};
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, PartialOrd)]
 pub struct Mtime(i64, u32);
 
+/// Precision at which mtimes are compared. File systems storing mtimes with a
+/// coarser granularity truncate the finer part, so mtimes coming from different
+/// file systems should be compared at the coarsest of their precisions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MtimePrecision {
+    #[default]
+    Full,
+    Milliseconds,
+    Seconds,
+}
+impl std::str::FromStr for MtimePrecision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(MtimePrecision::Full),
+            "ms" => Ok(MtimePrecision::Milliseconds),
+            "s" => Ok(MtimePrecision::Seconds),
+            _ => Err(format!(
+                "invalid mtime precision '{s}', expected one of: full, ms, s"
+            )),
+        }
+    }
+}
+
 impl Mtime {
     pub fn from(time: i64, nanoseconds: u32) -> Mtime {
         Mtime(time, nanoseconds)
@@ -16,6 +41,16 @@ impl Mtime {
 
         bytes
     }
+
+    /// Whether the two mtimes are equal when truncated to the given precision
+    pub fn eq_at(&self, other: &Mtime, precision: MtimePrecision) -> bool {
+        let nanos = |Mtime(_, nanoseconds): &Mtime| match precision {
+            MtimePrecision::Full => *nanoseconds,
+            MtimePrecision::Milliseconds => nanoseconds / 1_000_000,
+            MtimePrecision::Seconds => 0,
+        };
+        self.0 == other.0 && nanos(self) == nanos(other)
+    }
 }
 
 impl std::fmt::Display for Mtime {
@@ -50,6 +85,7 @@ pub fn get_mtime(path: &AbstPath) -> Result<Mtime, Error> {
 /// - std::fs fails to retrieve metadata from the specified path
 /// - filetime fails to set times
 /// - std::fs fails to set permissions
+///
 /// in any of these cases it returns the wrapped error
 pub fn set_mtime(path: &AbstPath, mtime: &Mtime) -> Result<(), Error> {
     let errctx = error_context(format!("could not set mtime at path {path}"));
@@ -65,7 +101,7 @@ pub fn set_mtime(path: &AbstPath, mtime: &Mtime) -> Result<(), Error> {
 
 #[cfg(test)]
 mod tests {
-    use super::{get_mtime, set_mtime, AbstPath, Mtime};
+    use super::{get_mtime, set_mtime, AbstPath, Mtime, MtimePrecision};
     use std::path::PathBuf;
 
     const TEST_MTIME: Mtime = Mtime(498705663, 141592653);
@@ -90,9 +126,30 @@ mod tests {
         from();
         to_bytes();
         to_string();
+        eq_at();
         get_set_mtime();
     }
 
+    fn eq_at() {
+        use MtimePrecision::*;
+
+        let sub_second = Mtime(498705663, 735182781);
+        let sub_milli = Mtime(498705663, 141592999);
+        let other_second = Mtime(498705664, 141592653);
+
+        assert!(TEST_MTIME.eq_at(&TEST_MTIME, Full));
+        assert!(!TEST_MTIME.eq_at(&sub_milli, Full));
+        assert!(TEST_MTIME.eq_at(&sub_milli, Milliseconds));
+        assert!(!TEST_MTIME.eq_at(&sub_second, Milliseconds));
+        assert!(TEST_MTIME.eq_at(&sub_second, Seconds));
+        assert!(!TEST_MTIME.eq_at(&other_second, Seconds));
+
+        assert_eq!("full".parse(), Ok(Full));
+        assert_eq!("ms".parse(), Ok(Milliseconds));
+        assert_eq!("s".parse(), Ok(Seconds));
+        assert!("minutes".parse::<MtimePrecision>().is_err());
+    }
+
     fn from() {
         assert_eq!(TEST_MTIME, Mtime::from(498705663, 141592653));
         assert_eq!(
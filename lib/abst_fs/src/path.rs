@@ -3,7 +3,7 @@ use super::ABST_OBJ_HEADER;
 use std::{
     collections::VecDeque,
     io::Read,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
 };
 
 use serde::{Deserialize, Serialize};
@@ -39,6 +39,16 @@ pub enum ObjectType {
     File,
     SymLink,
     Dir,
+    /// Any other object, such as a FIFO, a socket or a device
+    Special,
+}
+
+/// Whether the component is the root (or the prefix, on Windows) of an absolute path
+fn is_root_component(comp: &str) -> bool {
+    matches!(
+        Path::new(comp).components().next(),
+        Some(Component::RootDir | Component::Prefix(_))
+    )
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -76,6 +86,16 @@ impl AbstPath {
         let AbstPath(path) = self;
         path.get(pos)
     }
+    /// Iterate over the components of the path, from the first to the last
+    pub fn components(&self) -> impl DoubleEndedIterator<Item = &str> + ExactSizeIterator {
+        let AbstPath(path) = self;
+        path.iter().map(String::as_str)
+    }
+    /// Number of components of the path, so that the depth of an object in a
+    /// tree is the depth of its path relative to the root of the tree
+    pub fn depth(&self) -> usize {
+        self.components().len()
+    }
 
     pub fn add_first<S: std::string::ToString>(&self, prefix: S) -> AbstPath {
         let AbstPath(mut path) = self.clone();
@@ -102,6 +122,51 @@ impl AbstPath {
         path.append(&mut appendix.clone());
         AbstPath(path)
     }
+    /// Strip base from the beginning of the path, returning what remains, or None
+    /// if the path is not under base
+    pub fn relative_to(&self, AbstPath(base): &AbstPath) -> Option<AbstPath> {
+        let AbstPath(path) = self;
+        if path.len() < base.len() || path.iter().zip(base).any(|(comp, base)| comp != base) {
+            return None;
+        }
+        Some(AbstPath(path.iter().skip(base.len()).cloned().collect()))
+    }
+    /// Whether the path, taken relative to some directory, refers to an object
+    /// inside of it: the path is not empty and it has no root, `.` or `..`
+    /// components (not even within a single component)
+    pub fn is_confined(&self) -> bool {
+        let AbstPath(path) = self;
+        !path.is_empty()
+            && path.iter().all(|comp| {
+                !comp.is_empty()
+                    && Path::new(comp)
+                        .components()
+                        .all(|comp| matches!(comp, Component::Normal(_)))
+            })
+    }
+    /// Collapse the `.` components and resolve the `..` components against the
+    /// preceding ones, without touching the file system (so symlinks are not
+    /// followed). Leading `..` of relative paths are kept, while `..` right
+    /// after the root of absolute paths are dropped, as the root is its own
+    /// parent
+    pub fn normalize(&self) -> AbstPath {
+        let AbstPath(path) = self;
+        let mut normalized: VecDeque<String> = VecDeque::new();
+        for comp in path {
+            match comp.as_str() {
+                "." => {}
+                ".." => match normalized.back() {
+                    Some(last) if is_root_component(last) => {}
+                    Some(last) if last != ".." => {
+                        normalized.pop_back();
+                    }
+                    _ => normalized.push_back(comp.clone()),
+                },
+                _ => normalized.push_back(comp.clone()),
+            }
+        }
+        AbstPath(normalized)
+    }
 
     pub fn parent(&self) -> Option<AbstPath> {
         // NOTE: while this could technically be easily done by just popping the
@@ -115,14 +180,39 @@ impl AbstPath {
         // NOTE: same as `fn parent(&self)`
         Some(self.to_path_buf().file_name()?.force_to_string())
     }
-    pub fn extension(&self) -> Option<&str> {
+    /// Split the last component into stem and extension, as in
+    /// `std::path::Path`: the extension is whatever follows the last dot, unless
+    /// that dot is the first character (so dotfiles have no extension)
+    fn split_last(&self) -> Option<(&str, Option<&str>)> {
         let AbstPath(path) = self;
-        let last = path.get(path.len() - 1)?;
-        let last_dot_occurrence = last.rfind('.')?;
-        let ext = &last[last_dot_occurrence + 1..];
-        match ext.is_empty() {
-            true => None,
-            false => Some(ext),
+        let last = path.back()?;
+        if last == ".." {
+            return None;
+        }
+        match last.rfind('.') {
+            None | Some(0) => Some((last, None)),
+            Some(pos) => Some((&last[..pos], Some(&last[pos + 1..]))),
+        }
+    }
+    pub fn file_stem(&self) -> Option<String> {
+        let (stem, _) = self.split_last()?;
+        Some(stem.to_string())
+    }
+    pub fn extension(&self) -> Option<String> {
+        match self.split_last()? {
+            (_, Some(ext)) if !ext.is_empty() => Some(ext.to_string()),
+            _ => None,
+        }
+    }
+
+    /// Whether the last component is the name of a hidden object, starting with
+    /// a dot. The `.` and `..` components refer to directories rather than
+    /// naming objects, so they are not hidden
+    pub fn is_hidden(&self) -> bool {
+        let AbstPath(path) = self;
+        match path.back() {
+            Some(name) => name.starts_with('.') && name != "." && name != "..",
+            None => false,
         }
     }
 
@@ -164,10 +254,7 @@ impl AbstPath {
                 Some(ObjectType::File)
             }
         } else {
-            panic!(
-                "Foreign file system object. Not a directory, a file nor a symlink, at path: {}",
-                self
-            )
+            Some(ObjectType::Special)
         }
     }
 }
@@ -207,15 +294,87 @@ impl std::fmt::Display for AbstPath {
     }
 }
 
+/// Target of a symlink, as stored by the os that created it. The bool of a Windows
+/// endpoint tells if the target is a directory.
+///
+/// Endpoints should be kept in their normalized form (see `Endpoint::normalize`),
+/// so that the same link has the same representation (and hash) on every platform
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub enum Endpoint {
     Unix(String),
     Windows(bool, String),
 }
 impl Endpoint {
+    /// Bring the endpoint to its normalized form:
+    /// - Unix endpoints keep `\` as it is, as it is a valid character in a unix
+    ///   filename and not a separator
+    /// - Windows endpoints use `/` as separator (which Windows accepts as well as
+    ///   `\`) and, if absolute, an uppercase drive letter. For example both
+    ///   `c:\Users\me` and `C:/Users/me` become `C:/Users/me`
+    /// - Both drop the `.` components and the redundant separators, which don't
+    ///   change where the endpoint leads. For example `./a//b` becomes `a/b`. The
+    ///   `..` components, the root of absolute endpoints and a trailing separator
+    ///   (which requires the target to be a directory) are kept
+    pub fn normalize(self) -> Endpoint {
+        match self {
+            Endpoint::Unix(endpath) => {
+                let root_len = match endpath.starts_with('/') {
+                    true => 1,
+                    false => 0,
+                };
+                Endpoint::Unix(collapse_endpath(&endpath, root_len))
+            }
+            Endpoint::Windows(is_dir, endpath) => {
+                let mut endpath = endpath.replace('\\', "/");
+                let bytes = endpath.as_bytes();
+                let has_drive =
+                    bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':';
+                if has_drive {
+                    endpath[..1].make_ascii_uppercase();
+                }
+                // The root is the drive (if any) followed by a separator, or the
+                //	`//` that starts a network path
+                let root_len = match (has_drive, endpath.starts_with("//")) {
+                    (true, _) if endpath[2..].starts_with('/') => 3,
+                    (true, _) => 2,
+                    (false, true) => 2,
+                    (false, false) if endpath.starts_with('/') => 1,
+                    (false, false) => 0,
+                };
+                Endpoint::Windows(is_dir, collapse_endpath(&endpath, root_len))
+            }
+        }
+    }
+
+    /// Get the endpath with the separators native to the os of the endpoint, which
+    /// is the form in which the os expects it when creating the symlink
+    pub fn native_endpath(&self) -> String {
+        match self {
+            Endpoint::Unix(endpath) => endpath.clone(),
+            Endpoint::Windows(_, endpath) => endpath.replace('/', "\\"),
+        }
+    }
+
+    /// Check if the endpoint is an absolute path, for the os of the endpoint
+    pub fn is_absolute(&self) -> bool {
+        match self {
+            Endpoint::Unix(endpath) => endpath.starts_with('/'),
+            Endpoint::Windows(_, endpath) => {
+                let bytes = endpath.as_bytes();
+                (bytes.len() >= 3
+                    && bytes[0].is_ascii_alphabetic()
+                    && bytes[1] == b':'
+                    && (bytes[2] == b'/' || bytes[2] == b'\\'))
+                    || endpath.starts_with("//")
+                    || endpath.starts_with("\\\\")
+            }
+        }
+    }
+
+    /// Serialize the endpoint (in its normalized form) to hash it
     pub fn as_bytes(&self) -> Vec<u8> {
         let mut bytes: Vec<u8> = Vec::new();
-        match self {
+        match self.clone().normalize() {
             Endpoint::Unix(endpath) => {
                 // OS byte
                 bytes.push(0);
@@ -242,7 +401,28 @@ impl Endpoint {
     }
 }
 
+/// Drop the `.` components and the redundant `/` separators of the endpath,
+/// whose first `root_len` bytes are its root and are kept as they are
+fn collapse_endpath(endpath: &str, root_len: usize) -> String {
+    let (root, rest) = endpath.split_at(root_len);
+    let components: Vec<&str> = rest
+        .split('/')
+        .filter(|component| !component.is_empty() && *component != ".")
+        .collect();
+    let trailing = !components.is_empty() && (rest.ends_with('/') || rest.ends_with("/."));
+
+    let mut collapsed = root.to_string() + &components.join("/");
+    if collapsed.is_empty() {
+        collapsed.push('.');
+    }
+    if trailing {
+        collapsed.push('/');
+    }
+    collapsed
+}
+
 #[cfg(test)]
+#[allow(clippy::join_absolute_paths)]
 mod tests {
     use super::{AbstPath, Endpoint};
     use std::collections::VecDeque;
@@ -262,16 +442,22 @@ mod tests {
         len();
         is_empty();
         get();
+        components();
 
         add_first();
         add_last();
         strip_first();
         strip_last();
         append();
+        relative_to();
+        is_confined();
+        normalize_path();
 
         parent();
         file_name();
         extension();
+        file_stem();
+        is_hidden();
 
         into_iter();
         into_iter_ref();
@@ -281,6 +467,9 @@ mod tests {
         exists_and_type();
 
         // Endpoint
+        normalize();
+        native_endpath();
+        is_absolute();
         as_bytes();
     }
 
@@ -386,6 +575,36 @@ mod tests {
         assert!(!AbstPath::from("test").is_empty());
     }
 
+    fn components() {
+        assert_eq!(AbstPath::empty().components().next(), None);
+        assert_eq!(AbstPath::from("").components().next(), None);
+        assert_eq!(AbstPath::empty().depth(), 0);
+
+        let single = AbstPath::single("a/b");
+        assert_eq!(single.components().collect::<Vec<_>>(), vec!["a/b"]);
+        assert_eq!(single.depth(), 1);
+
+        let nested = AbstPath::from("path/to/some/file.txt");
+        assert_eq!(
+            nested.components().collect::<Vec<_>>(),
+            vec!["path", "to", "some", "file.txt"]
+        );
+        assert_eq!(
+            nested.components().rev().collect::<Vec<_>>(),
+            vec!["file.txt", "some", "to", "path"]
+        );
+        assert_eq!(nested.depth(), 4);
+        assert_eq!(nested.depth(), nested.components().count());
+        assert_eq!(nested.depth(), nested.len());
+
+        let absolute = AbstPath::from("/home/user");
+        assert_eq!(
+            absolute.components().collect::<Vec<_>>(),
+            vec!["/", "home", "user"]
+        );
+        assert_eq!(absolute.depth(), 3);
+    }
+
     fn get() {
         let first = String::from("first");
         let second = String::from("second");
@@ -550,6 +769,91 @@ mod tests {
         );
     }
 
+    fn relative_to() {
+        let base = AbstPath::from("some/path/to");
+
+        // Nested
+        assert_eq!(
+            AbstPath::from("some/path/to/somewhere/else").relative_to(&base),
+            Some(AbstPath::from("somewhere/else"))
+        );
+        // Equal
+        assert_eq!(base.relative_to(&base), Some(AbstPath::empty()));
+        // Empty
+        assert_eq!(base.relative_to(&AbstPath::empty()), Some(base.clone()));
+        assert_eq!(
+            AbstPath::empty().relative_to(&AbstPath::empty()),
+            Some(AbstPath::empty())
+        );
+        assert_eq!(AbstPath::empty().relative_to(&base), None);
+        // Sibling
+        assert_eq!(AbstPath::from("some/path/from").relative_to(&base), None);
+        assert_eq!(
+            AbstPath::from("some/path/tomorrow").relative_to(&base),
+            None
+        );
+        // Unrelated
+        assert_eq!(AbstPath::from("other/path/to").relative_to(&base), None);
+        // Ancestor
+        assert_eq!(AbstPath::from("some/path").relative_to(&base), None);
+    }
+
+    fn is_confined() {
+        assert!(AbstPath::from("file").is_confined());
+        assert!(AbstPath::from("some/path/to/file").is_confined());
+        assert!(AbstPath::single("some/path").is_confined());
+        assert!(AbstPath::from("some/..file").is_confined());
+
+        assert!(!AbstPath::empty().is_confined());
+        assert!(!AbstPath::single("").is_confined());
+        assert!(!AbstPath::from("/some/path").is_confined());
+        assert!(!AbstPath::from("../some/path").is_confined());
+        assert!(!AbstPath::from("some/../../path").is_confined());
+        assert!(!AbstPath::from("./some/path").is_confined());
+        assert!(!AbstPath::single("some/../../path").is_confined());
+        assert!(!AbstPath::single("/").is_confined());
+    }
+
+    fn normalize_path() {
+        // `.` components, which `AbstPath::from` only keeps at the beginning
+        assert_eq!(
+            AbstPath::from("a").add_last(".").add_last("b").normalize(),
+            AbstPath::from("a/b")
+        );
+        assert_eq!(AbstPath::from("a/./b").normalize(), AbstPath::from("a/b"));
+        assert_eq!(AbstPath::from("./a/b").normalize(), AbstPath::from("a/b"));
+        assert_eq!(AbstPath::single(".").normalize(), AbstPath::empty());
+
+        // `..` components
+        assert_eq!(
+            AbstPath::from("a/b/../c").normalize(),
+            AbstPath::from("a/c")
+        );
+        assert_eq!(AbstPath::from("a/b/../..").normalize(), AbstPath::empty());
+        assert_eq!(AbstPath::from("../x").normalize(), AbstPath::from("../x"));
+        assert_eq!(
+            AbstPath::from("a/../../x").normalize(),
+            AbstPath::from("../x")
+        );
+        assert_eq!(
+            AbstPath::from("../../x/../y").normalize(),
+            AbstPath::from("../../y")
+        );
+        #[cfg(unix)]
+        {
+            assert_eq!(
+                AbstPath::from("/a/b/../c").normalize(),
+                AbstPath::from("/a/c")
+            );
+            assert_eq!(AbstPath::from("/../x").normalize(), AbstPath::from("/x"));
+        }
+
+        // Already normal
+        let path = AbstPath::from("some/path/to");
+        assert_eq!(path.normalize(), path);
+        assert_eq!(AbstPath::empty().normalize(), AbstPath::empty());
+    }
+
     fn parent() {
         let path = "path/to/somewhere";
         let child = "child";
@@ -567,16 +871,85 @@ mod tests {
 
     fn extension() {
         let path = "path/to/some/file.txt";
-        assert_eq!(AbstPath::from(path).extension(), Some("txt"));
+        assert_eq!(AbstPath::from(path).extension(), Some(String::from("txt")));
 
         let path = "path/to/some/file.tar.gz";
-        assert_eq!(AbstPath::from(path).extension(), Some("gz"));
+        assert_eq!(AbstPath::from(path).extension(), Some(String::from("gz")));
 
         let path = "path/to/some/file";
         assert_eq!(AbstPath::from(path).extension(), None);
 
         let path = "path/to/some/file.";
         assert_eq!(AbstPath::from(path).extension(), None);
+
+        let path = "path/to/some/.gitignore";
+        assert_eq!(AbstPath::from(path).extension(), None);
+
+        let path = "path/to/some/.config.toml";
+        assert_eq!(AbstPath::from(path).extension(), Some(String::from("toml")));
+
+        assert_eq!(AbstPath::empty().extension(), None);
+    }
+
+    fn file_stem() {
+        let path = "path/to/some/file.txt";
+        assert_eq!(AbstPath::from(path).file_stem(), Some(String::from("file")));
+
+        let path = "path/to/some/archive.tar.gz";
+        assert_eq!(
+            AbstPath::from(path).file_stem(),
+            Some(String::from("archive.tar"))
+        );
+
+        let path = "path/to/some/file";
+        assert_eq!(AbstPath::from(path).file_stem(), Some(String::from("file")));
+
+        let path = "path/to/some/file.";
+        assert_eq!(AbstPath::from(path).file_stem(), Some(String::from("file")));
+
+        let path = "path/to/some/.gitignore";
+        assert_eq!(
+            AbstPath::from(path).file_stem(),
+            Some(String::from(".gitignore"))
+        );
+
+        // Same as std::path::Path
+        for path in [
+            "file.tar.gz",
+            ".gitignore",
+            "file",
+            "file.",
+            "dir/.config.toml",
+        ] {
+            let std_path = std::path::Path::new(path);
+            assert_eq!(
+                AbstPath::from(path).file_stem(),
+                std_path
+                    .file_stem()
+                    .map(|val| val.to_string_lossy().to_string())
+            );
+        }
+
+        assert_eq!(AbstPath::empty().file_stem(), None);
+    }
+
+    fn is_hidden() {
+        assert!(AbstPath::from("path/to/.hidden").is_hidden());
+        assert!(AbstPath::from(".config.toml").is_hidden());
+        assert!(AbstPath::from("/path/to/.hidden-dir").is_hidden());
+        assert!(AbstPath::single("...").is_hidden());
+
+        // Only the last component counts
+        assert!(!AbstPath::from("path/.hidden/visible").is_hidden());
+        assert!(!AbstPath::from("path/to/visible").is_hidden());
+        assert!(!AbstPath::from("path/to/file.txt").is_hidden());
+
+        // `.` and `..` are not hidden objects
+        assert!(!AbstPath::single(".").is_hidden());
+        assert!(!AbstPath::single("..").is_hidden());
+        assert!(!AbstPath::from("path/..").is_hidden());
+        assert!(!AbstPath::from("./").is_hidden());
+        assert!(!AbstPath::empty().is_hidden());
     }
 
     fn into_iter() {
@@ -695,6 +1068,17 @@ mod tests {
             .unwrap();
             assert!(abst_symlink.0.exists());
             assert_eq!(abst_symlink.0.object_type(), Some(ObjectType::SymLink));
+
+            let fifo = path.safe_add_last("fifo");
+            assert!(!fifo.0.exists());
+            assert_eq!(fifo.0.object_type(), None);
+            let status = std::process::Command::new("mkfifo")
+                .arg(&fifo.1)
+                .status()
+                .unwrap();
+            assert!(status.success());
+            assert!(fifo.0.exists());
+            assert_eq!(fifo.0.object_type(), Some(ObjectType::Special));
         });
 
         std::fs::remove_dir_all(&path.1).unwrap();
@@ -702,7 +1086,136 @@ mod tests {
         assert!(result.is_ok())
     }
 
+    fn normalize() {
+        assert_eq!(
+            Endpoint::Unix(String::from("some\\path/to/somewhere")).normalize(),
+            Endpoint::Unix(String::from("some\\path/to/somewhere"))
+        );
+        assert_eq!(
+            Endpoint::Windows(false, String::from("some\\path\\to\\somewhere")).normalize(),
+            Endpoint::Windows(false, String::from("some/path/to/somewhere"))
+        );
+        assert_eq!(
+            Endpoint::Windows(true, String::from("..\\path/to\\somewhere")).normalize(),
+            Endpoint::Windows(true, String::from("../path/to/somewhere"))
+        );
+        assert_eq!(
+            Endpoint::Windows(true, String::from("c:\\Users\\me")).normalize(),
+            Endpoint::Windows(true, String::from("C:/Users/me"))
+        );
+        assert_eq!(
+            Endpoint::Windows(false, String::from("C:/Users/me/file")).normalize(),
+            Endpoint::Windows(false, String::from("C:/Users/me/file"))
+        );
+
+        // Dots and redundant separators are collapsed, everything else is kept
+        for (endpath, normalized) in [
+            ("./a/b", "a/b"),
+            ("a//b", "a/b"),
+            ("a/./b/.", "a/b/"),
+            ("a/b//", "a/b/"),
+            ("/./a//b", "/a/b"),
+            ("//a", "/a"),
+            ("../a/./../b", "../a/../b"),
+            (".", "."),
+            ("./", "."),
+            ("/", "/"),
+            ("/.", "/"),
+            ("a\\.\\b", "a\\.\\b"),
+        ] {
+            assert_eq!(
+                Endpoint::Unix(String::from(endpath)).normalize(),
+                Endpoint::Unix(String::from(normalized))
+            );
+        }
+        for (endpath, normalized) in [
+            (".\\a\\b", "a/b"),
+            ("a\\\\b\\.", "a/b/"),
+            ("c:\\.\\Users\\\\me", "C:/Users/me"),
+            ("C:.\\relative", "C:relative"),
+            ("\\\\server\\share\\.\\dir", "//server/share/dir"),
+            ("\\.\\root", "/root"),
+            ("..\\.\\a", "../a"),
+        ] {
+            assert_eq!(
+                Endpoint::Windows(true, String::from(endpath)).normalize(),
+                Endpoint::Windows(true, String::from(normalized))
+            );
+        }
+    }
+
+    fn native_endpath() {
+        assert_eq!(
+            Endpoint::Unix(String::from("/some/path")).native_endpath(),
+            String::from("/some/path")
+        );
+        assert_eq!(
+            Endpoint::Windows(true, String::from("C:/Users/me")).native_endpath(),
+            String::from("C:\\Users\\me")
+        );
+        assert_eq!(
+            Endpoint::Windows(false, String::from("../some/file")).native_endpath(),
+            String::from("..\\some\\file")
+        );
+    }
+
+    fn is_absolute() {
+        assert!(Endpoint::Unix(String::from("/some/path")).is_absolute());
+        assert!(!Endpoint::Unix(String::from("some/path")).is_absolute());
+        assert!(!Endpoint::Unix(String::from("C:/some/path")).is_absolute());
+        assert!(Endpoint::Windows(true, String::from("C:/some/path")).is_absolute());
+        assert!(Endpoint::Windows(true, String::from("c:\\some\\path")).is_absolute());
+        assert!(Endpoint::Windows(true, String::from("\\\\server\\share")).is_absolute());
+        assert!(!Endpoint::Windows(false, String::from("some\\path")).is_absolute());
+        assert!(!Endpoint::Windows(false, String::from("C:relative")).is_absolute());
+    }
+
     fn as_bytes() {
+        // Logically equivalent endpoints have the same representation
+        assert_eq!(
+            Endpoint::Windows(false, String::from("some\\path\\to\\somewhere")).as_bytes(),
+            Endpoint::Windows(false, String::from("some/path/to/somewhere")).as_bytes()
+        );
+        assert_eq!(
+            Endpoint::Windows(true, String::from("c:\\Users\\me")).as_bytes(),
+            Endpoint::Windows(true, String::from("C:/Users/me")).as_bytes()
+        );
+        assert_ne!(
+            Endpoint::Unix(String::from("some\\path")).as_bytes(),
+            Endpoint::Unix(String::from("some/path")).as_bytes()
+        );
+        // Equivalent paths hash the same, so they don't show up as edits
+        assert_eq!(
+            Endpoint::Unix(String::from("./a/b")).as_bytes(),
+            Endpoint::Unix(String::from("a/b")).as_bytes()
+        );
+        assert_eq!(
+            Endpoint::Unix(String::from("a//./b")).as_bytes(),
+            Endpoint::Unix(String::from("a/b")).as_bytes()
+        );
+        assert_eq!(
+            Endpoint::Windows(false, String::from(".\\a\\\\b")).as_bytes(),
+            Endpoint::Windows(false, String::from("a/b")).as_bytes()
+        );
+        // Absolute and relative endpoints stay distinct, and so do the ones that
+        //	lead elsewhere
+        assert_ne!(
+            Endpoint::Unix(String::from("/a/b")).as_bytes(),
+            Endpoint::Unix(String::from("a/b")).as_bytes()
+        );
+        assert_ne!(
+            Endpoint::Unix(String::from("./a/b")).as_bytes(),
+            Endpoint::Unix(String::from("../a/b")).as_bytes()
+        );
+        assert_ne!(
+            Endpoint::Unix(String::from("a/b/")).as_bytes(),
+            Endpoint::Unix(String::from("a/b")).as_bytes()
+        );
+        assert_ne!(
+            Endpoint::Windows(false, String::from("C:/a")).as_bytes(),
+            Endpoint::Windows(false, String::from("C:a")).as_bytes()
+        );
+
         assert_ne!(
             Endpoint::Unix(String::from("some/path/to/somewhere")).as_bytes(),
             Endpoint::Unix(String::from("path/to/somewhere/else")).as_bytes()
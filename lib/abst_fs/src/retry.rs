@@ -0,0 +1,99 @@
+use super::Error;
+
+use std::time::Duration;
+
+/// Run the operation, running it again (at most `retries` more times, waiting
+/// `delay` before each new attempt) for as long as it fails with a transient
+/// error. Non-transient errors are returned immediately
+pub fn retry_transient<T, F>(retries: u32, delay: Duration, mut operation: F) -> Result<T, Error>
+where
+    F: FnMut() -> Result<T, Error>,
+{
+    let mut attempt = 0;
+    loop {
+        match operation() {
+            Err(err) if err.is_transient() && attempt < retries => {
+                attempt += 1;
+                std::thread::sleep(delay);
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::inerr, retry_transient};
+    use std::{io::Write, time::Duration};
+
+    /// Writer failing with the given error the first `failures` times it is written to
+    struct FlakyWriter {
+        failures: u32,
+        kind: std::io::ErrorKind,
+        attempts: u32,
+        content: Vec<u8>,
+    }
+    impl FlakyWriter {
+        fn new(failures: u32, kind: std::io::ErrorKind) -> FlakyWriter {
+            FlakyWriter {
+                failures,
+                kind,
+                attempts: 0,
+                content: Vec::new(),
+            }
+        }
+    }
+    impl Write for FlakyWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.attempts += 1;
+            if self.attempts <= self.failures {
+                return Err(std::io::Error::from(self.kind));
+            }
+            self.content.write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() {
+        let delay = Duration::from_millis(1);
+        let content = b"some content";
+
+        // Succeeds on the second attempt
+        let mut writer = FlakyWriter::new(1, std::io::ErrorKind::WouldBlock);
+        let result = retry_transient(3, delay, || {
+            writer
+                .write(content)
+                .map_err(inerr("write content to flaky writer"))
+        });
+        assert_eq!(result, Ok(content.len()));
+        assert_eq!(writer.attempts, 2);
+        assert_eq!(writer.content, content);
+
+        // Gives up after the given number of retries
+        let mut writer = FlakyWriter::new(5, std::io::ErrorKind::Interrupted);
+        let result = retry_transient(3, delay, || {
+            writer
+                .write(content)
+                .map_err(inerr("write content to flaky writer"))
+        });
+        assert!(result.unwrap_err().is_transient());
+        assert_eq!(writer.attempts, 4);
+        assert!(writer.content.is_empty());
+
+        // Hard errors are not retried
+        let mut writer = FlakyWriter::new(1, std::io::ErrorKind::PermissionDenied);
+        let result = retry_transient(3, delay, || {
+            writer
+                .write(content)
+                .map_err(inerr("write content to flaky writer"))
+        });
+        assert_eq!(
+            result.unwrap_err().kind(),
+            Some(std::io::ErrorKind::PermissionDenied)
+        );
+        assert_eq!(writer.attempts, 1);
+    }
+}
@@ -1,25 +1,42 @@
-use super::{ensure_parent, error_context, generr, inerr, unkext, AbstPath, Error, ObjectType};
+use super::{
+    atomic_create_file, error_context, generr, inerr, sync_dir, unkext, AbstPath, Durability,
+    Error, ObjectType,
+};
 
+use bincode::Options;
 use serde::{de::DeserializeOwned, Serialize};
 
-#[derive(Debug, PartialEq)]
-enum Ext {
+/// Encoding of the content of a saved file. `Bin` is the compact one, `Toml` and
+/// `Json` are human readable (the latter can represent any data structure, so it is
+/// useful for debugging)
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Format {
     Bin,
     Toml,
+    Json,
 }
-fn get_ext(path: &AbstPath) -> Option<Ext> {
+fn get_format(path: &AbstPath) -> Option<Format> {
     let ext = path.extension()?;
     match ext.to_ascii_lowercase().as_str() {
-        "bin" => Some(Ext::Bin),
-        "toml" => Some(Ext::Toml),
+        "bin" => Some(Format::Bin),
+        "toml" => Some(Format::Toml),
+        "json" => Some(Format::Json),
         _ => None,
     }
 }
 
 /// Load the data from a file, interpreting the content of the file based on the
-/// extension (see [`Ext`] for the possible extensions) of the file and deserializing
-/// the content to the generic type T
+/// extension (`.bin`, `.toml` or `.json`, see [`Format`]) of the file and
+/// deserializing the content to the generic type T
 pub fn load<T: DeserializeOwned>(path: &AbstPath) -> Result<T, Error> {
+    match get_format(path) {
+        Some(format) => load_as(path, format),
+        None => Err(unkext(path)),
+    }
+}
+/// Same as `load`, but interpreting the content of the file with the given format,
+/// regardless of the extension of the file
+pub fn load_as<T: DeserializeOwned>(path: &AbstPath, format: Format) -> Result<T, Error> {
     let errctx = error_context(format!("could not load file at path {}", path));
     if !path.exists() {
         return Err(generr(errctx("open file"), "file doesn't exist"));
@@ -28,48 +45,95 @@ pub fn load<T: DeserializeOwned>(path: &AbstPath) -> Result<T, Error> {
         return Err(generr(errctx("open file"), "object at path is not a file"));
     }
 
-    match get_ext(path) {
-        Some(Ext::Toml) => {
+    match format {
+        Format::Toml => {
             let serialized = std::fs::read_to_string(path.to_path_buf())
                 .map_err(inerr(errctx("read content to string")))?;
             toml::from_str(&serialized).map_err(inerr(errctx("deserialize content from toml")))
         }
-        Some(Ext::Bin) => {
+        // The whole content must be consumed, so that data saved with a different
+        //	layout is an error instead of being silently misread
+        Format::Bin => {
+            let serialized =
+                std::fs::read(path.to_path_buf()).map_err(inerr(errctx("read content")))?;
+            bincode::DefaultOptions::new()
+                .with_fixint_encoding()
+                .reject_trailing_bytes()
+                .deserialize(&serialized)
+                .map_err(inerr(errctx("deserialize content from binary")))
+        }
+        Format::Json => {
             let file =
                 std::fs::File::open(path.to_path_buf()).map_err(inerr(errctx("open file")))?;
-            bincode::deserialize_from(file)
-                .map_err(inerr(errctx("deserialize content from binary")))
+            serde_json::from_reader(std::io::BufReader::new(file))
+                .map_err(inerr(errctx("deserialize content from json")))
         }
-        None => Err(unkext(path)),
     }
 }
 /// Save a serializable data structure of generic type T to a file, encoding the
-/// serialized data based on the extension of the file (see [`Ext`] for the possible
-/// extensions)
+/// serialized data based on the extension of the file (`.bin`, `.toml` or `.json`,
+/// see [`Format`]). The file and its parent directory are synced to the disk
+/// before returning
 pub fn save<T: Serialize>(path: &AbstPath, content: &T) -> Result<(), Error> {
+    save_with(path, content, Durability::Synced)
+}
+/// Same as `save`, but syncing the file to the disk only if asked by `durability`
+pub fn save_with<T: Serialize>(
+    path: &AbstPath,
+    content: &T,
+    durability: Durability,
+) -> Result<(), Error> {
+    match get_format(path) {
+        Some(format) => save_as_with(path, content, format, durability),
+        None => Err(unkext(path)),
+    }
+}
+/// Same as `save`, but encoding the serialized data with the given format,
+/// regardless of the extension of the file
+pub fn save_as<T: Serialize>(path: &AbstPath, content: &T, format: Format) -> Result<(), Error> {
+    save_as_with(path, content, format, Durability::Synced)
+}
+/// Same as `save_as`, but syncing the file to the disk only if asked by
+/// `durability`
+pub fn save_as_with<T: Serialize>(
+    path: &AbstPath,
+    content: &T,
+    format: Format,
+    durability: Durability,
+) -> Result<(), Error> {
     let errctx = error_context(format!("could not save file at path {}", path));
-    match get_ext(path) {
-        Some(Ext::Toml) => {
-            let serialized =
-                toml::to_string(content).map_err(inerr(errctx("serialize content to toml")))?;
-            ensure_parent(path)?;
-            std::fs::write(path.to_path_buf(), serialized)
-                .map_err(inerr(errctx("write content to file")))
+    let serialized = match format {
+        Format::Toml => toml::to_string(content)
+            .map(String::into_bytes)
+            .map_err(inerr(errctx("serialize content to toml")))?,
+        Format::Bin => {
+            bincode::serialize(content).map_err(inerr(errctx("serialize content to binary")))?
         }
-        Some(Ext::Bin) => {
-            let serialized = bincode::serialize(content)
-                .map_err(inerr(errctx("serialize content to binary")))?;
-            ensure_parent(path)?;
-            std::fs::write(path.to_path_buf(), serialized)
-                .map_err(inerr(errctx("write content to file")))
+        Format::Json => serde_json::to_string_pretty(content)
+            .map(String::into_bytes)
+            .map_err(inerr(errctx("serialize content to json")))?,
+    };
+    // The content is moved in place only once completely written, so that a
+    //	crash never leaves the file truncated
+    let mut file = atomic_create_file(path).map_err(inerr(errctx("create file")))?;
+    std::io::Write::write_all(&mut file, &serialized)
+        .map_err(inerr(errctx("write content to file")))?;
+    file.persist_with(durability)
+        .map_err(inerr(errctx("move content in place")))?;
+    if durability == Durability::Synced {
+        if let Some(parent) = path.parent() {
+            sync_dir(&parent)?;
         }
-        None => Err(unkext(path)),
     }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{get_ext, load, save, AbstPath, Ext};
+    use super::{
+        get_format, load, load_as, save, save_as, save_with, AbstPath, Durability, Format,
+    };
+    use crate::durability::SYNCS;
     use serde::{Deserialize, Serialize};
     use std::path::PathBuf;
 
@@ -134,7 +198,7 @@ mod tests {
 
         let result = std::panic::catch_unwind(|| {
             let (file_bin, _) = path.safe_add_last("file.bin");
-            assert_eq!(get_ext(&file_bin), Some(Ext::Bin));
+            assert_eq!(get_format(&file_bin), Some(Format::Bin));
             assert!(load::<TestStruct>(&file_bin).is_err());
             save(&file_bin, &TestStruct::test_default()).unwrap();
             assert_eq!(
@@ -143,7 +207,7 @@ mod tests {
             );
 
             let (file_toml, _) = path.safe_add_last("file.toml");
-            assert_eq!(get_ext(&file_toml), Some(Ext::Toml));
+            assert_eq!(get_format(&file_toml), Some(Format::Toml));
             assert!(load::<TestStruct>(&file_toml).is_err());
             save(&file_toml, &TestStruct::test_default()).unwrap();
             assert_eq!(
@@ -151,8 +215,42 @@ mod tests {
                 TestStruct::test_default()
             );
 
+            let (file_json, _) = path.safe_add_last("file.json");
+            assert_eq!(get_format(&file_json), Some(Format::Json));
+            assert!(load::<TestStruct>(&file_json).is_err());
+            save(&file_json, &TestStruct::test_default()).unwrap();
+            assert_eq!(
+                load::<TestStruct>(&file_json).unwrap(),
+                TestStruct::test_default()
+            );
+            // Binary content with anything left over is not what was asked for
+            let mut trailing = bincode::serialize(&TestStruct::test_default()).unwrap();
+            trailing.push(0);
+            std::fs::write(file_bin.to_path_buf(), trailing).unwrap();
+            assert!(load::<TestStruct>(&file_bin).is_err());
+            save(&file_bin, &TestStruct::test_default()).unwrap();
+
+            // The binary encoding is the most compact
+            assert!(
+                std::fs::metadata(file_bin.to_path_buf()).unwrap().len()
+                    < std::fs::metadata(file_json.to_path_buf()).unwrap().len()
+            );
+
+            // An explicit format overrides the extension
+            let (file_dat, _) = path.safe_add_last("file.dat");
+            assert_eq!(get_format(&file_dat), None);
+            for format in [Format::Bin, Format::Toml, Format::Json] {
+                save_as(&file_dat, &TestStruct::test_default(), format).unwrap();
+                assert_eq!(
+                    load_as::<TestStruct>(&file_dat, format).unwrap(),
+                    TestStruct::test_default()
+                );
+            }
+            assert!(load::<TestStruct>(&file_dat).is_err());
+            assert!(load_as::<TestStruct>(&file_toml, Format::Json).is_err());
+
             let (file_txt, file_txt_pb) = path.safe_add_last("file.txt");
-            assert_eq!(get_ext(&file_txt), None);
+            assert_eq!(get_format(&file_txt), None);
             assert!(load::<TestStruct>(&file_txt).is_err());
             assert!(save(&file_txt, &TestStruct::test_default()).is_err());
             std::fs::write(
@@ -163,14 +261,14 @@ mod tests {
             assert!(load::<TestStruct>(&file_txt).is_err());
 
             let (symlink, symlink_pb) = path.safe_add_last("symlink");
-            assert_eq!(get_ext(&symlink), None);
+            assert_eq!(get_format(&symlink), None);
             assert!(load::<TestStruct>(&symlink).is_err());
             assert!(save(&symlink, &TestStruct::test_default()).is_err());
             std::os::unix::fs::symlink("some/path/to/somewhere", symlink_pb).unwrap();
             assert!(load::<TestStruct>(&symlink).is_err());
 
             let (extensionless_file, _) = path.safe_add_last("extensionless_file");
-            assert_eq!(get_ext(&extensionless_file), None);
+            assert_eq!(get_format(&extensionless_file), None);
             assert!(load::<TestStruct>(&extensionless_file).is_err());
             assert!(save(&extensionless_file, &TestStruct::test_default()).is_err());
             std::fs::write(
@@ -180,8 +278,47 @@ mod tests {
             .unwrap();
             assert!(load::<TestStruct>(&extensionless_file).is_err());
 
+            // Malformed content is an error in every format
+            for (name, content) in [
+                ("malformed.bin", "\u{1}\u{2}".as_bytes()),
+                ("malformed.toml", "byte = [unclosed".as_bytes()),
+                ("malformed.json", "{\"byte\": 0,".as_bytes()),
+                ("mismatched.json", "{\"byte\": \"not a byte\"}".as_bytes()),
+                ("empty.bin", "".as_bytes()),
+            ] {
+                let (malformed, malformed_pb) = path.safe_add_last(name);
+                std::fs::write(malformed_pb, content).unwrap();
+                assert!(load::<TestStruct>(&malformed).is_err());
+            }
+
+            // Saving syncs the file and its parent directory, unless disabled
+            let (durable, _) = path.safe_add_last("durable.bin");
+            let syncs = || SYNCS.with(|syncs| syncs.get());
+            let before = syncs();
+            save(&durable, &TestStruct::test_default()).unwrap();
+            assert_eq!(syncs(), before + 2);
+            save_with(&durable, &TestStruct::test_default(), Durability::Unsynced).unwrap();
+            assert_eq!(syncs(), before + 2);
+            assert_eq!(
+                load::<TestStruct>(&durable).unwrap(),
+                TestStruct::test_default()
+            );
+            assert_eq!(Durability::default(), Durability::Synced);
+
+            // Saving over a file replaces it as a whole, through a temporary
+            //	sibling that doesn't outlive the save
+            let (replaced, _) = path.safe_add_last("replaced.json");
+            save(&replaced, &vec![0u8; 1000]).unwrap();
+            save(&replaced, &TestStruct::test_default()).unwrap();
+            assert_eq!(
+                load::<TestStruct>(&replaced).unwrap(),
+                TestStruct::test_default()
+            );
+            let (temp, _) = path.safe_add_last(".replaced.json.bbup-tmp");
+            assert!(!temp.exists());
+
             let (non_existing_file, _) = path.safe_add_last("non_existing_file.bin");
-            assert_eq!(get_ext(&non_existing_file), Some(Ext::Bin));
+            assert_eq!(get_format(&non_existing_file), Some(Format::Bin));
             assert!(load::<TestStruct>(&non_existing_file).is_err());
         });
 
@@ -6,10 +6,14 @@ use super::{
 
 pub const ABST_OBJ_HEADER: &str = "[[bbup abstract symlink object]]";
 
-/// Create a symbolic link
+/// Create a symbolic link. Endpoints of a different os than the current one can't
+/// be turned into actual symlinks, so they are stored as abstract symlinks (a file
+/// describing the endpoint), which `read_link` reads back as the same endpoint.<br>
+/// The endpoint is normalized before being written (see `Endpoint::normalize`)
 pub fn create_symlink(path: &AbstPath, endpoint: Endpoint) -> Result<(), Error> {
     let errctx = error_context(format!("could not create symlink at path {path}"));
     ensure_parent(path).map_err(inerr(errctx("ensure parent directory")))?;
+    let endpoint = endpoint.normalize();
 
     #[cfg(unix)]
     match endpoint {
@@ -30,7 +34,8 @@ pub fn create_symlink(path: &AbstPath, endpoint: Endpoint) -> Result<(), Error>
 
     #[cfg(windows)]
     match endpoint {
-        Endpoint::Windows(is_dir, endpath) => {
+        Endpoint::Windows(is_dir, _) => {
+            let endpath = endpoint.native_endpath();
             match is_dir {
                 true => std::os::windows::fs::symlink_dir(&endpath, path.to_path_buf())
                     .map_err(inerr(errctx("create windows dir symlink")))?,
@@ -50,7 +55,8 @@ pub fn create_symlink(path: &AbstPath, endpoint: Endpoint) -> Result<(), Error>
     Ok(())
 }
 
-/// Attempts to read the endpoint link of a symlink
+/// Attempts to read the endpoint link of a symlink, either actual or abstract. The
+/// endpoint is returned normalized (see `Endpoint::normalize`)
 pub fn read_link(path: &AbstPath) -> Result<Endpoint, Error> {
     use super::path::ForceToString;
 
@@ -89,7 +95,7 @@ pub fn read_link(path: &AbstPath) -> Result<Endpoint, Error> {
                 .map_err(inerr(errctx("read endpoint of actual symlink")))?;
 
             let is_dir = (metadata.file_attributes() & 16) == 16;
-            Ok(Endpoint::Windows(is_dir, endpath.force_to_string()))
+            Ok(Endpoint::Windows(is_dir, endpath.force_to_string()).normalize())
         }
         false => {
             let abstract_symlink = std::fs::File::open(path.to_path_buf())
@@ -136,7 +142,7 @@ pub fn read_link(path: &AbstPath) -> Result<Endpoint, Error> {
                     reader
                         .read_line(&mut endpath)
                         .map_err(inerr(errctx("read abstract symlink endpath")))?;
-                    Ok(Endpoint::Windows(is_dir, endpath))
+                    Ok(Endpoint::Windows(is_dir, endpath).normalize())
                 }
                 val => Err(generr(
                     errmsg + "\nInvalid os",
@@ -171,9 +177,9 @@ pub fn remove_symlink(path: &AbstPath) -> Result<(), Error> {
     std::fs::remove_file(path.to_path_buf()).map_err(inerr(errctx("remove file")))
 }
 
-/// Attempts to move a symlink from a specified position to a specified position (does
-/// not copy, only attempts to move), creating the necessary subdirectories if
-/// needed.<br>
+/// Attempts to move a symlink from a specified position to a specified position,
+/// creating the necessary subdirectories if needed. If the two positions are on
+/// different file systems, the symlink is recreated and the original is removed.<br>
 /// Note that this works as expected on the symlink's endpoint: it renames the symlink at
 /// the specified path, preserving the endpoint path (which might not work anymore if it
 /// was a relative path), and does NOT move the object pointed by the symlink at the
@@ -197,7 +203,15 @@ pub fn rename_symlink(from: &AbstPath, to: &AbstPath) -> Result<(), Error> {
         }
     }
     ensure_parent(to).map_err(inerr(errctx("ensure parent directory")))?;
-    std::fs::rename(from.to_path_buf(), to.to_path_buf()).map_err(inerr(errctx("rename object")))
+    match std::fs::rename(from.to_path_buf(), to.to_path_buf()) {
+        // A symlink can't be renamed across file systems, but it can be recreated
+        Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => {
+            let endpoint = read_link(from).map_err(inerr(errctx("read endpoint of object")))?;
+            create_symlink(to, endpoint).map_err(inerr(errctx("recreate object")))?;
+            remove_symlink(from).map_err(inerr(errctx("remove original object")))
+        }
+        result => result.map_err(inerr(errctx("rename object"))),
+    }
 }
 
 fn trim_newline(s: &mut String) {
@@ -314,6 +328,31 @@ mod tests {
             assert_eq!(read_link(&symlink).unwrap(), windows_dir_endpoint);
             remove_symlink(&symlink).unwrap();
 
+            // Round trips of relative and absolute endpoints of both kinds
+            for endpoint in [
+                Endpoint::Unix(String::from("../relative/path")),
+                Endpoint::Unix(String::from("/absolute/path")),
+                Endpoint::Windows(false, String::from("..\\relative\\file")),
+                Endpoint::Windows(true, String::from("c:\\absolute\\directory")),
+                Endpoint::Windows(true, String::from("\\\\server\\share")),
+            ] {
+                create_symlink(&symlink, endpoint.clone()).unwrap();
+                let read = read_link(&symlink).unwrap();
+                assert_eq!(read, endpoint.clone().normalize());
+                assert_eq!(read.as_bytes(), endpoint.as_bytes());
+                assert_eq!(read.is_absolute(), endpoint.is_absolute());
+                remove_symlink(&symlink).unwrap();
+            }
+
+            // Abstract symlinks written with any separator are read normalized
+            std::fs::write(
+                &symlink_pb,
+                format!("{ABST_OBJ_HEADER}\nwindows\nfile\nsome\\path\\to\\some\\file"),
+            )
+            .unwrap();
+            assert_eq!(read_link(&symlink).unwrap(), windows_file_endpoint);
+            remove_symlink(&symlink).unwrap();
+
             let (other_symlink, _) = path.safe_add_last("other_symlink");
             create_symlink(&symlink, unix_endpoint).unwrap();
             rename_symlink(&symlink, &other_symlink).unwrap();
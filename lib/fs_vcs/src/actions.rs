@@ -1,10 +1,12 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-use abst_fs::{AbstPath, Mtime};
+use abst_fs::{AbstPath, Mode, Mtime};
 
 use hasher::Hash;
 
-use super::{Delta, DeltaNode, FSNode, FSTree};
+use serde::{Deserialize, Serialize};
+
+use super::{hash_tree, Delta, DeltaNode, FSNode, FSTree, HardLinkGroups};
 
 #[allow(clippy::large_enum_variant)]
 #[derive(PartialEq, Debug)]
@@ -20,28 +22,130 @@ impl Conflicts {
         let conflicts = HashMap::new();
         Conflicts(conflicts)
     }
+
+    /// Full paths of all the conflicting objects, sorted
+    pub fn paths(&self) -> Vec<AbstPath> {
+        let mut paths = Vec::new();
+        self.paths_rec(&AbstPath::empty(), &mut paths);
+        paths.sort_by_key(|path| path.to_string());
+        paths
+    }
+    fn paths_rec(&self, prefix: &AbstPath, paths: &mut Vec<AbstPath>) {
+        let Conflicts(conflicts) = self;
+        for (name, node) in conflicts {
+            match node {
+                ConflictNode::Leaf(_, _) => paths.push(prefix.add_last(name)),
+                ConflictNode::Branch(subconflicts) => {
+                    subconflicts.paths_rec(&prefix.add_last(name), paths)
+                }
+            }
+        }
+    }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+/// How to resolve a conflict between the local delta and the missed delta
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictStrategy {
+    /// Keep the local version of the conflicting object, which will then be
+    /// pushed over the missed version
+    PreferLocal,
+    /// Replace the local version of the conflicting object with the missed
+    /// version, discarding the local changes
+    PreferRemote,
+    /// Do not resolve the conflict
+    Abort,
+    /// Replace the local version of a file whose content conflicts with the
+    /// missed version, keeping a copy of the local version next to it, named
+    /// after the file and the time the conflict is resolved (see
+    /// `conflict_copy_name`). Other conflicts are not resolved
+    KeepBoth,
+}
+impl std::str::FromStr for ConflictStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "prefer-local" => Ok(ConflictStrategy::PreferLocal),
+            "prefer-remote" => Ok(ConflictStrategy::PreferRemote),
+            "abort" => Ok(ConflictStrategy::Abort),
+            "keep-both" => Ok(ConflictStrategy::KeepBoth),
+            _ => Err(format!(
+                "invalid conflict strategy '{s}', expected one of: prefer-local, prefer-remote, abort, keep-both"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Action {
     AddDir,
-    AddFile(Mtime, Hash),
+    AddFile(Mtime, Hash, Mode),
     AddSymLink(Mtime, Hash),
+    /// Create a hard link to the file at the given path, relative to the same root
+    AddHardLink(AbstPath),
+    /// Move the file at the given path, relative to the same root, which has the
+    /// same content as the added file
+    Move(AbstPath, Mtime, Hash, Mode),
+    /// Copy the file at the given path, relative to the same root, which has the
+    /// same content as the added file. The copy is made before anything is added
+    /// or edited, so it is a copy of the file as it was before the actions
+    Copy(AbstPath, Mtime, Hash, Mode),
     EditDir(Mtime),
-    EditFile(Option<Mtime>, Option<Hash>),
+    EditFile(Option<Mtime>, Option<Hash>, Option<Mode>),
     EditSymLink(Option<Mtime>, Option<Hash>),
     RemoveDir,
     RemoveFile,
     RemoveSymLink,
 }
+impl Action {
+    /// Whether the action only creates new objects, never deleting nor
+    /// overwriting anything already existing. Directories created to hold new
+    /// objects get their mtime set, so editing the mtime of a directory counts
+    /// as well
+    pub fn is_addition(&self) -> bool {
+        matches!(
+            self,
+            Action::AddDir
+                | Action::AddFile(..)
+                | Action::AddSymLink(..)
+                | Action::AddHardLink(..)
+                | Action::Copy(..)
+                | Action::EditDir(..)
+        )
+    }
+    /// Short name of the kind of action, such as `add-file`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Action::AddDir => "add-dir",
+            Action::AddFile(..) => "add-file",
+            Action::AddSymLink(..) => "add-symlink",
+            Action::AddHardLink(..) => "add-hardlink",
+            Action::Move(..) => "move-file",
+            Action::Copy(..) => "copy-file",
+            Action::EditDir(..) => "edit-dir",
+            Action::EditFile(..) => "edit-file",
+            Action::EditSymLink(..) => "edit-symlink",
+            Action::RemoveDir => "remove-dir",
+            Action::RemoveFile => "remove-file",
+            Action::RemoveSymLink => "remove-symlink",
+        }
+    }
+}
+
+/// Work of an operation that only touches metadata (creating a directory or a
+/// symlink, moving or removing an object, setting an mtime), counted as the bytes
+/// of a small write, so that many such operations weigh against the content of
+/// the files written
+pub const METADATA_WORK: u64 = 4096;
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Actions(Vec<(AbstPath, Action)>);
 impl PartialEq for Actions {
     fn eq(&self, other: &Self) -> bool {
         self.0.len() == other.0.len()
-            && (&self.0).iter().all(|(path, action)| {
-                (&other.0)
+            && self.0.iter().all(|(path, action)| {
+                other
+                    .0
                     .iter()
                     .any(|(other_path, other_action)| path == other_path && action == other_action)
             })
@@ -53,6 +157,10 @@ impl Actions {
     pub fn new() -> Actions {
         Actions(Vec::new())
     }
+    pub fn is_empty(&self) -> bool {
+        let Actions(vec) = self;
+        vec.is_empty()
+    }
     pub fn push(&mut self, path: AbstPath, action: Action) {
         let Actions(vec) = self;
         vec.push((path, action));
@@ -61,14 +169,171 @@ impl Actions {
         let Actions(vec) = self;
         vec.append(appendix);
     }
+    /// Prefix the paths of the actions, as well as the paths of the objects
+    /// they refer to, which are relative to the same root
     pub fn add_prefix<S: std::string::ToString>(self, prefix: S) -> Actions {
         let Actions(vec) = self;
+        let prefix = prefix.to_string();
         Actions(
             vec.into_iter()
-                .map(|(path, action)| (path.add_first(prefix.to_string()), action))
+                .map(|(path, action)| {
+                    let action = match action {
+                        Action::AddHardLink(original) => {
+                            Action::AddHardLink(original.add_first(&prefix))
+                        }
+                        Action::Move(from, mtime, hash, mode) => {
+                            Action::Move(from.add_first(&prefix), mtime, hash, mode)
+                        }
+                        Action::Copy(from, mtime, hash, mode) => {
+                            Action::Copy(from.add_first(&prefix), mtime, hash, mode)
+                        }
+                        action => action,
+                    };
+                    (path.add_first(&prefix), action)
+                })
                 .collect(),
         )
     }
+
+    /// Reorder the actions so that they can be safely applied one after the
+    /// other: removals come first (children before their parent, and before
+    /// anything is added in their place), then copies (while the copied files
+    /// are still untouched), then additions and edits (parents
+    /// before their children), and lastly the mtimes of the directories are set
+    /// (children before their parent), once their content won't change anymore.
+    /// Files are moved after the additions, so that the directories they are moved
+    /// to already exist, and hard links are created after that, so that the files
+    /// they link to already exist.
+    /// Actions of the same kind and depth keep their relative order
+    pub fn sort_for_apply(&mut self) {
+        let Actions(vec) = self;
+        vec.sort_by_key(|(path, action)| {
+            let depth = path.depth() as i64;
+            match action {
+                Action::RemoveDir | Action::RemoveFile | Action::RemoveSymLink => (0, -depth),
+                Action::Copy(..) => (1, depth),
+                Action::AddDir
+                | Action::AddFile(..)
+                | Action::AddSymLink(..)
+                | Action::EditFile(..)
+                | Action::EditSymLink(..) => (2, depth),
+                Action::Move(..) => (3, depth),
+                Action::AddHardLink(..) => (4, depth),
+                Action::EditDir(..) => (5, -depth),
+            }
+        });
+    }
+
+    /// Drop every action that is not an addition (see [`Action::is_addition`]),
+    /// so that applying the actions never deletes nor overwrites anything
+    pub fn retain_additions(&mut self) {
+        let Actions(vec) = self;
+        vec.retain(|(_, action)| action.is_addition());
+    }
+
+    /// Work needed to apply each action, in the order of the actions: the size of
+    /// the content written by the action, if any, plus `METADATA_WORK`. The sizes
+    /// are taken from `tree`, the tree the actions lead to, and files whose size
+    /// is unknown count as empty
+    pub fn work(&self, tree: &FSTree) -> Vec<u64> {
+        let Actions(vec) = self;
+        vec.iter()
+            .map(|(path, action)| {
+                let writes_content = matches!(
+                    action,
+                    Action::AddFile(..) | Action::EditFile(_, Some(_), _) | Action::Copy(..)
+                );
+                let size = match tree.get_at(path) {
+                    Some(FSNode::File(_, _, _, Some(size))) if writes_content => *size,
+                    _ => 0,
+                };
+                size + METADATA_WORK
+            })
+            .collect()
+    }
+
+    /// Directories (relative to the same root as the actions) whose entries change
+    /// when the actions are applied: the parents of the objects added, edited or
+    /// removed, and of the files moved away. Each directory is listed once
+    pub fn parent_dirs(&self) -> Vec<AbstPath> {
+        let Actions(vec) = self;
+        let mut dirs = BTreeMap::new();
+        for (path, action) in vec {
+            let moved_from = match action {
+                Action::Move(from, ..) => Some(from),
+                _ => None,
+            };
+            for path in std::iter::once(path).chain(moved_from) {
+                if let Some(parent) = path.parent() {
+                    dirs.insert(parent.to_string(), parent);
+                }
+            }
+        }
+        dirs.into_values().collect()
+    }
+
+    /// Replace the additions of files that are hard links to the same object with
+    /// the addition of the first of them and the creation of hard links to it for
+    /// the others, so that their content is transferred and stored only once
+    pub fn link_hardlinks(&mut self, HardLinkGroups(groups): &HardLinkGroups) {
+        let Actions(vec) = self;
+        for group in groups {
+            let mut original: Option<(AbstPath, Hash, Mode)> = None;
+            for (path, action) in vec.iter_mut() {
+                if !group.contains(path) {
+                    continue;
+                }
+                let (hash, mode) = match action {
+                    Action::AddFile(_, hash, mode) => (hash.clone(), *mode),
+                    _ => continue,
+                };
+                match &original {
+                    Some((original_path, original_hash, original_mode)) => {
+                        if *original_hash == hash && *original_mode == mode {
+                            *action = Action::AddHardLink(original_path.clone());
+                        }
+                    }
+                    None => original = Some((path.clone(), hash, mode)),
+                }
+            }
+        }
+    }
+
+    /// Replace the additions of files with the same content as a file removed by
+    /// `delta` with a move of the removed file, dropping its removal, so that the
+    /// content doesn't have to be transferred again. Only files removed from a
+    /// directory that is kept are moved, as the directory containing them could
+    /// otherwise be removed before they are moved out of it.
+    /// As a move changes the content of two directories, the mtimes of the
+    /// directories are then set after everything else
+    pub fn detect_moves(&mut self, delta: &Delta) {
+        let Actions(vec) = self;
+        let mut removed = Vec::new();
+        delta.removed_files(&AbstPath::empty(), &mut removed);
+        removed.retain(|(path, _)| {
+            vec.iter()
+                .any(|(other, action)| other == path && *action == Action::RemoveFile)
+        });
+
+        let mut moved = Vec::new();
+        for (_, action) in vec.iter_mut() {
+            let (mtime, hash, mode) = match action {
+                Action::AddFile(mtime, hash, mode) => (mtime, hash, mode),
+                _ => continue,
+            };
+            let index = match removed.iter().position(|(_, other)| other == hash) {
+                Some(index) => index,
+                None => continue,
+            };
+            let (from, _) = removed.swap_remove(index);
+            moved.push(from.clone());
+            *action = Action::Move(from, mtime.clone(), hash.clone(), *mode);
+        }
+        vec.retain(|(path, action)| !(*action == Action::RemoveFile && moved.contains(path)));
+        if !moved.is_empty() {
+            vec.sort_by_key(|(_, action)| matches!(action, Action::EditDir(_)));
+        }
+    }
 }
 impl IntoIterator for Actions {
     type Item = (AbstPath, Action);
@@ -95,9 +360,9 @@ impl FSNode {
     fn to_add_actions(&self) -> Actions {
         let mut actions = Actions::new();
         match self {
-            FSNode::File(mtime, hash) => actions.push(
+            FSNode::File(mtime, hash, mode, _) => actions.push(
                 AbstPath::empty(),
-                Action::AddFile(mtime.clone(), hash.clone()),
+                Action::AddFile(mtime.clone(), hash.clone(), *mode),
             ),
             FSNode::SymLink(mtime, hash) => actions.push(
                 AbstPath::empty(),
@@ -125,6 +390,23 @@ impl FSTree {
 }
 
 impl Delta {
+    /// Files removed by the delta from a directory that is not removed, together
+    /// with their hash
+    fn removed_files(&self, prefix: &AbstPath, removed: &mut Vec<(AbstPath, Hash)>) {
+        let Delta(delta) = self;
+        for (name, child) in delta {
+            match child {
+                DeltaNode::Leaf(Some(FSNode::File(_, hash, _, _)), None) => {
+                    removed.push((prefix.add_last(name), hash.clone()))
+                }
+                DeltaNode::Branch(_, subdelta) => {
+                    subdelta.removed_files(&prefix.add_last(name), removed)
+                }
+                DeltaNode::Leaf(_, _) => {}
+            }
+        }
+    }
+
     /// Convert a delta into a series of actions to be performed on the file
     /// system in order to actually apply the delta on the file system and not
     /// just virtually on the fstree
@@ -153,11 +435,21 @@ impl DeltaNode {
                 // TODO maybe make these errors better?
                 panic!("trying to flat an unshaken delta");
             }
-            DeltaNode::Leaf(Some(FSNode::File(m0, h0)), Some(FSNode::File(m1, h1))) => {
+            DeltaNode::Leaf(
+                Some(FSNode::File(m0, h0, p0, _)),
+                Some(FSNode::File(m1, h1, p1, _)),
+            ) => {
                 let optm = if m0.ne(m1) { Some(m1.clone()) } else { None };
                 let opth = if h0.ne(h1) { Some(h1.clone()) } else { None };
-                if optm.is_some() || opth.is_some() {
-                    actions.push(AbstPath::empty(), Action::EditFile(optm, opth));
+                // When the content changes the file is replaced, so the mode has
+                //	to be set again even if it didn't change
+                let optp = if p0.ne(p1) || h0.ne(h1) {
+                    Some(*p1)
+                } else {
+                    None
+                };
+                if optm.is_some() || opth.is_some() || optp.is_some() {
+                    actions.push(AbstPath::empty(), Action::EditFile(optm, opth, optp));
                 } else {
                     // TODO maybe make these errors better?
                     panic!("trying to flat an unshaken delta");
@@ -175,7 +467,9 @@ impl DeltaNode {
             }
             DeltaNode::Leaf(pre, post) => {
                 match pre {
-                    Some(FSNode::File(_, _)) => actions.push(AbstPath::empty(), Action::RemoveFile),
+                    Some(FSNode::File(_, _, _, _)) => {
+                        actions.push(AbstPath::empty(), Action::RemoveFile)
+                    }
                     Some(FSNode::SymLink(_, _)) => {
                         actions.push(AbstPath::empty(), Action::RemoveSymLink)
                     }
@@ -189,10 +483,10 @@ impl DeltaNode {
                     None => {}
                 }
                 match post {
-                    Some(FSNode::File(mtime, hash)) => {
+                    Some(FSNode::File(mtime, hash, mode, _)) => {
                         actions.push(
                             AbstPath::empty(),
-                            Action::AddFile(mtime.clone(), hash.clone()),
+                            Action::AddFile(mtime.clone(), hash.clone(), *mode),
                         );
                     }
                     Some(FSNode::SymLink(mtime, hash)) => {
@@ -231,14 +525,23 @@ fn add_tree_actions_or_conflicts(
                 let mut add_child_actions = miss_child.to_add_actions().add_prefix(name);
                 necessary_actions.append(&mut add_child_actions);
             }
-            (Some(FSNode::File(loc_mtime, loc_hash)), FSNode::File(miss_mtime, miss_hash))
-                if miss_hash == loc_hash =>
-            {
-                if miss_mtime != loc_mtime {
-                    necessary_actions.push(
-                        AbstPath::single(name),
-                        Action::EditFile(Some(miss_mtime.clone()), None),
-                    );
+            (
+                Some(FSNode::File(loc_mtime, loc_hash, loc_mode, _)),
+                FSNode::File(miss_mtime, miss_hash, miss_mode, _),
+            ) if miss_hash == loc_hash => {
+                if miss_mtime != loc_mtime || miss_mode != loc_mode {
+                    let optm = if miss_mtime != loc_mtime {
+                        Some(miss_mtime.clone())
+                    } else {
+                        None
+                    };
+                    let optp = if miss_mode != loc_mode {
+                        Some(*miss_mode)
+                    } else {
+                        None
+                    };
+                    necessary_actions
+                        .push(AbstPath::single(name), Action::EditFile(optm, None, optp));
                 }
             }
             (
@@ -252,18 +555,32 @@ fn add_tree_actions_or_conflicts(
                     );
                 }
             }
-            (Some(FSNode::Dir(_, _, loc_subtree)), FSNode::Dir(miss_mtime, _, miss_subtree)) => {
+            (
+                Some(FSNode::Dir(loc_mtime, loc_hash, loc_subtree)),
+                FSNode::Dir(miss_mtime, miss_hash, miss_subtree),
+            ) => {
+                // Equal hashes mean equal content, so there is nothing to add
+                //	and at most the mtime to fix
+                if loc_hash == miss_hash {
+                    if loc_mtime != miss_mtime {
+                        necessary_actions
+                            .push(AbstPath::single(name), Action::EditDir(miss_mtime.clone()));
+                    }
+                    continue;
+                }
                 let subadd = add_tree_actions_or_conflicts(loc_subtree, miss_subtree);
                 match subadd {
                     Ok(subactions) => {
-                        necessary_actions.append(&mut subactions.add_prefix(name));
-
                         // IMPORTANT: this edit dir action is necessary even if
-                        //	loc_mtime == miss_mtime, because the subactions executed
-                        //	before this will probably change the actual mtime of
+                        //	loc_mtime == miss_mtime when there are subactions,
+                        //	because they will probably change the actual mtime of
                         //	the directory on the file system
-                        necessary_actions
-                            .push(AbstPath::single(name), Action::EditDir(miss_mtime.clone()));
+                        let edit_dir = !subactions.is_empty() || loc_mtime != miss_mtime;
+                        necessary_actions.append(&mut subactions.add_prefix(name));
+                        if edit_dir {
+                            necessary_actions
+                                .push(AbstPath::single(name), Action::EditDir(miss_mtime.clone()));
+                        }
                     }
                     Err(()) => return Err(()),
                 }
@@ -274,6 +591,84 @@ fn add_tree_actions_or_conflicts(
     Ok(necessary_actions)
 }
 
+/// State of a conflicting object after the delta node was applied. If the node is
+/// a branch, the previous state of the directory is taken from the other node of
+/// the conflict
+fn post_state(node: &DeltaNode, other: &DeltaNode) -> Result<Option<FSNode>, ()> {
+    match (node, other) {
+        (DeltaNode::Leaf(_, post), _) => Ok(post.clone()),
+        (
+            DeltaNode::Branch(optm, subdelta),
+            DeltaNode::Leaf(Some(FSNode::Dir(mtime, _, subtree)), _),
+        ) => {
+            let mut subtree = subtree.clone();
+            subtree.apply_delta(subdelta).map_err(|_| ())?;
+            let mtime = match optm {
+                Some((_, postmtime)) => postmtime.clone(),
+                None => mtime.clone(),
+            };
+            let hash = hash_tree(&subtree);
+            Ok(Some(FSNode::Dir(mtime, hash, subtree)))
+        }
+        _ => Err(()),
+    }
+}
+
+/// Try to resolve the conflict on the object `name` according to the strategy,
+/// returning the actions needed to resolve it or `Err(())` if it can't be
+/// resolved. Conflict copies are named after `timestamp`
+fn resolve_conflict(
+    name: &str,
+    loc_node: &DeltaNode,
+    miss_node: &DeltaNode,
+    strategy: ConflictStrategy,
+    timestamp: i64,
+) -> Result<Actions, ()> {
+    match strategy {
+        ConflictStrategy::Abort => Err(()),
+        // The local version is already on the file system
+        ConflictStrategy::PreferLocal => Ok(Actions::new()),
+        ConflictStrategy::PreferRemote => {
+            let loc_post = post_state(loc_node, miss_node)?;
+            let miss_post = post_state(miss_node, loc_node)?;
+            let mut replace = Delta(HashMap::from([(
+                name.to_string(),
+                DeltaNode::Leaf(loc_post, miss_post),
+            )]));
+            replace.shake();
+            Ok(replace.to_actions())
+        }
+        ConflictStrategy::KeepBoth => match (loc_node, miss_node) {
+            (
+                DeltaNode::Leaf(_, Some(FSNode::File(mtime, hash, mode, _))),
+                DeltaNode::Leaf(_, Some(FSNode::File(..))),
+            ) => {
+                // The local version is copied before being replaced
+                let mut actions = Actions::new();
+                actions.push(
+                    AbstPath::single(conflict_copy_name(name, timestamp)),
+                    Action::Copy(AbstPath::single(name), mtime.clone(), hash.clone(), *mode),
+                );
+                actions.append(&mut resolve_conflict(
+                    name,
+                    loc_node,
+                    miss_node,
+                    ConflictStrategy::PreferRemote,
+                    timestamp,
+                )?);
+                Ok(actions)
+            }
+            _ => Err(()),
+        },
+    }
+}
+
+/// Name of the copy of the local version of the file `name` kept by the
+/// `KeepBoth` strategy when resolving a conflict at `timestamp`
+pub fn conflict_copy_name(name: &str, timestamp: i64) -> String {
+    format!("{name}.conflict-{timestamp}")
+}
+
 /// Calculates the necessary updates for a missed delta, given the local delta.
 ///
 /// This function has the only purpuse to resolve only the following situation:
@@ -328,10 +723,47 @@ fn add_tree_actions_or_conflicts(
 /// efficency
 ///
 /// This function returns `Ok(necessary_actions)` if there is no conflict,
-/// otherwise `Err(conflicts)`
-pub fn get_actions_or_conflicts(
+/// otherwise `Err(conflicts)`. It is equivalent to
+/// `get_actions_with_strategy` with the `ConflictStrategy::Abort` strategy
+pub fn get_actions_or_conflicts(local: &Delta, missed: &Delta) -> Result<Actions, Conflicts> {
+    get_actions_with_strategy(local, missed, ConflictStrategy::Abort)
+}
+
+/// Same as `get_actions_or_conflicts`, but conflicts are resolved according to
+/// the strategy instead of being returned:
+/// - `PreferLocal` keeps the local version of the conflicting objects, so that
+///   the local version will be pushed over the missed one;
+/// - `PreferRemote` adds the actions needed to replace the local version of the
+///   conflicting objects with the missed version;
+/// - `KeepBoth` replaces the local version of the files whose content conflicts
+///   with the missed version, like `PreferRemote`, but first copies the local
+///   version next to them, leaving the other conflicts unresolved;
+/// - `Abort` doesn't resolve any conflict, and behaves exactly like
+///   `get_actions_or_conflicts`.
+///
+/// Conflicts are resolved on the whole conflicting object, so if two
+/// directories are added with conflicting content, the whole directory of the
+/// chosen side wins. The copies kept by `KeepBoth` are named after the current
+/// time
+pub fn get_actions_with_strategy(
+    local: &Delta,
+    missed: &Delta,
+    strategy: ConflictStrategy,
+) -> Result<Actions, Conflicts> {
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or_default();
+    get_actions_with_strategy_at(local, missed, strategy, timestamp)
+}
+
+/// Same as `get_actions_with_strategy`, naming the copies kept by `KeepBoth`
+/// after `timestamp` instead of the current time
+pub fn get_actions_with_strategy_at(
     Delta(local): &Delta,
     Delta(missed): &Delta,
+    strategy: ConflictStrategy,
+    timestamp: i64,
 ) -> Result<Actions, Conflicts> {
     let mut necessary_actions = Actions::new();
     let mut conflicts: HashMap<String, ConflictNode> = HashMap::new();
@@ -352,17 +784,33 @@ pub fn get_actions_or_conflicts(
                 //	optionally the mtime), recurse on the content of the
                 //	directory and set as final mtime the one from missed delta
                 (
-                    DeltaNode::Branch(_, loc_subdelta),
+                    DeltaNode::Branch(loc_optm, loc_subdelta),
                     DeltaNode::Branch(miss_optm, miss_subdelta),
                 ) => {
-                    match get_actions_or_conflicts(loc_subdelta, miss_subdelta) {
+                    match get_actions_with_strategy_at(
+                        loc_subdelta,
+                        miss_subdelta,
+                        strategy,
+                        timestamp,
+                    ) {
                         Ok(subnecessary) => {
+                            // The mtime is already right if the local delta set
+                            //	the same one and nothing else is done in the
+                            //	directory
+                            let edit_dir = match (loc_optm, miss_optm) {
+                                (_, None) => None,
+                                (Some((_, loc_postmtime)), Some((_, miss_postmtime)))
+                                    if loc_postmtime == miss_postmtime
+                                        && subnecessary.is_empty() =>
+                                {
+                                    None
+                                }
+                                (_, Some((_, miss_postmtime))) => Some(miss_postmtime.clone()),
+                            };
                             necessary_actions.append(&mut subnecessary.add_prefix(name));
-                            if let Some((_, miss_postmtime)) = miss_optm {
-                                necessary_actions.push(
-                                    AbstPath::single(name),
-                                    Action::EditDir(miss_postmtime.clone()),
-                                );
+                            if let Some(miss_postmtime) = edit_dir {
+                                necessary_actions
+                                    .push(AbstPath::single(name), Action::EditDir(miss_postmtime));
                             }
                         }
                         Err(subconflicts) => {
@@ -375,18 +823,26 @@ pub fn get_actions_or_conflicts(
                 (DeltaNode::Leaf(_, None), DeltaNode::Leaf(_, None)) => {}
 
                 // If the objects have the same content (same hash), the only
-                //	edit needed is if the local mtime is different to the missed
-                //	mtime, in which case the local mtime is set to the missed
-                //	mtime
+                //	edit needed is if the local mtime (or mode) is different to
+                //	the missed mtime (or mode), in which case the local mtime (or
+                //	mode) is set to the missed one
                 (
-                    DeltaNode::Leaf(_, Some(FSNode::File(loc_mtime, loc_hash))),
-                    DeltaNode::Leaf(_, Some(FSNode::File(miss_mtime, miss_hash))),
+                    DeltaNode::Leaf(_, Some(FSNode::File(loc_mtime, loc_hash, loc_mode, _))),
+                    DeltaNode::Leaf(_, Some(FSNode::File(miss_mtime, miss_hash, miss_mode, _))),
                 ) if loc_hash == miss_hash => {
-                    if loc_mtime != miss_mtime {
-                        necessary_actions.push(
-                            AbstPath::single(name),
-                            Action::EditFile(Some(miss_mtime.clone()), None),
-                        );
+                    if loc_mtime != miss_mtime || loc_mode != miss_mode {
+                        let optm = if loc_mtime != miss_mtime {
+                            Some(miss_mtime.clone())
+                        } else {
+                            None
+                        };
+                        let optp = if loc_mode != miss_mode {
+                            Some(*miss_mode)
+                        } else {
+                            None
+                        };
+                        necessary_actions
+                            .push(AbstPath::single(name), Action::EditFile(optm, None, optp));
                     }
                 }
 
@@ -406,30 +862,43 @@ pub fn get_actions_or_conflicts(
 
                 // Similar to the Branch-Branch branch
                 (
-                    DeltaNode::Leaf(_, Some(FSNode::Dir(_, _, loc_subtree))),
+                    DeltaNode::Leaf(_, Some(FSNode::Dir(loc_mtime, _, loc_subtree))),
                     DeltaNode::Leaf(_, Some(FSNode::Dir(miss_mtime, _, miss_subtree))),
                 ) => {
                     let subget = add_tree_actions_or_conflicts(loc_subtree, miss_subtree);
                     match subget {
                         Ok(subactions) => {
+                            let edit_dir = !subactions.is_empty() || loc_mtime != miss_mtime;
                             necessary_actions.append(&mut subactions.add_prefix(name));
-                            necessary_actions
-                                .push(AbstPath::single(name), Action::EditDir(miss_mtime.clone()));
+                            if edit_dir {
+                                necessary_actions.push(
+                                    AbstPath::single(name),
+                                    Action::EditDir(miss_mtime.clone()),
+                                );
+                            }
                         }
                         Err(()) => {
-                            conflicts.insert(
-                                name.clone(),
-                                ConflictNode::Leaf(loc_node.clone(), miss_node.clone()),
-                            );
+                            match resolve_conflict(name, loc_node, miss_node, strategy, timestamp) {
+                                Ok(mut resolution) => necessary_actions.append(&mut resolution),
+                                Err(()) => {
+                                    conflicts.insert(
+                                        name.clone(),
+                                        ConflictNode::Leaf(loc_node.clone(), miss_node.clone()),
+                                    );
+                                }
+                            }
                         }
                     }
                 }
-                _ => {
-                    conflicts.insert(
-                        name.clone(),
-                        ConflictNode::Leaf(loc_node.clone(), miss_node.clone()),
-                    );
-                }
+                _ => match resolve_conflict(name, loc_node, miss_node, strategy, timestamp) {
+                    Ok(mut resolution) => necessary_actions.append(&mut resolution),
+                    Err(()) => {
+                        conflicts.insert(
+                            name.clone(),
+                            ConflictNode::Leaf(loc_node.clone(), miss_node.clone()),
+                        );
+                    }
+                },
             },
         }
     }
@@ -443,11 +912,11 @@ pub fn get_actions_or_conflicts(
 #[cfg(test)]
 mod tests {
     use super::{
-        super::get_delta, add_tree_actions_or_conflicts, get_actions_or_conflicts, Action, Actions,
-        Conflicts, DeltaNode, FSNode, FSTree,
+        super::get_delta, add_tree_actions_or_conflicts, conflict_copy_name,
+        get_actions_or_conflicts, get_actions_with_strategy, get_actions_with_strategy_at, Action,
+        Actions, ConflictStrategy, Conflicts, DeltaNode, FSNode, FSTree, METADATA_WORK,
     };
-    use abst_fs::{AbstPath, Endpoint, Mtime};
-    use core::panic;
+    use abst_fs::{AbstPath, Endpoint, Mode, Mtime};
     use std::{collections::HashMap, path::Path, vec};
 
     //--- UTILITY FUNCTIONS ---//
@@ -465,6 +934,7 @@ mod tests {
             Action::AddFile(
                 Mtime::from(mtime.0, mtime.1),
                 hasher::hash_bytes(content.to_string().as_bytes()),
+                Mode::default(),
             ),
         )
     }
@@ -499,7 +969,10 @@ mod tests {
             AbstPath::from(path),
             Action::EditFile(
                 optm.map(|(optsec, optnano)| Mtime::from(optsec, optnano)),
-                content.map(|val| hasher::hash_bytes(val.to_string().as_bytes())),
+                content
+                    .as_ref()
+                    .map(|val| hasher::hash_bytes(val.to_string().as_bytes())),
+                content.map(|_| Mode::default()),
             ),
         )
     }
@@ -560,9 +1033,10 @@ mod tests {
             Actions(vec![(deep_path, action)])
         );
 
-        for _ in Actions::new() {
-            panic!("there should be no iterable");
-        }
+        assert!(
+            Actions::new().into_iter().next().is_none(),
+            "there should be no iterable"
+        );
 
         let mut actions = Actions::new();
         actions.push(AbstPath::from("path/to/somewhere"), Action::RemoveDir);
@@ -1129,6 +1603,11 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test() {
+        test_get_actions_or_conflicts();
+    }
+
     fn test_get_actions_or_conflicts() {
         let original_tree = FSTree::gen_from(|t| {
             t.add_file("untouched-file", (1664618719, 438929376), "content 0");
@@ -1215,7 +1694,7 @@ mod tests {
 
             t.add_file("both-added-file", (1667624266, 805140546), "content 16");
             t.add_symlink("both-added-symlink", (1667646560, 638616903), "path/16");
-            t.add_dir("both-added-dir", (1667759241, 483275199), |t| {
+            t.add_dir("both-added-dir", (1667759241, 483275199), |_t| {
                 //     t.add_file("subdir", (1667674703, 972416754), "content 17");
                 //     t.add_symlink("subsymlink", (1667715624, 937813631), "path/17");
                 //     t.add_empty_dir("subdir", (1667742157, 769758628));
@@ -1305,7 +1784,7 @@ mod tests {
 
             t.add_file("both-added-file", (1667624266, 805140546), "content 16");
             t.add_symlink("both-added-symlink", (1667646560, 638616903), "path/16");
-            t.add_dir("both-added-dir", (1667759241, 483275199), |t| {
+            t.add_dir("both-added-dir", (1667759241, 483275199), |_t| {
                 //     t.add_file("subdir", (1667674703, 972416754), "content 17");
                 //     t.add_symlink("subsymlink", (1667715624, 937813631), "path/17");
                 //     t.add_empty_dir("subdir", (1667742157, 769758628));
@@ -1383,4 +1862,727 @@ mod tests {
             supposed_actions
         );
     }
+
+    #[test]
+    fn conflict_strategies() {
+        let old_tree = FSTree::gen_from(|t| {
+            t.add_file("both-edited-file", (1667959532, 32950243), "old content");
+            t.add_file("removed-and-edited", (1667992821, 16282390), "old content");
+            t.add_file("edited-and-removed", (1668116249, 559633309), "old content");
+            t.add_dir("removed-and-edited-dir", (1668279333, 510631155), |t| {
+                t.add_file("file", (1668038371, 400185901), "old content");
+            });
+        });
+        let local_tree = FSTree::gen_from(|t| {
+            t.add_file("both-edited-file", (1667669963, 626480240), "local content");
+            t.add_file(
+                "edited-and-removed",
+                (1667681110, 459222078),
+                "local content",
+            );
+        });
+        let missed_tree = FSTree::gen_from(|t| {
+            t.add_file(
+                "both-edited-file",
+                (1667705278, 500975522),
+                "missed content",
+            );
+            t.add_file(
+                "removed-and-edited",
+                (1667736237, 102383002),
+                "missed content",
+            );
+            t.add_dir("removed-and-edited-dir", (1667758112, 316412296), |t| {
+                t.add_file("file", (1667772261, 321012663), "missed content");
+            });
+        });
+        let local_delta = get_delta(&old_tree, &local_tree);
+        let missed_delta = get_delta(&old_tree, &missed_tree);
+
+        // Abort behaves exactly like get_actions_or_conflicts
+        let conflicts = get_actions_or_conflicts(&local_delta, &missed_delta).unwrap_err();
+        assert_eq!(conflicts.0.len(), 4);
+        assert_eq!(
+            get_actions_with_strategy(&local_delta, &missed_delta, ConflictStrategy::Abort)
+                .unwrap_err(),
+            conflicts
+        );
+
+        // The local version is left untouched
+        assert_eq!(
+            get_actions_with_strategy(&local_delta, &missed_delta, ConflictStrategy::PreferLocal)
+                .unwrap(),
+            Actions::new()
+        );
+
+        // The local version is replaced with the missed version
+        assert_eq!(
+            get_actions_with_strategy(&local_delta, &missed_delta, ConflictStrategy::PreferRemote)
+                .unwrap(),
+            Actions(vec![
+                edit_file_at(
+                    "both-edited-file",
+                    Some((1667705278, 500975522)),
+                    Some("missed content")
+                ),
+                add_file_at(
+                    "removed-and-edited",
+                    (1667736237, 102383002),
+                    "missed content"
+                ),
+                remove_file_at("edited-and-removed"),
+                add_dir_at("removed-and-edited-dir"),
+                add_file_at(
+                    "removed-and-edited-dir/file",
+                    (1667772261, 321012663),
+                    "missed content"
+                ),
+                edit_dir_at("removed-and-edited-dir", (1667758112, 316412296)),
+            ])
+        );
+
+        // Every conflict is reported, not only the first one found
+        let old_tree = FSTree::gen_from(|t| {
+            t.add_file("file", (1668321405, 170581230), "old content");
+            t.add_dir("dir", (1668384120, 904466216), |t| {
+                t.add_file("file", (1668412563, 248305116), "old content");
+                t.add_file("untouched", (1668443071, 747290355), "old content");
+                t.add_dir("subdir", (1668480196, 391502841), |t| {
+                    t.add_symlink("symlink", (1668517934, 665217390), "old/path");
+                });
+            });
+        });
+        let local_tree = FSTree::gen_from(|t| {
+            t.add_file("file", (1668561227, 56001672), "local content");
+            t.add_dir("dir", (1668384120, 904466216), |t| {
+                t.add_file("untouched", (1668443071, 747290355), "old content");
+                t.add_dir("subdir", (1668480196, 391502841), |t| {
+                    t.add_symlink("symlink", (1668598350, 121914725), "local/path");
+                });
+            });
+        });
+        let missed_tree = FSTree::gen_from(|t| {
+            t.add_file("file", (1668634772, 813470299), "missed content");
+            t.add_dir("dir", (1668384120, 904466216), |t| {
+                t.add_file("file", (1668672104, 484813907), "missed content");
+                t.add_file("untouched", (1668443071, 747290355), "old content");
+                t.add_dir("subdir", (1668480196, 391502841), |t| {
+                    t.add_symlink("symlink", (1668705939, 956602177), "missed/path");
+                });
+            });
+        });
+        let conflicts = get_actions_or_conflicts(
+            &get_delta(&old_tree, &local_tree),
+            &get_delta(&old_tree, &missed_tree),
+        )
+        .unwrap_err();
+        assert_eq!(
+            conflicts.paths(),
+            vec![
+                AbstPath::from("dir/file"),
+                AbstPath::from("dir/subdir/symlink"),
+                AbstPath::from("file"),
+            ]
+        );
+        crate::set_colors(false);
+        assert_eq!(
+            conflicts.to_string(),
+            [
+                ".",
+                "├── dir/",
+                "│   ├── file",
+                "│   │   0: File [h:34a780] -> None",
+                "│   │   1: File [h:34a780] -> File [h:c75bf9]",
+                "│   └── subdir/",
+                "│       └── symlink",
+                "│           0: SymLink [h:c4056f] -> SymLink [h:edee77]",
+                "│           1: SymLink [h:c4056f] -> SymLink [h:853703]",
+                "└── file",
+                "    0: File [h:34a780] -> File [h:a2553c]",
+                "    1: File [h:34a780] -> File [h:c75bf9]",
+            ]
+            .join("\n")
+        );
+
+        // Parsing
+        assert_eq!(
+            "prefer-local".parse::<ConflictStrategy>(),
+            Ok(ConflictStrategy::PreferLocal)
+        );
+        assert_eq!(
+            "prefer-remote".parse::<ConflictStrategy>(),
+            Ok(ConflictStrategy::PreferRemote)
+        );
+        assert_eq!(
+            "abort".parse::<ConflictStrategy>(),
+            Ok(ConflictStrategy::Abort)
+        );
+        assert_eq!(
+            "keep-both".parse::<ConflictStrategy>(),
+            Ok(ConflictStrategy::KeepBoth)
+        );
+        // Parsing is pure, the time is only taken when resolving conflicts
+        assert_eq!(
+            "keep-both".parse::<ConflictStrategy>(),
+            "keep-both".parse::<ConflictStrategy>()
+        );
+        assert!("something-else".parse::<ConflictStrategy>().is_err());
+    }
+
+    #[test]
+    fn keep_both() {
+        let old_tree = FSTree::gen_from(|t| {
+            t.add_file("both-edited-file", (1667959532, 32950243), "old content");
+            t.add_file("removed-and-edited", (1667992821, 16282390), "old content");
+            t.add_dir("dir", (1668279333, 510631155), |t| {
+                t.add_file("file", (1668038371, 400185901), "old content");
+            });
+        });
+        let local_tree = FSTree::gen_from(|t| {
+            t.add_file("both-edited-file", (1667669963, 626480240), "local content");
+            t.add_file("both-added-file", (1667681110, 459222078), "local added");
+            t.add_dir("dir", (1668279333, 510631155), |t| {
+                t.add_file("file", (1667705278, 500975522), "local nested");
+            });
+        });
+        let missed_tree = FSTree::gen_from(|t| {
+            t.add_file(
+                "both-edited-file",
+                (1667736237, 102383002),
+                "missed content",
+            );
+            t.add_file("both-added-file", (1667758112, 316412296), "missed added");
+            t.add_file(
+                "removed-and-edited",
+                (1667772261, 321012663),
+                "missed content",
+            );
+            t.add_dir("dir", (1668321405, 170581230), |t| {
+                t.add_file("file", (1668384120, 904466216), "missed nested");
+            });
+        });
+        let local_delta = get_delta(&old_tree, &local_tree);
+        let missed_delta = get_delta(&old_tree, &missed_tree);
+        let strategy = ConflictStrategy::KeepBoth;
+
+        // A file removed locally and edited remotely doesn't conflict on content
+        let conflicts =
+            get_actions_with_strategy_at(&local_delta, &missed_delta, strategy, 1700000000)
+                .unwrap_err();
+        assert_eq!(
+            conflicts.paths(),
+            vec![AbstPath::from("removed-and-edited")]
+        );
+
+        let old_tree = FSTree::gen_from(|t| {
+            t.add_file("both-edited-file", (1667959532, 32950243), "old content");
+            t.add_dir("dir", (1668279333, 510631155), |t| {
+                t.add_file("file", (1668038371, 400185901), "old content");
+            });
+        });
+        let missed_tree = FSTree::gen_from(|t| {
+            t.add_file(
+                "both-edited-file",
+                (1667736237, 102383002),
+                "missed content",
+            );
+            t.add_file("both-added-file", (1667758112, 316412296), "missed added");
+            t.add_dir("dir", (1668321405, 170581230), |t| {
+                t.add_file("file", (1668384120, 904466216), "missed nested");
+            });
+        });
+        let local_delta = get_delta(&old_tree, &local_tree);
+        let missed_delta = get_delta(&old_tree, &missed_tree);
+        let actions =
+            get_actions_with_strategy_at(&local_delta, &missed_delta, strategy, 1700000000)
+                .unwrap();
+
+        // The missed version replaces the local one, which is copied next to it
+        let copy_at = |path: &str, mtime: (i64, u32), content: &str| {
+            let from = AbstPath::from(path);
+            let name = from.file_name().unwrap();
+            (
+                from.strip_last()
+                    .add_last(format!("{name}.conflict-1700000000")),
+                Action::Copy(
+                    from,
+                    Mtime::from(mtime.0, mtime.1),
+                    hasher::hash_bytes(content.as_bytes()),
+                    Mode::default(),
+                ),
+            )
+        };
+        assert_eq!(
+            actions,
+            Actions(vec![
+                copy_at("both-edited-file", (1667669963, 626480240), "local content"),
+                edit_file_at(
+                    "both-edited-file",
+                    Some((1667736237, 102383002)),
+                    Some("missed content")
+                ),
+                copy_at("both-added-file", (1667681110, 459222078), "local added"),
+                edit_file_at(
+                    "both-added-file",
+                    Some((1667758112, 316412296)),
+                    Some("missed added")
+                ),
+                copy_at("dir/file", (1667705278, 500975522), "local nested"),
+                edit_file_at(
+                    "dir/file",
+                    Some((1668384120, 904466216)),
+                    Some("missed nested")
+                ),
+                edit_dir_at("dir", (1668321405, 170581230)),
+            ])
+        );
+
+        // Each copy is made before its file is replaced, also once sorted
+        let check_order = |actions: &Actions| {
+            for (index, (_, action)) in actions.into_iter().enumerate() {
+                if let Action::Copy(from, _, _, _) = action {
+                    let replaced = actions
+                        .into_iter()
+                        .position(|(path, action)| {
+                            path == from && matches!(action, Action::EditFile(..))
+                        })
+                        .unwrap();
+                    assert!(index < replaced);
+                }
+            }
+        };
+        check_order(&actions);
+        let mut sorted = actions;
+        sorted.sort_for_apply();
+        check_order(&sorted);
+        assert_eq!(
+            conflict_copy_name("notes.txt", 1700000000),
+            "notes.txt.conflict-1700000000"
+        );
+    }
+
+    #[test]
+    fn mode_edits() {
+        let old_tree = FSTree::gen_from(|t| {
+            t.add_file("script", (1667959532, 32950243), "echo hello");
+        });
+        let exec_tree = FSTree::gen_from(|t| {
+            t.0.insert(
+                String::from("script"),
+                FSNode::file_with_mode((1667959532, 32950243), "echo hello", 0o755),
+            );
+        });
+        let exec_actions = Actions(vec![(
+            AbstPath::from("script"),
+            Action::EditFile(None, None, Some(Mode::from(0o755))),
+        )]);
+
+        // A mode change alone is an edit
+        let delta = get_delta(&old_tree, &exec_tree);
+        assert!(!delta.is_empty());
+        assert_eq!(delta.to_actions(), exec_actions);
+
+        // If both deltas edit the content in the same way, the missed mode wins
+        let local_delta = get_delta(
+            &old_tree,
+            &FSTree::gen_from(|t| {
+                t.add_file("script", (1667669963, 626480240), "echo world");
+            }),
+        );
+        let missed_delta = get_delta(
+            &old_tree,
+            &FSTree::gen_from(|t| {
+                t.0.insert(
+                    String::from("script"),
+                    FSNode::file_with_mode((1667669963, 626480240), "echo world", 0o755),
+                );
+            }),
+        );
+        assert_eq!(
+            get_actions_or_conflicts(&local_delta, &missed_delta).unwrap(),
+            exec_actions
+        );
+    }
+
+    #[test]
+    fn detect_moves() {
+        let pre_tree = FSTree::gen_from(|t| {
+            t.add_dir("dir", (1667000000, 0), |t| {
+                t.add_file("large", (1667000000, 0), "large content");
+            });
+            t.add_dir("removed", (1667000000, 0), |t| {
+                t.add_file("file", (1667000000, 0), "removed with its dir");
+            });
+        });
+        let post_tree = FSTree::gen_from(|t| {
+            t.add_dir("dir", (1667100000, 0), |t| {
+                t.add_file("renamed", (1667000000, 0), "large content");
+            });
+            t.add_file("file", (1667100000, 0), "removed with its dir");
+        });
+        let delta = get_delta(&pre_tree, &post_tree);
+        let mut actions = delta.to_actions();
+        actions.detect_moves(&delta);
+
+        // The renamed file is moved instead of being removed and transferred
+        //	again, while files removed together with their directory are not
+        let (_, Action::AddFile(mtime, hash, mode)) =
+            add_file_at("dir/renamed", (1667000000, 0), "large content")
+        else {
+            unreachable!()
+        };
+        assert_eq!(
+            actions,
+            Actions(vec![
+                (
+                    AbstPath::from("dir/renamed"),
+                    Action::Move(AbstPath::from("dir/large"), mtime, hash, mode)
+                ),
+                edit_dir_at("dir", (1667100000, 0)),
+                remove_dir_at("removed"),
+                add_file_at("file", (1667100000, 0), "removed with its dir"),
+            ])
+        );
+
+        // Each removed file is moved at most once
+        let post_tree = FSTree::gen_from(|t| {
+            t.add_dir("dir", (1667000000, 0), |t| {
+                t.add_file("first", (1667000000, 0), "large content");
+                t.add_file("second", (1667000000, 0), "large content");
+            });
+        });
+        let delta = get_delta(&pre_tree, &post_tree);
+        let mut actions = delta.to_actions();
+        actions.detect_moves(&delta);
+        let actions: Vec<(AbstPath, Action)> = actions.into_iter().collect();
+        let moves = actions
+            .iter()
+            .filter(|(_, action)| matches!(action, Action::Move(..)))
+            .count();
+        let adds = actions
+            .iter()
+            .filter(|(_, action)| matches!(action, Action::AddFile(..)))
+            .count();
+        assert_eq!((moves, adds), (1, 1));
+        // The mtimes of directories are set after the move
+        let moved = actions
+            .iter()
+            .position(|(_, action)| matches!(action, Action::Move(..)))
+            .unwrap();
+        assert!(actions
+            .iter()
+            .enumerate()
+            .all(|(index, (_, action))| !matches!(action, Action::EditDir(_)) || index > moved));
+        assert!(!actions
+            .iter()
+            .any(|(_, action)| *action == Action::RemoveFile));
+    }
+
+    #[test]
+    fn sort_for_apply() {
+        let pre_tree = FSTree::gen_from(|t| {
+            t.add_dir("old", (1667000000, 0), |t| {
+                t.add_dir("sub", (1667000000, 0), |t| {
+                    t.add_file("file", (1667000000, 0), "old content");
+                });
+            });
+            t.add_file("replaced", (1667000000, 0), "a file");
+            t.add_dir("kept", (1667000000, 0), |t| {
+                t.add_file("edited", (1667000000, 0), "some content");
+                t.add_symlink("removed", (1667000000, 0), "somewhere");
+            });
+        });
+        let post_tree = FSTree::gen_from(|t| {
+            t.add_dir("replaced", (1667100000, 0), |t| {
+                t.add_file("file", (1667100000, 0), "now a dir");
+            });
+            t.add_dir("kept", (1667100000, 0), |t| {
+                t.add_file("edited", (1667100000, 0), "other content");
+                t.add_dir("new", (1667100000, 0), |t| {
+                    t.add_dir("deep", (1667100000, 0), |t| {
+                        t.add_file("file", (1667100000, 0), "deep content");
+                    });
+                });
+            });
+        });
+        let mut actions = get_delta(&pre_tree, &post_tree).to_actions();
+        // Removals of nested objects that come after the removal of their parent
+        let (path, action) = remove_dir_at("old/sub");
+        actions.push(path, action);
+        let (path, action) = remove_file_at("old/sub/file");
+        actions.push(path, action);
+        actions.sort_for_apply();
+        let vec: Vec<(AbstPath, Action)> = actions.into_iter().collect();
+        assert_eq!(vec.len(), 15);
+
+        let position = |path: &str, pred: fn(&Action) -> bool| {
+            vec.iter()
+                .position(|(p, a)| p == &AbstPath::from(path) && pred(a))
+                .unwrap()
+        };
+        let is_remove = |a: &Action| {
+            matches!(
+                a,
+                Action::RemoveDir | Action::RemoveFile | Action::RemoveSymLink
+            )
+        };
+        let is_add = |a: &Action| {
+            matches!(
+                a,
+                Action::AddDir | Action::AddFile(..) | Action::AddSymLink(..)
+            )
+        };
+        let is_edit_dir = |a: &Action| matches!(a, Action::EditDir(..));
+        let is_edit = |a: &Action| matches!(a, Action::EditFile(..) | Action::EditSymLink(..));
+
+        for (index, (path, action)) in vec.iter().enumerate() {
+            for (other_index, (other_path, other_action)) in vec.iter().enumerate() {
+                let is_ancestor =
+                    other_path.len() > path.len() && other_path.relative_to(path).is_some();
+                // Directories are created before their children and removed
+                //	after them, and their mtime is set after everything else
+                //	is done in their content
+                if is_ancestor && is_add(action) && !is_remove(other_action) {
+                    assert!(index < other_index);
+                }
+                if is_ancestor && is_remove(action) && is_remove(other_action) {
+                    assert!(index > other_index);
+                }
+                if is_ancestor && is_edit_dir(action) {
+                    assert!(index > other_index);
+                }
+                // Objects are removed before something else is added in
+                //	their place
+                if path == other_path && is_remove(action) && !is_remove(other_action) {
+                    assert!(index < other_index);
+                }
+            }
+        }
+        assert!(position("replaced", is_remove) < position("replaced", is_add));
+        assert!(position("old/sub/file", is_remove) < position("old/sub", is_remove));
+        assert!(position("old/sub", is_remove) < position("old", is_remove));
+        assert!(position("kept/new", is_add) < position("kept/new/deep/file", is_add));
+        assert!(position("kept/edited", is_edit) < position("kept", is_edit_dir));
+        assert!(position("kept/new/deep", is_edit_dir) < position("kept/new", is_edit_dir));
+    }
+
+    #[test]
+    fn retain_additions() {
+        let pre_tree = FSTree::gen_from(|t| {
+            t.add_file("removed", (1667000000, 0), "removed content");
+            t.add_file("edited", (1667000000, 0), "old content");
+            t.add_symlink("symlink", (1667000000, 0), "somewhere");
+            t.add_empty_dir("dir", (1667000000, 0));
+        });
+        let post_tree = FSTree::gen_from(|t| {
+            t.add_file("edited", (1667100000, 0), "new content");
+            t.add_symlink("symlink", (1667100000, 0), "elsewhere");
+            t.add_dir("dir", (1667100000, 0), |t| {
+                t.add_file("added", (1667100000, 0), "added content");
+            });
+            t.add_file("added", (1667100000, 0), "added content");
+        });
+        let mut actions = get_delta(&pre_tree, &post_tree).to_actions();
+        actions.retain_additions();
+        assert_eq!(actions, {
+            let mut supposed = Actions::new();
+            supposed.push(
+                AbstPath::from("added"),
+                Action::AddFile(
+                    Mtime::from(1667100000, 0),
+                    hasher::hash_bytes(b"added content"),
+                    Mode::default(),
+                ),
+            );
+            supposed.push(
+                AbstPath::from("dir/added"),
+                Action::AddFile(
+                    Mtime::from(1667100000, 0),
+                    hasher::hash_bytes(b"added content"),
+                    Mode::default(),
+                ),
+            );
+            supposed.push(
+                AbstPath::from("dir"),
+                Action::EditDir(Mtime::from(1667100000, 0)),
+            );
+            supposed
+        });
+        assert!((&actions)
+            .into_iter()
+            .all(|(_, action)| action.is_addition()));
+        assert!(!Action::RemoveFile.is_addition());
+        assert!(!Action::EditFile(None, None, Some(Mode::from(0o755))).is_addition());
+    }
+
+    #[test]
+    fn no_redundant_edit_dirs() {
+        let pre_tree = FSTree::gen_from(|t| {
+            t.add_dir("a", (1667000000, 0), |t| {
+                t.add_empty_dir("x", (1667000000, 0));
+                t.add_empty_dir("y", (1667000000, 0));
+            });
+            t.add_empty_dir("c", (1667000000, 0));
+            t.add_dir("untouched", (1667000000, 0), |t| {
+                t.add_file("file", (1667000000, 0), "content");
+            });
+        });
+        let shared = |t: &mut FSTree| {
+            t.add_dir("c", (1667100000, 0), |t| {
+                t.add_file("same", (1667100000, 0), "same content");
+            });
+            t.add_dir("untouched", (1667000000, 0), |t| {
+                t.add_file("file", (1667000000, 0), "content");
+            });
+        };
+        // Both deltas touch unrelated subdirectories of `a`, add the same file in
+        //	`c` and add the same directory `new`, whose subdirectory `s2` only
+        //	has an extra file in the missed version
+        let loc_tree = FSTree::gen_from(|t| {
+            shared(t);
+            t.add_dir("a", (1667000000, 0), |t| {
+                t.add_dir("x", (1667100000, 0), |t| {
+                    t.add_file("local", (1667100000, 0), "local content");
+                });
+                t.add_empty_dir("y", (1667000000, 0));
+            });
+            t.add_dir("new", (1667100000, 0), |t| {
+                t.add_dir("s1", (1667100000, 0), |t| {
+                    t.add_file("file", (1667100000, 0), "content");
+                });
+                t.add_empty_dir("s2", (1667100000, 0));
+            });
+        });
+        let miss_tree = FSTree::gen_from(|t| {
+            shared(t);
+            t.add_dir("a", (1667000000, 0), |t| {
+                t.add_empty_dir("x", (1667000000, 0));
+                t.add_dir("y", (1667100000, 0), |t| {
+                    t.add_file("missed", (1667100000, 0), "missed content");
+                });
+            });
+            t.add_dir("new", (1667100000, 0), |t| {
+                t.add_dir("s1", (1667100000, 0), |t| {
+                    t.add_file("file", (1667100000, 0), "content");
+                });
+                t.add_dir("s2", (1667200000, 0), |t| {
+                    t.add_file("extra", (1667200000, 0), "extra content");
+                });
+            });
+        });
+        let local = get_delta(&pre_tree, &loc_tree);
+        let missed = get_delta(&pre_tree, &miss_tree);
+
+        let actions = get_actions_or_conflicts(&local, &missed).unwrap();
+        assert_eq!(
+            actions,
+            Actions(vec![
+                add_file_at("a/y/missed", (1667100000, 0), "missed content"),
+                edit_dir_at("a/y", (1667100000, 0)),
+                add_file_at("new/s2/extra", (1667200000, 0), "extra content"),
+                edit_dir_at("new/s2", (1667200000, 0)),
+                edit_dir_at("new", (1667100000, 0)),
+            ])
+        );
+
+        // Identical deltas need no action at all
+        assert_eq!(
+            get_actions_or_conflicts(&missed, &missed).unwrap(),
+            Actions::new()
+        );
+    }
+
+    #[test]
+    fn work() {
+        let pre_tree = FSTree::gen_from(|t| {
+            t.add_file("edited", (1667000000, 0), "old");
+            t.add_file("touched", (1667000000, 0), "same");
+            t.add_file("removed", (1667000000, 0), "removed content");
+        });
+        let post_tree = FSTree::gen_from(|t| {
+            t.add_file("edited", (1667000000, 0), "new content");
+            t.add_file("touched", (1667100000, 0), "same");
+            t.add_dir("dir", (1667100000, 0), |t| {
+                t.add_file("added", (1667100000, 0), "a".repeat(10000));
+            });
+        });
+        let mut actions = get_delta(&pre_tree, &post_tree).to_actions();
+        actions.sort_for_apply();
+        let work = actions.work(&post_tree);
+        let work_of = |path: &str| {
+            let index = (&actions)
+                .into_iter()
+                .position(|(p, _)| *p == AbstPath::from(path))
+                .unwrap();
+            work[index]
+        };
+        assert_eq!(work.len(), (&actions).into_iter().count());
+        assert_eq!(work_of("removed"), METADATA_WORK);
+        assert_eq!(work_of("dir"), METADATA_WORK);
+        assert_eq!(work_of("dir/added"), 10000 + METADATA_WORK);
+        assert_eq!(work_of("edited"), 11 + METADATA_WORK);
+        // Only the mtime changes, no content is written
+        assert_eq!(work_of("touched"), METADATA_WORK);
+
+        // Unknown sizes count as empty files
+        assert_eq!(
+            actions.work(&FSTree::empty()),
+            vec![METADATA_WORK; work.len()]
+        );
+    }
+
+    #[test]
+    fn parent_dirs() {
+        let mut actions = Actions::new();
+        actions.push(AbstPath::from("top"), Action::AddDir);
+        actions.push(AbstPath::from("a/b/file"), Action::RemoveFile);
+        actions.push(AbstPath::from("a/b/other"), Action::RemoveSymLink);
+        actions.push(
+            AbstPath::from("c/moved"),
+            Action::Move(
+                AbstPath::from("a/source"),
+                Mtime::from(1667000000, 0),
+                hasher::hash_bytes(b"content"),
+                Mode::from(0o644),
+            ),
+        );
+        assert_eq!(
+            actions.parent_dirs(),
+            vec![
+                AbstPath::empty(),
+                AbstPath::from("a"),
+                AbstPath::from("a/b"),
+                AbstPath::from("c"),
+            ]
+        );
+        assert!(Actions::new().parent_dirs().is_empty());
+    }
+
+    #[test]
+    fn json() {
+        let pre_tree = FSTree::gen_from(|t| {
+            t.add_file("edited", (1667000000, 0), "some content");
+            t.add_symlink("removed", (1667000000, 0), "somewhere");
+        });
+        let post_tree = FSTree::gen_from(|t| {
+            t.add_file("edited", (1667100000, 0), "other content");
+            t.add_dir("dir", (1667100000, 0), |t| {
+                t.add_symlink("symlink", (1667100000, 0), "elsewhere");
+            });
+        });
+        let actions = get_delta(&pre_tree, &post_tree).to_actions();
+
+        let serialized = serde_json::to_string(&actions).unwrap();
+        let deserialized: Actions = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(
+            deserialized,
+            Actions(vec![
+                edit_file_at("edited", Some((1667100000, 0)), Some("other content")),
+                remove_symlink_at("removed"),
+                add_dir_at("dir"),
+                add_symlink_at("dir/symlink", (1667100000, 0), "elsewhere"),
+                edit_dir_at("dir", (1667100000, 0)),
+            ])
+        );
+    }
 }
@@ -1,55 +1,153 @@
-use super::{delta::UnmergeableDelta, Delta, DeltaNode};
+use super::{
+    delta::UnmergeableDelta,
+    legacy::{FileV0, FileV1, FileV2, LegacyCommit},
+    Delta, DeltaNode,
+};
 
-use abst_fs::AbstPath;
+use abst_fs::{self as fs, AbstPath};
 
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 use std::collections::HashMap;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Provenance of a commit. Every field is optional, as commits made before
+/// metadata existed (and those collapsed by pruning) have none
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct CommitMetadata {
+    pub author: Option<String>,
+    pub message: Option<String>,
+    /// Creation time of the commit, in seconds since the Unix epoch
+    pub timestamp: Option<i64>,
+}
+
+#[derive(Error, Debug, PartialEq)]
+#[error("Commit ID Error: invalid commit id [{0}]\nreason: a commit id is made of exactly 64 lowercase hexadecimal digits")]
+pub struct CommitIDError(String);
+
+/// Identifier of a commit: 64 lowercase hexadecimal digits, the null id (all
+/// zeros) being the id of the base commit. Both its string form (see `Display`
+/// and `FromStr`) and its serialized form are the plain string of digits, which
+/// is also how ids have always been stored, so stored ids remain valid
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub struct CommitID(String);
+impl CommitID {
+    const LEN: usize = 64;
+
+    /// Id of the base commit, standing for no commit at all
+    pub fn null() -> CommitID {
+        CommitID(String::from("0").repeat(CommitID::LEN))
+    }
+    pub fn is_null(&self) -> bool {
+        *self == CommitID::null()
+    }
+    fn gen_rand() -> CommitID {
+        use rand::Rng;
+        const CHARSET: &[u8] = b"0123456789abcdef";
+        let mut rng = rand::thread_rng();
+
+        CommitID(
+            (0..CommitID::LEN)
+                .map(|_| {
+                    let idx = rng.gen_range(0..CHARSET.len());
+                    CHARSET[idx] as char
+                })
+                .collect(),
+        )
+    }
+    /// Random id, different from the null id
+    pub fn gen_valid() -> CommitID {
+        let mut id = CommitID::gen_rand();
+        while id.is_null() {
+            id = CommitID::gen_rand();
+        }
+        id
+    }
+}
+impl std::str::FromStr for CommitID {
+    type Err = CommitIDError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let valid = s.len() == CommitID::LEN
+            && s.chars()
+                .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c));
+        match valid {
+            true => Ok(CommitID(s.to_string())),
+            false => Err(CommitIDError(s.to_string())),
+        }
+    }
+}
+impl TryFrom<String> for CommitID {
+    type Error = CommitIDError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+impl From<CommitID> for String {
+    fn from(CommitID(id): CommitID) -> String {
+        id
+    }
+}
+impl std::fmt::Display for CommitID {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let CommitID(id) = self;
+        write!(f, "{}", id)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Commit {
-    pub commit_id: String,
+    pub commit_id: CommitID,
     pub endpoint: AbstPath,
     pub delta: Delta,
+    #[serde(default)]
+    pub metadata: CommitMetadata,
 }
 impl Commit {
-    const ID_LEN: usize = 64;
     pub fn base_commit() -> Commit {
         Commit {
-            commit_id: Commit::gen_null_id(),
+            commit_id: CommitID::null(),
             endpoint: AbstPath::empty(),
             delta: Delta::empty(),
+            metadata: CommitMetadata::default(),
         }
     }
-    pub fn gen_null_id() -> String {
-        String::from("0").repeat(Commit::ID_LEN)
-    }
-    fn gen_rand_id() -> String {
-        use rand::Rng;
-        const CHARSET: &[u8] = b"0123456789abcdef";
-        let mut rng = rand::thread_rng();
+}
 
-        (0..Commit::ID_LEN)
-            .map(|_| {
-                let idx = rng.gen_range(0..CHARSET.len());
-                CHARSET[idx] as char
-            })
-            .collect()
-    }
-    pub fn gen_valid_id() -> String {
-        let null_id = Commit::gen_null_id();
-        let mut id = Commit::gen_rand_id();
-        while id == null_id {
-            id = Commit::gen_rand_id();
-        }
-        id
-    }
+#[derive(Error, Debug)]
+pub enum GetUpdError {
+    #[error("Get Update Delta Error: Failed to get the update delta since the last known commit\nproblematic commit id: {0}\nreason: {1}")]
+    Unmergeable(CommitID, UnmergeableDelta),
+
+    #[error("Get Update Delta Error: Failed to get the update delta since the last known commit\nunknown commit id: {0}\nreason: the commit is not in the commit list, it might have been pruned")]
+    UnknownCommit(CommitID),
 }
 
 #[derive(Error, Debug)]
-#[error("Get Update Delta Error: Failed to get the update delta since the last known commit\nproblematic commit id: {0}\nreason: {1}")]
-pub struct GetUpdError(String, UnmergeableDelta);
+#[error("Prune Error: Failed to collapse the pruned commits into a baseline commit\nproblematic commit id: {0}\nreason: {1}")]
+pub struct PruneError(CommitID, UnmergeableDelta);
+
+/// Delta of the commit, relative to the archive root instead of the commit's
+/// endpoint
+fn delta_at_root(commit: &Commit) -> Delta {
+    let mut delta = commit.delta.clone();
+    for component in commit.endpoint.clone().into_iter().rev() {
+        let node = DeltaNode::Branch(None, delta);
+        let tree = HashMap::from([(component, node)]);
+        delta = Delta(tree)
+    }
+    delta
+}
+
+/// Summary of the commits made on a single endpoint
+#[derive(Debug, PartialEq)]
+pub struct EndpointSummary {
+    pub endpoint: AbstPath,
+    pub head: CommitID,
+    pub commit_count: usize,
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CommitList(Vec<Commit>);
@@ -58,10 +156,35 @@ impl CommitList {
         CommitList(vec![Commit::base_commit()])
     }
 
+    /// Load the commit list saved at path (see `abst_fs::load`). Commit lists
+    /// saved before commits had metadata are still loaded, with empty metadata,
+    /// even from the binary format, which can't tell the missing fields apart.
+    /// So are those saved before files had a mode or a size (see
+    /// `FSTree::load`)
+    pub fn load(path: &AbstPath) -> Result<CommitList, fs::Error> {
+        let err = match fs::load(path) {
+            Ok(commit_list) => return Ok(commit_list),
+            Err(err) => err,
+        };
+        fn upgrade<F: super::legacy::FileLayout>(list: Vec<LegacyCommit<F>>) -> CommitList {
+            CommitList(list.into_iter().map(LegacyCommit::upgrade).collect())
+        }
+        if let Ok(list) = fs::load::<Vec<LegacyCommit<FileV2>>>(path) {
+            return Ok(upgrade(list));
+        }
+        if let Ok(list) = fs::load::<Vec<LegacyCommit<FileV1>>>(path) {
+            return Ok(upgrade(list));
+        }
+        match fs::load::<Vec<LegacyCommit<FileV0>>>(path) {
+            Ok(list) => Ok(upgrade(list)),
+            Err(_) => Err(err),
+        }
+    }
+
     pub fn most_recent_commit(&self) -> &Commit {
         let CommitList(list) = self;
         // TODO unwrap here eeeeeeeeeee
-        list.get(list.len() - 1).unwrap()
+        list.last().unwrap()
     }
 
     pub fn push(&mut self, commit: Commit) {
@@ -69,14 +192,110 @@ impl CommitList {
         list.push(commit);
     }
 
-    pub fn get_update_delta(&self, endpoint: &AbstPath, lkc: String) -> Result<Delta, GetUpdError> {
+    /// Ids that an update delta can be computed from (see `get_update_delta`),
+    /// from the oldest to the most recent: the null id, standing for no commit at
+    /// all, followed by the ids of the commits in the list
+    pub fn ids(&self) -> Vec<CommitID> {
+        let CommitList(list) = self;
+        let mut ids = vec![CommitID::null()];
+        ids.extend(
+            list.iter()
+                .map(|commit| commit.commit_id.clone())
+                .filter(|id| !id.is_null()),
+        );
+        ids
+    }
+
+    /// Commits made at `endpoint` or at any path under it, from the most recent to
+    /// the oldest. The base commit is never included, while the baseline left by
+    /// pruning is included only for the archive root (the empty endpoint)
+    pub fn log(&self, endpoint: &AbstPath) -> Vec<&Commit> {
+        let CommitList(list) = self;
+        list.iter()
+            .rev()
+            .filter(|commit| !commit.commit_id.is_null())
+            .filter(|commit| commit.endpoint.relative_to(endpoint).is_some())
+            .collect()
+    }
+
+    /// Summary of every endpoint that has been committed to, sorted by endpoint.
+    /// Commits at the archive root (the base commit and the baseline left by
+    /// pruning) don't belong to any endpoint and are not counted
+    pub fn endpoints(&self) -> Vec<EndpointSummary> {
+        let CommitList(list) = self;
+        let mut summaries: Vec<EndpointSummary> = Vec::new();
+        for commit in list.iter().filter(|commit| !commit.endpoint.is_empty()) {
+            match summaries
+                .iter_mut()
+                .find(|summary| summary.endpoint == commit.endpoint)
+            {
+                Some(summary) => {
+                    summary.head = commit.commit_id.clone();
+                    summary.commit_count += 1;
+                }
+                None => summaries.push(EndpointSummary {
+                    endpoint: commit.endpoint.clone(),
+                    head: commit.commit_id.clone(),
+                    commit_count: 1,
+                }),
+            }
+        }
+        summaries.sort_by_key(|summary| summary.endpoint.to_string());
+        summaries
+    }
+
+    /// Retain only the most recent `keep` commits, collapsing all the older ones
+    /// into a single baseline commit at the archive root, whose delta is the
+    /// composition of the pruned deltas.
+    ///
+    /// The baseline commit takes the id of the most recent pruned commit, so
+    /// that clients synchronized at any retained commit, at the most recent
+    /// pruned commit or at no commit at all can still get their update delta.
+    /// Clients synchronized at older pruned commits cannot
+    pub fn prune(&mut self, keep: usize) -> Result<(), PruneError> {
+        let CommitList(list) = self;
+        if list.len() <= keep {
+            return Ok(());
+        }
+
+        let retained = list.split_off(list.len() - keep);
+        let mut baseline_delta = Delta::empty();
+        let mut baseline_id = CommitID::null();
+        for commit in list.iter() {
+            baseline_delta = baseline_delta
+                .compose(&delta_at_root(commit))
+                .map_err(|err| PruneError(commit.commit_id.clone(), err))?;
+            baseline_id = commit.commit_id.clone();
+        }
+
+        *list = vec![Commit {
+            commit_id: baseline_id,
+            endpoint: AbstPath::empty(),
+            delta: baseline_delta,
+            metadata: CommitMetadata::default(),
+        }];
+        list.extend(retained);
+        Ok(())
+    }
+
+    /// Get the delta that brings `endpoint` from its state at commit `lkc` to its
+    /// state at the most recent commit. The null commit id stands for a client
+    /// that never synchronized and gets the whole history. Any other id must be
+    /// in the commit list, as the delta from a pruned commit cannot be computed
+    pub fn get_update_delta(
+        &self,
+        endpoint: &AbstPath,
+        lkc: CommitID,
+    ) -> Result<Delta, GetUpdError> {
         let mut output: Delta = Delta::empty();
         let CommitList(list) = self;
+        if !lkc.is_null() && !list.iter().any(|commit| commit.commit_id == lkc) {
+            return Err(GetUpdError::UnknownCommit(lkc));
+        }
         'commit_loop: for commit in list.iter().rev() {
             if commit.commit_id.eq(&lkc) {
                 break;
             }
-            let mut delta = commit.delta.clone();
             let mut commit_endpoint = commit.endpoint.clone();
             let mut curr_endpoint = endpoint.clone();
 
@@ -90,18 +309,486 @@ impl CommitList {
                     None => break,
                 }
             }
-            for component in commit_endpoint.into_iter().rev() {
-                let node = DeltaNode::Branch(None, delta);
-                let tree = HashMap::from([(component, node)]);
-                delta = Delta(tree)
-            }
+            let delta = commit.delta.clone().prefixed(&commit_endpoint);
 
             if let Some(delta_at_endpoint) = delta.get_subdelta_tree_copy(&curr_endpoint) {
                 if let Err(err) = output.merge_prec(&delta_at_endpoint) {
-                    return Err(GetUpdError(commit.commit_id.clone(), err));
+                    return Err(GetUpdError::Unmergeable(commit.commit_id.clone(), err));
                 }
             }
         }
         Ok(output)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Commit, CommitID, CommitIDError, CommitList, CommitMetadata, EndpointSummary, GetUpdError,
+    };
+    use crate::{get_delta, Delta, FSNode, FSTree};
+
+    use abst_fs::AbstPath;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test() {
+        injection();
+        prune();
+        endpoints();
+        log();
+        since();
+        metadata();
+        commit_id();
+    }
+
+    fn commit_id() {
+        // The string form round-trips and only accepts valid ids
+        let id = CommitID::gen_valid();
+        assert_eq!(id.to_string().len(), 64);
+        assert_eq!(id.to_string().parse::<CommitID>(), Ok(id.clone()));
+        assert!(!id.is_null());
+        let null = "0".repeat(64).parse::<CommitID>().unwrap();
+        assert!(null.is_null());
+        assert_eq!(null, CommitID::null());
+        for invalid in [
+            "",
+            "0123abcd",
+            &"A".repeat(64),
+            &"g".repeat(64),
+            &"0".repeat(65),
+        ] {
+            assert_eq!(
+                invalid.parse::<CommitID>(),
+                Err(CommitIDError(invalid.to_string()))
+            );
+        }
+
+        // The serialized form is the plain string, as ids were stored before
+        //	having their own type
+        let serialized = serde_json::to_string(&id).unwrap();
+        assert_eq!(serialized, format!("\"{}\"", id));
+        assert_eq!(serde_json::from_str::<CommitID>(&serialized).unwrap(), id);
+        assert!(serde_json::from_str::<CommitID>("\"not an id\"").is_err());
+        let path = PathBuf::from("/tmp/bbup-test-fs_vcs-commit-id");
+        assert!(!path.exists());
+        std::fs::create_dir(&path).unwrap();
+        let result = std::panic::catch_unwind(|| {
+            let file = AbstPath::from(path.join("id.bin"));
+            abst_fs::save(&file, &id.to_string()).unwrap();
+            assert_eq!(abst_fs::load::<CommitID>(&file).unwrap(), id);
+            abst_fs::save(&file, &id).unwrap();
+            assert_eq!(abst_fs::load::<String>(&file).unwrap(), id.to_string());
+        });
+        std::fs::remove_dir_all(&path).unwrap();
+        assert!(result.is_ok());
+
+        // Every id in the list can be used to get an update delta
+        let endpoint = AbstPath::from("endpoint");
+        let mut commit_list = CommitList::base_commit_list();
+        for _ in 0..3 {
+            commit_list.push(Commit {
+                commit_id: CommitID::gen_valid(),
+                endpoint: endpoint.clone(),
+                delta: Delta::empty(),
+                metadata: CommitMetadata::default(),
+            });
+        }
+        commit_list.prune(2).unwrap();
+        let ids = commit_list.ids();
+        assert_eq!(ids.len(), 4);
+        assert!(ids[0].is_null());
+        assert_eq!(ids[3], commit_list.most_recent_commit().commit_id);
+        for id in ids {
+            assert!(commit_list.get_update_delta(&endpoint, id).is_ok());
+        }
+    }
+
+    fn metadata() {
+        let path = PathBuf::from("/tmp/bbup-test-fs_vcs-commit-metadata");
+        assert!(!path.exists());
+        std::fs::create_dir(&path).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            let tree = FSTree::gen_from(|t| {
+                t.add_file("file", (1611850953, 971525938), "some content");
+            });
+            let metadata = CommitMetadata {
+                author: Some(String::from("someone")),
+                message: Some(String::from("add some content")),
+                timestamp: Some(1611850960),
+            };
+            let mut commit_list = CommitList::base_commit_list();
+            commit_list.push(Commit {
+                commit_id: CommitID::gen_valid(),
+                endpoint: AbstPath::from("endpoint"),
+                delta: get_delta(&FSTree::empty(), &tree),
+                metadata: metadata.clone(),
+            });
+
+            // Metadata round-trips through every format
+            for name in ["commit-list.bin", "commit-list.json"] {
+                let file = AbstPath::from(path.join(name));
+                abst_fs::save(&file, &commit_list).unwrap();
+                let CommitList(loaded) = CommitList::load(&file).unwrap();
+                assert_eq!(loaded.len(), 2);
+                assert_eq!(loaded[0].metadata, CommitMetadata::default());
+                assert_eq!(loaded[1].metadata, metadata);
+                assert_eq!(
+                    loaded[1].commit_id,
+                    commit_list.most_recent_commit().commit_id
+                );
+            }
+
+            // Commit lists saved before commits had metadata still load
+            #[derive(serde::Serialize)]
+            struct LegacyCommit {
+                commit_id: CommitID,
+                endpoint: AbstPath,
+                delta: Delta,
+            }
+            let legacy = vec![
+                LegacyCommit {
+                    commit_id: CommitID::null(),
+                    endpoint: AbstPath::empty(),
+                    delta: Delta::empty(),
+                },
+                LegacyCommit {
+                    commit_id: CommitID::gen_valid(),
+                    endpoint: AbstPath::from("endpoint"),
+                    delta: get_delta(&FSTree::empty(), &tree),
+                },
+            ];
+            for name in ["legacy.bin", "legacy.json"] {
+                let file = AbstPath::from(path.join(name));
+                abst_fs::save(&file, &legacy).unwrap();
+                let CommitList(loaded) = CommitList::load(&file).unwrap();
+                assert_eq!(loaded.len(), 2);
+                assert_eq!(loaded[1].commit_id, legacy[1].commit_id);
+                assert_eq!(loaded[1].delta, legacy[1].delta);
+                assert_eq!(loaded[1].metadata, CommitMetadata::default());
+            }
+
+            // Anything else is still an error
+            let file = AbstPath::from(path.join("garbage.json"));
+            std::fs::write(file.to_path_buf(), b"not a commit list").unwrap();
+            assert!(CommitList::load(&file).is_err());
+        });
+        std::fs::remove_dir_all(&path).unwrap();
+        assert!(result.is_ok())
+    }
+
+    fn since() {
+        let tree_a = FSTree::gen_from(|t| {
+            t.add_file("file", (1611850953, 971525938), "first content");
+        });
+        let tree_b = FSTree::gen_from(|t| {
+            t.add_file("file", (1611850953, 971525938), "second content");
+            t.add_dir("dir", (1364181678, 477789959), |t| {
+                t.add_symlink("symlink", (808926076, 398339329), "some/fake/path");
+            });
+        });
+        let tree_c = FSTree::gen_from(|t| {
+            t.add_dir("dir", (1364181678, 477789959), |t| {
+                t.add_symlink("symlink", (808926076, 398339329), "some/fake/path");
+                t.add_file("new-file", (1245890614, 586345017), "new content");
+            });
+        });
+        let trees = [FSTree::empty(), tree_a, tree_b, tree_c];
+
+        let endpoint = AbstPath::from("endpoint");
+        let mut commit_list = CommitList::base_commit_list();
+        let mut ids = vec![CommitID::null()];
+        for pair in trees.windows(2) {
+            let commit_id = CommitID::gen_valid();
+            commit_list.push(Commit {
+                commit_id: commit_id.clone(),
+                endpoint: endpoint.clone(),
+                delta: get_delta(&pair[0], &pair[1]),
+                metadata: CommitMetadata::default(),
+            });
+            ids.push(commit_id);
+        }
+
+        // A client bootstrapped from the snapshot of a commit in the middle of
+        //	the history converges to the head
+        let delta = commit_list
+            .get_update_delta(&endpoint, ids[2].clone())
+            .unwrap();
+        let mut tree = trees[2].clone();
+        tree.apply_delta(&delta).unwrap();
+        assert_eq!(tree, trees[3]);
+
+        // Commits that are not in the list are rejected instead of silently
+        //	replaying the whole history
+        let unknown_id = CommitID::gen_valid();
+        assert!(matches!(
+            commit_list.get_update_delta(&endpoint, unknown_id.clone()),
+            Err(GetUpdError::UnknownCommit(id)) if id == unknown_id
+        ));
+        commit_list.prune(1).unwrap();
+        assert!(matches!(
+            commit_list.get_update_delta(&endpoint, ids[1].clone()),
+            Err(GetUpdError::UnknownCommit(_))
+        ));
+        assert!(commit_list
+            .get_update_delta(&endpoint, ids[2].clone())
+            .is_ok());
+    }
+
+    fn endpoints() {
+        let mut commit_list = CommitList::base_commit_list();
+        assert_eq!(commit_list.endpoints(), vec![]);
+
+        let mut push = |endpoint: &str| {
+            let commit_id = CommitID::gen_valid();
+            commit_list.push(Commit {
+                commit_id: commit_id.clone(),
+                endpoint: AbstPath::from(endpoint),
+                delta: Delta::empty(),
+                metadata: CommitMetadata::default(),
+            });
+            commit_id
+        };
+        push("photos");
+        let docs_head = push("docs/work");
+        push("photos");
+        let photos_head = push("photos");
+        push("");
+
+        assert_eq!(
+            commit_list.endpoints(),
+            vec![
+                EndpointSummary {
+                    endpoint: AbstPath::from("docs/work"),
+                    head: docs_head,
+                    commit_count: 1,
+                },
+                EndpointSummary {
+                    endpoint: AbstPath::from("photos"),
+                    head: photos_head,
+                    commit_count: 3,
+                },
+            ]
+        );
+    }
+
+    fn injection() {
+        // A link injected at a subpath of the archive, which holds more than the
+        //	link itself
+        let endpoint = AbstPath::from("backups/laptop");
+        let link_before = FSTree::gen_from(|t| {
+            t.add_file("kept", (1443314904, 885035178), "some content");
+            t.add_file("removed", (1443314904, 885035178), "some content");
+            t.add_dir("dir", (1512376465, 64263102), |t| {
+                t.add_file("edited", (1443314904, 885035178), "some content");
+            });
+        });
+        let link_after = FSTree::gen_from(|t| {
+            t.add_file("kept", (1443314904, 885035178), "some content");
+            t.add_file("added", (1420465793, 406504192), "other content");
+            t.add_dir("dir", (1512376465, 64263102), |t| {
+                t.add_file("edited", (1420465793, 406504192), "other content");
+            });
+        });
+        let archive = |link: &FSTree| {
+            let FSTree(link) = link.clone();
+            FSTree::gen_from(|t| {
+                t.add_file("unrelated", (1287750559, 427846972), "unrelated");
+                t.add_dir("backups", (1287750559, 427846972), |t| {
+                    t.add_empty_dir("desktop", (1287750559, 427846972));
+                    t.add_dir("laptop", (1287750559, 427846972), |t| {
+                        let FSTree(tree) = t;
+                        tree.extend(link.clone());
+                    });
+                });
+            })
+        };
+        let delta = get_delta(&link_before, &link_after);
+
+        // The link pushes its changes prefixed by the subpath, which apply at
+        //	the archive root as the plain changes apply at the subpath
+        let pushed = delta.clone().prefixed(&endpoint);
+        let mut at_root = archive(&link_before);
+        at_root.apply_delta(&pushed).unwrap();
+        let mut at_endpoint = archive(&link_before);
+        at_endpoint
+            .apply_delta_at_endpoint(&delta, endpoint.clone())
+            .unwrap();
+        assert_eq!(at_root, at_endpoint);
+        assert_eq!(at_root, archive(&link_after));
+        assert!(Delta::empty().prefixed(&endpoint).is_empty());
+        assert_eq!(delta.clone().prefixed(&AbstPath::empty()), delta);
+
+        // Committed at the archive root, the changes come back from the update
+        //	of the root stripped of the subpath, together with the changes made
+        //	elsewhere under the subpath, and without the ones outside of it
+        let mut commit_list = CommitList::base_commit_list();
+        let mut push = |endpoint: &str, delta: Delta| {
+            commit_list.push(Commit {
+                commit_id: CommitID::gen_valid(),
+                endpoint: AbstPath::from(endpoint),
+                delta,
+                metadata: CommitMetadata::default(),
+            });
+        };
+        push("", pushed);
+        let mut link_final = link_after.clone();
+        let FSTree(tree) = &mut link_final;
+        tree.insert(
+            String::from("from-elsewhere"),
+            FSNode::file((1420465793, 406504192), "elsewhere"),
+        );
+        push("backups/laptop", get_delta(&link_after, &link_final));
+        let outside = get_delta(
+            &FSTree::empty(),
+            &FSTree::gen_from(|t| t.add_file("new", (1420465793, 406504192), "new")),
+        );
+        push("backups/desktop", outside);
+        let update = commit_list
+            .get_update_delta(&AbstPath::empty(), CommitID::null())
+            .unwrap();
+        let expected = get_delta(&link_before, &link_final);
+        assert_eq!(
+            update.get_subdelta_tree_copy(&endpoint),
+            Some(expected.clone())
+        );
+        assert_eq!(
+            commit_list
+                .get_update_delta(&endpoint, CommitID::null())
+                .unwrap(),
+            expected
+        );
+    }
+
+    fn log() {
+        let mut commit_list = CommitList::base_commit_list();
+        assert!(commit_list.log(&AbstPath::empty()).is_empty());
+
+        let mut push = |endpoint: &str| {
+            let commit_id = CommitID::gen_valid();
+            commit_list.push(Commit {
+                commit_id: commit_id.clone(),
+                endpoint: AbstPath::from(endpoint),
+                delta: Delta::empty(),
+                metadata: CommitMetadata::default(),
+            });
+            commit_id
+        };
+        let first = push("photos");
+        let second = push("docs/work");
+        let third = push("photos/2022");
+        let fourth = push("photos");
+        let ids = |commits: Vec<&Commit>| -> Vec<CommitID> {
+            commits
+                .into_iter()
+                .map(|commit| commit.commit_id.clone())
+                .collect()
+        };
+
+        assert_eq!(
+            ids(commit_list.log(&AbstPath::from("photos"))),
+            vec![fourth.clone(), third.clone(), first.clone()]
+        );
+        assert_eq!(
+            ids(commit_list.log(&AbstPath::from("photos/2022"))),
+            vec![third.clone()]
+        );
+        assert_eq!(
+            ids(commit_list.log(&AbstPath::from("docs"))),
+            vec![second.clone()]
+        );
+        assert!(commit_list.log(&AbstPath::from("music")).is_empty());
+        assert!(commit_list.log(&AbstPath::from("photo")).is_empty());
+        assert_eq!(
+            ids(commit_list.log(&AbstPath::empty())),
+            vec![fourth, third, second, first]
+        );
+    }
+
+    fn prune() {
+        let tree_a = FSTree::gen_from(|t| {
+            t.add_file("file", (1611850953, 971525938), "first content");
+            t.add_dir("dir", (1364181678, 477789959), |t| {
+                t.add_symlink("symlink", (808926076, 398339329), "some/fake/path");
+            });
+        });
+        let tree_b = FSTree::gen_from(|t| {
+            t.add_file("file", (1611850953, 971525938), "second content");
+            t.add_dir("dir", (1364181678, 477789959), |t| {
+                t.add_symlink("symlink", (808926076, 398339329), "some/fake/path");
+                t.add_file("new-file", (1245890614, 586345017), "new content");
+            });
+        });
+        let tree_c = FSTree::gen_from(|t| {
+            t.add_file("file", (1611850953, 971525938), "second content");
+            t.add_empty_dir("other-dir", (590816735, 667223352));
+        });
+        let tree_d = FSTree::gen_from(|t| {
+            t.add_file("file", (997012509, 922451121), "third content");
+            t.add_empty_dir("other-dir", (590816735, 667223352));
+        });
+        let trees = [FSTree::empty(), tree_a, tree_b, tree_c, tree_d];
+
+        let endpoint = AbstPath::from("some/endpoint");
+        let mut commit_list = CommitList::base_commit_list();
+        let mut ids = vec![CommitID::null()];
+        for (i, pair) in trees.windows(2).enumerate() {
+            let commit_id = CommitID::gen_valid();
+            commit_list.push(Commit {
+                commit_id: commit_id.clone(),
+                endpoint: endpoint.clone(),
+                delta: get_delta(&pair[0], &pair[1]),
+                metadata: CommitMetadata::default(),
+            });
+            ids.push(commit_id);
+
+            // Commits on other endpoints must not interfere
+            commit_list.push(Commit {
+                commit_id: CommitID::gen_valid(),
+                endpoint: AbstPath::from(format!("some/other-endpoint-{i}")),
+                delta: get_delta(&FSTree::empty(), &pair[1]),
+                metadata: CommitMetadata::default(),
+            });
+        }
+        let head_id = commit_list.most_recent_commit().commit_id.clone();
+
+        let converges = |commit_list: &CommitList, lkc: &CommitID, tree: &FSTree| {
+            let delta = commit_list
+                .get_update_delta(&endpoint, lkc.clone())
+                .unwrap();
+            let mut tree = tree.clone();
+            tree.apply_delta(&delta).unwrap();
+            assert_eq!(tree, trees[4]);
+        };
+
+        // Nothing to prune
+        commit_list.prune(100).unwrap();
+        assert_eq!(commit_list.0.len(), 9);
+        for (id, tree) in ids.iter().zip(trees.iter()) {
+            converges(&commit_list, id, tree);
+        }
+
+        // Retain the commits after tree_b (and the ones on the other endpoint
+        //	interleaved with them), collapse the rest
+        commit_list.prune(4).unwrap();
+        assert_eq!(commit_list.0.len(), 5);
+        assert_eq!(commit_list.0[0].endpoint, AbstPath::empty());
+        assert_eq!(commit_list.most_recent_commit().commit_id, head_id);
+
+        // Client never synchronized
+        converges(&commit_list, &ids[0], &trees[0]);
+        // Client synchronized at the most recent pruned commit
+        converges(&commit_list, &commit_list.0[0].commit_id.clone(), &trees[2]);
+        // Clients synchronized at retained commits
+        converges(&commit_list, &ids[3], &trees[3]);
+        converges(&commit_list, &ids[4], &trees[4]);
+
+        // Collapse everything
+        commit_list.prune(0).unwrap();
+        assert_eq!(commit_list.0.len(), 1);
+        assert_eq!(commit_list.most_recent_commit().commit_id, head_id);
+        converges(&commit_list, &ids[0], &trees[0]);
+        converges(&commit_list, &head_id, &trees[4]);
+    }
+}
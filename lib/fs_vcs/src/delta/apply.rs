@@ -28,10 +28,14 @@ impl FSTree {
             Some(name) => {
                 let FSTree(fstree) = self;
                 match fstree.get_mut(name) {
-                    Some(FSNode::Dir(_, _, subtree)) => {
-                        subtree.apply_delta_at_endpoint(delta, endpoint.strip_first())
+                    Some(FSNode::Dir(_, hash, subtree)) => {
+                        subtree
+                            .apply_delta_at_endpoint(delta, endpoint.strip_first())
+                            .map_err(push_inapp(name))?;
+                        *hash = hash_tree(subtree);
+                        Ok(())
                     }
-                    Some(FSNode::File(_, _)) => Err(inapperr(
+                    Some(FSNode::File(_, _, _, _)) => Err(inapperr(
                         &AbstPath::single(name),
                         "endpoint claims this node is a directory, but it is a file",
                     )),
@@ -122,7 +126,7 @@ impl FSTree {
                             subtree.apply_delta(subdelta).map_err(push_inapp(name))?;
                             *hash = hash_tree(subtree);
                         }
-                        FSNode::File(_, _) => {
+                        FSNode::File(_, _, _, _) => {
                             return Err(inapperr(
                                 &AbstPath::single(name),
                                 "delta claims this node is a directory, but it is a file in tree",
@@ -221,7 +225,7 @@ impl FSTree {
                             subtree.undo_delta(subdelta).map_err(push_inapp(name))?;
                             *hash = hash_tree(subtree);
                         }
-                        FSNode::File(_, _) => {
+                        FSNode::File(_, _, _, _) => {
                             return Err(inapperr(
                                 &AbstPath::single(name),
                                 "delta claims this node is a directory, but it is a file in tree",
@@ -1,23 +1,59 @@
 use super::{hash_tree, AbstPath, Delta, DeltaNode, ExcludeList, FSNode, FSTree};
 
+use std::collections::HashMap;
+
+/// Whether the two files (or symlinks) have the same content, and the same mode
+/// in the case of files, regardless of their mtime
+fn same_content(node0: &FSNode, node1: &FSNode) -> bool {
+    match (node0, node1) {
+        (FSNode::File(_, h0, p0, _), FSNode::File(_, h1, p1, _)) => h0 == h1 && p0 == p1,
+        (FSNode::SymLink(_, h0), FSNode::SymLink(_, h1)) => h0 == h1,
+        _ => false,
+    }
+}
+
 impl FSTree {
     fn filter_out_rec(&mut self, rel_path: &AbstPath, exclude_list: &ExcludeList) {
         let FSTree(tree) = self;
         tree.retain(|name, child| match child {
-            FSNode::File(_, _) => !exclude_list.should_exclude(&rel_path.add_last(name), false),
+            FSNode::File(_, _, _, _) => {
+                !exclude_list.should_exclude(&rel_path.add_last(name), false)
+            }
             FSNode::SymLink(_, _) => !exclude_list.should_exclude(&rel_path.add_last(name), false),
             FSNode::Dir(_, hash, subtree) => {
-                if exclude_list.should_exclude(&rel_path.add_last(name), true) {
+                let excluded = exclude_list.should_exclude(&rel_path.add_last(name), true);
+                if excluded && !exclude_list.has_negations() {
                     return false;
                 }
                 subtree.filter_out_rec(&rel_path.add_last(name), exclude_list);
                 *hash = hash_tree(subtree);
-                true
+                // An excluded directory is kept only as a container for the
+                //	objects that have been re-included
+                !(excluded && subtree.0.is_empty())
             }
         });
     }
 }
 
+/// Filter the subtree of a leaf state (if it's a directory) and tell whether
+/// the state should be kept
+fn node_survives_filter(
+    node: &mut Option<FSNode>,
+    path: &AbstPath,
+    exclude_list: &ExcludeList,
+) -> bool {
+    match node {
+        Some(FSNode::Dir(_, hash, subtree)) => {
+            subtree.filter_out_rec(path, exclude_list);
+            *hash = hash_tree(subtree);
+            !exclude_list.should_exclude(path, true)
+                || (exclude_list.has_negations() && !subtree.0.is_empty())
+        }
+        Some(_) => !exclude_list.should_exclude(path, false),
+        None => true,
+    }
+}
+
 impl Delta {
     // TODO maybe these should return something about what they have filtered out?
     pub fn filter_out(&mut self, exclude_list: &ExcludeList) {
@@ -28,52 +64,494 @@ impl Delta {
         for (name, child) in tree {
             match child {
                 DeltaNode::Leaf(pre, post) => {
-                    let is_pre_dir = if let Some(FSNode::Dir(_, hash, subtree)) = pre {
-                        subtree.filter_out_rec(&rel_path.add_last(name), exclude_list);
-                        *hash = hash_tree(subtree);
-                        true
-                    } else {
-                        false
-                    };
-                    if exclude_list.should_exclude(&rel_path.add_last(name), is_pre_dir) {
+                    if !node_survives_filter(pre, &rel_path.add_last(name), exclude_list) {
                         *pre = None;
                     }
-
-                    let is_post_dir = if let Some(FSNode::Dir(_, hash, subtree)) = post {
-                        subtree.filter_out_rec(&rel_path.add_last(name), exclude_list);
-                        *hash = hash_tree(subtree);
-                        true
-                    } else {
-                        false
-                    };
-                    if exclude_list.should_exclude(&rel_path.add_last(name), is_post_dir) {
+                    if !node_survives_filter(post, &rel_path.add_last(name), exclude_list) {
                         *post = None;
                     }
                 }
                 DeltaNode::Branch(optm, subdelta) => {
-                    if exclude_list.should_exclude(&rel_path.add_last(name), true) {
+                    if !exclude_list.should_exclude(&rel_path.add_last(name), true) {
+                        subdelta.filter_out_rec(&rel_path.add_last(name), exclude_list);
+                    } else if exclude_list.has_negations() {
+                        // Only the changes to re-included objects survive
+                        subdelta.filter_out_rec(&rel_path.add_last(name), exclude_list);
+                        if subdelta.is_empty() {
+                            *optm = None;
+                        }
+                    } else {
                         // Make it so that the branch will be removed once the
                         //	delta gets shaken at the end of the function
                         *optm = None;
                         *subdelta = Delta::empty();
-                    } else {
-                        subdelta.filter_out_rec(&rel_path.add_last(name), exclude_list);
                     }
                 }
             }
         }
         self.shake();
     }
+
+    /// Remove from the delta every node that would delete or overwrite an object,
+    /// edits and replacements included, so that the delta only adds new objects
+    /// (and sets the mtimes of the directories they are added to). This is used
+    /// by add-only pushes, where nothing already in the archive is touched
+    pub fn strip_to_additions(&mut self) {
+        let Delta(tree) = self;
+        for child in tree.values_mut() {
+            match child {
+                DeltaNode::Leaf(pre, post) if pre.is_some() => {
+                    // Make it so that the leaf will be removed once the
+                    //	delta gets shaken at the end of the function
+                    *pre = None;
+                    *post = None;
+                }
+                DeltaNode::Leaf(_, _) => {}
+                DeltaNode::Branch(_, subdelta) => subdelta.strip_to_additions(),
+            }
+        }
+        self.shake();
+    }
+
+    /// Remove from the delta the changes to the mtimes of directories, dropping
+    /// the directories left with no change at all. Used when the mtimes of
+    /// directories are ignored, so that applying the delta doesn't edit them
+    pub fn strip_dir_mtimes(&mut self) {
+        let Delta(tree) = self;
+        for child in tree.values_mut() {
+            if let DeltaNode::Branch(optm, subdelta) = child {
+                *optm = None;
+                subdelta.strip_dir_mtimes();
+            }
+        }
+        tree.retain(|_, child| match child {
+            DeltaNode::Branch(optm, subdelta) => optm.is_some() || !subdelta.is_empty(),
+            DeltaNode::Leaf(_, _) => true,
+        });
+    }
+
+    /// Remove from the delta every change that only touches the mtime of an
+    /// object, and return these changes as a separate delta: files and symlinks
+    /// whose content (and mode) didn't change, and directories whose content
+    /// didn't change either. Used when mtimes are not trusted and objects are
+    /// compared by content only
+    pub fn take_mtime_only_edits(&mut self) -> Delta {
+        let Delta(tree) = self;
+        let mut taken = HashMap::new();
+        for (name, child) in tree.iter_mut() {
+            match child {
+                DeltaNode::Leaf(Some(pre), Some(post)) if same_content(pre, post) => {
+                    taken.insert(
+                        name.clone(),
+                        DeltaNode::Leaf(Some(pre.clone()), Some(post.clone())),
+                    );
+                    // The leaf left behind will be removed once the delta
+                    //	gets shaken at the end of the function
+                    *post = pre.clone();
+                }
+                DeltaNode::Leaf(_, _) => {}
+                DeltaNode::Branch(optm, subdelta) => {
+                    let subtaken = subdelta.take_mtime_only_edits();
+                    let optm = match subdelta.is_empty() {
+                        true => optm.take(),
+                        false => None,
+                    };
+                    if optm.is_some() || !subtaken.is_empty() {
+                        taken.insert(name.clone(), DeltaNode::Branch(optm, subtaken));
+                    }
+                }
+            }
+        }
+        self.shake();
+        Delta(taken)
+    }
+
+    /// Remove from the delta every node that would delete an object matching
+    /// the exclude list, and return these nodes as a separate delta. Tracked
+    /// objects that become excluded are missing from the newly generated tree
+    /// even though they have not actually been deleted, so their removals
+    /// should only be pushed if explicitly requested
+    pub fn take_excluded_removals(&mut self, exclude_list: &ExcludeList) -> Delta {
+        self.take_excluded_removals_rec(&AbstPath::single("."), exclude_list)
+    }
+    fn take_excluded_removals_rec(
+        &mut self,
+        rel_path: &AbstPath,
+        exclude_list: &ExcludeList,
+    ) -> Delta {
+        let Delta(tree) = self;
+        let mut taken = HashMap::new();
+        for (name, child) in tree.iter_mut() {
+            match child {
+                DeltaNode::Leaf(pre, None) => {
+                    let is_dir = matches!(pre, Some(FSNode::Dir(_, _, _)));
+                    if pre.is_some()
+                        && exclude_list.should_exclude(&rel_path.add_last(name), is_dir)
+                    {
+                        // The leaf left behind will be removed once the delta
+                        //	gets shaken at the end of the function
+                        taken.insert(name.clone(), DeltaNode::Leaf(pre.take(), None));
+                    }
+                }
+                DeltaNode::Leaf(_, Some(_)) => {}
+                DeltaNode::Branch(_, subdelta) => {
+                    let subtaken =
+                        subdelta.take_excluded_removals_rec(&rel_path.add_last(name), exclude_list);
+                    if !subtaken.is_empty() {
+                        taken.insert(name.clone(), DeltaNode::Branch(None, subtaken));
+                    }
+                }
+            }
+        }
+        self.shake();
+        Delta(taken)
+    }
+
+    /// Get the delta without the changes under the directories `max_depth` levels
+    /// below its root (1 for the objects directly in it), which a tree generated
+    /// with `generate_fstree_to_depth` doesn't look into. The removal or the
+    /// replacement of one of these directories is left out as well, as it would
+    /// take away content whose changes are not known, while adding one is kept
+    pub fn filter_depth(&self, max_depth: usize) -> Delta {
+        let Delta(tree) = self;
+        let mut filtered = HashMap::new();
+        for (name, node) in tree {
+            let node = match node {
+                DeltaNode::Branch(..) | DeltaNode::Leaf(Some(FSNode::Dir(..)), _)
+                    if max_depth <= 1 =>
+                {
+                    continue
+                }
+                DeltaNode::Branch(optm, subdelta) => {
+                    let subdelta = subdelta.filter_depth(max_depth - 1);
+                    if optm.is_none() && subdelta.is_empty() {
+                        continue;
+                    }
+                    DeltaNode::Branch(optm.clone(), subdelta)
+                }
+                DeltaNode::Leaf(pre, post) => DeltaNode::Leaf(pre.clone(), post.clone()),
+            };
+            filtered.insert(name.clone(), node);
+        }
+        Delta(filtered)
+    }
+
+    /// Get the delta containing only the changes at or under path (relative to
+    /// the root of the delta), enclosed in the branches of its ancestors. The
+    /// changes to the mtimes of the ancestors are left out, as they are not under
+    /// path. If an ancestor of path is itself added, removed or replaced, its whole
+    /// change is kept, as the changes under path can't be applied without it
+    pub fn filter_subpath(&self, path: &AbstPath) -> Delta {
+        let Delta(tree) = self;
+        let name = match path.get(0) {
+            Some(name) => name,
+            None => return self.clone(),
+        };
+        let rest = path.strip_first();
+        let node = match tree.get(name) {
+            Some(DeltaNode::Branch(optm, subdelta)) => {
+                if rest.is_empty() {
+                    DeltaNode::Branch(optm.clone(), subdelta.clone())
+                } else {
+                    let subdelta = subdelta.filter_subpath(&rest);
+                    if subdelta.is_empty() {
+                        return Delta::empty();
+                    }
+                    DeltaNode::Branch(None, subdelta)
+                }
+            }
+            Some(DeltaNode::Leaf(pre, post)) => {
+                let contains_rest = |node: &Option<FSNode>| match node {
+                    Some(FSNode::Dir(_, _, subtree)) => subtree.get_at(&rest).is_some(),
+                    _ => false,
+                };
+                if !rest.is_empty() && !contains_rest(pre) && !contains_rest(post) {
+                    return Delta::empty();
+                }
+                DeltaNode::Leaf(pre.clone(), post.clone())
+            }
+            None => return Delta::empty(),
+        };
+        Delta(HashMap::from([(name.clone(), node)]))
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::FSTree;
+    use crate::{get_delta, Action, FSTree};
 
-    use super::{Delta, ExcludeList, FSNode};
+    use super::{AbstPath, Delta, ExcludeList, FSNode};
 
     #[test]
     fn test() {
+        filter_out();
+        strip_to_additions();
+        strip_dir_mtimes();
+        take_mtime_only_edits();
+        take_excluded_removals();
+        filter_subpath();
+        filter_depth();
+    }
+
+    fn take_mtime_only_edits() {
+        // Same content everywhere, but with shuffled mtimes
+        let pre_tree = FSTree::gen_from(|t| {
+            t.add_file("file", (1443314904, 885035178), "some content");
+            t.add_symlink("symlink", (1420465793, 406504192), "some/fake/path");
+            t.add_dir("dir", (1395328184, 869950727), |t| {
+                t.add_file("file", (1396487263, 534084134), "other content");
+                t.add_empty_dir("empty", (1512376465, 64263102));
+            });
+        });
+        let post_tree = FSTree::gen_from(|t| {
+            t.add_file("file", (1396487263, 534084134), "some content");
+            t.add_symlink("symlink", (1443314904, 885035178), "some/fake/path");
+            t.add_dir("dir", (1512376465, 64263102), |t| {
+                t.add_file("file", (1420465793, 406504192), "other content");
+                t.add_empty_dir("empty", (1395328184, 869950727));
+            });
+        });
+        let mut delta = get_delta(&pre_tree, &post_tree);
+        let full_delta = delta.clone();
+        let taken = delta.take_mtime_only_edits();
+        assert_eq!(delta, Delta::empty());
+        assert_eq!(taken, full_delta);
+
+        // Reverting the taken edits sets the mtimes back without transferring
+        //	any content
+        let actions = taken.invert().to_actions();
+        assert!(!actions.is_empty());
+        assert!((&actions).into_iter().all(|(_, action)| matches!(
+            action,
+            Action::EditFile(Some(_), None, None)
+                | Action::EditSymLink(Some(_), None)
+                | Action::EditDir(_)
+        )));
+        let mut reverted_tree = post_tree.clone();
+        reverted_tree.undo_delta(&taken).unwrap();
+        assert_eq!(reverted_tree, pre_tree);
+
+        // Changes to the content or the mode are kept, together with their mtime
+        let post_tree = FSTree::gen_from(|t| {
+            t.add_file("file", (1396487263, 534084134), "edited content");
+            t.add_symlink("symlink", (1443314904, 885035178), "some/fake/path");
+            t.add_dir("dir", (1512376465, 64263102), |t| {
+                t.add_file("file", (1420465793, 406504192), "other content");
+                t.add_empty_dir("empty", (1395328184, 869950727));
+                t.add_file("added", (1420465793, 406504192), "new content");
+            });
+        });
+        let mut delta = get_delta(&pre_tree, &post_tree);
+        let taken = delta.take_mtime_only_edits();
+        let supposed_delta = Delta::gen_from(|d| {
+            d.add_leaf(
+                "file",
+                Some(FSNode::file((1443314904, 885035178), "some content")),
+                Some(FSNode::file((1396487263, 534084134), "edited content")),
+            );
+            d.add_branch(
+                "dir",
+                Some(((1395328184, 869950727), (1512376465, 64263102))),
+                |d| {
+                    d.add_leaf(
+                        "added",
+                        None,
+                        Some(FSNode::file((1420465793, 406504192), "new content")),
+                    );
+                },
+            );
+        });
+        assert_eq!(delta, supposed_delta);
+        let mut composed = taken.compose(&delta).unwrap();
+        composed.shake();
+        assert_eq!(composed, get_delta(&pre_tree, &post_tree));
+    }
+
+    fn take_excluded_removals() {
+        let exclude_list = ExcludeList::from(&vec![String::from("build/")]).unwrap();
+        let tracked_tree = FSTree::gen_from(|t| {
+            t.add_dir("build", (1512376465, 64263102), |t| {
+                t.add_file("out", (1443314904, 885035178), "some content");
+            });
+            t.add_dir("src", (1512376465, 64263102), |t| {
+                t.add_file("main", (1443314904, 885035178), "some content");
+                t.add_file("removed", (1443314904, 885035178), "some content");
+            });
+        });
+        // The directory is now excluded, so it is missing from the new tree
+        let new_tree = FSTree::gen_from(|t| {
+            t.add_dir("src", (1512376465, 64263102), |t| {
+                t.add_file("main", (1420465793, 406504192), "other content");
+            });
+        });
+
+        let mut delta = get_delta(&tracked_tree, &new_tree);
+        let excluded_removals = delta.take_excluded_removals(&exclude_list);
+        assert_eq!(
+            excluded_removals,
+            Delta::gen_from(|d| {
+                d.add_leaf(
+                    "build",
+                    tracked_tree.get_at(&AbstPath::from("build")).cloned(),
+                    None,
+                );
+            })
+        );
+        // Actual removals of objects that are not excluded are left untouched
+        assert_eq!(
+            delta,
+            Delta::gen_from(|d| {
+                d.add_branch("src", None, |d| {
+                    d.add_leaf(
+                        "main",
+                        Some(FSNode::file((1443314904, 885035178), "some content")),
+                        Some(FSNode::file((1420465793, 406504192), "other content")),
+                    );
+                    d.add_leaf(
+                        "removed",
+                        Some(FSNode::file((1443314904, 885035178), "some content")),
+                        None,
+                    );
+                });
+            })
+        );
+
+        // Without the excluded removals the archive keeps the excluded directory
+        let mut archive_tree = tracked_tree.clone();
+        archive_tree.apply_delta(&delta).unwrap();
+        assert!(archive_tree.get_at(&AbstPath::from("build")).is_some());
+
+        // With them the directory gets removed from the archive as well
+        let pushed_delta = excluded_removals.compose(&delta).unwrap();
+        assert!(
+            (&pushed_delta.to_actions())
+                .into_iter()
+                .any(|(path, action)| path == &AbstPath::from("build")
+                    && action == &Action::RemoveDir)
+        );
+        let mut archive_tree = tracked_tree.clone();
+        archive_tree.apply_delta(&pushed_delta).unwrap();
+        assert_eq!(archive_tree, new_tree);
+    }
+
+    fn filter_subpath() {
+        let file = FSNode::file((1443314904, 885035178), "some content");
+        let new_file = FSNode::file((1420465793, 406504192), "other content");
+        let dir = FSNode::dir((1512376465, 64263102), |t| {
+            t.add_file("name1", (1443314904, 885035178), "some content");
+        });
+        let premtime = (1395328184, 869950727);
+        let postmtime = (1396487263, 534084134);
+
+        let delta = Delta::gen_from(|d| {
+            d.add_leaf("unrelated", Some(file.clone()), None);
+            d.add_branch("docs", Some((premtime, postmtime)), |d| {
+                d.add_leaf("unrelated", None, Some(file.clone()));
+                d.add_branch("work", Some((premtime, postmtime)), |d| {
+                    d.add_leaf("edited", Some(file.clone()), Some(new_file.clone()));
+                    d.add_branch("deep", None, |d| {
+                        d.add_leaf("added", None, Some(file.clone()));
+                    });
+                });
+            });
+            d.add_leaf("added-dir", None, Some(dir.clone()));
+        });
+
+        // Only the changes under the subpath survive, enclosed in branches
+        //	without the mtime changes of the ancestors
+        let supposed_delta = Delta::gen_from(|d| {
+            d.add_branch("docs", None, |d| {
+                d.add_branch("work", Some((premtime, postmtime)), |d| {
+                    d.add_leaf("edited", Some(file.clone()), Some(new_file.clone()));
+                    d.add_branch("deep", None, |d| {
+                        d.add_leaf("added", None, Some(file.clone()));
+                    });
+                });
+            });
+        });
+        assert_eq!(
+            delta.filter_subpath(&AbstPath::from("docs/work")),
+            supposed_delta
+        );
+        let supposed_delta = Delta::gen_from(|d| {
+            d.add_branch("docs", None, |d| {
+                d.add_branch("work", None, |d| {
+                    d.add_leaf("edited", Some(file.clone()), Some(new_file.clone()));
+                });
+            });
+        });
+        assert_eq!(
+            delta.filter_subpath(&AbstPath::from("docs/work/edited")),
+            supposed_delta
+        );
+
+        // Changes of an ancestor of the subpath are kept whole
+        let supposed_delta = Delta::gen_from(|d| {
+            d.add_leaf("added-dir", None, Some(dir.clone()));
+        });
+        assert_eq!(
+            delta.filter_subpath(&AbstPath::from("added-dir/name1")),
+            supposed_delta
+        );
+
+        // Unrelated or missing subpaths give an empty delta
+        assert!(delta
+            .filter_subpath(&AbstPath::from("added-dir/missing"))
+            .is_empty());
+        assert!(delta
+            .filter_subpath(&AbstPath::from("unrelated/child"))
+            .is_empty());
+        assert!(delta.filter_subpath(&AbstPath::from("missing")).is_empty());
+        assert!(delta
+            .filter_subpath(&AbstPath::from("docs/other"))
+            .is_empty());
+        assert_eq!(delta.filter_subpath(&AbstPath::empty()), delta);
+    }
+
+    fn filter_depth() {
+        let file = FSNode::file((1443314904, 885035178), "some content");
+        let new_file = FSNode::file((1420465793, 406504192), "other content");
+        let dir = FSNode::dir((1512376465, 64263102), |t| {
+            t.add_file("name1", (1443314904, 885035178), "some content");
+        });
+        let premtime = (1395328184, 869950727);
+        let postmtime = (1396487263, 534084134);
+
+        let delta = Delta::gen_from(|d| {
+            d.add_leaf("edited", Some(file.clone()), Some(new_file.clone()));
+            d.add_branch("docs", Some((premtime, postmtime)), |d| {
+                d.add_leaf("added", None, Some(file.clone()));
+                d.add_leaf("added-dir", None, Some(dir.clone()));
+                d.add_leaf("removed-dir", Some(dir.clone()), None);
+                d.add_branch("work", Some((premtime, postmtime)), |d| {
+                    d.add_leaf("edited", Some(file.clone()), Some(new_file.clone()));
+                });
+                d.add_branch("deep", None, |d| {
+                    d.add_leaf("added", None, Some(file.clone()));
+                });
+            });
+        });
+
+        // The changes under the directories at the last level are left out, and
+        //	so are the ones that would remove such a directory
+        let supposed_delta = Delta::gen_from(|d| {
+            d.add_leaf("edited", Some(file.clone()), Some(new_file.clone()));
+            d.add_branch("docs", Some((premtime, postmtime)), |d| {
+                d.add_leaf("added", None, Some(file.clone()));
+                d.add_leaf("added-dir", None, Some(dir.clone()));
+            });
+        });
+        assert_eq!(delta.filter_depth(2), supposed_delta);
+
+        let supposed_delta = Delta::gen_from(|d| {
+            d.add_leaf("edited", Some(file.clone()), Some(new_file.clone()));
+        });
+        assert_eq!(delta.filter_depth(1), supposed_delta);
+        assert_eq!(delta.filter_depth(0), supposed_delta);
+        assert_eq!(delta.filter_depth(3), delta);
+    }
+
+    fn filter_out() {
         let exclude_list = ExcludeList::from(&vec![
             String::from("name1/"),
             String::from("name2"),
@@ -272,4 +750,104 @@ mod tests {
             assert_eq!(unfiltered_delta, supposed_filtered_delta);
         }
     }
+
+    fn strip_dir_mtimes() {
+        let file = FSNode::file((1443314904, 885035178), "some content");
+        let new_file = FSNode::file((1420465793, 406504192), "other content");
+        let premtime = (1395328184, 869950727);
+        let postmtime = (1396487263, 534084134);
+
+        let mut delta = Delta::gen_from(|d| {
+            d.add_leaf("edited-file", Some(file.clone()), Some(new_file.clone()));
+            d.add_empty_branch("touched-dir", Some((premtime, postmtime)));
+            d.add_branch("deep", Some((premtime, postmtime)), |d| {
+                d.add_leaf("added-file", None, Some(file.clone()));
+                d.add_empty_branch("touched-dir", Some((premtime, postmtime)));
+                d.add_branch("only-touched", None, |d| {
+                    d.add_empty_branch("touched-dir", Some((premtime, postmtime)));
+                });
+            });
+        });
+        let supposed_stripped_delta = Delta::gen_from(|d| {
+            d.add_leaf("edited-file", Some(file.clone()), Some(new_file.clone()));
+            d.add_branch("deep", None, |d| {
+                d.add_leaf("added-file", None, Some(file.clone()));
+            });
+        });
+        delta.strip_dir_mtimes();
+        assert_eq!(delta, supposed_stripped_delta);
+        assert!(delta
+            .to_actions()
+            .into_iter()
+            .all(|(_, action)| !matches!(action, Action::EditDir(_))));
+
+        let mut delta = Delta::empty();
+        delta.strip_dir_mtimes();
+        assert_eq!(delta, Delta::empty());
+    }
+
+    fn strip_to_additions() {
+        let file = FSNode::file((1443314904, 885035178), "some content");
+        let new_file = FSNode::file((1420465793, 406504192), "other content");
+        let dir = FSNode::dir((1512376465, 64263102), |t| {
+            t.add_file("name1", (1443314904, 885035178), "some content");
+        });
+        let premtime = (1395328184, 869950727);
+        let postmtime = (1396487263, 534084134);
+
+        let mut delta = Delta::gen_from(|d| {
+            d.add_leaf("removed-file", Some(file.clone()), None);
+            d.add_leaf("added-file", None, Some(file.clone()));
+            d.add_leaf("edited-file", Some(file.clone()), Some(new_file.clone()));
+            d.add_leaf("replaced-dir", Some(dir.clone()), Some(file.clone()));
+            d.add_branch("deep", Some((premtime, postmtime)), |d| {
+                d.add_leaf("edited-file", Some(file.clone()), Some(new_file.clone()));
+                d.add_leaf("added-dir", None, Some(dir.clone()));
+            });
+            d.add_branch("only-edits", None, |d| {
+                d.add_leaf("edited-file", Some(file.clone()), Some(new_file.clone()));
+            });
+        });
+        let supposed_stripped_delta = Delta::gen_from(|d| {
+            d.add_leaf("added-file", None, Some(file.clone()));
+            d.add_branch("deep", Some((premtime, postmtime)), |d| {
+                d.add_leaf("added-dir", None, Some(dir.clone()));
+            });
+        });
+        delta.strip_to_additions();
+        assert_eq!(delta, supposed_stripped_delta);
+
+        // Neither the removal nor the edit get applied, while the addition does
+        let pre_tree = FSTree::gen_from(|t| {
+            t.add_file("removed", (1443314904, 885035178), "some content");
+            t.add_file("edited", (1443314904, 885035178), "some content");
+        });
+        let post_tree = FSTree::gen_from(|t| {
+            t.add_file("edited", (1420465793, 406504192), "other content");
+            t.add_file("added", (1420465793, 406504192), "other content");
+        });
+        let mut delta = get_delta(&pre_tree, &post_tree);
+        delta.strip_to_additions();
+        assert_eq!(
+            delta.to_actions(),
+            Delta::gen_from(|d| {
+                d.add_leaf(
+                    "added",
+                    None,
+                    Some(FSNode::file((1420465793, 406504192), "other content")),
+                );
+            })
+            .to_actions()
+        );
+        let mut archive_tree = pre_tree.clone();
+        archive_tree.apply_delta(&delta).unwrap();
+        assert_eq!(
+            archive_tree,
+            FSTree::gen_from(|t| {
+                t.add_file("removed", (1443314904, 885035178), "some content");
+                t.add_file("edited", (1443314904, 885035178), "some content");
+                t.add_file("added", (1420465793, 406504192), "other content");
+            })
+        );
+    }
 }
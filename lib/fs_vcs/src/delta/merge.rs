@@ -37,6 +37,9 @@ impl Delta {
                             (Some((premtime0, postmtime0)), Some((premtime1, postmtime1))) => {
                                 if postmtime0 != premtime1 {
                                     return Err(unmergerr(AbstPath::single(name), "new mtime of precedent delta does not match with old mtime of successive delta"));
+                                } else if premtime0 == postmtime1 {
+                                    // The successive delta restored the mtime
+                                    None
                                 } else {
                                     Some((premtime0, postmtime1))
                                 }
@@ -102,15 +105,26 @@ impl Delta {
         Ok(())
     }
 
+    /// Compose two deltas that apply sequentially: if self goes from A to B and
+    /// next goes from B to C, the result goes from A to C.
+    ///
+    /// Changes that are undone by next (such as a file added by self and removed
+    /// by next) are dropped, and the result is shaken
+    pub fn compose(&self, next: &Delta) -> Result<Delta, UnmergeableDelta> {
+        let mut composed = next.clone();
+        composed.merge_prec(self)?;
+        Ok(composed)
+    }
+
     /// Given a delta (self) and a path of a possible subtree, tries to get what
     /// the delta changed on the subtree at the specified path, assuming that
     /// the specified path is an actual subtree and not just a leaf.
     ///
     /// This results in:
     /// - the total delta, if the path is empty (hence the "subtree" was the
-    ///     whole tree)
+    ///   whole tree)
     /// - nothing, if the subtree was untouched by the delta (ie if the
-    ///     subdelta for the specified subtree doesn't exist)
+    ///   subdelta for the specified subtree doesn't exist)
     /// - nothing, if the specified subtree was actually a leaf
     /// - the subdelta, translated in a subdeltatree if it was a subdeltaleaf
     ///
@@ -156,6 +170,7 @@ mod tests {
     use abst_fs::AbstPath;
 
     use super::{push_unmerg, unmergerr, Delta, FSNode, UnmergeableDelta};
+    use crate::{get_delta, FSTree};
 
     #[test]
     fn test() {
@@ -163,6 +178,8 @@ mod tests {
 
         merge();
 
+        compose();
+
         subdelta_tree();
     }
 
@@ -530,6 +547,61 @@ mod tests {
         }
     }
 
+    fn compose() {
+        let tree_a = FSTree::gen_from(|t| {
+            t.add_file("edited-file", (1611850953, 971525938), "first content");
+            t.add_file("restored-file", (1245890614, 586345017), "same content");
+            t.add_symlink("removed-symlink", (808926076, 398339329), "some/fake/path");
+            t.add_dir("dir", (1364181678, 477789959), |t| {
+                t.add_file("file", (1598728573, 546351705), "mock content");
+                t.add_empty_dir("replaced-dir", (590816735, 667223352));
+            });
+        });
+        let tree_b = FSTree::gen_from(|t| {
+            t.add_file("edited-file", (1611850953, 971525938), "second content");
+            t.add_file("added-then-removed", (1482493363, 159242111), "short lived");
+            t.add_dir("dir", (1343075884, 988762165), |t| {
+                t.add_file("file", (1598728573, 546351705), "mock content");
+                t.add_symlink("new-symlink", (1280173113, 825083229), "some/endpoint");
+            });
+        });
+        let tree_c = FSTree::gen_from(|t| {
+            t.add_file("edited-file", (997012509, 922451121), "third content");
+            t.add_file("restored-file", (1245890614, 586345017), "same content");
+            t.add_dir("dir", (1364181678, 477789959), |t| {
+                t.add_file("file", (1598728573, 546351705), "mock content");
+                t.add_symlink("new-symlink", (1280173113, 825083229), "some/endpoint");
+                t.add_dir("replaced-dir", (927155718, 657468981), |t| {
+                    t.add_file("file", (1302727190, 651605513), "test content");
+                });
+            });
+        });
+        let trees = [FSTree::empty(), tree_a, tree_b, tree_c];
+
+        for (i, tree_x) in trees.iter().enumerate() {
+            for tree_y in &trees[i..] {
+                for tree_z in &trees {
+                    let composed = get_delta(tree_x, tree_y)
+                        .compose(&get_delta(tree_y, tree_z))
+                        .unwrap();
+                    let mut expected = get_delta(tree_x, tree_z);
+                    expected.shake();
+                    assert_eq!(composed, expected);
+                }
+            }
+        }
+
+        // Composing with the empty delta does nothing
+        let delta = get_delta(&trees[1], &trees[2]);
+        assert_eq!(delta.compose(&Delta::empty()).unwrap(), delta);
+        assert_eq!(Delta::empty().compose(&delta).unwrap(), delta);
+
+        // Non sequential deltas can't be composed
+        assert!(get_delta(&trees[1], &trees[2])
+            .compose(&get_delta(&trees[1], &trees[3]))
+            .is_err());
+    }
+
     fn subdelta_tree() {
         // Correct
         {
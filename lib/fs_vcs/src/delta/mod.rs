@@ -1,5 +1,5 @@
 use super::{hash_tree, ExcludeList, FSNode, FSTree};
-use abst_fs::{AbstPath, Mtime};
+use abst_fs::{AbstPath, Mtime, MtimePrecision};
 use ior::{union, IOr};
 
 use serde::{Deserialize, Serialize};
@@ -8,8 +8,10 @@ use std::collections::HashMap;
 mod apply;
 mod filter;
 mod merge;
+mod stats;
 
 pub use merge::UnmergeableDelta;
+pub use stats::{DeltaStats, ObjectCount};
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum DeltaNode {
@@ -60,10 +62,125 @@ impl Delta {
             Branch(optm, subdelta) => optm.is_some() || (!subdelta.is_empty()),
         });
     }
+
+    /// Get the delta that reverts this one, swapping the pre and post states of
+    /// every node. Applying the inverted delta is the same as undoing this one
+    pub fn invert(&self) -> Delta {
+        use DeltaNode::*;
+
+        let Delta(tree) = self;
+        Delta(
+            tree.iter()
+                .map(|(name, child)| {
+                    let inverted = match child {
+                        Leaf(pre, post) => Leaf(post.clone(), pre.clone()),
+                        Branch(optm, subdelta) => Branch(
+                            optm.as_ref().map(|(pre, post)| (post.clone(), pre.clone())),
+                            subdelta.invert(),
+                        ),
+                    };
+                    (name.clone(), inverted)
+                })
+                .collect(),
+        )
+    }
+
+    /// Get the delta enclosed in the branches of the components of `prefix`, so
+    /// that applying it at the root of a tree is the same as applying this one at
+    /// `prefix`. The opposite of `get_subdelta_tree_copy`
+    pub fn prefixed(self, prefix: &AbstPath) -> Delta {
+        if self.is_empty() {
+            return self;
+        }
+        prefix.into_iter().rev().fold(self, |delta, component| {
+            Delta(HashMap::from([(
+                component.clone(),
+                DeltaNode::Branch(None, delta),
+            )]))
+        })
+    }
+}
+
+/// Options for the computation of a delta, for comparisons that are looser
+/// than the default exact one
+#[derive(Debug, Clone, Default)]
+pub struct DeltaOptions {
+    /// Precision at which mtimes are compared
+    pub precision: MtimePrecision,
+    /// Consider a directory unchanged if only its mtime changed, and never
+    /// record the changes in the mtimes of directories, so that syncing a
+    /// change inside a directory doesn't edit the directory itself
+    pub ignore_dir_mtimes: bool,
+}
+
+pub fn get_delta(last_known_fstree: &FSTree, new_tree: &FSTree) -> Delta {
+    get_delta_with(last_known_fstree, new_tree, false, &DeltaOptions::default())
+}
+
+/// Same as `get_delta`, but comparing mtimes only up to the given precision, so
+/// that objects whose mtimes differ by less than that are considered unchanged.
+/// Useful when the two trees come from file systems storing mtimes with
+/// different granularity
+pub fn get_delta_with_precision(
+    last_known_fstree: &FSTree,
+    new_tree: &FSTree,
+    precision: MtimePrecision,
+) -> Delta {
+    let options = DeltaOptions {
+        precision,
+        ..DeltaOptions::default()
+    };
+    get_delta_with(last_known_fstree, new_tree, false, &options)
 }
 
-pub fn get_delta(FSTree(last_known_fstree): &FSTree, FSTree(new_tree): &FSTree) -> Delta {
+/// Same as `get_delta`, but comparing the trees as specified by `options`
+pub fn get_delta_with_options(
+    last_known_fstree: &FSTree,
+    new_tree: &FSTree,
+    options: &DeltaOptions,
+) -> Delta {
+    get_delta_with(last_known_fstree, new_tree, false, options)
+}
+
+/// Get the delta between the two trees ignoring differences in mtime only: a
+/// file or symlink is considered changed only if its content (or mode)
+/// changed, and a directory only if something inside it changed.
+///
+/// Applying the resulting delta gives the new tree's content, and the new
+/// tree's mtimes on every changed object, but leaves the mtimes of unchanged
+/// objects as they were
+pub fn get_delta_content_only(last_known_fstree: &FSTree, new_tree: &FSTree) -> Delta {
+    get_delta_with(last_known_fstree, new_tree, true, &DeltaOptions::default())
+}
+
+/// Whether the two nodes (which are not both directories) are to be considered
+/// unchanged, ignoring their mtimes if `content_only` or comparing them up to
+/// the given precision otherwise
+fn equivalent(
+    node0: &FSNode,
+    node1: &FSNode,
+    content_only: bool,
+    precision: MtimePrecision,
+) -> bool {
     use FSNode::*;
+    let same_mtime = |m0: &Mtime, m1: &Mtime| content_only || m0.eq_at(m1, precision);
+    match (node0, node1) {
+        (File(m0, h0, mode0, _), File(m1, h1, mode1, _)) => {
+            same_mtime(m0, m1) && h0 == h1 && mode0 == mode1
+        }
+        (SymLink(m0, h0), SymLink(m1, h1)) => same_mtime(m0, m1) && h0 == h1,
+        _ => false,
+    }
+}
+
+fn get_delta_with(
+    FSTree(last_known_fstree): &FSTree,
+    FSTree(new_tree): &FSTree,
+    content_only: bool,
+    options: &DeltaOptions,
+) -> Delta {
+    use FSNode::*;
+    let precision = options.precision;
     let mut delta: HashMap<String, DeltaNode> = HashMap::new();
 
     for (key, ior) in union(last_known_fstree, new_tree) {
@@ -76,18 +193,23 @@ pub fn get_delta(FSTree(last_known_fstree): &FSTree, FSTree(new_tree): &FSTree)
             }
             IOr::Both(child0, child1) => {
                 if let (Dir(m0, h0, subtree0), Dir(m1, h1, subtree1)) = (child0, child1) {
-                    if m0.ne(m1) || h0.ne(h1) {
-                        let delta_mtime = match m0.ne(m1) {
+                    // When mtimes are not compared exactly, the hashes might differ
+                    //	even if nothing relevant changed inside the directory, so
+                    //	the directory is changed only if its subdelta is not empty
+                    let delta_subtree = match h0.ne(h1) {
+                        true => get_delta_with(subtree0, subtree1, content_only, options),
+                        false => Delta::empty(),
+                    };
+                    let mtime_changed =
+                        !content_only && !options.ignore_dir_mtimes && !m0.eq_at(m1, precision);
+                    if mtime_changed || !delta_subtree.is_empty() {
+                        let delta_mtime = match !options.ignore_dir_mtimes && m0.ne(m1) {
                             true => Some((m0.clone(), m1.clone())),
                             false => None,
                         };
-                        let delta_subtree = match h0.ne(h1) {
-                            true => get_delta(subtree0, subtree1),
-                            false => Delta::empty(),
-                        };
                         delta.insert(key, DeltaNode::Branch(delta_mtime, delta_subtree));
                     }
-                } else if child0 != child1 {
+                } else if !equivalent(child0, child1, content_only, precision) {
                     delta.insert(key, DeltaNode::edit(child0, child1));
                 }
             }
@@ -101,9 +223,12 @@ pub fn get_delta(FSTree(last_known_fstree): &FSTree, FSTree(new_tree): &FSTree)
 mod tests {
     use std::collections::HashMap;
 
-    use super::{get_delta, Delta, DeltaNode, FSNode, FSTree};
+    use super::{
+        get_delta, get_delta_content_only, get_delta_with_options, get_delta_with_precision, Delta,
+        DeltaNode, DeltaOptions, DeltaStats, FSNode, FSTree, ObjectCount,
+    };
 
-    use abst_fs::Mtime;
+    use abst_fs::{Mtime, MtimePrecision};
 
     impl DeltaNode {
         pub fn leaf(pre: Option<FSNode>, post: Option<FSNode>) -> DeltaNode {
@@ -164,6 +289,9 @@ mod tests {
         delta_empty();
         delta_shake();
         get();
+        get_content_only();
+        get_with_precision();
+        get_ignoring_dir_mtimes();
     }
 
     fn delta_node_impl() {
@@ -516,12 +644,259 @@ mod tests {
 
         assert_eq!(supposed_delta, get_delta(&pre_fstree, &post_fstree));
 
+        let all_nine = ObjectCount {
+            files: 9,
+            symlinks: 9,
+            dirs: 9,
+        };
+        assert_eq!(
+            supposed_delta.stats(),
+            DeltaStats {
+                added: all_nine.clone(),
+                edited: ObjectCount {
+                    files: 3,
+                    symlinks: 3,
+                    dirs: 2,
+                },
+                removed: all_nine,
+                added_bytes: 118,
+                edited_bytes: 49,
+            }
+        );
+
         let mut fstree_to_upgrade = pre_fstree.clone();
         fstree_to_upgrade.apply_delta(&supposed_delta).unwrap();
         assert_eq!(fstree_to_upgrade, post_fstree);
 
+        invert(&supposed_delta, &pre_fstree, &post_fstree);
+
         let mut fstree_to_downgrade = post_fstree;
         fstree_to_downgrade.undo_delta(&supposed_delta).unwrap();
         assert_eq!(fstree_to_downgrade, pre_fstree);
+
+        serialize(&supposed_delta, &pre_fstree);
+    }
+
+    fn get_content_only() {
+        let pre_fstree = FSTree::gen_from(|t| {
+            t.add_file("file", (1611850953, 971525938), "fixed content");
+            t.add_symlink("symlink", (1245890614, 586345017), "fixed/path");
+            t.add_dir("dir", (1364181678, 477789959), |t| {
+                t.add_file("file2", (1598728573, 546351705), "fixed content 2");
+                t.add_empty_dir("dir2", (590816735, 667223352));
+            });
+        });
+
+        // Only mtimes changed
+        let post_fstree = FSTree::gen_from(|t| {
+            t.add_file("file", (1048587011, 445332193), "fixed content");
+            t.add_symlink("symlink", (692432309, 274032817), "fixed/path");
+            t.add_dir("dir", (1535927666, 535018497), |t| {
+                t.add_file("file2", (815892169, 640255056), "fixed content 2");
+                t.add_empty_dir("dir2", (1428359331, 168489967));
+            });
+        });
+        assert!(!get_delta(&pre_fstree, &post_fstree).is_empty());
+        assert!(get_delta_content_only(&pre_fstree, &post_fstree).is_empty());
+
+        // Content changed too, deep inside a directory
+        let post_fstree = FSTree::gen_from(|t| {
+            t.add_file("file", (1048587011, 445332193), "fixed content");
+            t.add_symlink("symlink", (692432309, 274032817), "fixed/path");
+            t.add_dir("dir", (1535927666, 535018497), |t| {
+                t.add_file("file2", (815892169, 640255056), "changed content 2");
+                t.add_empty_dir("dir2", (1428359331, 168489967));
+            });
+        });
+        let supposed_delta = Delta::gen_from(|d| {
+            d.add_branch(
+                "dir",
+                Some(((1364181678, 477789959), (1535927666, 535018497))),
+                |d| {
+                    d.add_leaf(
+                        "file2",
+                        Some(FSNode::file((1598728573, 546351705), "fixed content 2")),
+                        Some(FSNode::file((815892169, 640255056), "changed content 2")),
+                    );
+                },
+            );
+        });
+        let delta = get_delta_content_only(&pre_fstree, &post_fstree);
+        assert_eq!(delta, supposed_delta);
+
+        // The delta is applicable, and brings the changed objects to the new tree's
+        //	content and mtimes
+        let mut tree = pre_fstree.clone();
+        tree.apply_delta(&delta).unwrap();
+        assert_eq!(
+            tree.get_at(&abst_fs::AbstPath::from("dir/file2")),
+            post_fstree.get_at(&abst_fs::AbstPath::from("dir/file2"))
+        );
+        assert!(get_delta_content_only(&tree, &post_fstree).is_empty());
+    }
+
+    fn get_with_precision() {
+        let pre_fstree = FSTree::gen_from(|t| {
+            t.add_file("file", (1611850953, 971525938), "fixed content");
+            t.add_symlink("symlink", (1245890614, 586345017), "fixed/path");
+            t.add_dir("dir", (1364181678, 477789959), |t| {
+                t.add_file("file2", (1598728573, 546351705), "fixed content 2");
+            });
+        });
+
+        // Mtimes truncated to the second, as stored by a coarser file system
+        let post_fstree = FSTree::gen_from(|t| {
+            t.add_file("file", (1611850953, 0), "fixed content");
+            t.add_symlink("symlink", (1245890614, 0), "fixed/path");
+            t.add_dir("dir", (1364181678, 0), |t| {
+                t.add_file("file2", (1598728573, 0), "fixed content 2");
+            });
+        });
+        assert_eq!(
+            get_delta_with_precision(&pre_fstree, &post_fstree, MtimePrecision::Full),
+            get_delta(&pre_fstree, &post_fstree)
+        );
+        assert!(!get_delta(&pre_fstree, &post_fstree).is_empty());
+        assert!(
+            !get_delta_with_precision(&pre_fstree, &post_fstree, MtimePrecision::Milliseconds)
+                .is_empty()
+        );
+        assert!(
+            get_delta_with_precision(&pre_fstree, &post_fstree, MtimePrecision::Seconds).is_empty()
+        );
+
+        // Actual changes are still detected, with the mtimes of the changed objects
+        let post_fstree = FSTree::gen_from(|t| {
+            t.add_file("file", (1611850953, 0), "fixed content");
+            t.add_symlink("symlink", (1245890615, 0), "fixed/path");
+            t.add_dir("dir", (1364181678, 0), |t| {
+                t.add_file("file2", (1598728573, 0), "changed content 2");
+            });
+        });
+        let supposed_delta = Delta::gen_from(|d| {
+            d.add_leaf(
+                "symlink",
+                Some(FSNode::symlink((1245890614, 586345017), "fixed/path")),
+                Some(FSNode::symlink((1245890615, 0), "fixed/path")),
+            );
+            d.add_branch(
+                "dir",
+                Some(((1364181678, 477789959), (1364181678, 0))),
+                |d| {
+                    d.add_leaf(
+                        "file2",
+                        Some(FSNode::file((1598728573, 546351705), "fixed content 2")),
+                        Some(FSNode::file((1598728573, 0), "changed content 2")),
+                    );
+                },
+            );
+        });
+        assert_eq!(
+            get_delta_with_precision(&pre_fstree, &post_fstree, MtimePrecision::Seconds),
+            supposed_delta
+        );
+    }
+
+    fn get_ignoring_dir_mtimes() {
+        let options = DeltaOptions {
+            ignore_dir_mtimes: true,
+            ..DeltaOptions::default()
+        };
+        let pre_fstree = FSTree::gen_from(|t| {
+            t.add_file("file", (1611850953, 971525938), "fixed content");
+            t.add_dir("dir", (1364181678, 477789959), |t| {
+                t.add_file("file2", (1598728573, 546351705), "fixed content 2");
+                t.add_empty_dir("dir2", (590816735, 667223352));
+            });
+        });
+
+        // Only the mtimes of directories changed
+        let post_fstree = FSTree::gen_from(|t| {
+            t.add_file("file", (1611850953, 971525938), "fixed content");
+            t.add_dir("dir", (1535927666, 535018497), |t| {
+                t.add_file("file2", (1598728573, 546351705), "fixed content 2");
+                t.add_empty_dir("dir2", (1428359331, 168489967));
+            });
+        });
+        assert!(!get_delta(&pre_fstree, &post_fstree).is_empty());
+        assert!(get_delta_with_options(&pre_fstree, &post_fstree, &options).is_empty());
+        assert_eq!(
+            get_delta_with_options(&pre_fstree, &post_fstree, &DeltaOptions::default()),
+            get_delta(&pre_fstree, &post_fstree)
+        );
+
+        // Changes inside a directory are still detected, without editing the
+        //	mtime of the directory, while the mtimes of files are still compared
+        let post_fstree = FSTree::gen_from(|t| {
+            t.add_file("file", (1048587011, 445332193), "fixed content");
+            t.add_dir("dir", (1535927666, 535018497), |t| {
+                t.add_file("file2", (1598728573, 546351705), "changed content 2");
+                t.add_empty_dir("dir2", (1428359331, 168489967));
+            });
+        });
+        let supposed_delta = Delta::gen_from(|d| {
+            d.add_leaf(
+                "file",
+                Some(FSNode::file((1611850953, 971525938), "fixed content")),
+                Some(FSNode::file((1048587011, 445332193), "fixed content")),
+            );
+            d.add_branch("dir", None, |d| {
+                d.add_leaf(
+                    "file2",
+                    Some(FSNode::file((1598728573, 546351705), "fixed content 2")),
+                    Some(FSNode::file((1598728573, 546351705), "changed content 2")),
+                );
+            });
+        });
+        let delta = get_delta_with_options(&pre_fstree, &post_fstree, &options);
+        assert_eq!(delta, supposed_delta);
+        assert!(delta
+            .to_actions()
+            .into_iter()
+            .all(|(_, action)| !matches!(action, crate::Action::EditDir(_))));
+    }
+
+    fn invert(delta: &Delta, pre_fstree: &FSTree, post_fstree: &FSTree) {
+        let inverted = delta.invert();
+        assert_ne!(&inverted, delta);
+        assert_eq!(&inverted.invert(), delta);
+        assert_eq!(inverted, get_delta(post_fstree, pre_fstree));
+        assert_eq!(Delta::empty().invert(), Delta::empty());
+
+        let mut downgraded_by_apply = post_fstree.clone();
+        downgraded_by_apply.apply_delta(&inverted).unwrap();
+        let mut downgraded_by_undo = post_fstree.clone();
+        downgraded_by_undo.undo_delta(delta).unwrap();
+        assert_eq!(downgraded_by_apply, downgraded_by_undo);
+        assert_eq!(&downgraded_by_apply, pre_fstree);
+    }
+
+    fn serialize(delta: &Delta, tree: &FSTree) {
+        use abst_fs::{load, save, AbstPath};
+
+        let path = std::path::PathBuf::from("/tmp/bbup-test-fs_vcs-delta-serialize");
+        assert!(!path.exists());
+        std::fs::create_dir(&path).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            let root = AbstPath::from(&path);
+            let size = |p: &AbstPath| std::fs::metadata(p.to_path_buf()).unwrap().len();
+
+            let (delta_bin, delta_json) = (root.add_last("delta.bin"), root.add_last("delta.json"));
+            save(&delta_bin, delta).unwrap();
+            save(&delta_json, delta).unwrap();
+            assert_eq!(&load::<Delta>(&delta_bin).unwrap(), delta);
+            assert_eq!(&load::<Delta>(&delta_json).unwrap(), delta);
+            assert!(size(&delta_bin) < size(&delta_json));
+
+            let (tree_bin, tree_json) = (root.add_last("tree.bin"), root.add_last("tree.json"));
+            save(&tree_bin, tree).unwrap();
+            save(&tree_json, tree).unwrap();
+            assert_eq!(&load::<FSTree>(&tree_bin).unwrap(), tree);
+            assert_eq!(&load::<FSTree>(&tree_json).unwrap(), tree);
+            assert!(size(&tree_bin) < size(&tree_json));
+        });
+        std::fs::remove_dir_all(&path).unwrap();
+        assert!(result.is_ok())
     }
 }
@@ -0,0 +1,191 @@
+use serde::{Deserialize, Serialize};
+
+use super::{Delta, DeltaNode, FSNode, FSTree};
+
+/// Number of objects of each type
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ObjectCount {
+    pub files: usize,
+    pub symlinks: usize,
+    pub dirs: usize,
+}
+impl ObjectCount {
+    pub fn total(&self) -> usize {
+        self.files + self.symlinks + self.dirs
+    }
+    fn count_node(&mut self, node: &FSNode) {
+        match node {
+            FSNode::File(_, _, _, _) => self.files += 1,
+            FSNode::SymLink(_, _) => self.symlinks += 1,
+            FSNode::Dir(_, _, subtree) => {
+                self.dirs += 1;
+                self.count_tree(subtree);
+            }
+        }
+    }
+    fn count_tree(&mut self, FSTree(tree): &FSTree) {
+        for child in tree.values() {
+            self.count_node(child);
+        }
+    }
+}
+
+/// Total size of the files in the node, skipping the files of unknown size
+fn content_size(node: &FSNode) -> u64 {
+    match node {
+        FSNode::File(_, _, _, size) => size.unwrap_or(0),
+        FSNode::SymLink(_, _) => 0,
+        FSNode::Dir(_, _, FSTree(tree)) => tree.values().map(content_size).sum(),
+    }
+}
+
+/// Summary of the changes contained in a delta
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DeltaStats {
+    pub added: ObjectCount,
+    pub edited: ObjectCount,
+    pub removed: ObjectCount,
+    /// Bytes of content of the files added, where their size is known
+    pub added_bytes: u64,
+    /// Bytes of new content of the files whose content was edited, where their
+    /// size is known
+    pub edited_bytes: u64,
+}
+impl DeltaStats {
+    pub fn is_empty(&self) -> bool {
+        self.added.total() == 0 && self.edited.total() == 0 && self.removed.total() == 0
+    }
+}
+
+impl Delta {
+    /// Count the objects added, edited and removed by the delta.
+    /// A removed or added directory counts as well all the objects inside it,
+    /// and an object changing type counts as removed and then added.
+    /// Files whose size is unknown count as empty in the byte totals
+    pub fn stats(&self) -> DeltaStats {
+        let mut stats = DeltaStats::default();
+        self.stats_rec(&mut stats);
+        stats
+    }
+    fn stats_rec(&self, stats: &mut DeltaStats) {
+        let Delta(tree) = self;
+        for child in tree.values() {
+            match child {
+                DeltaNode::Leaf(
+                    Some(FSNode::File(_, pre_hash, _, _)),
+                    Some(FSNode::File(_, post_hash, _, size)),
+                ) => {
+                    stats.edited.files += 1;
+                    if pre_hash != post_hash {
+                        stats.edited_bytes += size.unwrap_or(0);
+                    }
+                }
+                DeltaNode::Leaf(Some(FSNode::SymLink(_, _)), Some(FSNode::SymLink(_, _))) => {
+                    stats.edited.symlinks += 1;
+                }
+                DeltaNode::Leaf(pre, post) => {
+                    if let Some(pre) = pre {
+                        stats.removed.count_node(pre);
+                    }
+                    if let Some(post) = post {
+                        stats.added.count_node(post);
+                        stats.added_bytes += content_size(post);
+                    }
+                }
+                DeltaNode::Branch(optm, subdelta) => {
+                    if optm.is_some() {
+                        stats.edited.dirs += 1;
+                    }
+                    subdelta.stats_rec(stats);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Delta, DeltaStats, FSNode, ObjectCount};
+
+    #[test]
+    fn test() {
+        assert_eq!(Delta::empty().stats(), DeltaStats::default());
+        assert!(Delta::empty().stats().is_empty());
+
+        let delta = Delta::gen_from(|d| {
+            d.add_leaf(
+                "file",
+                Some(FSNode::file((1245890614, 586345017), "some content")),
+                Some(FSNode::file((1245890614, 586345017), "other content")),
+            );
+            d.add_branch(
+                "dir",
+                Some(((820956170, 426474588), (1535927666, 535018497))),
+                |d| {
+                    d.add_leaf(
+                        "added-dir",
+                        None,
+                        Some(FSNode::dir((1364181678, 477789959), |t| {
+                            t.add_file("file", (997012509, 922451121), "mock content");
+                            t.add_empty_dir("dir", (590816735, 667223352));
+                        })),
+                    );
+                    d.add_leaf(
+                        "removed-symlink",
+                        Some(FSNode::symlink((808926076, 398339329), "some/fake/path")),
+                        None,
+                    );
+                },
+            );
+        });
+        let stats = delta.stats();
+        assert!(!stats.is_empty());
+        assert_eq!(
+            stats,
+            DeltaStats {
+                added: ObjectCount {
+                    files: 1,
+                    symlinks: 0,
+                    dirs: 2,
+                },
+                edited: ObjectCount {
+                    files: 1,
+                    symlinks: 0,
+                    dirs: 1,
+                },
+                removed: ObjectCount {
+                    files: 0,
+                    symlinks: 1,
+                    dirs: 0,
+                },
+                added_bytes: "mock content".len() as u64,
+                edited_bytes: "other content".len() as u64,
+            }
+        );
+        assert_eq!(stats.added.total(), 3);
+
+        // Touching a file edits it without new content, and files of unknown
+        // size count as empty
+        let delta = Delta::gen_from(|d| {
+            d.add_leaf(
+                "touched",
+                Some(FSNode::file((1245890614, 586345017), "some content")),
+                Some(FSNode::file((1245890700, 0), "some content")),
+            );
+            d.add_leaf(
+                "unknown-size",
+                None,
+                Some(FSNode::File(
+                    abst_fs::Mtime::from(1245890614, 0),
+                    hasher::hash_bytes("content"),
+                    abst_fs::Mode::default(),
+                    None,
+                )),
+            );
+        });
+        let stats = delta.stats();
+        assert_eq!(stats.edited.files, 1);
+        assert_eq!(stats.added.files, 1);
+        assert_eq!((stats.added_bytes, stats.edited_bytes), (0, 0));
+    }
+}
@@ -1,4 +1,7 @@
-use super::{ConflictNode, Conflicts, Delta, DeltaNode, FSNode, FSTree};
+use super::{
+    Action, Actions, ConflictNode, Conflicts, Delta, DeltaNode, DeltaStats, FSNode, FSTree,
+    ObjectCount, TreeSummary,
+};
 
 use colored::Color;
 use colored::Colorize;
@@ -55,6 +58,40 @@ impl std::fmt::Display for StringTree {
     }
 }
 
+/// Enable or disable colors in the displayed trees, deltas, actions and stats.
+/// By default colors are used only if stdout is a terminal
+pub fn set_colors(enabled: bool) {
+    colored::control::set_override(enabled);
+}
+
+/// Format a number of bytes in human readable binary units (e.g. 1.5 MiB)
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// Format a count with commas as thousands separators (e.g. 12,403)
+fn with_separators(count: usize) -> String {
+    let digits = count.to_string();
+    let mut result = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            result.push(',');
+        }
+        result.push(digit);
+    }
+    result
+}
+
 fn styled<S: std::string::ToString, C: Into<Color>>(text: S, color: C) -> String {
     text.to_string().color(color).to_string()
 }
@@ -70,13 +107,13 @@ fn fstree_to_stringtree<S: std::string::ToString, C: Clone + Into<Color>>(
     color: C,
 ) -> StringTree {
     let mut children = tree.iter().collect::<Vec<(&String, &FSNode)>>();
-    children.sort_by(|(name0, _), (name1, _)| name0.cmp(name1));
+    children.sort_by_key(|(name0, _)| *name0);
     StringTree {
         text: root_text.to_string(),
         children: children
             .into_iter()
             .map(|(name, child)| match child {
-                FSNode::File(_, _) => {
+                FSNode::File(_, _, _, _) => {
                     let name = styled(name, color.clone());
                     StringTree::leaf(typed("f", name))
                 }
@@ -106,7 +143,7 @@ fn deltafstree_to_stringtree<S: std::string::ToString>(
     use DeltaNode::*;
     use FSNode::*;
     let mut children = tree.iter().collect::<Vec<(&String, &DeltaNode)>>();
-    children.sort_by(|(name0, _), (name1, _)| name0.cmp(name1));
+    children.sort_by_key(|(name0, _)| *name0);
 
     StringTree {
         text: root_text.to_string(),
@@ -128,7 +165,7 @@ fn deltafstree_to_stringtree<S: std::string::ToString>(
                         vec![StringTree::leaf(typed("d", styled_dir(name, color)))]
                     }
                 }
-                Leaf(Some(File(_, _)), Some(File(_, _))) => {
+                Leaf(Some(File(_, _, _, _)), Some(File(_, _, _, _))) => {
                     vec![StringTree::leaf(typed("f", styled(name, "yellow")))]
                 }
                 Leaf(Some(SymLink(_, _)), Some(SymLink(_, _))) => {
@@ -144,7 +181,7 @@ fn deltafstree_to_stringtree<S: std::string::ToString>(
                     let mut output = vec![];
                     if let Some(val) = pre {
                         let removed = match val {
-                            File(_, _) => StringTree::leaf(typed("f", styled(name, "red"))),
+                            File(_, _, _, _) => StringTree::leaf(typed("f", styled(name, "red"))),
                             SymLink(_, _) => StringTree::leaf(typed("s", styled(name, "red"))),
                             Dir(_, _, subtree) => fstree_to_stringtree(
                                 typed("d", styled_dir(name, "red")),
@@ -156,7 +193,7 @@ fn deltafstree_to_stringtree<S: std::string::ToString>(
                     }
                     if let Some(val) = post {
                         let added = match val {
-                            File(_, _) => StringTree::leaf(typed("f", styled(name, "green"))),
+                            File(_, _, _, _) => StringTree::leaf(typed("f", styled(name, "green"))),
                             SymLink(_, _) => StringTree::leaf(typed("s", styled(name, "green"))),
                             Dir(_, _, subtree) => fstree_to_stringtree(
                                 typed("d", styled_dir(name, "green")),
@@ -186,9 +223,95 @@ impl std::fmt::Display for Delta {
     }
 }
 
+/// Name of the object as shown in the diff view: directories end with `/` and
+/// symlinks with `@`
+fn diff_name(name: &str, node: &FSNode) -> String {
+    match node {
+        FSNode::File(_, _, _, _) => name.to_string(),
+        FSNode::SymLink(_, _) => format!("{name}@"),
+        FSNode::Dir(_, _, _) => format!("{name}/"),
+    }
+}
+fn fstree_diff_lines(
+    lines: &mut Vec<String>,
+    FSTree(tree): &FSTree,
+    depth: usize,
+    marker: &str,
+    color: &str,
+) {
+    let mut children = tree.iter().collect::<Vec<(&String, &FSNode)>>();
+    children.sort_by_key(|(name0, _)| *name0);
+    for (name, child) in children {
+        let line = format!(
+            "{}{} {}",
+            "    ".repeat(depth),
+            marker,
+            diff_name(name, child)
+        );
+        lines.push(styled(line, color));
+        if let FSNode::Dir(_, _, subtree) = child {
+            fstree_diff_lines(lines, subtree, depth + 1, marker, color);
+        }
+    }
+}
+fn delta_diff_lines(lines: &mut Vec<String>, Delta(tree): &Delta, depth: usize) {
+    use DeltaNode::*;
+    let indent = "    ".repeat(depth);
+    let mut children = tree.iter().collect::<Vec<(&String, &DeltaNode)>>();
+    children.sort_by_key(|(name0, _)| *name0);
+    for (name, child) in children {
+        match child {
+            Branch(optm, subdelta) => {
+                let line = match optm {
+                    Some(_) => styled(format!("{indent}~ {name}/"), "yellow"),
+                    None => format!("{indent}  {name}/"),
+                };
+                lines.push(line);
+                delta_diff_lines(lines, subdelta, depth + 1);
+            }
+            Leaf(Some(pre), Some(post))
+                if matches!(
+                    (pre, post),
+                    (FSNode::File(..), FSNode::File(..))
+                        | (FSNode::SymLink(..), FSNode::SymLink(..))
+                ) =>
+            {
+                lines.push(styled(
+                    format!("{indent}~ {}", diff_name(name, post)),
+                    "yellow",
+                ));
+            }
+            Leaf(pre, post) => {
+                for (node, marker, color) in [(pre, "-", "red"), (post, "+", "green")] {
+                    if let Some(node) = node {
+                        let line = format!("{indent}{marker} {}", diff_name(name, node));
+                        lines.push(styled(line, color));
+                        if let FSNode::Dir(_, _, subtree) = node {
+                            fstree_diff_lines(lines, subtree, depth + 1, marker, color);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Delta {
+    /// Render the delta as an indented tree, in the style of a unified diff: every
+    /// changed object is on its own line, under the directory containing it and
+    /// marked with `+` if added, `-` if removed or `~` if edited. Directories whose
+    /// only change is in their content are not marked. The content of added and
+    /// removed directories is listed with the same marker as the directory
+    pub fn display_tree(&self) -> String {
+        let mut lines = Vec::new();
+        delta_diff_lines(&mut lines, self, 0);
+        lines.join("\n")
+    }
+}
+
 fn format_leaf_state(val: &Option<FSNode>) -> String {
     match val {
-        Some(FSNode::File(_, hash)) => {
+        Some(FSNode::File(_, hash, _, _)) => {
             format!("File [h:{}]", hash.to_hex(6),)
         }
         Some(FSNode::SymLink(_, hash)) => {
@@ -221,7 +344,7 @@ fn conflicts_to_stringtree<S: std::string::ToString>(
     use FSNode as FN;
 
     let mut children = tree.iter().collect::<Vec<(&String, &ConflictNode)>>();
-    children.sort_by(|(name0, _), (name1, _)| name0.cmp(name1));
+    children.sort_by_key(|(name0, _)| *name0);
 
     StringTree {
         text: text.to_string(),
@@ -257,3 +380,232 @@ impl std::fmt::Display for Conflicts {
         write!(f, "{}", conflicts_to_stringtree(".", self))
     }
 }
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            Action::AddDir => styled("add dir", "green"),
+            Action::AddFile(_, _, _) => styled("add file", "green"),
+            Action::AddSymLink(_, _) => styled("add symlink", "green"),
+            Action::AddHardLink(_) => styled("add hardlink", "green"),
+            Action::Move(from, _, _, _) => styled(format!("move file from {from}"), "blue"),
+            Action::Copy(from, _, _, _) => styled(format!("copy file from {from}"), "green"),
+            Action::EditDir(_) => styled("edit dir", "yellow"),
+            Action::EditFile(_, _, _) => styled("edit file", "yellow"),
+            Action::EditSymLink(_, _) => styled("edit symlink", "yellow"),
+            Action::RemoveDir => styled("remove dir", "red"),
+            Action::RemoveFile => styled("remove file", "red"),
+            Action::RemoveSymLink => styled("remove symlink", "red"),
+        };
+        write!(f, "{}", text)
+    }
+}
+
+impl std::fmt::Display for Actions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let lines = self
+            .into_iter()
+            .map(|(path, action)| format!("{}: {}", action, path))
+            .collect::<Vec<String>>();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+impl std::fmt::Display for ObjectCount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} files, {} symlinks, {} dirs",
+            self.files, self.symlinks, self.dirs
+        )
+    }
+}
+
+impl std::fmt::Display for TreeSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} files, {} symlinks, {} dirs, {}",
+            with_separators(self.count.files),
+            with_separators(self.count.symlinks),
+            with_separators(self.count.dirs),
+            human_size(self.total_size)
+        )
+    }
+}
+
+impl std::fmt::Display for DeltaStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {}, {}\n{} {}, {}\n{} {}",
+            styled("added:  ", "green"),
+            self.added,
+            human_size(self.added_bytes),
+            styled("edited: ", "yellow"),
+            self.edited,
+            human_size(self.edited_bytes),
+            styled("removed:", "red"),
+            self.removed
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{human_size, set_colors, with_separators};
+    use crate::{Delta, FSNode, ObjectCount, TreeSummary};
+
+    #[test]
+    fn test() {
+        plain_output();
+        diff_view();
+        sizes();
+    }
+
+    fn plain_output() {
+        set_colors(false);
+
+        let file = FSNode::file((1443314904, 885035178), "some content");
+        let new_file = FSNode::file((1420465793, 406504192), "other content");
+        let dir = FSNode::dir((1512376465, 64263102), |t| {
+            t.add_file("inner", (1443314904, 885035178), "some content");
+        });
+        let delta = Delta::gen_from(|d| {
+            d.add_leaf("removed", Some(file.clone()), None);
+            d.add_leaf("added-dir", None, Some(dir.clone()));
+            d.add_branch(
+                "edited-dir",
+                Some(((1395328184, 869950727), (1396487263, 534084134))),
+                |d| {
+                    d.add_leaf("edited", Some(file.clone()), Some(new_file.clone()));
+                },
+            );
+        });
+
+        assert_eq!(
+            delta.to_string(),
+            [
+                ".",
+                "├── [d] added-dir/",
+                "│   └── [f] inner",
+                "├── [d] edited-dir/",
+                "│   └── [f] edited",
+                "└── [f] removed",
+            ]
+            .join("\n")
+        );
+        assert_eq!(
+            delta.stats().to_string(),
+            [
+                "added:   1 files, 0 symlinks, 1 dirs, 12 B",
+                "edited:  1 files, 0 symlinks, 1 dirs, 13 B",
+                "removed: 1 files, 0 symlinks, 0 dirs",
+            ]
+            .join("\n")
+        );
+    }
+
+    fn diff_view() {
+        set_colors(false);
+
+        let file = FSNode::file((1443314904, 885035178), "some content");
+        let new_file = FSNode::file((1420465793, 406504192), "other content");
+        let symlink = FSNode::symlink((1443314904, 885035178), "some/path");
+        let new_symlink = FSNode::symlink((1420465793, 406504192), "other/path");
+        let dir = FSNode::dir((1512376465, 64263102), |t| {
+            t.add_file("inner", (1443314904, 885035178), "some content");
+            t.add_symlink("link", (1443314904, 885035178), "inner");
+            t.add_dir("nested", (1512376465, 64263102), |t| {
+                t.add_file("deep", (1443314904, 885035178), "deep content");
+            });
+            t.add_empty_dir("empty", (1512376465, 64263102));
+        });
+        let optm = Some(((1395328184, 869950727), (1396487263, 534084134)));
+        let delta = Delta::gen_from(|d| {
+            d.add_leaf("removed", Some(file.clone()), None);
+            d.add_leaf("added-dir", None, Some(dir.clone()));
+            d.add_leaf("removed-dir", Some(dir.clone()), None);
+            d.add_leaf("file-to-dir", Some(file.clone()), Some(dir.clone()));
+            d.add_leaf("symlink", Some(symlink.clone()), Some(new_symlink.clone()));
+            d.add_branch("edited-dir", optm, |d| {
+                d.add_leaf("edited", Some(file.clone()), Some(new_file.clone()));
+                d.add_leaf("symlink-to-file", Some(symlink.clone()), Some(file.clone()));
+                d.add_branch("touched", optm, |_| {});
+                d.add_branch("content-only", None, |d| {
+                    d.add_branch("deeper", None, |d| {
+                        d.add_leaf("added", None, Some(new_file.clone()));
+                    });
+                    d.add_leaf("added-symlink", None, Some(symlink.clone()));
+                });
+            });
+        });
+
+        assert_eq!(
+            delta.display_tree(),
+            [
+                "+ added-dir/",
+                "    + empty/",
+                "    + inner",
+                "    + link@",
+                "    + nested/",
+                "        + deep",
+                "~ edited-dir/",
+                "      content-only/",
+                "        + added-symlink@",
+                "          deeper/",
+                "            + added",
+                "    ~ edited",
+                "    - symlink-to-file@",
+                "    + symlink-to-file",
+                "    ~ touched/",
+                "- file-to-dir",
+                "+ file-to-dir/",
+                "    + empty/",
+                "    + inner",
+                "    + link@",
+                "    + nested/",
+                "        + deep",
+                "- removed",
+                "- removed-dir/",
+                "    - empty/",
+                "    - inner",
+                "    - link@",
+                "    - nested/",
+                "        - deep",
+                "~ symlink@",
+            ]
+            .join("\n")
+        );
+        assert_eq!(Delta::empty().display_tree(), "");
+    }
+
+    fn sizes() {
+        assert_eq!(human_size(0), "0 B");
+        assert_eq!(human_size(1023), "1023 B");
+        assert_eq!(human_size(1024), "1.0 KiB");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(5 * 1024 * 1024), "5.0 MiB");
+        assert_eq!(human_size(3 * 1024 * 1024 * 1024 + 1), "3.0 GiB");
+        assert_eq!(human_size(u64::MAX), "16.0 EiB");
+
+        assert_eq!(with_separators(0), "0");
+        assert_eq!(with_separators(999), "999");
+        assert_eq!(with_separators(1201), "1,201");
+        assert_eq!(with_separators(12403), "12,403");
+        assert_eq!(with_separators(1234567), "1,234,567");
+
+        let summary = TreeSummary {
+            count: ObjectCount {
+                files: 12403,
+                symlinks: 3,
+                dirs: 1201,
+            },
+            total_size: 4509715661,
+        };
+        assert_eq!(
+            summary.to_string(),
+            "12,403 files, 3 symlinks, 1,201 dirs, 4.2 GiB"
+        );
+    }
+}
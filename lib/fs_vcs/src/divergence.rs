@@ -0,0 +1,254 @@
+use super::{get_delta, Delta, DeltaNode, FSNode, FSTree};
+
+use abst_fs::AbstPath;
+use thiserror::Error;
+
+use std::collections::HashMap;
+
+/// How to handle the objects of the local tree that diverge from the stored
+/// tree (see `find_divergence`) before computing the local delta
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DivergencePolicy {
+    /// Take the local version of the divergent objects as a regular change,
+    /// which will then be pushed
+    TrustLocal,
+    /// Ignore the divergent objects, leaving them out of the local delta so that
+    /// the stored tree keeps recording their stored version. Nothing restores the
+    /// stored version on disk, so the objects keep diverging, and are found again
+    /// by every sync, until they are edited or synced with another policy
+    Ignore,
+    /// Do not sync if any object diverges
+    Abort,
+}
+impl std::str::FromStr for DivergencePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "trust-local" => Ok(DivergencePolicy::TrustLocal),
+            "ignore" => Ok(DivergencePolicy::Ignore),
+            "abort" => Ok(DivergencePolicy::Abort),
+            _ => Err(format!(
+                "invalid divergence policy '{s}', expected one of: trust-local, ignore, abort"
+            )),
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum DivergenceError {
+    #[error("Divergence Error: the local tree diverges from the stored tree in a way that edits can't explain\npaths:{}", list_paths(.0))]
+    Divergent(Vec<AbstPath>),
+
+    #[error("Divergence Error: could not ignore the divergent objects\nreason: {0}")]
+    Unignorable(String),
+}
+fn list_paths(paths: &[AbstPath]) -> String {
+    paths.iter().map(|path| format!("\n\t{path}")).collect()
+}
+
+/// Paths of the files and symlinks whose content differs between the stored
+/// tree and the local tree while their mtime did not move forward, sorted by
+/// path. An edit always moves the mtime forward, so these objects were changed
+/// out of band (for example restored from an older copy, or written by a tool
+/// that preserves mtimes), and the local version is not necessarily the newest
+pub fn find_divergence(stored: &FSTree, local: &FSTree) -> Vec<AbstPath> {
+    let mut divergent = Vec::new();
+    find_divergence_rec(
+        &get_delta(stored, local),
+        &AbstPath::empty(),
+        &mut divergent,
+    );
+    divergent.sort_by_key(|path| path.to_string());
+    divergent
+}
+fn find_divergence_rec(Delta(delta): &Delta, prefix: &AbstPath, divergent: &mut Vec<AbstPath>) {
+    for (name, node) in delta {
+        let path = prefix.add_last(name);
+        match node {
+            DeltaNode::Leaf(Some(pre), Some(post)) => {
+                let moved_back = match (pre, post) {
+                    (FSNode::File(m0, h0, _, _), FSNode::File(m1, h1, _, _))
+                    | (FSNode::SymLink(m0, h0), FSNode::SymLink(m1, h1)) => h0 != h1 && m1 <= m0,
+                    _ => false,
+                };
+                if moved_back {
+                    divergent.push(path);
+                }
+            }
+            DeltaNode::Branch(_, subdelta) => find_divergence_rec(subdelta, &path, divergent),
+            DeltaNode::Leaf(_, _) => {}
+        }
+    }
+}
+
+/// Apply the policy to the objects of `local` that diverge from `stored`,
+/// returning their paths. With `Ignore` the divergent objects of `local` are
+/// replaced with their stored version, so that they don't show up in the delta
+/// between the two trees. Only the tree is changed, never the file system
+pub fn reconcile_divergence(
+    stored: &FSTree,
+    local: &mut FSTree,
+    policy: DivergencePolicy,
+) -> Result<Vec<AbstPath>, DivergenceError> {
+    let divergent = find_divergence(stored, local);
+    if divergent.is_empty() {
+        return Ok(divergent);
+    }
+    match policy {
+        DivergencePolicy::TrustLocal => {}
+        DivergencePolicy::Ignore => {
+            for path in &divergent {
+                if let (Some(pre), Some(post)) = (local.get_at(path), stored.get_at(path)) {
+                    let delta =
+                        leaf_at(path, DeltaNode::Leaf(Some(pre.clone()), Some(post.clone())));
+                    local
+                        .apply_delta(&delta)
+                        .map_err(|err| DivergenceError::Unignorable(err.to_string()))?;
+                }
+            }
+        }
+        DivergencePolicy::Abort => return Err(DivergenceError::Divergent(divergent)),
+    }
+    Ok(divergent)
+}
+/// Delta made of the single node at path, reached through unchanged directories
+fn leaf_at(path: &AbstPath, leaf: DeltaNode) -> Delta {
+    let mut components = path.components().rev();
+    let name = components.next().unwrap_or_default().to_string();
+    let mut delta = Delta(HashMap::from([(name, leaf)]));
+    for component in components {
+        delta = Delta(HashMap::from([(
+            component.to_string(),
+            DeltaNode::Branch(None, delta),
+        )]));
+    }
+    delta
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_divergence, reconcile_divergence, DivergenceError, DivergencePolicy};
+    use crate::{get_delta, FSTree};
+    use abst_fs::AbstPath;
+
+    fn stored() -> FSTree {
+        FSTree::gen_from(|t| {
+            t.add_file("notes.txt", (1667000000, 0), "new notes");
+            t.add_dir("dir", (1667000000, 0), |t| {
+                t.add_file("report.txt", (1667000100, 0), "final report");
+                t.add_symlink("link", (1667000000, 0), "report.txt");
+            });
+        })
+    }
+    /// The stored tree, with `dir/report.txt` restored out of band from an older
+    /// copy and `notes.txt` edited as usual
+    fn local() -> FSTree {
+        FSTree::gen_from(|t| {
+            t.add_file("notes.txt", (1667000200, 0), "newer notes");
+            t.add_dir("dir", (1667000000, 0), |t| {
+                t.add_file("report.txt", (1667000050, 0), "draft report");
+                t.add_symlink("link", (1667000000, 0), "report.txt");
+            });
+        })
+    }
+
+    #[test]
+    fn test() {
+        divergence();
+        trust_local();
+        ignore();
+        abort();
+        parse();
+    }
+
+    fn divergence() {
+        assert_eq!(
+            find_divergence(&stored(), &local()),
+            vec![AbstPath::from("dir/report.txt")]
+        );
+        assert!(find_divergence(&stored(), &stored()).is_empty());
+
+        // Changed content with the same mtime diverges too, a touched file with
+        // the same content does not
+        let same_mtime = FSTree::gen_from(|t| {
+            t.add_file("notes.txt", (1667000000, 0), "changed notes");
+            t.add_dir("dir", (1667000000, 0), |t| {
+                t.add_file("report.txt", (1667000000, 0), "final report");
+                t.add_symlink("link", (1667000000, 0), "elsewhere");
+            });
+        });
+        assert_eq!(
+            find_divergence(&stored(), &same_mtime),
+            vec![AbstPath::from("dir/link"), AbstPath::from("notes.txt")]
+        );
+    }
+
+    fn trust_local() {
+        let mut local = local();
+        let divergent =
+            reconcile_divergence(&stored(), &mut local, DivergencePolicy::TrustLocal).unwrap();
+        assert_eq!(divergent, vec![AbstPath::from("dir/report.txt")]);
+        assert_eq!(local, self::local());
+        assert!(get_delta(&stored(), &local)
+            .to_actions()
+            .into_iter()
+            .any(|(path, _)| path == AbstPath::from("dir/report.txt")));
+    }
+
+    fn ignore() {
+        let mut local = local();
+        let divergent =
+            reconcile_divergence(&stored(), &mut local, DivergencePolicy::Ignore).unwrap();
+        assert_eq!(divergent, vec![AbstPath::from("dir/report.txt")]);
+        assert_eq!(
+            local.get_at(&AbstPath::from("dir/report.txt")),
+            stored().get_at(&AbstPath::from("dir/report.txt"))
+        );
+        // Only the regular edit is left in the delta
+        let actions: Vec<AbstPath> = get_delta(&stored(), &local)
+            .to_actions()
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+        assert_eq!(actions, vec![AbstPath::from("notes.txt")]);
+        // The hashes of the parents are updated as well
+        assert_eq!(
+            local.root_hash(),
+            FSTree::gen_from(|t| {
+                t.add_file("notes.txt", (1667000200, 0), "newer notes");
+                t.add_dir("dir", (1667000000, 0), |t| {
+                    t.add_file("report.txt", (1667000100, 0), "final report");
+                    t.add_symlink("link", (1667000000, 0), "report.txt");
+                });
+            })
+            .root_hash()
+        );
+    }
+
+    fn abort() {
+        let mut local = local();
+        assert_eq!(
+            reconcile_divergence(&stored(), &mut local, DivergencePolicy::Abort),
+            Err(DivergenceError::Divergent(vec![AbstPath::from(
+                "dir/report.txt"
+            )]))
+        );
+        assert_eq!(local, self::local());
+
+        // Without divergence nothing is aborted
+        let mut edited = stored();
+        assert_eq!(
+            reconcile_divergence(&stored(), &mut edited, DivergencePolicy::Abort),
+            Ok(vec![])
+        );
+    }
+
+    fn parse() {
+        assert_eq!("trust-local".parse(), Ok(DivergencePolicy::TrustLocal));
+        assert_eq!("ignore".parse(), Ok(DivergencePolicy::Ignore));
+        assert!("trust-stored".parse::<DivergencePolicy>().is_err());
+        assert_eq!("abort".parse(), Ok(DivergencePolicy::Abort));
+        assert!("trust-nobody".parse::<DivergencePolicy>().is_err());
+    }
+}
@@ -1,11 +1,16 @@
-use abst_fs::AbstPath;
+use abst_fs::{self as fs, AbstPath, ObjectType};
 use regex::Regex;
 use thiserror::Error;
 
+use std::io::Read;
+
 #[derive(Error, Debug, PartialEq)]
 pub enum ExcludeListError {
     #[error("Exclude List Error: Failed to parse rule to regex\nrule: {rule}\nreason: {err}")]
     UnparsableRule { rule: String, err: regex::Error },
+
+    #[error("Exclude List Error: Failed to read ignore file\npath: {path}\nreason: {err}")]
+    UnreadableIgnoreFile { path: AbstPath, err: String },
 }
 fn unparerr<S: std::string::ToString>(rule: S) -> impl Fn(regex::Error) -> ExcludeListError {
     move |err: regex::Error| -> ExcludeListError {
@@ -15,24 +20,175 @@ fn unparerr<S: std::string::ToString>(rule: S) -> impl Fn(regex::Error) -> Exclu
         }
     }
 }
+fn readerr<E: std::string::ToString>(path: &AbstPath) -> impl Fn(E) -> ExcludeListError {
+    let path = path.clone();
+    move |err: E| -> ExcludeListError {
+        ExcludeListError::UnreadableIgnoreFile {
+            path: path.clone(),
+            err: err.to_string(),
+        }
+    }
+}
+
+/// Name of the files listing exclude rules for the directory they are in (see
+/// `ExcludeList::join_ignore_files`)
+pub const IGNORE_FILE_NAME: &str = ".bbupignore";
+
+/// A single rule of an exclude list. Rules starting with `!` re-include the
+/// objects they match, even if a previous rule excluded them (to match a
+/// literal leading `!`, use `[!]`)
+pub enum ExcludeRule {
+    Exclude(Regex),
+    Include(Regex),
+    /// Rule applying only to the objects inside the directory at the given
+    /// path (as `./path/to/dir/`), which it is matched against as if the
+    /// directory was the root
+    Scoped(String, Box<ExcludeRule>),
+}
+impl ExcludeRule {
+    fn from(rule: &str) -> Result<ExcludeRule, ExcludeListError> {
+        match rule.strip_prefix('!') {
+            Some(negated) => Ok(ExcludeRule::Include(
+                Regex::new(negated).map_err(unparerr(rule))?,
+            )),
+            None => Ok(ExcludeRule::Exclude(
+                Regex::new(rule).map_err(unparerr(rule))?,
+            )),
+        }
+    }
+}
+impl ExcludeRule {
+    /// Decision of the rule about the path (as `./path/to/object`, with a
+    /// trailing `/` for directories), if it matches
+    fn decide(&self, path: &str) -> Option<ExcludeDecision> {
+        match self {
+            ExcludeRule::Exclude(rgx) if rgx.is_match(path) => Some(ExcludeDecision::Exclude),
+            ExcludeRule::Include(rgx) if rgx.is_match(path) => Some(ExcludeDecision::Include),
+            ExcludeRule::Scoped(scope, rule) => match path.strip_prefix(scope.as_str()) {
+                Some(rest) if !rest.is_empty() => rule.decide(&format!("./{rest}")),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+impl std::fmt::Display for ExcludeRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExcludeRule::Exclude(rgx) => write!(f, "{}", rgx),
+            ExcludeRule::Include(rgx) => write!(f, "!{}", rgx),
+            ExcludeRule::Scoped(scope, rule) => write!(f, "{scope}: {rule}"),
+        }
+    }
+}
+
+/// Final decision of an exclude list about a path, given by the last rule
+/// that matches it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExcludeDecision {
+    Include,
+    Exclude,
+}
 
-pub struct ExcludeList(Vec<Regex>);
+pub struct ExcludeList(Vec<ExcludeRule>);
 
 impl ExcludeList {
     pub fn from(rules: &Vec<String>) -> Result<ExcludeList, ExcludeListError> {
-        ExcludeList(vec![Regex::new("\\.bbup/").unwrap()]).join(rules)
+        ExcludeList(vec![ExcludeRule::Exclude(Regex::new("\\.bbup/").unwrap())]).join(rules)
+    }
+    /// Exclude list made of the `defaults` rules followed by `rules`. Being
+    /// evaluated later, `rules` can re-include what a default rule excludes
+    pub fn with_defaults(
+        defaults: &Vec<String>,
+        rules: &Vec<String>,
+    ) -> Result<ExcludeList, ExcludeListError> {
+        ExcludeList::from(defaults)?.join(rules)
     }
     pub fn join(self, rules: &Vec<String>) -> Result<ExcludeList, ExcludeListError> {
         let ExcludeList(mut list) = self;
         for rule in rules {
-            let rgx = Regex::new(rule).map_err(unparerr(rule))?;
-            list.push(rgx);
+            list.push(ExcludeRule::from(rule)?);
         }
         Ok(ExcludeList(list))
     }
 
+    /// Append the rules of the `.bbupignore` files found in the directory at
+    /// `root` and in its subdirectories, one rule per line, skipping empty
+    /// lines and comments starting with `#` (to match a literal leading `#`,
+    /// use `[#]`). The rules of a file apply only to the objects inside its
+    /// directory, matched as if the directory was the root, and come after the
+    /// rules of the files above it, so that they take precedence. Symlinks are
+    /// not followed, and neither are the directories excluded by the rules found
+    /// so far, unless some rule re-includes objects
+    pub fn join_ignore_files(self, root: &AbstPath) -> Result<ExcludeList, ExcludeListError> {
+        let mut list = self;
+        list.join_ignore_files_rec(root, &AbstPath::single("."))?;
+        Ok(list)
+    }
+    fn join_ignore_files_rec(
+        &mut self,
+        path: &AbstPath,
+        rel_path: &AbstPath,
+    ) -> Result<(), ExcludeListError> {
+        let ignore_file = path.add_last(IGNORE_FILE_NAME);
+        if ignore_file.object_type() == Some(ObjectType::File) {
+            let mut content = String::new();
+            fs::read_file(&ignore_file)
+                .map_err(readerr(&ignore_file))?
+                .read_to_string(&mut content)
+                .map_err(readerr(&ignore_file))?;
+            let scope = format!("{rel_path}/");
+            let ExcludeList(list) = self;
+            for line in content.lines().map(|line| line.trim_end_matches('\r')) {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let rule = ExcludeRule::from(line)?;
+                list.push(match rel_path.len() {
+                    1 => rule,
+                    _ => ExcludeRule::Scoped(scope.clone(), Box::new(rule)),
+                });
+            }
+        }
+
+        let mut children = fs::list_dir_content(path).map_err(readerr(path))?;
+        children.sort_by_key(|child| child.to_string());
+        for child in children {
+            if child.object_type() != Some(ObjectType::Dir) {
+                continue;
+            }
+            let name = match child.file_name() {
+                Some(name) => name,
+                None => continue,
+            };
+            let rel_child = rel_path.add_last(name);
+            if self.should_exclude(&rel_child, true) && !self.has_negations() {
+                continue;
+            }
+            self.join_ignore_files_rec(&child, &rel_child)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the list contains any rule re-including objects. If it does,
+    /// an excluded directory might still contain some included object, so
+    /// it can't be pruned as a whole
+    pub fn has_negations(&self) -> bool {
+        fn is_negation(rule: &ExcludeRule) -> bool {
+            match rule {
+                ExcludeRule::Include(_) => true,
+                ExcludeRule::Scoped(_, rule) => is_negation(rule),
+                ExcludeRule::Exclude(_) => false,
+            }
+        }
+        let ExcludeList(list) = self;
+        list.iter().any(is_negation)
+    }
+
+    /// Evaluate the rules in order and return the decision of the last rule
+    /// matching the path, if any
     // TODO this should somehow implement the blob pattern thingy
-    pub fn should_exclude(&self, path: &AbstPath, is_dir: bool) -> bool {
+    pub fn decide(&self, path: &AbstPath, is_dir: bool) -> Option<ExcludeDecision> {
         let ExcludeList(list) = self;
         let path_as_string = {
             let mut tmp = path.to_string();
@@ -42,21 +198,23 @@ impl ExcludeList {
             tmp
         };
 
-        for rule in list {
-            if rule.is_match(path_as_string.as_str()) {
-                return true;
-            }
-        }
+        list.iter()
+            .rev()
+            .find_map(|rule| rule.decide(path_as_string.as_str()))
+    }
 
-        false
+    pub fn should_exclude(&self, path: &AbstPath, is_dir: bool) -> bool {
+        self.decide(path, is_dir) == Some(ExcludeDecision::Exclude)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{unparerr, ExcludeList, ExcludeListError};
+    use super::{unparerr, ExcludeDecision, ExcludeList, ExcludeListError, ExcludeRule};
+    use crate::generate_fstree;
     use abst_fs::AbstPath;
     use regex::Regex;
+    use std::path::PathBuf;
 
     #[test]
     fn test() {
@@ -66,7 +224,13 @@ mod tests {
 
         join();
 
+        with_defaults();
+
         should_exclude();
+
+        negations();
+
+        ignore_files();
     }
 
     fn assert_lists_eq(
@@ -102,7 +266,7 @@ mod tests {
     fn from() {
         assert_lists_eq(
             ExcludeList::from(&vec![]).unwrap(),
-            ExcludeList(vec![Regex::new("\\.bbup/").unwrap()]),
+            ExcludeList(vec![ExcludeRule::Exclude(Regex::new("\\.bbup/").unwrap())]),
         )
         .unwrap();
 
@@ -114,10 +278,10 @@ mod tests {
             ])
             .unwrap(),
             ExcludeList(vec![
-                Regex::new("\\.bbup/").unwrap(),
-                Regex::new("^prova$").unwrap(),
-                Regex::new("[0-9]?[0-9]:[0-9][0-9]").unwrap(),
-                Regex::new("^[a-zA-Z0-9 ]*$").unwrap(),
+                ExcludeRule::Exclude(Regex::new("\\.bbup/").unwrap()),
+                ExcludeRule::Exclude(Regex::new("^prova$").unwrap()),
+                ExcludeRule::Exclude(Regex::new("[0-9]?[0-9]:[0-9][0-9]").unwrap()),
+                ExcludeRule::Exclude(Regex::new("^[a-zA-Z0-9 ]*$").unwrap()),
             ]),
         )
         .unwrap();
@@ -133,15 +297,35 @@ mod tests {
                 ])
                 .unwrap(),
             ExcludeList(vec![
-                Regex::new("\\.bbup/").unwrap(),
-                Regex::new("[0-9]?[0-9]:[0-9][0-9]").unwrap(),
-                Regex::new("^prova$").unwrap(),
-                Regex::new("^[a-zA-Z0-9 ]*$").unwrap(),
+                ExcludeRule::Exclude(Regex::new("\\.bbup/").unwrap()),
+                ExcludeRule::Exclude(Regex::new("[0-9]?[0-9]:[0-9][0-9]").unwrap()),
+                ExcludeRule::Exclude(Regex::new("^prova$").unwrap()),
+                ExcludeRule::Exclude(Regex::new("^[a-zA-Z0-9 ]*$").unwrap()),
             ]),
         )
         .unwrap();
     }
 
+    fn with_defaults() {
+        let defaults = vec![String::from("\\.DS_Store$"), String::from("\\.swp$")];
+        let rules = vec![String::from("build/"), String::from("!keep\\.swp$")];
+
+        let exclude_list = ExcludeList::with_defaults(&defaults, &rules).unwrap();
+        assert!(exclude_list.should_exclude(&AbstPath::from("./dir/.DS_Store"), false));
+        assert!(exclude_list.should_exclude(&AbstPath::from("./file.swp"), false));
+        assert!(exclude_list.should_exclude(&AbstPath::from("./build"), true));
+        assert!(!exclude_list.should_exclude(&AbstPath::from("./keep.swp"), false));
+        assert!(!exclude_list.should_exclude(&AbstPath::from("./file"), false));
+
+        // Without defaults, as for a link opting out of them
+        let exclude_list = ExcludeList::with_defaults(&vec![], &rules).unwrap();
+        assert!(!exclude_list.should_exclude(&AbstPath::from("./dir/.DS_Store"), false));
+        assert!(!exclude_list.should_exclude(&AbstPath::from("./file.swp"), false));
+        assert!(exclude_list.should_exclude(&AbstPath::from("./build"), true));
+
+        assert!(ExcludeList::with_defaults(&vec![String::from("BOOM\\")], &rules).is_err());
+    }
+
     fn should_exclude() {
         let exclude_list = ExcludeList::from(&vec![
             String::from("some-directory/"),
@@ -163,4 +347,133 @@ mod tests {
         assert!(!exclude_list.should_exclude(&AbstPath::from("./path/to/root-file"), true));
         assert!(!exclude_list.should_exclude(&AbstPath::from("./path/to/root-file"), false));
     }
+
+    fn negations() {
+        let exclude_list = ExcludeList::from(&vec![
+            String::from("foo/"),
+            String::from("!foo/bar"),
+            String::from("baz/$"),
+        ])
+        .unwrap();
+        assert!(exclude_list.has_negations());
+        assert!(!ExcludeList::from(&vec![String::from("foo/")])
+            .unwrap()
+            .has_negations());
+
+        // `foo/` excludes the directory and everything inside it...
+        assert!(exclude_list.should_exclude(&AbstPath::from("./foo"), true));
+        assert!(!exclude_list.should_exclude(&AbstPath::from("./foo"), false));
+        assert!(exclude_list.should_exclude(&AbstPath::from("./foo/other"), false));
+        assert!(exclude_list.should_exclude(&AbstPath::from("./foo/other"), true));
+
+        // ...but `!foo/bar` re-includes what comes after it
+        assert_eq!(
+            exclude_list.decide(&AbstPath::from("./foo/bar"), false),
+            Some(ExcludeDecision::Include)
+        );
+        assert!(!exclude_list.should_exclude(&AbstPath::from("./foo/bar"), false));
+        assert!(!exclude_list.should_exclude(&AbstPath::from("./foo/bar"), true));
+        assert_eq!(
+            exclude_list.decide(&AbstPath::from("./foo/other"), false),
+            Some(ExcludeDecision::Exclude)
+        );
+        assert_eq!(exclude_list.decide(&AbstPath::from("./other"), false), None);
+
+        // trailing-slash directory-only patterns
+        assert!(exclude_list.should_exclude(&AbstPath::from("./path/to/baz"), true));
+        assert!(!exclude_list.should_exclude(&AbstPath::from("./path/to/baz"), false));
+
+        // last match wins
+        let exclude_list =
+            ExcludeList::from(&vec![String::from("!foo/bar"), String::from("foo/")]).unwrap();
+        assert!(exclude_list.should_exclude(&AbstPath::from("./foo/bar"), false));
+
+        // rules that can't be parsed once the negation is stripped are still errors
+        assert!(ExcludeList::from(&vec![String::from("!BOOM\\")]).is_err());
+    }
+
+    fn ignore_files() {
+        let path_bf = PathBuf::from("/tmp/bbup-test-fs_vcs-exclude-ignore-files");
+        assert!(!path_bf.exists());
+        std::fs::create_dir(&path_bf).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            let write = |rel: &str, content: &str| {
+                let path = path_bf.join(rel);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, content).unwrap();
+            };
+            write(".bbupignore", "# logs are never backed up\n\n\\.log$\r\n");
+            write("app.log", "log");
+            write("build/out", "root build output");
+            write("sub/.bbupignore", "^\\./build/\n!keep\\.log$\n");
+            write("sub/build/out", "nested build output");
+            write("sub/debug.log", "log");
+            write("sub/keep.log", "log");
+            write("sub/deep/other.log", "log");
+            write("sub/deep/build/out", "deeper build output");
+            // Ignore files inside excluded directories are read only because
+            //	the list has negations, which could re-include their content
+            write("sub/build/.bbupignore", "^\\./out$\n");
+
+            let root = AbstPath::from(&path_bf);
+            let exclude_list = ExcludeList::from(&vec![])
+                .unwrap()
+                .join_ignore_files(&root)
+                .unwrap();
+            let rules: Vec<String> = exclude_list.0.iter().map(|rule| rule.to_string()).collect();
+            assert_eq!(
+                rules,
+                vec![
+                    "\\.bbup/",
+                    "\\.log$",
+                    "./sub/: ^\\./build/",
+                    "./sub/: !keep\\.log$",
+                    "./sub/build/: ^\\./out$",
+                ]
+            );
+
+            // The root file applies everywhere...
+            assert!(exclude_list.should_exclude(&AbstPath::from("./app.log"), false));
+            assert!(exclude_list.should_exclude(&AbstPath::from("./sub/deep/other.log"), false));
+            // ...while the nested one only inside its directory, as if it was
+            //	the root
+            assert!(exclude_list.should_exclude(&AbstPath::from("./sub/build"), true));
+            assert!(!exclude_list.should_exclude(&AbstPath::from("./build"), true));
+            assert!(!exclude_list.should_exclude(&AbstPath::from("./sub/deep/build"), true));
+            assert!(!exclude_list.should_exclude(&AbstPath::from("./sub"), true));
+            assert!(!exclude_list.should_exclude(&AbstPath::from("./sub/keep.log"), false));
+            assert!(exclude_list.should_exclude(&AbstPath::from("./keep.log"), false));
+            assert!(exclude_list.has_negations());
+
+            let tree = generate_fstree(&root, &exclude_list).unwrap();
+            for (path, included) in [
+                ("app.log", false),
+                ("build/out", true),
+                ("sub/build", false),
+                ("sub/debug.log", false),
+                ("sub/keep.log", true),
+                ("sub/deep/other.log", false),
+                ("sub/deep/build/out", true),
+                ("sub/.bbupignore", true),
+            ] {
+                assert_eq!(
+                    tree.get_at(&AbstPath::from(path)).is_some(),
+                    included,
+                    "{path}"
+                );
+            }
+
+            // Invalid rules in an ignore file are errors
+            write("sub/deep/.bbupignore", "BOOM\\\n");
+            assert!(ExcludeList::from(&vec![])
+                .unwrap()
+                .join_ignore_files(&root)
+                .is_err());
+        });
+
+        std::fs::remove_dir_all(&path_bf).unwrap();
+
+        assert!(result.is_ok())
+    }
 }
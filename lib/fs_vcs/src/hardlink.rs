@@ -0,0 +1,153 @@
+use super::{FSNode, FSTree, FSTreeError};
+
+use abst_fs::AbstPath;
+
+use serde::{Deserialize, Serialize};
+
+/// Groups of files of a tree that are hard links to the same object. Each group
+/// holds the paths (relative to the root of the tree) of at least two files, in
+/// lexicographic order
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct HardLinkGroups(pub Vec<Vec<AbstPath>>);
+impl HardLinkGroups {
+    pub fn empty() -> HardLinkGroups {
+        HardLinkGroups(Vec::new())
+    }
+    pub fn is_empty(&self) -> bool {
+        let HardLinkGroups(groups) = self;
+        groups.is_empty()
+    }
+}
+
+/// Find the files of the tree (generated from `root`) that are hard links to the
+/// same object. Hard links are detected only on Unix, on other systems no group
+/// is ever found
+pub fn find_hardlinks(root: &AbstPath, tree: &FSTree) -> Result<HardLinkGroups, FSTreeError> {
+    let mut files = Vec::new();
+    collect_files(tree, &AbstPath::empty(), &mut files);
+
+    #[cfg(unix)]
+    {
+        use std::collections::HashMap;
+        use std::os::unix::fs::MetadataExt;
+
+        let mut by_inode: HashMap<(u64, u64), Vec<AbstPath>> = HashMap::new();
+        for path in files {
+            let full_path = root.append(&path);
+            let metadata = std::fs::symlink_metadata(full_path.to_path_buf()).map_err(|err| {
+                FSTreeError::Inner {
+                    src: format!("could not get metadata of file at path {full_path}"),
+                    err: err.to_string(),
+                }
+            })?;
+            if metadata.nlink() > 1 {
+                by_inode
+                    .entry((metadata.dev(), metadata.ino()))
+                    .or_default()
+                    .push(path);
+            }
+        }
+        let mut groups: Vec<Vec<AbstPath>> = by_inode
+            .into_values()
+            .filter(|group| group.len() > 1)
+            .map(|mut group| {
+                group.sort_by_key(|path| path.to_string());
+                group
+            })
+            .collect();
+        groups.sort_by_key(|group| group[0].to_string());
+        Ok(HardLinkGroups(groups))
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = (root, files);
+        Ok(HardLinkGroups::empty())
+    }
+}
+
+fn collect_files(FSTree(tree): &FSTree, path: &AbstPath, files: &mut Vec<AbstPath>) {
+    for (name, node) in tree {
+        match node {
+            FSNode::File(..) => files.push(path.add_last(name)),
+            FSNode::Dir(_, _, subtree) => collect_files(subtree, &path.add_last(name), files),
+            FSNode::SymLink(..) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_hardlinks, HardLinkGroups};
+    use crate::{generate_fstree, get_delta, Action, ExcludeList, FSTree};
+
+    use abst_fs::{self as fs, AbstPath};
+
+    #[test]
+    fn test() {
+        let path = std::path::PathBuf::from("/tmp/bbup-test-fs_vcs-hardlink");
+        assert!(!path.exists());
+        std::fs::create_dir(&path).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            let source = AbstPath::from(path.join("source"));
+            let target = AbstPath::from(path.join("target"));
+            std::fs::create_dir_all(path.join("source").join("dir")).unwrap();
+            std::fs::create_dir(path.join("target")).unwrap();
+            std::fs::write(path.join("source").join("file"), "shared content").unwrap();
+            std::fs::hard_link(
+                path.join("source").join("file"),
+                path.join("source").join("dir").join("link"),
+            )
+            .unwrap();
+            std::fs::write(path.join("source").join("other"), "shared content").unwrap();
+
+            // The group is detected, while identical but unrelated files are not
+            let tree = generate_fstree(&source, &ExcludeList::from(&vec![]).unwrap()).unwrap();
+            let groups = find_hardlinks(&source, &tree).unwrap();
+            assert_eq!(
+                groups,
+                HardLinkGroups(vec![vec![
+                    AbstPath::from("dir/link"),
+                    AbstPath::from("file")
+                ]])
+            );
+            assert!(find_hardlinks(&source, &FSTree::empty())
+                .unwrap()
+                .is_empty());
+
+            // Only the first file of the group is added, the other is linked to it
+            let mut actions = get_delta(&FSTree::empty(), &tree).to_actions();
+            actions.link_hardlinks(&groups);
+            actions.sort_for_apply();
+            let hardlinks: Vec<_> = (&actions)
+                .into_iter()
+                .filter(|(_, action)| matches!(action, Action::AddHardLink(_)))
+                .collect();
+            assert_eq!(hardlinks.len(), 1);
+
+            // The group is recreated by applying the actions
+            for (rel_path, action) in &actions {
+                let to_path = target.append(rel_path);
+                match action {
+                    Action::AddDir => fs::create_dir(&to_path).unwrap(),
+                    Action::AddFile(..) => {
+                        std::fs::copy(source.append(rel_path).to_path_buf(), to_path.to_path_buf())
+                            .unwrap();
+                    }
+                    Action::AddHardLink(original) => {
+                        fs::create_hardlink(&target.append(original), &to_path).unwrap()
+                    }
+                    _ => {}
+                }
+            }
+            let target_tree =
+                generate_fstree(&target, &ExcludeList::from(&vec![]).unwrap()).unwrap();
+            assert_eq!(find_hardlinks(&target, &target_tree).unwrap(), groups);
+        });
+
+        std::fs::remove_dir_all(&path).unwrap();
+
+        assert!(result.is_ok())
+    }
+}
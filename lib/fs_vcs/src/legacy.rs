@@ -0,0 +1,310 @@
+use super::{hash_tree, Commit, CommitID, CommitMetadata, Delta, DeltaNode, FSNode, FSTree};
+
+use abst_fs::{AbstPath, Mode, Mtime};
+use hasher::Hash;
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+
+/// Fields of a file node in one of the layouts `FSNode::File` had. The binary
+/// format can't tell missing fields apart, so trees and deltas saved with an
+/// older layout are read with the matching one and then upgraded
+pub(crate) trait FileLayout {
+    fn upgrade(self) -> FSNode;
+}
+/// Layout of the first versions: mtime and hash
+pub(crate) type FileV0 = (Mtime, Hash);
+/// Layout once files had a mode
+pub(crate) type FileV1 = (Mtime, Hash, Mode);
+/// Current layout, once files had a size
+pub(crate) type FileV2 = (Mtime, Hash, Mode, Option<u64>);
+impl FileLayout for FileV0 {
+    fn upgrade(self) -> FSNode {
+        FSNode::File(self.0, self.1, Mode::default(), None)
+    }
+}
+impl FileLayout for FileV1 {
+    fn upgrade(self) -> FSNode {
+        FSNode::File(self.0, self.1, self.2, None)
+    }
+}
+impl FileLayout for FileV2 {
+    fn upgrade(self) -> FSNode {
+        FSNode::File(self.0, self.1, self.2, self.3)
+    }
+}
+
+/// `FSNode` with the file layout `F`. A newtype variant holding a tuple is
+/// serialized exactly like a tuple variant with the same fields
+#[derive(Serialize, Deserialize)]
+pub(crate) enum LegacyNode<F> {
+    File(F),
+    SymLink(Mtime, Hash),
+    Dir(Mtime, Hash, LegacyTree<F>),
+}
+#[derive(Serialize, Deserialize)]
+pub(crate) struct LegacyTree<F>(pub HashMap<String, LegacyNode<F>>);
+#[derive(Serialize, Deserialize)]
+pub(crate) enum LegacyDeltaNode<F> {
+    Leaf(Option<LegacyNode<F>>, Option<LegacyNode<F>>),
+    Branch(Option<(Mtime, Mtime)>, LegacyDelta<F>),
+}
+#[derive(Serialize, Deserialize)]
+pub(crate) struct LegacyDelta<F>(pub HashMap<String, LegacyDeltaNode<F>>);
+/// Commit as it was saved before commits had metadata
+#[derive(Serialize, Deserialize)]
+pub(crate) struct LegacyCommit<F> {
+    pub commit_id: CommitID,
+    pub endpoint: AbstPath,
+    pub delta: LegacyDelta<F>,
+}
+
+impl<F: FileLayout> LegacyNode<F> {
+    /// The hashes of the directories are computed again, as the hash of a
+    /// directory covers the fields its files didn't have
+    fn upgrade(self) -> FSNode {
+        match self {
+            LegacyNode::File(file) => file.upgrade(),
+            LegacyNode::SymLink(mtime, hash) => FSNode::SymLink(mtime, hash),
+            LegacyNode::Dir(mtime, _, tree) => {
+                let tree = tree.upgrade();
+                FSNode::Dir(mtime, hash_tree(&tree), tree)
+            }
+        }
+    }
+}
+impl<F: FileLayout> LegacyTree<F> {
+    pub fn upgrade(self) -> FSTree {
+        let LegacyTree(tree) = self;
+        FSTree(
+            tree.into_iter()
+                .map(|(name, node)| (name, node.upgrade()))
+                .collect(),
+        )
+    }
+}
+impl<F: FileLayout> LegacyDelta<F> {
+    pub fn upgrade(self) -> Delta {
+        let LegacyDelta(delta) = self;
+        Delta(
+            delta
+                .into_iter()
+                .map(|(name, node)| {
+                    let node = match node {
+                        LegacyDeltaNode::Leaf(pre, post) => DeltaNode::Leaf(
+                            pre.map(LegacyNode::upgrade),
+                            post.map(LegacyNode::upgrade),
+                        ),
+                        LegacyDeltaNode::Branch(optm, subdelta) => {
+                            DeltaNode::Branch(optm, subdelta.upgrade())
+                        }
+                    };
+                    (name, node)
+                })
+                .collect(),
+        )
+    }
+}
+impl<F: FileLayout> LegacyCommit<F> {
+    pub fn upgrade(self) -> Commit {
+        Commit {
+            commit_id: self.commit_id,
+            endpoint: self.endpoint,
+            delta: self.delta.upgrade(),
+            metadata: CommitMetadata::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        FileV0, FileV1, LegacyCommit, LegacyDelta, LegacyDeltaNode, LegacyNode, LegacyTree,
+    };
+    use crate::{CommitID, CommitList, CommitMetadata, FSNode, FSTree};
+
+    use abst_fs::{AbstPath, Mode, Mtime};
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test() {
+        let path = PathBuf::from("/tmp/bbup-test-fs_vcs-legacy");
+        assert!(!path.exists());
+        std::fs::create_dir(&path).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            let path = AbstPath::from(&path);
+            baseline_tree(&path);
+            mode_tree(&path);
+            baseline_commit_list(&path);
+        });
+        std::fs::remove_dir_all(&path).unwrap();
+        assert!(result.is_ok())
+    }
+
+    /// The tree every layout upgrades to: the baseline only knew the default mode
+    fn upgraded() -> FSTree {
+        FSTree::gen_from(|t| {
+            t.add_file("file", (1667000000, 5), "content");
+            t.add_dir("dir", (1667000100, 0), |t| {
+                t.add_file("inner", (1667000050, 0), "inner content");
+                t.add_symlink("link", (1667000060, 0), "../file");
+            });
+        })
+    }
+    fn baseline_file(mtime: (i64, u32), content: &str) -> LegacyNode<FileV0> {
+        LegacyNode::File((
+            Mtime::from(mtime.0, mtime.1),
+            hasher::hash_bytes(content.as_bytes()),
+        ))
+    }
+    /// The tree of `upgraded` as the baseline saved it. The stored hash of the
+    /// directory is not the one of the current layout, and gets computed again
+    fn baseline() -> LegacyTree<FileV0> {
+        let dir = LegacyTree(HashMap::from([
+            (
+                String::from("inner"),
+                baseline_file((1667000050, 0), "inner content"),
+            ),
+            (
+                String::from("link"),
+                match FSNode::symlink((1667000060, 0), "../file") {
+                    FSNode::SymLink(mtime, hash) => LegacyNode::SymLink(mtime, hash),
+                    _ => unreachable!(),
+                },
+            ),
+        ]));
+        LegacyTree(HashMap::from([
+            (
+                String::from("file"),
+                baseline_file((1667000000, 5), "content"),
+            ),
+            (
+                String::from("dir"),
+                LegacyNode::Dir(
+                    Mtime::from(1667000100, 0),
+                    hasher::hash_bytes("stale hash"),
+                    dir,
+                ),
+            ),
+        ]))
+    }
+
+    fn baseline_tree(path: &AbstPath) {
+        // Bytes of a single file tree as saved by the baseline, written by hand
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend(1u64.to_le_bytes());
+        bytes.extend(4u64.to_le_bytes());
+        bytes.extend(b"file");
+        bytes.extend(0u32.to_le_bytes());
+        bytes.extend(1667000000i64.to_le_bytes());
+        bytes.extend(5u32.to_le_bytes());
+        bytes.extend(hasher::hash_bytes("content").to_bytes());
+        let file = path.add_last("handmade.bin");
+        std::fs::write(file.to_path_buf(), &bytes).unwrap();
+        let tree = FSTree::load(&file).unwrap();
+        assert_eq!(
+            tree,
+            FSTree::gen_from(|t| t.add_file("file", (1667000000, 5), "content"))
+        );
+        assert_eq!(
+            tree.get_at(&AbstPath::from("file")),
+            Some(&FSNode::File(
+                Mtime::from(1667000000, 5),
+                hasher::hash_bytes("content"),
+                Mode::default(),
+                None
+            ))
+        );
+
+        // The whole baseline tree, in both formats
+        for name in ["baseline.bin", "baseline.json"] {
+            let file = path.add_last(name);
+            abst_fs::save(&file, &baseline()).unwrap();
+            let tree = FSTree::load(&file).unwrap();
+            assert_eq!(tree, upgraded());
+            assert_eq!(tree.root_hash(), upgraded().root_hash());
+        }
+
+        // Current trees load as they are, and anything else is still an error
+        let file = path.add_last("current.bin");
+        abst_fs::save(&file, &upgraded()).unwrap();
+        assert_eq!(FSTree::load(&file).unwrap(), upgraded());
+        let file = path.add_last("garbage.bin");
+        std::fs::write(file.to_path_buf(), b"not a tree").unwrap();
+        assert!(FSTree::load(&file).is_err());
+    }
+
+    fn mode_tree(path: &AbstPath) {
+        // Trees saved once files had a mode, but not yet a size, keep the mode
+        let tree: LegacyTree<FileV1> = LegacyTree(HashMap::from([(
+            String::from("script"),
+            LegacyNode::File((
+                Mtime::from(1667000000, 0),
+                hasher::hash_bytes("#!/bin/sh"),
+                Mode::from(0o755),
+            )),
+        )]));
+        let file = path.add_last("mode.bin");
+        abst_fs::save(&file, &tree).unwrap();
+        let loaded = FSTree::load(&file).unwrap();
+        assert_eq!(
+            loaded,
+            FSTree(HashMap::from([(
+                String::from("script"),
+                FSNode::file_with_mode((1667000000, 0), "#!/bin/sh", 0o755)
+            )]))
+        );
+        // Their size is unknown until the file is hashed again
+        assert!(matches!(
+            loaded.get_at(&AbstPath::from("script")),
+            Some(FSNode::File(_, _, _, None))
+        ));
+    }
+
+    fn baseline_commit_list(path: &AbstPath) {
+        let added = LegacyDelta(HashMap::from([(
+            String::from("dir"),
+            LegacyDeltaNode::Branch(
+                Some((Mtime::from(1667000000, 0), Mtime::from(1667000100, 0))),
+                LegacyDelta(HashMap::from([(
+                    String::from("new"),
+                    LegacyDeltaNode::Leaf(None, Some(baseline_file((1667000090, 0), "new"))),
+                )])),
+            ),
+        )]));
+        let id = CommitID::gen_valid();
+        let legacy = vec![
+            LegacyCommit {
+                commit_id: CommitID::null(),
+                endpoint: AbstPath::empty(),
+                delta: LegacyDelta::<FileV0>(HashMap::new()),
+            },
+            LegacyCommit {
+                commit_id: id.clone(),
+                endpoint: AbstPath::from("endpoint"),
+                delta: added,
+            },
+        ];
+        let file = path.add_last("commit-list.bin");
+        abst_fs::save(&file, &legacy).unwrap();
+        let commit_list = CommitList::load(&file).unwrap();
+        let commit = commit_list.most_recent_commit();
+        assert_eq!(commit.commit_id, id);
+        assert_eq!(commit.metadata, CommitMetadata::default());
+
+        // The upgraded delta still applies to the upgraded tree
+        let mut tree = FSTree::gen_from(|t| t.add_empty_dir("dir", (1667000000, 0)));
+        tree.apply_delta(&commit.delta).unwrap();
+        assert_eq!(
+            tree,
+            FSTree::gen_from(|t| {
+                t.add_dir("dir", (1667000100, 0), |t| {
+                    t.add_file("new", (1667000090, 0), "new");
+                })
+            })
+        );
+    }
+}
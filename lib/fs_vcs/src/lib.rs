@@ -1,24 +1,50 @@
 mod actions;
 pub use actions::{
+    conflict_copy_name,
     get_actions_or_conflicts,
+    get_actions_with_strategy,
+    get_actions_with_strategy_at,
     Action,
     Actions,
     ConflictNode,
+    ConflictStrategy,
     Conflicts,
+    METADATA_WORK,
     //Queries, Query,
 };
 
 mod commit;
-pub use commit::{Commit, CommitList};
+pub use commit::{Commit, CommitID, CommitIDError, CommitList, CommitMetadata, EndpointSummary};
 
 mod delta;
-pub use delta::{get_delta, Delta, DeltaNode};
+pub use delta::{
+    get_delta, get_delta_content_only, get_delta_with_options, get_delta_with_precision, Delta,
+    DeltaNode, DeltaOptions, DeltaStats, ObjectCount,
+};
+
+mod divergence;
+pub use divergence::{find_divergence, reconcile_divergence, DivergenceError, DivergencePolicy};
+
+mod hardlink;
+pub use hardlink::{find_hardlinks, HardLinkGroups};
+
+mod names;
+pub use names::{check_name, NameIssue, TargetOs, WINDOWS_MAX_NAME, WINDOWS_MAX_PATH};
 
 mod exclude;
-pub use exclude::ExcludeList;
+pub use exclude::{ExcludeDecision, ExcludeList, ExcludeRule, IGNORE_FILE_NAME};
+
+mod legacy;
 
 mod tree;
 use tree::hash_tree;
-pub use tree::{generate_fstree, FSNode, FSTree};
+pub use tree::{
+    dereference_symlinks, fill_hashes, generate_fstree, generate_fstree_at,
+    generate_fstree_parallel, generate_fstree_skipping_unreadable, generate_fstree_structural,
+    generate_fstree_to_depth, generate_fstree_with_options, generate_fstree_with_prior,
+    generate_fstree_with_skipped, set_small_file_size, FSNode, FSTree, FSTreeError,
+    GenerationOptions, StructNode, StructTree, TreeSummary, DEFAULT_SMALL_FILE_SIZE,
+};
 
 mod display;
+pub use display::{human_size, set_colors};
@@ -0,0 +1,250 @@
+use abst_fs::AbstPath;
+
+use super::{FSNode, FSTree};
+
+/// Operating system whose file systems the names of a tree have to be valid on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TargetOs {
+    /// Any name goes, as long as it's valid on the local file system
+    Unix,
+    /// Names can't contain some characters, can't be reserved device names and
+    /// can't end with a dot or a space, and paths can't be too long
+    Windows,
+}
+impl std::str::FromStr for TargetOs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unix" => Ok(TargetOs::Unix),
+            "windows" => Ok(TargetOs::Windows),
+            _ => Err(format!(
+                "invalid target os '{s}', expected one of: unix, windows"
+            )),
+        }
+    }
+}
+
+/// Maximum length, in characters, of a path on Windows (`MAX_PATH`, terminator
+/// excluded). Paths are checked relative to the root of the tree, so the root
+/// itself has to fit in what's left
+pub const WINDOWS_MAX_PATH: usize = 259;
+/// Maximum length, in characters, of a single name on Windows
+pub const WINDOWS_MAX_NAME: usize = 255;
+
+const WINDOWS_ILLEGAL_CHARS: [char; 9] = ['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+const WINDOWS_RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Reason why a path can't be created on the target os
+#[derive(Debug, Clone, PartialEq)]
+pub enum NameIssue {
+    /// The name contains a character that is not allowed
+    IllegalChar(char),
+    /// The name is reserved for a device, even with an extension (e.g. `con.txt`)
+    ReservedName,
+    /// The name ends with a dot or a space, which get silently stripped
+    TrailingDotOrSpace,
+    /// The name is longer than `WINDOWS_MAX_NAME`, with its length in characters
+    NameTooLong(usize),
+    /// The whole path is longer than `WINDOWS_MAX_PATH`, with its length in
+    /// characters
+    PathTooLong(usize),
+}
+impl std::fmt::Display for NameIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NameIssue::IllegalChar(c) if c.is_control() => {
+                write!(f, "illegal control character {:?}", c)
+            }
+            NameIssue::IllegalChar(c) => write!(f, "illegal character '{c}'"),
+            NameIssue::ReservedName => write!(f, "reserved name"),
+            NameIssue::TrailingDotOrSpace => write!(f, "trailing dot or space"),
+            NameIssue::NameTooLong(len) => {
+                write!(f, "name too long ({len} > {WINDOWS_MAX_NAME} characters)")
+            }
+            NameIssue::PathTooLong(len) => {
+                write!(f, "path too long ({len} > {WINDOWS_MAX_PATH} characters)")
+            }
+        }
+    }
+}
+
+/// Issue that would prevent creating an object with the given name on the target
+/// os, if any
+pub fn check_name(name: &str, target: TargetOs) -> Option<NameIssue> {
+    match target {
+        TargetOs::Unix => None,
+        TargetOs::Windows => {
+            if let Some(c) = name
+                .chars()
+                .find(|c| c.is_control() || WINDOWS_ILLEGAL_CHARS.contains(c))
+            {
+                return Some(NameIssue::IllegalChar(c));
+            }
+            // The reserved names can't be used even with an extension
+            let stem = name.split('.').next().unwrap_or(name).trim_end();
+            if WINDOWS_RESERVED_NAMES
+                .iter()
+                .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+            {
+                return Some(NameIssue::ReservedName);
+            }
+            if name.ends_with('.') || name.ends_with(' ') {
+                return Some(NameIssue::TrailingDotOrSpace);
+            }
+            let len = name.chars().count();
+            if len > WINDOWS_MAX_NAME {
+                return Some(NameIssue::NameTooLong(len));
+            }
+            None
+        }
+    }
+}
+
+impl FSTree {
+    /// Paths of the tree (relative to its root) that can't be created on the
+    /// target os, each with the first issue found, sorted by path
+    pub fn invalid_names(&self, target: TargetOs) -> Vec<(AbstPath, NameIssue)> {
+        let mut invalid = Vec::new();
+        if target != TargetOs::Unix {
+            invalid_names_rec(self, &AbstPath::empty(), target, &mut invalid);
+        }
+        invalid.sort_by_key(|(path, _)| path.to_string());
+        invalid
+    }
+}
+fn invalid_names_rec(
+    FSTree(tree): &FSTree,
+    prefix: &AbstPath,
+    target: TargetOs,
+    invalid: &mut Vec<(AbstPath, NameIssue)>,
+) {
+    for (name, node) in tree {
+        let path = prefix.add_last(name);
+        // Each separator counts as one character, as the backslashes of Windows
+        let len = path.to_string().chars().count();
+        match check_name(name, target) {
+            Some(issue) => invalid.push((path.clone(), issue)),
+            None if len > WINDOWS_MAX_PATH => {
+                invalid.push((path.clone(), NameIssue::PathTooLong(len)))
+            }
+            None => {}
+        }
+        if let FSNode::Dir(_, _, subtree) = node {
+            invalid_names_rec(subtree, &path, target, invalid);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_name, NameIssue, TargetOs, WINDOWS_MAX_PATH};
+    use crate::FSTree;
+    use abst_fs::AbstPath;
+
+    #[test]
+    fn test() {
+        check_names();
+        invalid_names();
+        parse();
+    }
+
+    fn check_names() {
+        let windows = TargetOs::Windows;
+        for name in [
+            "file.txt",
+            "con-artist",
+            "icon.txt",
+            "COM10",
+            ".hidden",
+            "dir name",
+        ] {
+            assert_eq!(check_name(name, windows), None, "{name}");
+        }
+        assert_eq!(
+            check_name("con.txt", windows),
+            Some(NameIssue::ReservedName)
+        );
+        assert_eq!(check_name("CON", windows), Some(NameIssue::ReservedName));
+        assert_eq!(
+            check_name("Lpt1.tar.gz", windows),
+            Some(NameIssue::ReservedName)
+        );
+        assert_eq!(
+            check_name("nul .txt", windows),
+            Some(NameIssue::ReservedName)
+        );
+        assert_eq!(
+            check_name("what?", windows),
+            Some(NameIssue::IllegalChar('?'))
+        );
+        assert_eq!(
+            check_name("a:b", windows),
+            Some(NameIssue::IllegalChar(':'))
+        );
+        assert_eq!(
+            check_name("star*", windows),
+            Some(NameIssue::IllegalChar('*'))
+        );
+        assert_eq!(
+            check_name("tab\t", windows),
+            Some(NameIssue::IllegalChar('\t'))
+        );
+        assert_eq!(
+            check_name("file.", windows),
+            Some(NameIssue::TrailingDotOrSpace)
+        );
+        assert_eq!(
+            check_name("file ", windows),
+            Some(NameIssue::TrailingDotOrSpace)
+        );
+        assert_eq!(
+            check_name(&"a".repeat(256), windows),
+            Some(NameIssue::NameTooLong(256))
+        );
+
+        // Nothing is checked for unix targets
+        assert_eq!(check_name("con.txt", TargetOs::Unix), None);
+        assert_eq!(check_name("what?", TargetOs::Unix), None);
+    }
+
+    fn invalid_names() {
+        let long = "d".repeat(200);
+        let tree = FSTree::gen_from(|t| {
+            t.add_file("con.txt", (1667000000, 0), "content");
+            t.add_file("normal.txt", (1667000000, 0), "content");
+            t.add_dir("dir", (1667000000, 0), |t| {
+                t.add_file("what?", (1667000000, 0), "content");
+                t.add_symlink("fine", (1667000000, 0), "what?");
+            });
+            t.add_dir(&long, (1667000000, 0), |t| {
+                t.add_file("f".repeat(100), (1667000000, 0), "content");
+            });
+        });
+
+        let invalid = tree.invalid_names(TargetOs::Windows);
+        let long_path = AbstPath::from(&long).add_last("f".repeat(100));
+        assert_eq!(
+            invalid,
+            vec![
+                (AbstPath::from("con.txt"), NameIssue::ReservedName),
+                (AbstPath::from(&long).add_last("f".repeat(100)), {
+                    NameIssue::PathTooLong(long_path.to_string().chars().count())
+                }),
+                (AbstPath::from("dir/what?"), NameIssue::IllegalChar('?')),
+            ]
+        );
+        assert!(long_path.to_string().chars().count() > WINDOWS_MAX_PATH);
+        assert!(tree.invalid_names(TargetOs::Unix).is_empty());
+        assert!(FSTree::empty().invalid_names(TargetOs::Windows).is_empty());
+    }
+
+    fn parse() {
+        assert_eq!("windows".parse(), Ok(TargetOs::Windows));
+        assert_eq!("unix".parse(), Ok(TargetOs::Unix));
+        assert!("macos".parse::<TargetOs>().is_err());
+    }
+}
@@ -1,12 +1,19 @@
-use super::ExcludeList;
+use super::{
+    legacy::{FileV0, FileV1, LegacyTree},
+    ExcludeList, ObjectCount,
+};
 
-use abst_fs::{self as fs, AbstPath, Mtime, ObjectType};
+use abst_fs::{self as fs, AbstPath, Endpoint, Mode, Mtime, ObjectType};
 use hasher::Hash;
 
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 use std::collections::HashMap;
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
 
 #[derive(Error, Debug, PartialEq)]
 pub enum FSTreeError {
@@ -15,6 +22,29 @@ pub enum FSTreeError {
     )]
     NonDirEntryPoint { path: AbstPath },
 
+    #[error(
+        "File System Tree Error: found objects whose names differ only by case, which would collide on a case-insensitive file system\nparent: {parent}\nnames: {first}, {second}"
+    )]
+    CaseCollision {
+        parent: AbstPath,
+        first: String,
+        second: String,
+    },
+
+    #[error(
+        "File System Tree Error: could not generate fs-tree of a subpath whose parent is not a directory of the known tree\npath: {path}"
+    )]
+    UnknownSubpath { path: AbstPath },
+
+    #[error("File System Tree Error: following the symlink leads to a cycle\npath: {path}")]
+    SymlinkCycle { path: AbstPath },
+
+    #[error("File System Tree Error: the target of the symlink does not exist\npath: {path}")]
+    DanglingSymlink { path: AbstPath },
+
+    #[error("File System Tree Error: permission denied while reading the object\npath: {path}")]
+    PermissionDenied { path: AbstPath },
+
     #[error("File System Tree Error: inner error occurred\nSource: {src}\nError: {err}")]
     Inner { src: String, err: String },
 
@@ -39,18 +69,39 @@ fn generr<S: std::string::ToString, T: std::string::ToString>(src: S, err: T) ->
 fn error_context<S: std::string::ToString>(context: S) -> impl Fn(&str) -> String {
     move |failure: &str| -> String { format!("{}\nFailed to {}", context.to_string(), failure) }
 }
+/// Report the failures to read the object at path due to the lack of permissions
+/// as `FSTreeError::PermissionDenied`, and any other failure with `otherwise`
+fn denied_or<'a, F>(path: &'a AbstPath, otherwise: F) -> impl Fn(fs::Error) -> FSTreeError + 'a
+where
+    F: Fn(fs::Error) -> FSTreeError + 'a,
+{
+    move |err: fs::Error| -> FSTreeError {
+        match err.kind() {
+            Some(std::io::ErrorKind::PermissionDenied) => {
+                FSTreeError::PermissionDenied { path: path.clone() }
+            }
+            _ => otherwise(err),
+        }
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum FSNode {
-    File(Mtime, Hash),
+    /// The last field is the size of the file in bytes, when known. It is derived
+    /// from the content, so it is neither compared nor hashed. Trees saved before
+    /// it existed are loaded with `None` (see `FSTree::load`)
+    File(Mtime, Hash, Mode, #[serde(default)] Option<u64>),
     SymLink(Mtime, Hash),
     Dir(Mtime, Hash, FSTree),
 }
 impl PartialEq for FSNode {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
-            (Self::File(mtime_l, hash_l), Self::File(mtime_r, hash_r))
-            | (Self::SymLink(mtime_l, hash_l), Self::SymLink(mtime_r, hash_r))
+            (Self::File(mtime_l, hash_l, mode_l, _), Self::File(mtime_r, hash_r, mode_r, _)) => {
+                mtime_l == mtime_r && hash_l == hash_r && mode_l == mode_r
+            }
+
+            (Self::SymLink(mtime_l, hash_l), Self::SymLink(mtime_r, hash_r))
 
             // Do not check for subtree structure: the idea is that the hash represents
             //	itself the tree structure, so the trees are equal iff the hashes are
@@ -70,6 +121,193 @@ impl FSTree {
     pub fn empty() -> FSTree {
         FSTree(HashMap::new())
     }
+
+    /// Load the tree saved at path (see `abst_fs::load`). Trees saved before
+    /// files had a mode or a size are still loaded, even from the binary format,
+    /// which can't tell the missing fields apart: their files get the default
+    /// mode and no size, and the hashes of their directories are computed again
+    pub fn load(path: &AbstPath) -> Result<FSTree, fs::Error> {
+        let err = match fs::load(path) {
+            Ok(tree) => return Ok(tree),
+            Err(err) => err,
+        };
+        if let Ok(tree) = fs::load::<LegacyTree<FileV1>>(path) {
+            return Ok(tree.upgrade());
+        }
+        match fs::load::<LegacyTree<FileV0>>(path) {
+            Ok(tree) => Ok(tree.upgrade()),
+            Err(_) => Err(err),
+        }
+    }
+
+    /// Get the node at `path` (relative to the root of the tree), descending
+    /// through directories. Returns `None` if the path is empty, if any of its
+    /// components is missing or if a non-final component is not a directory
+    pub fn get_at(&self, path: &AbstPath) -> Option<&FSNode> {
+        let mut components = path.into_iter();
+        let FSTree(tree) = self;
+        let mut node = tree.get(components.next()?)?;
+        for component in components {
+            match node {
+                FSNode::Dir(_, _, FSTree(subtree)) => node = subtree.get(component)?,
+                _ => return None,
+            }
+        }
+        Some(node)
+    }
+
+    /// Hash of the whole tree, aggregated from its children exactly like the
+    /// hash of a directory with this content. Two trees have the same root
+    /// hash if and only if they are equal (barring hash collisions), so a
+    /// single comparison is enough to tell whether anything changed
+    pub fn root_hash(&self) -> Hash {
+        hash_tree(self)
+    }
+
+    /// Compare the two trees node by node, returning the path of the first
+    /// object that differs: an object missing from either tree, an object of a
+    /// different type, or an object whose mtime, hash or mode differ.
+    /// Children are visited in lexicographic order and the content of a
+    /// directory is compared before its own mtime, so a single changed object
+    /// is reported at its own path rather than at one of its ancestors
+    pub fn structurally_eq(&self, other: &FSTree) -> Result<(), AbstPath> {
+        self.structurally_eq_rec(other, &AbstPath::empty())
+    }
+    fn structurally_eq_rec(&self, other: &FSTree, path: &AbstPath) -> Result<(), AbstPath> {
+        let (FSTree(left), FSTree(right)) = (self, other);
+        let mut names = left.keys().chain(right.keys()).collect::<Vec<&String>>();
+        names.sort();
+        names.dedup();
+        for name in names {
+            let child_path = path.add_last(name);
+            match (left.get(name), right.get(name)) {
+                (
+                    Some(FSNode::Dir(mtime_l, _, subtree_l)),
+                    Some(FSNode::Dir(mtime_r, _, subtree_r)),
+                ) => {
+                    subtree_l.structurally_eq_rec(subtree_r, &child_path)?;
+                    if mtime_l != mtime_r {
+                        return Err(child_path);
+                    }
+                }
+                (Some(node_l), Some(node_r)) if node_l == node_r => {}
+                _ => return Err(child_path),
+            }
+        }
+        Ok(())
+    }
+
+    /// Count the objects of each type in the tree and sum the sizes of the
+    /// files, in a single pass. Files whose size is not known are counted but
+    /// do not contribute to the total size
+    pub fn summary(&self) -> TreeSummary {
+        let mut summary = TreeSummary::default();
+        self.summary_rec(&mut summary);
+        summary
+    }
+    fn summary_rec(&self, summary: &mut TreeSummary) {
+        let FSTree(tree) = self;
+        for node in tree.values() {
+            match node {
+                FSNode::File(_, _, _, size) => {
+                    summary.count.files += 1;
+                    summary.total_size += size.unwrap_or(0);
+                }
+                FSNode::SymLink(_, _) => summary.count.symlinks += 1,
+                FSNode::Dir(_, _, subtree) => {
+                    summary.count.dirs += 1;
+                    subtree.summary_rec(summary);
+                }
+            }
+        }
+    }
+}
+
+// Helpers to build trees in memory, without reading anything from the file
+//	system. The hashes are computed from the given content as if it was read
+//	from a file or symlink
+impl FSNode {
+    /// File with the given mtime and content, and default mode
+    pub fn file(mtime: (i64, u32), content: impl ToString) -> FSNode {
+        let content = content.to_string();
+        FSNode::File(
+            Mtime::from(mtime.0, mtime.1),
+            hasher::hash_bytes(content.as_bytes()),
+            Mode::default(),
+            Some(content.len() as u64),
+        )
+    }
+    /// File with the given mtime, content and mode
+    pub fn file_with_mode(mtime: (i64, u32), content: impl ToString, mode: u32) -> FSNode {
+        let content = content.to_string();
+        FSNode::File(
+            Mtime::from(mtime.0, mtime.1),
+            hasher::hash_bytes(content.as_bytes()),
+            Mode::from(mode),
+            Some(content.len() as u64),
+        )
+    }
+    /// Symlink with the given mtime, pointing to the given (unix) path
+    pub fn symlink(mtime: (i64, u32), path: impl ToString) -> FSNode {
+        FSNode::SymLink(
+            Mtime::from(mtime.0, mtime.1),
+            hasher::hash_bytes(Endpoint::Unix(path.to_string()).as_bytes()),
+        )
+    }
+    /// Directory with the given mtime, containing the tree built by `subtree_gen`
+    pub fn dir(mtime: (i64, u32), subtree_gen: impl Fn(&mut FSTree)) -> FSNode {
+        let mut subtree = FSTree::empty();
+        subtree_gen(&mut subtree);
+        FSNode::Dir(Mtime::from(mtime.0, mtime.1), hash_tree(&subtree), subtree)
+    }
+    /// Empty directory with the given mtime
+    pub fn empty_dir(mtime: (i64, u32)) -> FSNode {
+        FSNode::Dir(
+            Mtime::from(mtime.0, mtime.1),
+            hash_tree(&FSTree::empty()),
+            FSTree::empty(),
+        )
+    }
+}
+impl FSTree {
+    /// Build a tree in memory, starting from an empty tree and letting `gen`
+    /// add its content with the `add_*` functions. Meant to describe synthetic
+    /// trees concisely, for tests and benchmarks
+    pub fn gen_from(gen: impl Fn(&mut FSTree)) -> FSTree {
+        let mut tree = FSTree::empty();
+        gen(&mut tree);
+        tree
+    }
+
+    pub fn add_file(&mut self, name: impl ToString, mtime: (i64, u32), content: impl ToString) {
+        let FSTree(tree) = self;
+        tree.insert(name.to_string(), FSNode::file(mtime, content));
+    }
+    pub fn add_symlink(&mut self, name: impl ToString, mtime: (i64, u32), path: impl ToString) {
+        let FSTree(tree) = self;
+        tree.insert(name.to_string(), FSNode::symlink(mtime, path));
+    }
+    pub fn add_dir(
+        &mut self,
+        name: impl ToString,
+        mtime: (i64, u32),
+        subtree_gen: impl Fn(&mut FSTree),
+    ) {
+        let FSTree(tree) = self;
+
+        tree.insert(name.to_string(), FSNode::dir(mtime, subtree_gen));
+    }
+    pub fn add_empty_dir(&mut self, name: impl ToString, mtime: (i64, u32)) {
+        let FSTree(tree) = self;
+        tree.insert(name.to_string(), FSNode::empty_dir(mtime));
+    }
+}
+
+/// Number of objects of each type in a tree and total size of its files
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TreeSummary {
+    pub count: ObjectCount,
+    pub total_size: u64,
 }
 
 /// Hash the endpoint of a symlink
@@ -78,16 +316,50 @@ fn hash_symlink(path: &AbstPath) -> Result<Hash, FSTreeError> {
     let endpoint = fs::read_link(path).map_err(inerr(errctx("read symlink's endpoint")))?;
     Ok(hasher::hash_bytes(endpoint.as_bytes()))
 }
+#[cfg(test)]
+thread_local! {
+    /// Number of files hashed by this thread, to check which files get hashed
+    static HASHED_FILES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+/// Default of `set_small_file_size`
+pub const DEFAULT_SMALL_FILE_SIZE: u64 = 64 * 1024;
+static SMALL_FILE_SIZE: AtomicU64 = AtomicU64::new(DEFAULT_SMALL_FILE_SIZE);
+/// Set the size in bytes below which the content of a file is read whole and
+/// hashed from memory when generating trees, while larger files are streamed.
+/// On archives made of many tiny files this saves the overhead of the streamed
+/// copy, which dominates the time spent on each file. 0 streams every file
+pub fn set_small_file_size(bytes: u64) {
+    SMALL_FILE_SIZE.store(bytes, Ordering::Relaxed);
+}
 /// Hash the content of a file
 fn hash_file(path: &AbstPath) -> Result<Hash, FSTreeError> {
+    #[cfg(test)]
+    HASHED_FILES.with(|count| count.set(count.get() + 1));
     let errctx = error_context(format!("could not hash content of file at path {path}"));
-    let content = fs::read_file(path).map_err(inerr(errctx("read file content")))?;
-    hasher::hash_stream(content).map_err(inerr(errctx("hash file content")))
+    let mut content =
+        fs::read_file(path).map_err(denied_or(path, inerr(errctx("read file content"))))?;
+    let size = content
+        .metadata()
+        .map_err(inerr(errctx("get size of file")))?
+        .len();
+    match size < SMALL_FILE_SIZE.load(Ordering::Relaxed) {
+        true => {
+            // The extra byte lets read_to_end find the end of the file without
+            //	growing the buffer. If the file grew meanwhile, it is read whole
+            //	anyway
+            let mut buffer = Vec::with_capacity(size as usize + 1);
+            content
+                .read_to_end(&mut buffer)
+                .map_err(inerr(errctx("read file content")))?;
+            Ok(hasher::hash_bytes(buffer))
+        }
+        false => hasher::hash_stream(content).map_err(inerr(errctx("hash file content"))),
+    }
 }
 /// Hash children of a node by concatenating their names and their relative hashes
 pub fn hash_tree(FSTree(tree): &FSTree) -> Hash {
     let mut sorted_children = tree.iter().collect::<Vec<(&String, &FSNode)>>();
-    sorted_children.sort_by(|(name0, _), (name1, _)| name0.cmp(name1));
+    sorted_children.sort_by_key(|(name0, _)| *name0);
 
     let mut s: Vec<u8> = Vec::new();
     for (name, node) in sorted_children {
@@ -97,9 +369,10 @@ pub fn hash_tree(FSTree(tree): &FSTree) -> Hash {
         let name_hash = hasher::hash_bytes(name.as_bytes());
         s.append(&mut name_hash.to_bytes());
         match node {
-            FSNode::File(mtime, hash) => {
+            FSNode::File(mtime, hash, mode, _) => {
                 s.append(&mut mtime.to_bytes());
                 s.append(&mut hash.to_bytes());
+                s.append(&mut mode.to_bytes());
             }
             FSNode::SymLink(mtime, hash) => {
                 s.append(&mut mtime.to_bytes());
@@ -114,198 +387,1024 @@ pub fn hash_tree(FSTree(tree): &FSTree) -> Hash {
     hasher::hash_bytes(s)
 }
 
+/// Paths of the objects skipped while generating a tree: the special objects
+/// (FIFOs, sockets and devices), as they can't be synchronized, and the objects
+/// that can't be read for lack of permissions, if they are tolerated (otherwise
+/// they fail the generation)
+#[derive(Default)]
+struct Skipped {
+    special: Mutex<Vec<AbstPath>>,
+    unreadable: Option<Mutex<Vec<AbstPath>>>,
+}
+impl Skipped {
+    fn tolerating_unreadable() -> Skipped {
+        Skipped {
+            special: Mutex::default(),
+            unreadable: Some(Mutex::default()),
+        }
+    }
+    fn push(&self, path: &AbstPath) {
+        self.special
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(path.clone());
+    }
+    /// If unreadable objects are tolerated, turn the failure to read `entry` for
+    /// lack of permissions into None, recording `entry` as unreadable
+    fn tolerate<T>(
+        &self,
+        entry: &AbstPath,
+        result: Result<T, FSTreeError>,
+    ) -> Result<Option<T>, FSTreeError> {
+        match (result, &self.unreadable) {
+            (Err(FSTreeError::PermissionDenied { path }), Some(unreadable)) if path == *entry => {
+                unreadable
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push(path);
+                Ok(None)
+            }
+            (result, _) => result.map(Some),
+        }
+    }
+    fn into_sorted(self) -> Vec<AbstPath> {
+        sorted(self.special)
+    }
+    fn into_sorted_with_unreadable(self) -> (Vec<AbstPath>, Vec<AbstPath>) {
+        let unreadable = self.unreadable.map(sorted).unwrap_or_default();
+        (sorted(self.special), unreadable)
+    }
+}
+fn sorted(paths: Mutex<Vec<AbstPath>>) -> Vec<AbstPath> {
+    let mut paths = paths.into_inner().unwrap_or_else(PoisonError::into_inner);
+    paths.sort_by_key(|path| path.to_string());
+    paths
+}
+
 /// Generate a tree representation of the content of a path specified, saving the hashes
-/// at every node and leaf to be able to detect changes
+/// at every node and leaf to be able to detect changes.
+/// Special objects (FIFOs, sockets and devices) are skipped
 pub fn generate_fstree(root: &AbstPath, exclude_list: &ExcludeList) -> Result<FSTree, FSTreeError> {
+    let (tree, _) = generate_fstree_with_skipped(root, exclude_list)?;
+    Ok(tree)
+}
+
+/// Same as `generate_fstree`, but also returns the paths of the special objects
+/// that have been skipped, sorted
+pub fn generate_fstree_with_skipped(
+    root: &AbstPath,
+    exclude_list: &ExcludeList,
+) -> Result<(FSTree, Vec<AbstPath>), FSTreeError> {
     if root.object_type() != Some(ObjectType::Dir) {
         return Err(FSTreeError::NonDirEntryPoint { path: root.clone() });
     }
-    generate_fstree_rec(root, &AbstPath::single("."), exclude_list)
+    let skipped = Skipped::default();
+    let tree = generate_fstree_rec(root, &AbstPath::single("."), exclude_list, &skipped)?;
+    Ok((tree, skipped.into_sorted()))
 }
 
-fn generate_fstree_rec(
-    path: &AbstPath,
-    rel_path: &AbstPath,
+/// Same as `generate_fstree_with_skipped`, but instead of failing on the objects
+/// that can't be read for lack of permissions, leave them out of the tree and
+/// carry on. Returns the tree of the readable objects, the paths of the special
+/// objects skipped and the paths of the unreadable objects, both sorted
+pub fn generate_fstree_skipping_unreadable(
+    root: &AbstPath,
     exclude_list: &ExcludeList,
-) -> Result<FSTree, FSTreeError> {
-    let errctx = error_context(format!(
-        "could not generate fstree from subtree at path {path}"
-    ));
-    let mut tree: HashMap<String, FSNode> = HashMap::new();
+) -> Result<(FSTree, Vec<AbstPath>, Vec<AbstPath>), FSTreeError> {
+    if root.object_type() != Some(ObjectType::Dir) {
+        return Err(FSTreeError::NonDirEntryPoint { path: root.clone() });
+    }
+    let skipped = Skipped::tolerating_unreadable();
+    let tree = generate_fstree_rec(root, &AbstPath::single("."), exclude_list, &skipped)?;
+    let (special, unreadable) = skipped.into_sorted_with_unreadable();
+    Ok((tree, special, unreadable))
+}
 
-    let read_dir_instance =
-        fs::list_dir_content(path).map_err(inerr(errctx("list content of dir")))?;
-    for entry in read_dir_instance {
-        let objec_type = entry.object_type().ok_or_else(|| {
-            generr(
-                errctx(format!("get type of child at path {entry}").as_str()),
-                "child should exist but doesn't have a type (as if it doesn't exist)",
-            )
-        })?;
-        let file_name = entry.file_name().ok_or_else(|| {
-            generr(
-                errctx(format!("get filename of child at path {entry}").as_str()),
-                "child path might be ending in `..`",
-            )
-        })?;
-        let rel_subpath = rel_path.add_last(&file_name);
-        if exclude_list.should_exclude(&rel_subpath, objec_type == ObjectType::Dir) {
-            continue;
-        }
+/// Same as `generate_fstree_with_skipped`, but taking the hashes of files and
+/// symlinks whose mtime and size did not change from `prior` (a tree previously
+/// generated from the same root) instead of reading their content again, as
+/// `fill_hashes` does. If `prior` is None, every object is hashed
+pub fn generate_fstree_with_prior(
+    root: &AbstPath,
+    exclude_list: &ExcludeList,
+    prior: Option<&FSTree>,
+) -> Result<(FSTree, Vec<AbstPath>), FSTreeError> {
+    let prior = match prior {
+        Some(prior) => prior,
+        None => return generate_fstree_with_skipped(root, exclude_list),
+    };
+    if root.object_type() != Some(ObjectType::Dir) {
+        return Err(FSTreeError::NonDirEntryPoint { path: root.clone() });
+    }
+    let skipped = Skipped::default();
+    let structural =
+        generate_fstree_structural_rec(root, &AbstPath::single("."), exclude_list, &skipped, None)?;
+    let tree = fill_hashes(root, &structural, prior)?;
+    Ok((tree, skipped.into_sorted()))
+}
 
-        let node = match objec_type {
-            ObjectType::Dir => {
-                let mtime = fs::get_mtime(&entry).map_err(inerr(errctx(
-                    format!("get mtime of dir at path {entry}").as_str(),
-                )))?;
-                let subtree = generate_fstree_rec(&entry, &rel_subpath, exclude_list)?;
-                let hash = hash_tree(&subtree);
-                FSNode::Dir(mtime, hash, subtree)
-            }
-            ObjectType::File => {
-                let mtime = fs::get_mtime(&entry).map_err(inerr(errctx(
-                    format!("get mtime of file at path {entry}").as_str(),
-                )))?;
-                let hash = hash_file(&entry)
-                    .map_err(inerr(errctx(format!("hash file at path {entry}").as_str())))?;
-                FSNode::File(mtime, hash)
+/// Same as `generate_fstree_with_prior`, but only looking at the objects at or
+/// under `subpath` (relative to `root`). Every other object is taken as it is
+/// from `known`, a tree previously generated from the same root, in which the
+/// parent of `subpath` must be a directory. The exclude list is still matched
+/// against paths relative to `root`
+pub fn generate_fstree_at(
+    root: &AbstPath,
+    subpath: &AbstPath,
+    exclude_list: &ExcludeList,
+    known: &FSTree,
+    prior: Option<&FSTree>,
+) -> Result<(FSTree, Vec<AbstPath>), FSTreeError> {
+    if subpath.is_empty() {
+        return generate_fstree_with_prior(root, exclude_list, prior);
+    }
+    let path = root.append(subpath);
+    if path.object_type() != Some(ObjectType::Dir) {
+        return Err(FSTreeError::NonDirEntryPoint { path });
+    }
+    let skipped = Skipped::default();
+    let rel_path = AbstPath::single(".").append(subpath);
+    let structural =
+        generate_fstree_structural_rec(&path, &rel_path, exclude_list, &skipped, None)?;
+    let prior = match prior.and_then(|prior| prior.get_at(subpath)) {
+        Some(FSNode::Dir(_, _, prior_subtree)) => prior_subtree.clone(),
+        _ => FSTree::empty(),
+    };
+    let subtree = fill_hashes(&path, &structural, &prior)?;
+    let mtime = fs::get_mtime(&path).map_err(inerr(format!(
+        "could not generate fstree from subpath at path {path}\nFailed to get mtime"
+    )))?;
+    let node = FSNode::Dir(mtime, hash_tree(&subtree), subtree);
+
+    let mut tree = known.clone();
+    if !replace_at(&mut tree, subpath, node) {
+        return Err(FSTreeError::UnknownSubpath {
+            path: subpath.clone(),
+        });
+    }
+    Ok((tree, skipped.into_sorted()))
+}
+
+/// Same as `generate_fstree_with_prior`, but only looking `max_depth` levels
+/// below `root` (1 to look only at the objects directly in it, which is also
+/// what 0 does). The directories at the last level are not listed: they are
+/// taken as they are from `known`, a tree previously generated from the same
+/// root, so that the changes under them don't show up in the delta from
+/// `known`, and what is under them doesn't look removed either. The ones that
+/// are not in `known` are recorded empty, their content being left for a sync
+/// without limit, unless they are excluded
+pub fn generate_fstree_to_depth(
+    root: &AbstPath,
+    exclude_list: &ExcludeList,
+    known: &FSTree,
+    prior: Option<&FSTree>,
+    max_depth: usize,
+) -> Result<(FSTree, Vec<AbstPath>), FSTreeError> {
+    if root.object_type() != Some(ObjectType::Dir) {
+        return Err(FSTreeError::NonDirEntryPoint { path: root.clone() });
+    }
+    let max_depth = max_depth.max(1);
+    let skipped = Skipped::default();
+    let structural = generate_fstree_structural_rec(
+        root,
+        &AbstPath::single("."),
+        exclude_list,
+        &skipped,
+        Some(max_depth),
+    )?;
+    let mut tree = fill_hashes_rec(root, &structural, prior)?;
+    graft_at_depth(
+        &mut tree,
+        known,
+        &AbstPath::single("."),
+        exclude_list,
+        max_depth,
+    );
+    Ok((tree, skipped.into_sorted()))
+}
+
+/// Replace the directories `depth` levels below the root of the tree with the
+/// ones of `known`, updating the hashes of their ancestors. The directories that
+/// `known` doesn't have are left as they are, unless excluded
+fn graft_at_depth(
+    FSTree(tree): &mut FSTree,
+    FSTree(known): &FSTree,
+    rel_path: &AbstPath,
+    exclude_list: &ExcludeList,
+    depth: usize,
+) {
+    let empty = FSTree::empty();
+    let mut dropped = Vec::new();
+    for (name, node) in tree.iter_mut() {
+        let known_node = known.get(name);
+        match node {
+            FSNode::Dir(..) if depth <= 1 => match known_node {
+                Some(known_node @ FSNode::Dir(..)) => *node = known_node.clone(),
+                _ if exclude_list.should_exclude(&rel_path.add_last(name), true) => {
+                    dropped.push(name.clone())
+                }
+                _ => {}
+            },
+            FSNode::Dir(_, hash, subtree) => {
+                let known_subtree = match known_node {
+                    Some(FSNode::Dir(_, _, known_subtree)) => known_subtree,
+                    _ => &empty,
+                };
+                let rel_subpath = rel_path.add_last(name);
+                graft_at_depth(
+                    subtree,
+                    known_subtree,
+                    &rel_subpath,
+                    exclude_list,
+                    depth - 1,
+                );
+                *hash = hash_tree(subtree);
             }
-            ObjectType::SymLink => {
-                let mtime = fs::get_mtime(&entry).map_err(inerr(errctx(
-                    format!("get mtime of symlink at path {entry}").as_str(),
-                )))?;
-                let hash = hash_symlink(&entry).map_err(inerr(errctx(
-                    format!("hash symlink at path {entry}").as_str(),
-                )))?;
-                FSNode::SymLink(mtime, hash)
+            _ => {}
+        }
+    }
+    for name in dropped {
+        tree.remove(&name);
+    }
+}
+
+/// Put `node` at `path` in the tree, updating the hashes of its ancestors.
+/// Returns false if the parent of `path` is not a directory of the tree
+fn replace_at(FSTree(tree): &mut FSTree, path: &AbstPath, node: FSNode) -> bool {
+    let name = match path.get(0) {
+        Some(name) => name.clone(),
+        None => return false,
+    };
+    let rest = path.strip_first();
+    if rest.is_empty() {
+        tree.insert(name, node);
+        return true;
+    }
+    match tree.get_mut(&name) {
+        Some(FSNode::Dir(_, hash, subtree)) => {
+            if !replace_at(subtree, &rest, node) {
+                return false;
             }
-        };
+            *hash = hash_tree(subtree);
+            true
+        }
+        _ => false,
+    }
+}
 
-        tree.insert(file_name, node);
+/// Same as `generate_fstree`, but hashes the content of the tree concurrently on
+/// a pool of `threads` threads (or as many as the available cores if `threads`
+/// is 0). The resulting tree is identical to the one generated serially
+pub fn generate_fstree_parallel(
+    root: &AbstPath,
+    exclude_list: &ExcludeList,
+    threads: usize,
+) -> Result<FSTree, FSTreeError> {
+    if root.object_type() != Some(ObjectType::Dir) {
+        return Err(FSTreeError::NonDirEntryPoint { path: root.clone() });
     }
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .map_err(inerr(format!(
+            "could not generate fstree from path {root}\nFailed to build thread pool"
+        )))?;
+    let skipped = Skipped::default();
+    pool.install(|| {
+        generate_fstree_parallel_rec(root, &AbstPath::single("."), exclude_list, &skipped)
+    })
+}
 
-    Ok(FSTree(tree))
+/// Options for the generation of a tree, for checks that are not always wanted
+#[derive(Debug, Clone, Default)]
+pub struct GenerationOptions {
+    /// Fail if a directory contains objects whose names differ only by case, as
+    /// the resulting tree could not be applied on a case-insensitive file system
+    pub check_case_collisions: bool,
+    /// Record the objects pointed by symlinks instead of the symlinks themselves.
+    /// Fail if following a symlink leads to a cycle
+    pub follow_symlinks: bool,
+    /// Fail if a symlink points to an object that does not exist
+    pub check_dangling_symlinks: bool,
+    /// Leave out hidden objects (whose name starts with a dot), without looking
+    /// into hidden directories. Applied on top of the exclude list, so negated
+    /// rules don't bring hidden objects back
+    pub skip_hidden: bool,
 }
 
-#[cfg(test)]
-mod tests {
+/// Same as `generate_fstree`, but generating the tree as specified by `options`
+pub fn generate_fstree_with_options(
+    root: &AbstPath,
+    exclude_list: &ExcludeList,
+    options: &GenerationOptions,
+) -> Result<FSTree, FSTreeError> {
+    if root.object_type() != Some(ObjectType::Dir) {
+        return Err(FSTreeError::NonDirEntryPoint { path: root.clone() });
+    }
+    let skipped = Skipped::default();
+    let tree = generate_fstree_with_options_rec(
+        root,
+        &AbstPath::single("."),
+        exclude_list,
+        options,
+        &[],
+        &skipped,
+    )?;
+    if options.check_case_collisions {
+        check_case_collisions(&tree, root)?;
+    }
+    Ok(tree)
+}
 
-    use super::{
-        generate_fstree, generr, hash_tree, inerr, ExcludeList, FSNode, FSTree, FSTreeError,
-    };
-    use abst_fs::{AbstPath, Endpoint, Mtime};
-    use std::collections::HashMap;
-    use std::path::PathBuf;
+/// Check that no directory of the tree (rooted at `path`) contains two objects
+/// whose names differ only by case
+fn check_case_collisions(FSTree(tree): &FSTree, path: &AbstPath) -> Result<(), FSTreeError> {
+    let mut names = tree.keys().collect::<Vec<&String>>();
+    names.sort();
 
-    impl FSNode {
-        pub fn file(mtime: (i64, u32), content: impl ToString) -> FSNode {
-            FSNode::File(
-                Mtime::from(mtime.0, mtime.1),
-                hasher::hash_bytes(content.to_string().as_bytes()),
-            )
-        }
-        pub fn symlink(mtime: (i64, u32), path: impl ToString) -> FSNode {
-            FSNode::SymLink(
-                Mtime::from(mtime.0, mtime.1),
-                hasher::hash_bytes(Endpoint::Unix(path.to_string()).as_bytes()),
-            )
-        }
-        pub fn dir(mtime: (i64, u32), subtree_gen: impl Fn(&mut FSTree)) -> FSNode {
-            let mut subtree = FSTree::empty();
-            subtree_gen(&mut subtree);
-            FSNode::Dir(Mtime::from(mtime.0, mtime.1), hash_tree(&subtree), subtree)
-        }
-        pub fn empty_dir(mtime: (i64, u32)) -> FSNode {
-            FSNode::Dir(
-                Mtime::from(mtime.0, mtime.1),
-                hash_tree(&FSTree::empty()),
-                FSTree::empty(),
-            )
+    let mut lowercase_names: HashMap<String, &String> = HashMap::new();
+    for name in names {
+        if let Some(first) = lowercase_names.insert(name.to_lowercase(), name) {
+            return Err(FSTreeError::CaseCollision {
+                parent: path.clone(),
+                first: first.clone(),
+                second: name.clone(),
+            });
         }
     }
-    impl FSTree {
-        pub fn gen_from(gen: impl Fn(&mut FSTree)) -> FSTree {
-            let mut tree = FSTree::empty();
-            gen(&mut tree);
-            tree
+    for (name, node) in tree {
+        if let FSNode::Dir(_, _, subtree) = node {
+            check_case_collisions(subtree, &path.add_last(name))?;
         }
+    }
+    Ok(())
+}
 
-        pub fn add_file(&mut self, name: impl ToString, mtime: (i64, u32), content: impl ToString) {
-            let FSTree(tree) = self;
-            tree.insert(name.to_string(), FSNode::file(mtime, content));
+/// Maximum number of symlinks followed to resolve a single symlink before
+/// considering it a cycle (same as the limit of Linux)
+const MAX_SYMLINK_HOPS: usize = 40;
+
+enum SymlinkTarget {
+    Object(std::path::PathBuf),
+    Dangling,
+    Cycle,
+}
+/// Follow the chain of symlinks starting at `path` until reaching an actual object
+fn resolve_symlink(path: &AbstPath) -> Result<SymlinkTarget, FSTreeError> {
+    let errctx = error_context(format!("could not resolve symlink at path {path}"));
+    let mut current = path.to_path_buf();
+    let mut visited = std::collections::HashSet::new();
+    loop {
+        // Collecting the components drops the `.` components, so that links to
+        //	`./self` are recognized
+        current = current.components().collect();
+        if visited.len() > MAX_SYMLINK_HOPS || !visited.insert(current.clone()) {
+            return Ok(SymlinkTarget::Cycle);
         }
-        pub fn add_symlink(&mut self, name: impl ToString, mtime: (i64, u32), path: impl ToString) {
-            let FSTree(tree) = self;
-            tree.insert(name.to_string(), FSNode::symlink(mtime, path));
+        match std::fs::symlink_metadata(&current) {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(SymlinkTarget::Dangling)
+            }
+            Err(err) => return Err(inerr(errctx("get metadata of target"))(err)),
+            Ok(metadata) if metadata.is_symlink() => {
+                let target = std::fs::read_link(&current)
+                    .map_err(inerr(errctx("read endpoint of symlink")))?;
+                current = match current.parent() {
+                    Some(parent) => parent.join(target),
+                    None => target,
+                };
+            }
+            Ok(_) => {
+                let canonical = std::fs::canonicalize(&current)
+                    .map_err(inerr(errctx("canonicalize target")))?;
+                return Ok(SymlinkTarget::Object(canonical));
+            }
         }
-        pub fn add_dir(
-            &mut self,
-            name: impl ToString,
-            mtime: (i64, u32),
-            subtree_gen: impl Fn(&mut FSTree),
-        ) {
-            let FSTree(tree) = self;
+    }
+}
 
-            tree.insert(name.to_string(), FSNode::dir(mtime, subtree_gen));
+/// Replace the symlinks of the tree (generated from `root`) at the given paths
+/// with the objects they point to, as files or subtrees. The content of the
+/// targets is hashed again, except for the files of subtrees whose mtime and
+/// size did not change since `prior`. Paths that are not symlinks of the tree
+/// are ignored. Fails if a symlink is dangling or if it points to one of its
+/// own ancestors. Returns the paths of the special objects skipped, sorted
+pub fn dereference_symlinks(
+    root: &AbstPath,
+    tree: &mut FSTree,
+    paths: &[AbstPath],
+    exclude_list: &ExcludeList,
+    prior: Option<&FSTree>,
+) -> Result<Vec<AbstPath>, FSTreeError> {
+    let skipped = Skipped::default();
+    // Symlinks under the target of another symlink are visible only once that
+    //	symlink has been dereferenced
+    let mut paths = paths.to_vec();
+    paths.sort_by_key(|path| path.len());
+    for path in paths {
+        if !matches!(tree.get_at(&path), Some(FSNode::SymLink(..))) {
+            continue;
         }
-        pub fn add_empty_dir(&mut self, name: impl ToString, mtime: (i64, u32)) {
-            let FSTree(tree) = self;
-            tree.insert(name.to_string(), FSNode::empty_dir(mtime));
+        let entry = root.append(&path);
+        let target = match resolve_symlink(&entry)? {
+            SymlinkTarget::Object(target) => target,
+            SymlinkTarget::Dangling => return Err(FSTreeError::DanglingSymlink { path: entry }),
+            SymlinkTarget::Cycle => return Err(FSTreeError::SymlinkCycle { path: entry }),
+        };
+        let parent = entry.parent().unwrap_or_else(AbstPath::empty);
+        let parent = std::fs::canonicalize(parent.to_path_buf()).map_err(inerr(format!(
+            "could not dereference symlink at path {entry}\nFailed to canonicalize its parent"
+        )))?;
+        if parent.starts_with(&target) {
+            return Err(FSTreeError::SymlinkCycle { path: entry });
         }
 
-        fn test_default() -> FSTree {
-            FSTree::gen_from(|t| {
-                t.add_file("file", (498705660, 314159265), "this is some test content");
-                t.add_symlink("symlink", (498705720, 271828182), "some/path/to/somewhere");
-                t.add_dir("dir", (498705900, 628318530), |t| {
-                    t.add_file("file1", (498705780, 161803398), "none of your business");
-                    t.add_symlink("symlink1", (498705720, 271828182), "some/other/path");
-                    t.add_empty_dir("dir1", (498705840, 141421356));
-                });
-            })
+        let prior_subtree = match prior.and_then(|prior| prior.get_at(&path)) {
+            Some(FSNode::Dir(_, _, prior_subtree)) => prior_subtree.clone(),
+            _ => FSTree::empty(),
+        };
+        let subtree_gen = |path: &AbstPath, rel_path: &AbstPath, exclude_list: &ExcludeList| {
+            let structural =
+                generate_fstree_structural_rec(path, rel_path, exclude_list, &skipped, None)?;
+            fill_hashes(path, &structural, &prior_subtree)
+        };
+        let rel_path = AbstPath::single(".").append(&path.strip_last());
+        let generated = generate_followed_fsnode(
+            &entry,
+            &target,
+            &rel_path,
+            exclude_list,
+            &subtree_gen,
+            &skipped,
+        )?;
+        if let Some((_, node)) = generated {
+            replace_at(tree, &path, node);
         }
     }
+    Ok(skipped.into_sorted())
+}
 
-    #[test]
-    fn test() {
-        errors();
-
-        various();
-
-        generate();
+/// Generate the tree as `generate_fstree_rec`, following symlinks and checking
+/// them as specified by `options`. `ancestors` are the canonical paths of the
+/// directories containing `path`, needed to detect cycles
+fn generate_fstree_with_options_rec(
+    path: &AbstPath,
+    rel_path: &AbstPath,
+    exclude_list: &ExcludeList,
+    options: &GenerationOptions,
+    ancestors: &[std::path::PathBuf],
+    skipped: &Skipped,
+) -> Result<FSTree, FSTreeError> {
+    let errctx = error_context(format!(
+        "could not generate fstree from subtree at path {path}"
+    ));
+    let mut ancestors = ancestors.to_vec();
+    if options.follow_symlinks {
+        ancestors.push(
+            std::fs::canonicalize(path.to_path_buf())
+                .map_err(inerr(errctx("canonicalize path of dir")))?,
+        );
     }
+    let subtree_gen = |path: &AbstPath, rel_path: &AbstPath, exclude_list: &ExcludeList| {
+        generate_fstree_with_options_rec(path, rel_path, exclude_list, options, &ancestors, skipped)
+    };
 
-    fn errors() {
-        let generic_error = FSTreeError::Generic {
-            src: "some source".to_string(),
-            err: "some error".to_string(),
+    let mut tree: HashMap<String, FSNode> = HashMap::new();
+    let read_dir_instance = fs::list_dir_content(path)
+        .map_err(denied_or(path, inerr(errctx("list content of dir"))))?;
+    for entry in read_dir_instance {
+        if options.skip_hidden && entry.is_hidden() {
+            continue;
+        }
+        let is_symlink = entry.object_type() == Some(ObjectType::SymLink);
+        let check_symlink = options.follow_symlinks || options.check_dangling_symlinks;
+        let target = match is_symlink && check_symlink {
+            true => Some(resolve_symlink(&entry)?),
+            false => None,
         };
-        assert_eq!(generr("some source", "some error"), generic_error);
-        assert_eq!(
-            FSTreeError::Inner {
-                src: "some source".to_string(),
-                err: generic_error.to_string()
-            },
-            inerr("some source")(generic_error),
-        );
+        let generated = match target {
+            Some(SymlinkTarget::Cycle) if options.follow_symlinks => {
+                return Err(FSTreeError::SymlinkCycle { path: entry });
+            }
+            Some(SymlinkTarget::Dangling | SymlinkTarget::Cycle)
+                if options.check_dangling_symlinks =>
+            {
+                return Err(FSTreeError::DanglingSymlink { path: entry });
+            }
+            Some(SymlinkTarget::Object(target)) if options.follow_symlinks => {
+                if ancestors.contains(&target) {
+                    return Err(FSTreeError::SymlinkCycle { path: entry });
+                }
+                generate_followed_fsnode(
+                    &entry,
+                    &target,
+                    rel_path,
+                    exclude_list,
+                    &subtree_gen,
+                    skipped,
+                )?
+            }
+            _ => generate_fsnode(path, &entry, rel_path, exclude_list, &subtree_gen, skipped)?,
+        };
+        if let Some((file_name, node)) = generated {
+            tree.insert(file_name, node);
+        }
     }
 
-    fn various() {
-        assert_eq!(FSTree::empty(), FSTree(HashMap::new()));
-        assert_eq!(FSTree::test_default(), FSTree::test_default());
-        assert_ne!(FSTree::empty(), FSTree::test_default());
-        assert_eq!(
-            FSNode::file((498705660, 314159265), "this is some test content"),
-            FSNode::file((498705660, 314159265), "this is some test content")
-        );
-        assert_ne!(
-            FSNode::file((498705660, 314159265), "this is some test content"),
-            FSNode::file((498705660, 314159265), "this is a different test content")
-        );
-        assert_ne!(
-            FSNode::file((498705660, 314159265), "this is some test content"),
-            FSNode::file((498705660, 0), "this is some test content")
-        );
+    Ok(FSTree(tree))
+}
+
+/// Generate the node relative to the symlink at `entry` as if it was the object at
+/// `target` (which is not a symlink)
+fn generate_followed_fsnode<F>(
+    entry: &AbstPath,
+    target: &std::path::Path,
+    rel_path: &AbstPath,
+    exclude_list: &ExcludeList,
+    subtree_gen: &F,
+    skipped: &Skipped,
+) -> Result<Option<(String, FSNode)>, FSTreeError>
+where
+    F: Fn(&AbstPath, &AbstPath, &ExcludeList) -> Result<FSTree, FSTreeError>,
+{
+    let errctx = error_context(format!("could not follow symlink at path {entry}"));
+    let target = AbstPath::from(target);
+
+    let file_name = entry.file_name().ok_or_else(|| {
+        generr(
+            errctx(format!("get filename of symlink at path {entry}").as_str()),
+            "symlink path might be ending in `..`",
+        )
+    })?;
+    let rel_subpath = rel_path.add_last(&file_name);
+    let target_type = target.object_type();
+    let is_dir = target_type == Some(ObjectType::Dir);
+    let excluded = exclude_list.should_exclude(&rel_subpath, is_dir);
+    if excluded && !(is_dir && exclude_list.has_negations()) {
+        return Ok(None);
+    }
+    if target_type == Some(ObjectType::Special) {
+        skipped.push(entry);
+        return Ok(None);
+    }
+
+    let mtime = fs::get_mtime(&target).map_err(inerr(errctx(
+        format!("get mtime of target at path {target}").as_str(),
+    )))?;
+    let node = match is_dir {
+        true => {
+            let subtree = subtree_gen(&target, &rel_subpath, exclude_list)?;
+            if excluded && subtree.0.is_empty() {
+                return Ok(None);
+            }
+            let hash = hash_tree(&subtree);
+            FSNode::Dir(mtime, hash, subtree)
+        }
+        false => {
+            let hash = hash_file(&target).map_err(inerr(errctx(
+                format!("hash target at path {target}").as_str(),
+            )))?;
+            let mode = fs::get_mode(&target).map_err(inerr(errctx(
+                format!("get mode of target at path {target}").as_str(),
+            )))?;
+            let size = fs::get_file_size(&target).map_err(inerr(errctx(
+                format!("get size of target at path {target}").as_str(),
+            )))?;
+            FSNode::File(mtime, hash, mode, Some(size))
+        }
+    };
+
+    Ok(Some((file_name, node)))
+}
+
+fn generate_fstree_rec(
+    path: &AbstPath,
+    rel_path: &AbstPath,
+    exclude_list: &ExcludeList,
+    skipped: &Skipped,
+) -> Result<FSTree, FSTreeError> {
+    let errctx = error_context(format!(
+        "could not generate fstree from subtree at path {path}"
+    ));
+    let subtree_gen = |path: &AbstPath, rel_path: &AbstPath, exclude_list: &ExcludeList| {
+        generate_fstree_rec(path, rel_path, exclude_list, skipped)
+    };
+    let mut tree: HashMap<String, FSNode> = HashMap::new();
+
+    let read_dir_instance = fs::list_dir_content(path)
+        .map_err(denied_or(path, inerr(errctx("list content of dir"))))?;
+    for entry in read_dir_instance {
+        if let Some((file_name, node)) =
+            generate_fsnode(path, &entry, rel_path, exclude_list, &subtree_gen, skipped)?
+        {
+            tree.insert(file_name, node);
+        }
+    }
+
+    Ok(FSTree(tree))
+}
+
+fn generate_fstree_parallel_rec(
+    path: &AbstPath,
+    rel_path: &AbstPath,
+    exclude_list: &ExcludeList,
+    skipped: &Skipped,
+) -> Result<FSTree, FSTreeError> {
+    let errctx = error_context(format!(
+        "could not generate fstree from subtree at path {path}"
+    ));
+    let subtree_gen = |path: &AbstPath, rel_path: &AbstPath, exclude_list: &ExcludeList| {
+        generate_fstree_parallel_rec(path, rel_path, exclude_list, skipped)
+    };
+
+    let read_dir_instance = fs::list_dir_content(path)
+        .map_err(denied_or(path, inerr(errctx("list content of dir"))))?;
+    let nodes = read_dir_instance
+        .par_iter()
+        .map(|entry| generate_fsnode(path, entry, rel_path, exclude_list, &subtree_gen, skipped))
+        .collect::<Result<Vec<Option<(String, FSNode)>>, FSTreeError>>()?;
+
+    Ok(FSTree(nodes.into_iter().flatten().collect()))
+}
+
+/// Generate the node relative to a single entry of the directory at `path`, using
+/// `subtree_gen` to generate the subtree if the entry is a directory.
+/// Returns `None` if the entry should be excluded or if it is a special object,
+/// which gets recorded in `skipped`
+fn generate_fsnode<F>(
+    path: &AbstPath,
+    entry: &AbstPath,
+    rel_path: &AbstPath,
+    exclude_list: &ExcludeList,
+    subtree_gen: &F,
+    skipped: &Skipped,
+) -> Result<Option<(String, FSNode)>, FSTreeError>
+where
+    F: Fn(&AbstPath, &AbstPath, &ExcludeList) -> Result<FSTree, FSTreeError>,
+{
+    let errctx = error_context(format!(
+        "could not generate fstree from subtree at path {path}"
+    ));
+
+    let objec_type = entry.object_type().ok_or_else(|| {
+        generr(
+            errctx(format!("get type of child at path {entry}").as_str()),
+            "child should exist but doesn't have a type (as if it doesn't exist)",
+        )
+    })?;
+    let file_name = entry.file_name().ok_or_else(|| {
+        generr(
+            errctx(format!("get filename of child at path {entry}").as_str()),
+            "child path might be ending in `..`",
+        )
+    })?;
+    let rel_subpath = rel_path.add_last(&file_name);
+    let is_dir = objec_type == ObjectType::Dir;
+    let excluded = exclude_list.should_exclude(&rel_subpath, is_dir);
+    // An excluded directory can't be pruned if some of its content might be
+    //	re-included by a negated rule
+    if excluded && !(is_dir && exclude_list.has_negations()) {
+        return Ok(None);
+    }
+
+    let node = match objec_type {
+        ObjectType::Dir => {
+            let mtime = fs::get_mtime(entry).map_err(inerr(errctx(
+                format!("get mtime of dir at path {entry}").as_str(),
+            )))?;
+            let subtree =
+                match skipped.tolerate(entry, subtree_gen(entry, &rel_subpath, exclude_list))? {
+                    Some(subtree) => subtree,
+                    None => return Ok(None),
+                };
+            // An excluded directory is kept only as a container for the objects
+            //	that have been re-included
+            if excluded && subtree.0.is_empty() {
+                return Ok(None);
+            }
+            let hash = hash_tree(&subtree);
+            FSNode::Dir(mtime, hash, subtree)
+        }
+        ObjectType::File => {
+            let mtime = fs::get_mtime(entry).map_err(inerr(errctx(
+                format!("get mtime of file at path {entry}").as_str(),
+            )))?;
+            let hash = match skipped
+                .tolerate(entry, hash_file(entry))
+                .map_err(inerr(errctx(format!("hash file at path {entry}").as_str())))?
+            {
+                Some(hash) => hash,
+                None => return Ok(None),
+            };
+            let mode = fs::get_mode(entry).map_err(inerr(errctx(
+                format!("get mode of file at path {entry}").as_str(),
+            )))?;
+            let size = fs::get_file_size(entry).map_err(inerr(errctx(
+                format!("get size of file at path {entry}").as_str(),
+            )))?;
+            FSNode::File(mtime, hash, mode, Some(size))
+        }
+        ObjectType::SymLink => {
+            let mtime = fs::get_mtime(entry).map_err(inerr(errctx(
+                format!("get mtime of symlink at path {entry}").as_str(),
+            )))?;
+            let hash = hash_symlink(entry).map_err(inerr(errctx(
+                format!("hash symlink at path {entry}").as_str(),
+            )))?;
+            FSNode::SymLink(mtime, hash)
+        }
+        ObjectType::Special => {
+            skipped.push(entry);
+            return Ok(None);
+        }
+    };
+
+    Ok(Some((file_name, node)))
+}
+
+/// Node of a structural tree, which records the objects in a tree without their
+/// content hashes (see `generate_fstree_structural`). Hashes are `None` until
+/// filled, and directories have none as it is derived from their content
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum StructNode {
+    File(Mtime, Option<Hash>, Mode, Option<u64>),
+    SymLink(Mtime, Option<Hash>),
+    Dir(Mtime, StructTree),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct StructTree(pub HashMap<String, StructNode>);
+
+/// Generate a tree representation of the content of a path specified as
+/// `generate_fstree` does, but without hashing the content of files and
+/// symlinks, so that it only costs a walk of the file system. The hashes can
+/// later be computed with `fill_hashes`
+pub fn generate_fstree_structural(
+    root: &AbstPath,
+    exclude_list: &ExcludeList,
+) -> Result<StructTree, FSTreeError> {
+    if root.object_type() != Some(ObjectType::Dir) {
+        return Err(FSTreeError::NonDirEntryPoint { path: root.clone() });
+    }
+    let skipped = Skipped::default();
+    generate_fstree_structural_rec(root, &AbstPath::single("."), exclude_list, &skipped, None)
+}
+
+/// If `depth` is given, only that many levels are listed: the directories of the
+/// last level are recorded empty, even if excluded, and left to the caller
+fn generate_fstree_structural_rec(
+    path: &AbstPath,
+    rel_path: &AbstPath,
+    exclude_list: &ExcludeList,
+    skipped: &Skipped,
+    depth: Option<usize>,
+) -> Result<StructTree, FSTreeError> {
+    let errctx = error_context(format!(
+        "could not generate structural fstree from subtree at path {path}"
+    ));
+    let mut tree: HashMap<String, StructNode> = HashMap::new();
+
+    let read_dir_instance = fs::list_dir_content(path)
+        .map_err(denied_or(path, inerr(errctx("list content of dir"))))?;
+    for entry in read_dir_instance {
+        let objec_type = entry.object_type().ok_or_else(|| {
+            generr(
+                errctx(format!("get type of child at path {entry}").as_str()),
+                "child should exist but doesn't have a type (as if it doesn't exist)",
+            )
+        })?;
+        let file_name = entry.file_name().ok_or_else(|| {
+            generr(
+                errctx(format!("get filename of child at path {entry}").as_str()),
+                "child path might be ending in `..`",
+            )
+        })?;
+        let rel_subpath = rel_path.add_last(&file_name);
+        let is_dir = objec_type == ObjectType::Dir;
+        let excluded = exclude_list.should_exclude(&rel_subpath, is_dir);
+        if excluded && !(is_dir && exclude_list.has_negations()) {
+            continue;
+        }
+
+        let get_mtime = || {
+            fs::get_mtime(&entry).map_err(inerr(errctx(
+                format!("get mtime of child at path {entry}").as_str(),
+            )))
+        };
+        let node = match objec_type {
+            ObjectType::Dir if depth.is_some_and(|depth| depth <= 1) => {
+                StructNode::Dir(get_mtime()?, StructTree(HashMap::new()))
+            }
+            ObjectType::Dir => {
+                let mtime = get_mtime()?;
+                let subtree = generate_fstree_structural_rec(
+                    &entry,
+                    &rel_subpath,
+                    exclude_list,
+                    skipped,
+                    depth.map(|depth| depth - 1),
+                )?;
+                if excluded && subtree.0.is_empty() {
+                    continue;
+                }
+                StructNode::Dir(mtime, subtree)
+            }
+            ObjectType::File => {
+                let mtime = get_mtime()?;
+                let mode = fs::get_mode(&entry).map_err(inerr(errctx(
+                    format!("get mode of file at path {entry}").as_str(),
+                )))?;
+                let size = fs::get_file_size(&entry).map_err(inerr(errctx(
+                    format!("get size of file at path {entry}").as_str(),
+                )))?;
+                StructNode::File(mtime, None, mode, Some(size))
+            }
+            ObjectType::SymLink => StructNode::SymLink(get_mtime()?, None),
+            ObjectType::Special => {
+                skipped.push(&entry);
+                continue;
+            }
+        };
+        tree.insert(file_name, node);
+    }
+
+    Ok(StructTree(tree))
+}
+
+/// Complete the structural tree generated from `root` into a full tree, hashing
+/// only the objects that changed since `prior` (a tree previously generated
+/// from the same root). The hash of an object is taken from `prior` if it has an
+/// object of the same type at the same path, with the same mtime (and size, if
+/// both are known), otherwise the object is hashed again
+pub fn fill_hashes(
+    root: &AbstPath,
+    tree: &StructTree,
+    prior: &FSTree,
+) -> Result<FSTree, FSTreeError> {
+    fill_hashes_rec(root, tree, Some(prior))
+}
+
+fn fill_hashes_rec(
+    path: &AbstPath,
+    StructTree(tree): &StructTree,
+    prior: Option<&FSTree>,
+) -> Result<FSTree, FSTreeError> {
+    let errctx = error_context(format!("could not fill hashes of subtree at path {path}"));
+    let mut filled: HashMap<String, FSNode> = HashMap::new();
+    for (name, node) in tree {
+        let entry = path.add_last(name);
+        let prior_node = prior.and_then(|FSTree(prior)| prior.get(name));
+        let node = match node {
+            StructNode::File(mtime, hash, mode, size) => {
+                let hash = match (hash, prior_node) {
+                    (Some(hash), _) => hash.clone(),
+                    (None, Some(FSNode::File(prior_mtime, prior_hash, _, prior_size)))
+                        if prior_mtime == mtime
+                            && (size.is_none() || prior_size.is_none() || size == prior_size) =>
+                    {
+                        prior_hash.clone()
+                    }
+                    (None, _) => hash_file(&entry)
+                        .map_err(inerr(errctx(format!("hash file at path {entry}").as_str())))?,
+                };
+                FSNode::File(mtime.clone(), hash, *mode, *size)
+            }
+            StructNode::SymLink(mtime, hash) => {
+                let hash = match (hash, prior_node) {
+                    (Some(hash), _) => hash.clone(),
+                    (None, Some(FSNode::SymLink(prior_mtime, prior_hash)))
+                        if prior_mtime == mtime =>
+                    {
+                        prior_hash.clone()
+                    }
+                    (None, _) => hash_symlink(&entry).map_err(inerr(errctx(
+                        format!("hash symlink at path {entry}").as_str(),
+                    )))?,
+                };
+                FSNode::SymLink(mtime.clone(), hash)
+            }
+            StructNode::Dir(mtime, subtree) => {
+                let prior_subtree = match prior_node {
+                    Some(FSNode::Dir(_, _, prior_subtree)) => Some(prior_subtree),
+                    _ => None,
+                };
+                let subtree = fill_hashes_rec(&entry, subtree, prior_subtree)?;
+                let hash = hash_tree(&subtree);
+                FSNode::Dir(mtime.clone(), hash, subtree)
+            }
+        };
+        filled.insert(name.clone(), node);
+    }
+
+    Ok(FSTree(filled))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{
+        dereference_symlinks, fill_hashes, generate_fstree, generate_fstree_at,
+        generate_fstree_parallel, generate_fstree_skipping_unreadable, generate_fstree_structural,
+        generate_fstree_to_depth, generate_fstree_with_options, generate_fstree_with_prior,
+        generate_fstree_with_skipped, generr, hash_file, hash_tree, inerr, set_small_file_size,
+        ExcludeList, FSNode, FSTree, FSTreeError, GenerationOptions, StructNode,
+        DEFAULT_SMALL_FILE_SIZE, HASHED_FILES,
+    };
+    use crate::get_delta;
+    use abst_fs::{AbstPath, Mode, Mtime};
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    impl FSTree {
+        fn test_default() -> FSTree {
+            FSTree::gen_from(|t| {
+                t.add_file("file", (498705660, 314159265), "this is some test content");
+                t.add_symlink("symlink", (498705720, 271828182), "some/path/to/somewhere");
+                t.add_dir("dir", (498705900, 628318530), |t| {
+                    t.add_file("file1", (498705780, 161803398), "none of your business");
+                    t.add_symlink("symlink1", (498705720, 271828182), "some/other/path");
+                    t.add_empty_dir("dir1", (498705840, 141421356));
+                });
+            })
+        }
+    }
+
+    #[test]
+    fn test() {
+        errors();
+
+        various();
+
+        get_at();
+
+        structurally_eq();
+
+        root_hash();
+
+        sizes();
+
+        generate();
+
+        generate_parallel();
+
+        generate_small_files();
+
+        generate_negations();
+
+        generate_mode();
+
+        generate_case_collisions();
+
+        generate_hidden();
+
+        generate_symlinks();
+
+        generate_special();
+
+        generate_structural();
+
+        generate_with_prior();
+
+        generate_at();
+
+        generate_to_depth();
+
+        dereference();
+
+        generate_unreadable();
+    }
+
+    fn errors() {
+        let generic_error = FSTreeError::Generic {
+            src: "some source".to_string(),
+            err: "some error".to_string(),
+        };
+        assert_eq!(generr("some source", "some error"), generic_error);
+        assert_eq!(
+            FSTreeError::Inner {
+                src: "some source".to_string(),
+                err: generic_error.to_string()
+            },
+            inerr("some source")(generic_error),
+        );
+    }
+
+    fn various() {
+        assert_eq!(FSTree::empty(), FSTree(HashMap::new()));
+        assert_eq!(FSTree::test_default(), FSTree::test_default());
+        assert_ne!(FSTree::empty(), FSTree::test_default());
+        assert_eq!(
+            FSNode::file((498705660, 314159265), "this is some test content"),
+            FSNode::file((498705660, 314159265), "this is some test content")
+        );
+        assert_ne!(
+            FSNode::file((498705660, 314159265), "this is some test content"),
+            FSNode::file((498705660, 314159265), "this is a different test content")
+        );
+        assert_ne!(
+            FSNode::file((498705660, 314159265), "this is some test content"),
+            FSNode::file((498705660, 0), "this is some test content")
+        );
 
         // Only the hash matters
         assert_eq!(
@@ -318,65 +1417,1149 @@ mod tests {
         );
     }
 
-    fn generate() {
-        let path = PathBuf::from("/tmp/bbup-test-fs_vcs-tree-generate");
+    fn get_at() {
+        let tree = FSTree::test_default();
+        let path = |s: &str| AbstPath::from(s);
+
+        assert_eq!(
+            tree.get_at(&path("dir/file1")),
+            Some(&FSNode::file(
+                (498705780, 161803398),
+                "none of your business"
+            ))
+        );
+        assert_eq!(
+            tree.get_at(&path("dir/dir1")),
+            Some(&FSNode::empty_dir((498705840, 141421356)))
+        );
+        assert_eq!(tree.get_at(&path("dir")), tree.0.get("dir"));
+        assert_eq!(
+            tree.get_at(&path("file")),
+            Some(&FSNode::file(
+                (498705660, 314159265),
+                "this is some test content"
+            ))
+        );
+
+        // Missing paths
+        assert_eq!(tree.get_at(&AbstPath::empty()), None);
+        assert_eq!(tree.get_at(&path("missing")), None);
+        assert_eq!(tree.get_at(&path("dir/missing")), None);
+        assert_eq!(tree.get_at(&path("dir/dir1/missing")), None);
+
+        // Traversing through objects that are not directories
+        assert_eq!(tree.get_at(&path("file/file1")), None);
+        assert_eq!(tree.get_at(&path("dir/symlink1/file")), None);
+    }
+
+    fn structurally_eq() {
+        let tree = FSTree::test_default();
+        let path = |s: &str| AbstPath::from(s);
+        let deep = |content: &str| {
+            FSTree::gen_from(|t| {
+                t.add_file("file", (498705660, 314159265), "this is some test content");
+                t.add_dir("a", (498705900, 0), |t| {
+                    t.add_file("x", (498705660, 0), "x");
+                    t.add_dir("b", (498705900, 0), |t| {
+                        t.add_dir("c", (498705900, 0), |t| {
+                            t.add_file("file", (498705660, 0), content);
+                            t.add_file("other", (498705660, 0), "other");
+                        });
+                    });
+                    t.add_file("z", (498705660, 0), "z");
+                });
+            })
+        };
+
+        assert_eq!(tree.structurally_eq(&tree), Ok(()));
+        assert_eq!(FSTree::empty().structurally_eq(&FSTree::empty()), Ok(()));
+        assert_eq!(deep("content").structurally_eq(&deep("content")), Ok(()));
+
+        // A single file differing deep in the tree
+        assert_eq!(
+            deep("content").structurally_eq(&deep("different content")),
+            Err(path("a/b/c/file"))
+        );
+
+        // Missing and extra children
+        let mut missing = tree.clone();
+        if let Some(FSNode::Dir(_, _, FSTree(subtree))) = missing.0.get_mut("dir") {
+            subtree.remove("symlink1");
+        }
+        assert_eq!(tree.structurally_eq(&missing), Err(path("dir/symlink1")));
+        assert_eq!(missing.structurally_eq(&tree), Err(path("dir/symlink1")));
+        assert_eq!(tree.structurally_eq(&FSTree::empty()), Err(path("dir")));
+
+        // Type mismatch
+        let mut retyped = tree.clone();
+        retyped.add_empty_dir("file", (498705660, 314159265));
+        assert_eq!(tree.structurally_eq(&retyped), Err(path("file")));
+
+        // Mtime and mode mismatches
+        let mut touched = tree.clone();
+        touched.add_empty_dir("dir", (0, 0));
+        assert_eq!(tree.structurally_eq(&touched), Err(path("dir/dir1")));
+        let mut touched = tree.clone();
+        if let Some(FSNode::Dir(mtime, _, _)) = touched.0.get_mut("dir") {
+            *mtime = Mtime::from(0, 0);
+        }
+        assert_eq!(tree.structurally_eq(&touched), Err(path("dir")));
+        let mut chmodded = tree.clone();
+        chmodded.0.insert(
+            "file".to_string(),
+            FSNode::file_with_mode(
+                (498705660, 314159265),
+                "this is some test content",
+                0o100600,
+            ),
+        );
+        assert_eq!(tree.structurally_eq(&chmodded), Err(path("file")));
+    }
+
+    fn root_hash() {
+        let deep = |content: &str| {
+            FSTree::gen_from(|t| {
+                t.add_file("file", (498705660, 314159265), "this is some test content");
+                t.add_symlink("symlink", (498705720, 0), "file");
+                t.add_dir("a", (498705900, 0), |t| {
+                    t.add_file("x", (498705660, 0), "x");
+                    t.add_dir("b", (498705900, 0), |t| {
+                        t.add_file("file", (498705660, 0), content);
+                    });
+                });
+            })
+        };
+        let tree = deep("content");
+        let changed = |edit: &dyn Fn(&mut FSTree)| {
+            let mut other = tree.clone();
+            edit(&mut other);
+            assert_ne!(tree.root_hash(), other.root_hash());
+        };
+
+        // Identical trees, regardless of the order in which they were built
+        assert_eq!(tree.root_hash(), deep("content").root_hash());
+        assert_eq!(FSTree::empty().root_hash(), FSTree::empty().root_hash());
+        let reordered = FSTree::gen_from(|t| {
+            t.add_dir("a", (498705900, 0), |t| {
+                t.add_dir("b", (498705900, 0), |t| {
+                    t.add_file("file", (498705660, 0), "content");
+                });
+                t.add_file("x", (498705660, 0), "x");
+            });
+            t.add_symlink("symlink", (498705720, 0), "file");
+            t.add_file("file", (498705660, 314159265), "this is some test content");
+        });
+        assert_eq!(tree.root_hash(), reordered.root_hash());
+
+        // Same aggregation as the hash of a directory with the same content
+        let wrapped = FSTree::gen_from(|t| {
+            t.add_dir("root", (0, 0), |t| *t = deep("content"));
+        });
+        match wrapped.get_at(&AbstPath::from("root")) {
+            Some(FSNode::Dir(_, hash, _)) => assert_eq!(*hash, tree.root_hash()),
+            _ => panic!("expected a directory"),
+        }
+
+        // Any single change alters the root hash
+        assert_ne!(tree.root_hash(), deep("different content").root_hash());
+        assert_ne!(tree.root_hash(), FSTree::empty().root_hash());
+        changed(&|t| t.add_file("file", (498705660, 314159265), "other content"));
+        changed(&|t| t.add_file("file", (498705660, 0), "this is some test content"));
+        changed(&|t| {
+            let node = FSNode::file_with_mode(
+                (498705660, 314159265),
+                "this is some test content",
+                0o100600,
+            );
+            t.0.insert("file".to_string(), node);
+        });
+        changed(&|t| {
+            let node = t.0.remove("file").unwrap();
+            t.0.insert("renamed".to_string(), node);
+        });
+        changed(&|t| t.add_symlink("symlink", (498705720, 0), "other"));
+        changed(&|t| t.add_symlink("symlink", (0, 0), "file"));
+        changed(&|t| {
+            if let Some(FSNode::Dir(mtime, _, _)) = t.0.get_mut("a") {
+                *mtime = Mtime::from(0, 0);
+            }
+        });
+        changed(&|t| t.add_empty_dir("new", (0, 0)));
+        changed(&|t| {
+            t.0.remove("symlink");
+        });
+    }
+
+    fn sizes() {
+        let tree = FSTree::test_default();
+        assert!(matches!(
+            tree.get_at(&AbstPath::from("file")),
+            Some(FSNode::File(_, _, _, Some(25)))
+        ));
+        assert!(matches!(
+            tree.get_at(&AbstPath::from("dir/file1")),
+            Some(FSNode::File(_, _, _, Some(21)))
+        ));
+        assert_eq!(tree.summary().total_size, 25 + 21);
+
+        // Sizes survive a round trip, and nodes saved without a size still load
+        let serialized = serde_json::to_string(&tree).unwrap();
+        let deserialized: FSTree = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.summary(), tree.summary());
+        let node = FSNode::File(
+            Mtime::from(498705660, 314159265),
+            hasher::hash_bytes(b"this is some test content"),
+            Mode::default(),
+            None,
+        );
+        let serialized = serde_json::to_string(&node).unwrap();
+        let without_size = serialized.replace(",null]", "]");
+        assert_ne!(serialized, without_size);
+        let deserialized: FSNode = serde_json::from_str(&without_size).unwrap();
+        assert!(matches!(deserialized, FSNode::File(_, _, _, None)));
+        assert_eq!(deserialized, node);
+    }
+
+    fn generate() {
+        let path = PathBuf::from("/tmp/bbup-test-fs_vcs-tree-generate");
+        assert!(!path.exists());
+        std::fs::create_dir(&path).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            std::fs::create_dir(path.join("dir")).unwrap();
+            std::fs::create_dir(path.join("dir").join("dir1")).unwrap();
+            abst_fs::set_mtime(
+                &AbstPath::from(path.join("dir").join("dir1")),
+                &Mtime::from(498705840, 141421356),
+            )
+            .unwrap();
+            std::fs::write(path.join("dir").join("file1"), b"none of your business").unwrap();
+            abst_fs::set_mtime(
+                &AbstPath::from(path.join("dir").join("file1")),
+                &Mtime::from(498705780, 161803398),
+            )
+            .unwrap();
+            std::os::unix::fs::symlink("some/other/path", path.join("dir").join("symlink1"))
+                .unwrap();
+            abst_fs::set_mtime(
+                &AbstPath::from(path.join("dir").join("symlink1")),
+                &Mtime::from(498705720, 271828182),
+            )
+            .unwrap();
+            abst_fs::set_mtime(
+                &AbstPath::from(path.join("dir")),
+                &Mtime::from(498705900, 628318530),
+            )
+            .unwrap();
+
+            std::fs::write(path.join("file"), b"this is some test content").unwrap();
+            abst_fs::set_mtime(
+                &AbstPath::from(path.join("file")),
+                &Mtime::from(498705660, 314159265),
+            )
+            .unwrap();
+            std::os::unix::fs::symlink("some/path/to/somewhere", path.join("symlink")).unwrap();
+            abst_fs::set_mtime(
+                &AbstPath::from(path.join("symlink")),
+                &Mtime::from(498705720, 271828182),
+            )
+            .unwrap();
+            std::fs::create_dir(path.join(".bbup")).unwrap();
+            std::fs::write(path.join("excluded-file"), b"this file will be excluded").unwrap();
+
+            let exclude_list = ExcludeList::from(&vec![String::from("excluded-file")]).unwrap();
+
+            let generated = generate_fstree(&AbstPath::from(&path), &exclude_list).unwrap();
+            assert_eq!(generated, FSTree::test_default());
+            assert_eq!(generated.summary(), FSTree::test_default().summary());
+            assert!(matches!(
+                generated.get_at(&AbstPath::from("dir/file1")),
+                Some(FSNode::File(_, _, _, Some(21)))
+            ));
+            assert_ne!(
+                generate_fstree(&AbstPath::from(&path), &ExcludeList::from(&vec![]).unwrap())
+                    .unwrap(),
+                FSTree::test_default()
+            );
+            assert!(generate_fstree(&AbstPath::from(path.join("file")), &exclude_list).is_err())
+        });
+        std::fs::remove_dir_all(&path).unwrap();
+        assert!(result.is_ok())
+    }
+
+    fn generate_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = PathBuf::from("/tmp/bbup-test-fs_vcs-tree-generate-mode");
+        assert!(!path.exists());
+        std::fs::create_dir(&path).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            let script = path.join("script.sh");
+            std::fs::write(&script, "#!/bin/sh\necho hello").unwrap();
+            std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+            let root = AbstPath::from(&path);
+            let exclude_list = ExcludeList::from(&vec![]).unwrap();
+            let before = generate_fstree(&root, &exclude_list).unwrap();
+            let mtime = abst_fs::get_mtime(&root.add_last("script.sh")).unwrap();
+
+            // Toggling the executable bit does not change content nor mtime, but
+            //	still changes the tree
+            std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+            let after = generate_fstree(&root, &exclude_list).unwrap();
+            assert_eq!(
+                abst_fs::get_mtime(&root.add_last("script.sh")).unwrap(),
+                mtime
+            );
+            assert_ne!(before, after);
+
+            let delta = crate::get_delta(&before, &after);
+            assert!(!delta.is_empty());
+            assert_eq!(
+                delta.to_actions().into_iter().collect::<Vec<_>>(),
+                vec![(
+                    AbstPath::single("script.sh"),
+                    crate::Action::EditFile(None, None, Some(Mode::from(0o755)))
+                )]
+            );
+            match after.0.get("script.sh") {
+                Some(FSNode::File(_, _, mode, _)) => assert_eq!(*mode, Mode::from(0o755)),
+                _ => panic!("script.sh should be a file"),
+            }
+        });
+        std::fs::remove_dir_all(&path).unwrap();
+        assert!(result.is_ok())
+    }
+
+    fn generate_case_collisions() {
+        let path = PathBuf::from("/tmp/bbup-test-fs_vcs-tree-generate-case-collisions");
+        assert!(!path.exists());
+        std::fs::create_dir(&path).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            std::fs::create_dir(path.join("dir")).unwrap();
+            std::fs::write(path.join("dir").join("File.txt"), b"some content").unwrap();
+            std::fs::write(path.join("dir").join("file.txt"), b"other content").unwrap();
+            std::fs::write(path.join("file.txt"), b"no collision here").unwrap();
+
+            let root = AbstPath::from(&path);
+            let exclude_list = ExcludeList::from(&vec![]).unwrap();
+
+            // Collisions are not checked by default
+            let tree = generate_fstree(&root, &exclude_list).unwrap();
+            assert_eq!(
+                generate_fstree_with_options(&root, &exclude_list, &GenerationOptions::default())
+                    .unwrap(),
+                tree
+            );
+
+            let options = GenerationOptions {
+                check_case_collisions: true,
+                ..GenerationOptions::default()
+            };
+            let err = generate_fstree_with_options(&root, &exclude_list, &options).unwrap_err();
+            assert_eq!(
+                err,
+                FSTreeError::CaseCollision {
+                    parent: root.add_last("dir"),
+                    first: String::from("File.txt"),
+                    second: String::from("file.txt"),
+                }
+            );
+            let message = err.to_string();
+            assert!(message.contains("File.txt") && message.contains("file.txt"));
+            assert!(message.contains(&root.add_last("dir").to_string()));
+
+            // Excluding one of the colliding objects resolves the collision
+            let exclude_list = ExcludeList::from(&vec![String::from("dir/File.txt")]).unwrap();
+            assert!(generate_fstree_with_options(&root, &exclude_list, &options).is_ok());
+        });
+        std::fs::remove_dir_all(&path).unwrap();
+        assert!(result.is_ok())
+    }
+
+    fn generate_hidden() {
+        let path = PathBuf::from("/tmp/bbup-test-fs_vcs-tree-generate-hidden");
         assert!(!path.exists());
         std::fs::create_dir(&path).unwrap();
 
         let result = std::panic::catch_unwind(|| {
+            std::fs::write(path.join(".hidden"), b"hidden content").unwrap();
+            std::fs::write(path.join("visible"), b"visible content").unwrap();
+            std::fs::write(path.join("excluded"), b"excluded content").unwrap();
+            std::fs::create_dir(path.join(".hidden-dir")).unwrap();
+            std::fs::write(path.join(".hidden-dir").join("inner"), b"inner").unwrap();
             std::fs::create_dir(path.join("dir")).unwrap();
-            std::fs::create_dir(path.join("dir").join("dir1")).unwrap();
-            abst_fs::set_mtime(
-                &AbstPath::from(path.join("dir").join("dir1")),
-                &Mtime::from(498705840, 141421356),
-            )
-            .unwrap();
-            std::fs::write(path.join("dir").join("file1"), b"none of your business").unwrap();
-            abst_fs::set_mtime(
-                &AbstPath::from(path.join("dir").join("file1")),
-                &Mtime::from(498705780, 161803398),
+            std::fs::write(path.join("dir").join(".inner-hidden"), b"inner").unwrap();
+            std::fs::write(path.join("dir").join("inner"), b"inner").unwrap();
+
+            let root = AbstPath::from(&path);
+            let exclude_list = ExcludeList::from(&vec![String::from("excluded")]).unwrap();
+
+            // Hidden objects are kept by default
+            let tree = generate_fstree(&root, &exclude_list).unwrap();
+            assert_eq!(
+                generate_fstree_with_options(&root, &exclude_list, &GenerationOptions::default())
+                    .unwrap(),
+                tree
+            );
+            assert!(tree.0.contains_key(".hidden"));
+            assert!(tree.0.contains_key(".hidden-dir"));
+
+            let options = GenerationOptions {
+                skip_hidden: true,
+                ..GenerationOptions::default()
+            };
+            let skipping = generate_fstree_with_options(&root, &exclude_list, &options).unwrap();
+            let mut names = skipping.0.keys().cloned().collect::<Vec<String>>();
+            names.sort();
+            assert_eq!(names, vec![String::from("dir"), String::from("visible")]);
+            match skipping.get_at(&AbstPath::from("dir")) {
+                Some(FSNode::Dir(_, _, FSTree(subtree))) => {
+                    assert!(subtree.contains_key("inner"));
+                    assert!(!subtree.contains_key(".inner-hidden"));
+                }
+                _ => panic!("expected a directory"),
+            }
+
+            // Negated rules don't re-include hidden objects
+            let exclude_list = ExcludeList::from(&vec![String::from("!\\.hidden")]).unwrap();
+            let skipping = generate_fstree_with_options(&root, &exclude_list, &options).unwrap();
+            assert!(!skipping.0.contains_key(".hidden"));
+            assert!(skipping.0.contains_key("excluded"));
+        });
+        std::fs::remove_dir_all(&path).unwrap();
+        assert!(result.is_ok())
+    }
+
+    fn generate_special() {
+        let path = PathBuf::from("/tmp/bbup-test-fs_vcs-tree-generate-special");
+        assert!(!path.exists());
+        std::fs::create_dir(&path).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            let exclude_list = ExcludeList::from(&vec![String::from("excluded-fifo")]).unwrap();
+            let mkfifo = |path: PathBuf| {
+                let status = std::process::Command::new("mkfifo")
+                    .arg(path)
+                    .status()
+                    .unwrap();
+                assert!(status.success());
+            };
+            std::fs::create_dir(path.join("dir")).unwrap();
+            mkfifo(path.join("dir").join("fifo"));
+            mkfifo(path.join("excluded-fifo"));
+            std::os::unix::fs::symlink("dir/fifo", path.join("link-to-fifo")).unwrap();
+            std::fs::write(path.join("empty"), b"").unwrap();
+
+            // FIFOs are reported and skipped instead of failing the generation (or
+            //	blocking forever while reading them), excluded ones are not reported
+            let root = AbstPath::from(&path);
+            let (tree, skipped) = generate_fstree_with_skipped(&root, &exclude_list).unwrap();
+            assert_eq!(skipped, vec![root.add_last("dir").add_last("fifo")]);
+            assert_eq!(tree.get_at(&AbstPath::from("dir/fifo")), None);
+            assert_eq!(tree.0.get("excluded-fifo"), None);
+            assert!(matches!(
+                tree.0.get("link-to-fifo"),
+                Some(FSNode::SymLink(_, _))
+            ));
+            assert_eq!(generate_fstree(&root, &exclude_list).unwrap(), tree);
+            assert_eq!(
+                generate_fstree_parallel(&root, &exclude_list, 2).unwrap(),
+                tree
+            );
+
+            // A followed symlink to a FIFO is skipped as well
+            let follow = GenerationOptions {
+                follow_symlinks: true,
+                ..GenerationOptions::default()
+            };
+            let followed = generate_fstree_with_options(&root, &exclude_list, &follow).unwrap();
+            assert_eq!(followed.0.get("link-to-fifo"), None);
+
+            // Zero-byte files are regular files and get synchronized as such
+            match tree.0.get("empty") {
+                Some(FSNode::File(_, hash, _, size)) => {
+                    assert_eq!(*hash, hasher::hash_bytes(b""));
+                    assert_eq!(*size, Some(0));
+                }
+                _ => panic!("empty file should be in the tree"),
+            }
+            let delta = crate::get_delta(&FSTree::empty(), &tree);
+            let mut synced = FSTree::empty();
+            synced.apply_delta(&delta).unwrap();
+            assert_eq!(synced, tree);
+        });
+        std::fs::remove_dir_all(&path).unwrap();
+        assert!(result.is_ok())
+    }
+
+    fn generate_structural() {
+        let path = PathBuf::from("/tmp/bbup-test-fs_vcs-tree-generate-structural");
+        assert!(!path.exists());
+        std::fs::create_dir(&path).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            let root = AbstPath::from(&path);
+            let exclude_list = ExcludeList::from(&vec![]).unwrap();
+            let write = |name: &str, content: &str, mtime: (i64, u32)| {
+                std::fs::write(path.join(name), content).unwrap();
+                abst_fs::set_mtime(
+                    &AbstPath::from(path.join(name)),
+                    &Mtime::from(mtime.0, mtime.1),
+                )
+                .unwrap();
+            };
+            std::fs::create_dir(path.join("dir")).unwrap();
+            write("dir/file", "some content", (1667000000, 0));
+            write("edited", "old content", (1667000000, 0));
+            write("touched", "old content", (1667000000, 0));
+            std::os::unix::fs::symlink("dir/file", path.join("symlink")).unwrap();
+
+            let structural = generate_fstree_structural(&root, &exclude_list).unwrap();
+            assert!(matches!(
+                structural.0.get("edited"),
+                Some(StructNode::File(_, None, _, Some(11)))
+            ));
+            assert!(matches!(
+                structural.0.get("symlink"),
+                Some(StructNode::SymLink(_, None))
+            ));
+            match structural.0.get("dir") {
+                Some(StructNode::Dir(_, subtree)) => assert!(matches!(
+                    subtree.0.get("file"),
+                    Some(StructNode::File(_, None, _, Some(12)))
+                )),
+                _ => panic!("dir should be a directory"),
+            }
+
+            // Without a prior tree everything is hashed
+            let prior = generate_fstree(&root, &exclude_list).unwrap();
+            assert_eq!(
+                fill_hashes(&root, &structural, &FSTree::empty()).unwrap(),
+                prior
+            );
+
+            // Content changed along with the mtime is hashed again, while content
+            //	changed with the same mtime and size is assumed to be unchanged
+            write("edited", "new content", (1667100000, 0));
+            write("touched", "new content", (1667000000, 0));
+            let structural = generate_fstree_structural(&root, &exclude_list).unwrap();
+            let filled = fill_hashes(&root, &structural, &prior).unwrap();
+            let fresh = generate_fstree(&root, &exclude_list).unwrap();
+
+            let edited = AbstPath::single("edited");
+            let touched = AbstPath::single("touched");
+            assert_eq!(filled.get_at(&edited), fresh.get_at(&edited));
+            assert_ne!(filled.get_at(&edited), prior.get_at(&edited));
+            assert_eq!(filled.get_at(&touched), prior.get_at(&touched));
+            assert_ne!(filled.get_at(&touched), fresh.get_at(&touched));
+            for unchanged in ["dir", "dir/file", "symlink"] {
+                let unchanged = AbstPath::from(unchanged);
+                assert_eq!(filled.get_at(&unchanged), fresh.get_at(&unchanged));
+            }
+
+            assert!(generate_fstree_structural(&root.add_last("edited"), &exclude_list).is_err());
+        });
+        std::fs::remove_dir_all(&path).unwrap();
+        assert!(result.is_ok())
+    }
+
+    fn generate_with_prior() {
+        let path = PathBuf::from("/tmp/bbup-test-fs_vcs-tree-generate-with-prior");
+        assert!(!path.exists());
+        std::fs::create_dir(&path).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            let root = AbstPath::from(&path);
+            let exclude_list = ExcludeList::from(&vec![]).unwrap();
+            let write = |name: &str, content: &str, mtime: (i64, u32)| {
+                std::fs::write(path.join(name), content).unwrap();
+                abst_fs::set_mtime(
+                    &AbstPath::from(path.join(name)),
+                    &Mtime::from(mtime.0, mtime.1),
+                )
+                .unwrap();
+            };
+            let hashed_files = |prior: Option<&FSTree>| {
+                let before = HASHED_FILES.with(|count| count.get());
+                let (tree, skipped) =
+                    generate_fstree_with_prior(&root, &exclude_list, prior).unwrap();
+                assert!(skipped.is_empty());
+                (tree, HASHED_FILES.with(|count| count.get()) - before)
+            };
+            std::fs::create_dir(path.join("dir")).unwrap();
+            write("dir/unchanged", "some content", (1667000000, 0));
+            write("unchanged", "some content", (1667000000, 0));
+            write("edited", "old content", (1667000000, 0));
+            write("resized", "old content", (1667000000, 0));
+
+            // Without a prior tree every file is hashed
+            let (prior, hashed) = hashed_files(None);
+            assert_eq!(hashed, 4);
+            assert_eq!(prior, generate_fstree(&root, &exclude_list).unwrap());
+
+            // With nothing changed no file is hashed
+            let (tree, hashed) = hashed_files(Some(&prior));
+            assert_eq!(hashed, 0);
+            assert_eq!(tree, prior);
+
+            // Only the files whose mtime or size changed are hashed
+            write("edited", "new content", (1667100000, 0));
+            write("resized", "older content", (1667000000, 0));
+            write("added", "new file", (1667100000, 0));
+            let (tree, hashed) = hashed_files(Some(&prior));
+            assert_eq!(hashed, 3);
+            assert_eq!(tree, generate_fstree(&root, &exclude_list).unwrap());
+        });
+        std::fs::remove_dir_all(&path).unwrap();
+        assert!(result.is_ok())
+    }
+
+    fn generate_to_depth() {
+        let path = PathBuf::from("/tmp/bbup-test-fs_vcs-tree-generate-to-depth");
+        assert!(!path.exists());
+        std::fs::create_dir(&path).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            let root = AbstPath::from(&path);
+            let exclude_list = ExcludeList::from(&vec![]).unwrap();
+            std::fs::create_dir_all(path.join("a").join("deep").join("deeper")).unwrap();
+            std::fs::write(path.join("a").join("file"), "old content").unwrap();
+            std::fs::write(path.join("a").join("deep").join("file"), "old content").unwrap();
+            std::fs::write(path.join("a").join("deep").join("removed"), "old file").unwrap();
+            std::fs::write(path.join("a/deep/deeper/file"), "old content").unwrap();
+            std::fs::write(path.join("file"), "old content").unwrap();
+            let known = generate_fstree(&root, &exclude_list).unwrap();
+
+            // Nothing changed, nothing to sync
+            let (limited, _) =
+                generate_fstree_to_depth(&root, &exclude_list, &known, Some(&known), 2).unwrap();
+            assert_eq!(limited, known);
+
+            // Changes below the limit, which must not show up
+            std::fs::write(path.join("a").join("deep").join("file"), "new content").unwrap();
+            std::fs::write(path.join("a").join("deep").join("added"), "new file").unwrap();
+            std::fs::remove_file(path.join("a").join("deep").join("removed")).unwrap();
+            std::fs::remove_dir_all(path.join("a/deep/deeper")).unwrap();
+            // Changes within the limit, which must
+            std::fs::write(path.join("a").join("file"), "new content").unwrap();
+            std::fs::write(path.join("file"), "new content").unwrap();
+            std::fs::create_dir_all(path.join("b").join("new")).unwrap();
+            std::fs::write(path.join("b").join("file"), "new file").unwrap();
+            std::fs::write(path.join("b").join("new").join("file"), "new file").unwrap();
+
+            let (limited, _) =
+                generate_fstree_to_depth(&root, &exclude_list, &known, Some(&known), 2).unwrap();
+            let actions: Vec<String> = get_delta(&known, &limited)
+                .to_actions()
+                .into_iter()
+                .map(|(path, action)| format!("{action}: {path}"))
+                .collect();
+            let full = generate_fstree(&root, &exclude_list).unwrap();
+            // The directories at the limit are kept as they were known, with
+            //	their content, and the new ones are recorded empty
+            assert_eq!(
+                limited.get_at(&AbstPath::from("a/deep")),
+                known.get_at(&AbstPath::from("a/deep"))
+            );
+            assert_eq!(
+                limited.get_at(&AbstPath::from("b/new")),
+                Some(&FSNode::Dir(
+                    abst_fs::get_mtime(&AbstPath::from(path.join("b").join("new"))).unwrap(),
+                    hash_tree(&FSTree::empty()),
+                    FSTree::empty()
+                ))
+            );
+            assert_eq!(
+                limited.get_at(&AbstPath::from("b/file")),
+                full.get_at(&AbstPath::from("b/file"))
+            );
+            assert_eq!(
+                limited.get_at(&AbstPath::from("file")),
+                full.get_at(&AbstPath::from("file"))
+            );
+            assert!(actions.iter().all(|action| !action.contains("a/deep")));
+            assert!(actions.iter().all(|action| !action.contains("remove")));
+            assert!(actions.iter().any(|action| action.ends_with(" a/file")));
+            assert!(actions.iter().any(|action| action.ends_with(" b/new")));
+            assert!(get_delta(&known, &full)
+                .to_actions()
+                .into_iter()
+                .any(|(path, _)| path == AbstPath::from("a/deep/deeper")));
+
+            // Depth 0 is the same as depth 1
+            assert_eq!(
+                generate_fstree_to_depth(&root, &exclude_list, &known, None, 0).unwrap(),
+                generate_fstree_to_depth(&root, &exclude_list, &known, None, 1).unwrap()
+            );
+        });
+        std::fs::remove_dir_all(&path).unwrap();
+        assert!(result.is_ok())
+    }
+
+    fn generate_at() {
+        let path = PathBuf::from("/tmp/bbup-test-fs_vcs-tree-generate-at");
+        assert!(!path.exists());
+        std::fs::create_dir(&path).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            let root = AbstPath::from(&path);
+            let exclude_list = ExcludeList::from(&vec!["a/ignored".to_string()]).unwrap();
+            std::fs::create_dir_all(path.join("a").join("dir")).unwrap();
+            std::fs::create_dir(path.join("b")).unwrap();
+            std::fs::write(path.join("a").join("dir").join("file"), "old content").unwrap();
+            std::fs::write(path.join("b").join("file"), "old content").unwrap();
+            let known = generate_fstree(&root, &exclude_list).unwrap();
+
+            std::fs::write(path.join("a").join("dir").join("file"), "new content").unwrap();
+            std::fs::write(path.join("a").join("added"), "new file").unwrap();
+            std::fs::write(path.join("a").join("ignored"), "excluded").unwrap();
+            std::fs::write(path.join("b").join("file"), "new content").unwrap();
+            let full = generate_fstree(&root, &exclude_list).unwrap();
+
+            // Only the objects under the subpath are looked at, siblings are
+            //	left as they are known
+            let (tree, skipped) = generate_fstree_at(
+                &root,
+                &AbstPath::single("a"),
+                &exclude_list,
+                &known,
+                Some(&known),
             )
             .unwrap();
-            std::os::unix::fs::symlink("some/other/path", path.join("dir").join("symlink1"))
+            assert!(skipped.is_empty());
+            assert_eq!(
+                tree.get_at(&AbstPath::single("a")),
+                full.get_at(&AbstPath::single("a"))
+            );
+            assert_eq!(
+                tree.get_at(&AbstPath::single("b")),
+                known.get_at(&AbstPath::single("b"))
+            );
+
+            // The changes under the subpath are the same as in the full delta
+            let delta = get_delta(&known, &tree);
+            assert_eq!(
+                delta,
+                get_delta(&known, &full).filter_subpath(&AbstPath::single("a"))
+            );
+            let mut applied = known.clone();
+            applied.apply_delta(&delta).unwrap();
+            assert_eq!(applied, tree);
+
+            // Deeper subpaths update the hashes of their ancestors
+            let (tree, _) =
+                generate_fstree_at(&root, &AbstPath::from("a/dir"), &exclude_list, &known, None)
+                    .unwrap();
+            assert_ne!(
+                tree.get_at(&AbstPath::single("a")),
+                known.get_at(&AbstPath::single("a"))
+            );
+            assert_eq!(
+                tree.get_at(&AbstPath::from("a/dir")),
+                full.get_at(&AbstPath::from("a/dir"))
+            );
+            assert_eq!(tree.get_at(&AbstPath::from("a/added")), None);
+
+            // The parent of the subpath must be a known directory
+            assert_eq!(
+                generate_fstree_at(
+                    &root,
+                    &AbstPath::from("a/dir"),
+                    &exclude_list,
+                    &FSTree::empty(),
+                    None
+                ),
+                Err(FSTreeError::UnknownSubpath {
+                    path: AbstPath::from("a/dir")
+                })
+            );
+        });
+        std::fs::remove_dir_all(&path).unwrap();
+        assert!(result.is_ok())
+    }
+
+    fn dereference() {
+        let path = PathBuf::from("/tmp/bbup-test-fs_vcs-tree-dereference");
+        assert!(!path.exists());
+        std::fs::create_dir(&path).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            let exclude_list = ExcludeList::from(&vec!["dir/excluded".to_string()]).unwrap();
+            let data = path.join("data");
+            std::fs::create_dir_all(data.join("dir")).unwrap();
+            std::fs::write(data.join("file"), "some content").unwrap();
+            std::fs::write(data.join("dir").join("file"), "other content").unwrap();
+            std::fs::write(data.join("dir").join("excluded"), "excluded content").unwrap();
+            let link = path.join("link");
+            std::fs::create_dir(&link).unwrap();
+            std::os::unix::fs::symlink(data.join("file"), link.join("to-file")).unwrap();
+            std::os::unix::fs::symlink(&data, link.join("to-data")).unwrap();
+            std::os::unix::fs::symlink(data.join("dir"), link.join("dir")).unwrap();
+            std::os::unix::fs::symlink("..", link.join("up")).unwrap();
+            std::os::unix::fs::symlink("nowhere", link.join("broken")).unwrap();
+            let root = AbstPath::from(&link);
+            let generated = generate_fstree(&root, &exclude_list).unwrap();
+            let data_tree =
+                generate_fstree(&AbstPath::from(&data), &ExcludeList::from(&vec![]).unwrap())
+                    .unwrap();
+
+            // Only the symlinks at the given paths are dereferenced
+            let mut tree = generated.clone();
+            let paths = [
+                AbstPath::single("to-file"),
+                AbstPath::single("dir"),
+                AbstPath::single("not-there"),
+            ];
+            let skipped =
+                dereference_symlinks(&root, &mut tree, &paths, &exclude_list, None).unwrap();
+            assert!(skipped.is_empty());
+            match (tree.0.get("to-file"), data_tree.0.get("file")) {
+                (Some(FSNode::File(_, link_hash, _, _)), Some(FSNode::File(_, hash, _, _))) => {
+                    assert_eq!(link_hash, hash)
+                }
+                _ => panic!("symlink to file should have been dereferenced"),
+            }
+            // The exclude list applies to the paths through the symlink
+            match (tree.0.get("dir"), data_tree.0.get("dir")) {
+                (Some(FSNode::Dir(_, _, subtree)), Some(FSNode::Dir(_, _, data_subtree))) => {
+                    assert!(subtree.0.contains_key("file"));
+                    assert!(!subtree.0.contains_key("excluded"));
+                    assert!(data_subtree.0.contains_key("excluded"));
+                }
+                _ => panic!("symlink to dir should have been dereferenced"),
+            }
+            assert_eq!(tree.0.get("to-data"), generated.0.get("to-data"));
+
+            // The result is the same with a prior tree
+            let mut with_prior = generated.clone();
+            dereference_symlinks(&root, &mut with_prior, &paths, &exclude_list, Some(&tree))
                 .unwrap();
-            abst_fs::set_mtime(
-                &AbstPath::from(path.join("dir").join("symlink1")),
-                &Mtime::from(498705720, 271828182),
+            assert_eq!(with_prior, tree);
+
+            // Symlinks to ancestors and dangling symlinks can't be dereferenced
+            assert_eq!(
+                dereference_symlinks(
+                    &root,
+                    &mut generated.clone(),
+                    &[AbstPath::single("up")],
+                    &exclude_list,
+                    None
+                ),
+                Err(FSTreeError::SymlinkCycle {
+                    path: root.add_last("up")
+                })
+            );
+            assert_eq!(
+                dereference_symlinks(
+                    &root,
+                    &mut generated.clone(),
+                    &[AbstPath::single("broken")],
+                    &exclude_list,
+                    None
+                ),
+                Err(FSTreeError::DanglingSymlink {
+                    path: root.add_last("broken")
+                })
+            );
+        });
+        std::fs::remove_dir_all(&path).unwrap();
+        assert!(result.is_ok())
+    }
+
+    fn generate_unreadable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = PathBuf::from("/tmp/bbup-test-fs_vcs-tree-generate-unreadable");
+        assert!(!path.exists());
+        std::fs::create_dir(&path).unwrap();
+        let set_mode = |name: &str, mode: u32| {
+            std::fs::set_permissions(path.join(name), std::fs::Permissions::from_mode(mode))
+        };
+
+        let result = std::panic::catch_unwind(|| {
+            let root = AbstPath::from(&path);
+            let exclude_list = ExcludeList::from(&vec![]).unwrap();
+            std::fs::write(path.join("readable"), "some content").unwrap();
+            std::fs::create_dir(path.join("dir")).unwrap();
+            std::fs::write(path.join("dir").join("file"), "some content").unwrap();
+            std::fs::create_dir(path.join("locked")).unwrap();
+            std::fs::write(path.join("locked").join("file"), "some content").unwrap();
+            std::fs::write(path.join("secret"), "some content").unwrap();
+            set_mode("locked", 0o000).unwrap();
+            set_mode("secret", 0o000).unwrap();
+
+            // Permissions are not enforced for privileged users
+            if std::fs::read_dir(path.join("locked")).is_ok() {
+                return;
+            }
+
+            assert!(generate_fstree(&root, &exclude_list).is_err());
+            assert_eq!(
+                generate_fstree(&root.add_last("locked"), &exclude_list),
+                Err(FSTreeError::PermissionDenied {
+                    path: root.add_last("locked")
+                })
+            );
+
+            let (tree, special, unreadable) =
+                generate_fstree_skipping_unreadable(&root, &exclude_list).unwrap();
+            let mut names = tree.0.keys().collect::<Vec<&String>>();
+            names.sort();
+            assert_eq!(names, vec!["dir", "readable"]);
+            assert!(tree.get_at(&AbstPath::from("dir/file")).is_some());
+            assert!(special.is_empty());
+            assert_eq!(
+                unreadable,
+                vec![root.add_last("locked"), root.add_last("secret")]
+            );
+        });
+        let _ = set_mode("locked", 0o755);
+        let _ = set_mode("secret", 0o644);
+        std::fs::remove_dir_all(&path).unwrap();
+        assert!(result.is_ok())
+    }
+
+    fn generate_symlinks() {
+        let path = PathBuf::from("/tmp/bbup-test-fs_vcs-tree-generate-symlinks");
+        assert!(!path.exists());
+        std::fs::create_dir(&path).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            let exclude_list = ExcludeList::from(&vec![]).unwrap();
+            let follow = GenerationOptions {
+                follow_symlinks: true,
+                ..GenerationOptions::default()
+            };
+            let check_dangling = GenerationOptions {
+                check_dangling_symlinks: true,
+                ..GenerationOptions::default()
+            };
+
+            // Followed symlinks are recorded as their target
+            let valid = path.join("valid");
+            std::fs::create_dir_all(valid.join("dir")).unwrap();
+            std::fs::write(valid.join("dir").join("file"), b"some content").unwrap();
+            std::os::unix::fs::symlink("dir/file", valid.join("link-file")).unwrap();
+            std::os::unix::fs::symlink("dir", valid.join("link-dir")).unwrap();
+            let root = AbstPath::from(&valid);
+            let tree = generate_fstree_with_options(&root, &exclude_list, &follow).unwrap();
+            assert_eq!(tree.0.get("link-dir"), tree.0.get("dir"));
+            match (
+                tree.0.get("link-file"),
+                tree.get_at(&AbstPath::from("dir/file")),
+            ) {
+                (Some(FSNode::File(_, link_hash, _, _)), Some(FSNode::File(_, hash, _, _))) => {
+                    assert_eq!(link_hash, hash)
+                }
+                _ => panic!("symlink to file should have been followed"),
+            }
+            assert!(generate_fstree_with_options(&root, &exclude_list, &check_dangling).is_ok());
+
+            // Self-referential symlink
+            let selfref = path.join("self");
+            std::fs::create_dir(&selfref).unwrap();
+            std::os::unix::fs::symlink("self", selfref.join("self")).unwrap();
+            let root = AbstPath::from(&selfref);
+            assert!(generate_fstree(&root, &exclude_list).is_ok());
+            assert!(generate_fstree_with_options(
+                &root,
+                &exclude_list,
+                &GenerationOptions::default()
             )
-            .unwrap();
-            abst_fs::set_mtime(
-                &AbstPath::from(path.join("dir")),
-                &Mtime::from(498705900, 628318530),
+            .is_ok());
+            assert_eq!(
+                generate_fstree_with_options(&root, &exclude_list, &follow).unwrap_err(),
+                FSTreeError::SymlinkCycle {
+                    path: root.add_last("self")
+                }
+            );
+
+            // Symlink to an ancestor
+            let ancestor = path.join("ancestor");
+            std::fs::create_dir_all(ancestor.join("dir")).unwrap();
+            std::os::unix::fs::symlink("..", ancestor.join("dir").join("up")).unwrap();
+            let root = AbstPath::from(&ancestor);
+            assert!(generate_fstree(&root, &exclude_list).is_ok());
+            assert_eq!(
+                generate_fstree_with_options(&root, &exclude_list, &follow).unwrap_err(),
+                FSTreeError::SymlinkCycle {
+                    path: root.add_last("dir").add_last("up")
+                }
+            );
+
+            // Broken symlink
+            let broken = path.join("broken");
+            std::fs::create_dir(&broken).unwrap();
+            std::os::unix::fs::symlink("nowhere", broken.join("broken")).unwrap();
+            let root = AbstPath::from(&broken);
+            assert!(generate_fstree(&root, &exclude_list).is_ok());
+            assert!(generate_fstree_with_options(&root, &exclude_list, &follow).is_ok());
+            let err =
+                generate_fstree_with_options(&root, &exclude_list, &check_dangling).unwrap_err();
+            assert_eq!(
+                err,
+                FSTreeError::DanglingSymlink {
+                    path: root.add_last("broken")
+                }
+            );
+            assert!(err
+                .to_string()
+                .contains(&root.add_last("broken").to_string()));
+        });
+        std::fs::remove_dir_all(&path).unwrap();
+        assert!(result.is_ok())
+    }
+
+    fn generate_parallel() {
+        let path = PathBuf::from("/tmp/bbup-test-fs_vcs-tree-generate-parallel");
+        assert!(!path.exists());
+        std::fs::create_dir(&path).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            fn populate(path: &std::path::Path, depth: usize) {
+                for i in 0..5 {
+                    std::fs::write(
+                        path.join(format!("file{i}")),
+                        format!("content {depth} {i}"),
+                    )
+                    .unwrap();
+                }
+                std::os::unix::fs::symlink(format!("some/path/{depth}"), path.join("symlink"))
+                    .unwrap();
+                std::fs::write(path.join("excluded-file"), b"this file will be excluded").unwrap();
+                if depth > 0 {
+                    for i in 0..3 {
+                        let subpath = path.join(format!("dir{i}"));
+                        std::fs::create_dir(&subpath).unwrap();
+                        populate(&subpath, depth - 1);
+                    }
+                }
+            }
+            populate(&path, 3);
+            std::fs::create_dir(path.join(".bbup")).unwrap();
+
+            let root = AbstPath::from(&path);
+            let exclude_list = ExcludeList::from(&vec![String::from("excluded-file")]).unwrap();
+            let serial = generate_fstree(&root, &exclude_list).unwrap();
+            // 40 directories (the root included) each with 5 files of 11 bytes
+            //	and a symlink
+            let summary = serial.summary();
+            assert_eq!(summary.count.files, 200);
+            assert_eq!(summary.count.symlinks, 40);
+            assert_eq!(summary.count.dirs, 39);
+            assert_eq!(summary.total_size, 200 * 11);
+            for threads in [0, 1, 2, 8] {
+                assert_eq!(
+                    generate_fstree_parallel(&root, &exclude_list, threads).unwrap(),
+                    serial
+                );
+            }
+            assert_ne!(
+                generate_fstree_parallel(&root, &ExcludeList::from(&vec![]).unwrap(), 4).unwrap(),
+                serial
+            );
+            assert!(generate_fstree_parallel(
+                &AbstPath::from(path.join("file0")),
+                &exclude_list,
+                4
             )
-            .unwrap();
+            .is_err());
+        });
+        std::fs::remove_dir_all(&path).unwrap();
+        assert!(result.is_ok())
+    }
 
-            std::fs::write(path.join("file"), b"this is some test content").unwrap();
+    fn generate_small_files() {
+        let path = PathBuf::from("/tmp/bbup-test-fs_vcs-tree-generate-small-files");
+        assert!(!path.exists());
+        std::fs::create_dir(&path).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            // Sizes around the threshold and around the usual buffer sizes
+            let sizes = [0, 1, 100, 4095, 4096, 8193, 65535, 65536, 65537, 300000];
+            for size in sizes {
+                let content: Vec<u8> = (0..size).map(|i| (i * 31 % 251) as u8).collect();
+                std::fs::write(path.join(format!("file{size}")), &content).unwrap();
+                let file = AbstPath::from(path.join(format!("file{size}")));
+
+                set_small_file_size(u64::MAX);
+                let buffered = hash_file(&file).unwrap();
+                set_small_file_size(0);
+                let streamed = hash_file(&file).unwrap();
+                assert_eq!(buffered, streamed);
+                assert_eq!(buffered, hasher::hash_bytes(&content));
+            }
+
+            // Many tiny files, as in the archives the buffered read is for
+            let tiny = path.join("tiny");
+            std::fs::create_dir(&tiny).unwrap();
+            for i in 0..2000 {
+                std::fs::write(tiny.join(format!("file{i}")), format!("tiny {i}")).unwrap();
+            }
+            let root = AbstPath::from(&path);
+            let exclude_list = ExcludeList::from(&vec![]).unwrap();
+            let mut trees = Vec::new();
+            for threshold in [DEFAULT_SMALL_FILE_SIZE, 0] {
+                set_small_file_size(threshold);
+                trees.push(generate_fstree(&root, &exclude_list).unwrap());
+            }
+            assert_eq!(trees[0], trees[1]);
+            assert_eq!(trees[0].root_hash(), trees[1].root_hash());
+        });
+        set_small_file_size(DEFAULT_SMALL_FILE_SIZE);
+        std::fs::remove_dir_all(&path).unwrap();
+        assert!(result.is_ok())
+    }
+
+    fn generate_negations() {
+        let path = PathBuf::from("/tmp/bbup-test-fs_vcs-tree-generate-negations");
+        assert!(!path.exists());
+        std::fs::create_dir(&path).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            std::fs::create_dir(path.join("target")).unwrap();
+            std::fs::write(path.join("target").join("keep-me.txt"), b"keep me").unwrap();
             abst_fs::set_mtime(
-                &AbstPath::from(path.join("file")),
+                &AbstPath::from(path.join("target").join("keep-me.txt")),
                 &Mtime::from(498705660, 314159265),
             )
             .unwrap();
-            std::os::unix::fs::symlink("some/path/to/somewhere", path.join("symlink")).unwrap();
+            std::fs::write(path.join("target").join("build"), b"excluded").unwrap();
+            std::fs::create_dir(path.join("target").join("deps")).unwrap();
+            std::fs::write(path.join("target").join("deps").join("dep"), b"excluded").unwrap();
             abst_fs::set_mtime(
-                &AbstPath::from(path.join("symlink")),
-                &Mtime::from(498705720, 271828182),
+                &AbstPath::from(path.join("target")),
+                &Mtime::from(498705900, 628318530),
             )
             .unwrap();
+            std::fs::create_dir(path.join("other")).unwrap();
+            std::fs::write(path.join("other").join("file"), b"excluded").unwrap();
             std::fs::create_dir(path.join(".bbup")).unwrap();
-            std::fs::write(path.join("excluded-file"), b"this file will be excluded").unwrap();
 
-            let exclude_list = ExcludeList::from(&vec![String::from("excluded-file")]).unwrap();
+            let exclude_list = ExcludeList::from(&vec![
+                String::from("target/"),
+                String::from("!target/keep-me.txt"),
+                String::from("other/"),
+            ])
+            .unwrap();
 
+            let supposed_tree = FSTree::gen_from(|t| {
+                t.add_dir("target", (498705900, 628318530), |t| {
+                    t.add_file("keep-me.txt", (498705660, 314159265), "keep me");
+                });
+            });
+            let root = AbstPath::from(&path);
             assert_eq!(
-                generate_fstree(&AbstPath::from(&path), &exclude_list).unwrap(),
-                FSTree::test_default()
+                generate_fstree(&root, &exclude_list).unwrap(),
+                supposed_tree
             );
-            assert_ne!(
-                generate_fstree(&AbstPath::from(&path), &ExcludeList::from(&vec![]).unwrap())
-                    .unwrap(),
-                FSTree::test_default()
+            assert_eq!(
+                generate_fstree_parallel(&root, &exclude_list, 4).unwrap(),
+                supposed_tree
             );
-            assert!(generate_fstree(&AbstPath::from(path.join("file")), &exclude_list).is_err())
         });
         std::fs::remove_dir_all(&path).unwrap();
         assert!(result.is_ok())
@@ -0,0 +1,384 @@
+use super::{hash_bytes, Hash, Hasher};
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// Bounds of the size of the blocks in which the basis file is split
+const MIN_BLOCK_SIZE: u32 = 1024;
+const MAX_BLOCK_SIZE: u32 = 64 * 1024;
+
+/// Maximum size of a single literal operation
+const MAX_LITERAL_SIZE: usize = 256 * 1024;
+
+/// Weak checksum of a window of bytes (the same used by rsync), which can be
+/// updated in constant time when the window slides forward by one byte
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RollingChecksum {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+impl RollingChecksum {
+    pub fn new(window: &[u8]) -> RollingChecksum {
+        let len = window.len() as u32;
+        let mut a = 0u32;
+        let mut b = 0u32;
+        for (i, byte) in window.iter().enumerate() {
+            a = a.wrapping_add(*byte as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(*byte as u32));
+        }
+        RollingChecksum { a, b, len }
+    }
+
+    /// Slide the window forward, removing `outgoing` from the front and adding
+    /// `incoming` at the back
+    pub fn roll(&mut self, outgoing: u8, incoming: u8) {
+        self.a = self
+            .a
+            .wrapping_sub(outgoing as u32)
+            .wrapping_add(incoming as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(outgoing as u32))
+            .wrapping_add(self.a);
+    }
+
+    pub fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: Hash,
+}
+
+/// Signature of the basis file, the one against which the delta is computed.
+/// `hash` is the hash of the whole basis
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Signature {
+    pub hash: Hash,
+    pub len: u64,
+    pub block_size: u32,
+    pub blocks: Vec<BlockSignature>,
+}
+impl Signature {
+    /// Length of the block at the given index, which is shorter than the block size
+    /// only for the last block
+    fn block_len(&self, index: usize) -> usize {
+        let start = index as u64 * self.block_size as u64;
+        (self.len - start).min(self.block_size as u64) as usize
+    }
+}
+
+/// Size of the blocks suitable for a basis file of `len` bytes, so that neither
+/// the blocks nor the signature get too big
+pub fn block_size_for(len: u64) -> u32 {
+    ((len as f64).sqrt() as u32).clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+}
+
+/// Read the whole basis and compute its signature, splitting it in blocks of
+/// `block_size` bytes
+pub fn signature<R: Read>(mut basis: R, block_size: u32) -> std::io::Result<Signature> {
+    let mut hasher = Hasher::new();
+    let mut blocks = Vec::new();
+    let mut len = 0u64;
+    let mut block = vec![0u8; block_size as usize];
+    loop {
+        let mut filled = 0;
+        while filled < block.len() {
+            match basis.read(&mut block[filled..])? {
+                0 => break,
+                bytes => filled += bytes,
+            }
+        }
+        if filled == 0 {
+            break;
+        }
+        let block = &block[..filled];
+        hasher.update(block);
+        blocks.push(BlockSignature {
+            weak: RollingChecksum::new(block).digest(),
+            strong: hash_bytes(block),
+        });
+        len += filled as u64;
+    }
+    Ok(Signature {
+        hash: hasher.finalize(),
+        len,
+        block_size,
+        blocks,
+    })
+}
+
+/// Operation needed to reconstruct the new file: either copy a block from the
+/// basis, or write some new content
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum DeltaOp {
+    Copy(u64),
+    Literal(Vec<u8>),
+}
+
+/// Compute the delta of some content against the signature of a basis, one
+/// operation at a time, so that the content never needs to be entirely in memory
+pub struct DeltaEncoder<'a, R: Read> {
+    reader: R,
+    signature: &'a Signature,
+    table: HashMap<u32, Vec<usize>>,
+    buffer: Vec<u8>,
+    pos: usize,
+    eof: bool,
+    checksum: Option<RollingChecksum>,
+    literal: Vec<u8>,
+    matched: Option<usize>,
+}
+impl<'a, R: Read> DeltaEncoder<'a, R> {
+    pub fn new(reader: R, signature: &'a Signature) -> DeltaEncoder<'a, R> {
+        let mut table: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (index, block) in signature.blocks.iter().enumerate() {
+            table.entry(block.weak).or_default().push(index);
+        }
+        DeltaEncoder {
+            reader,
+            signature,
+            table,
+            buffer: Vec::new(),
+            pos: 0,
+            eof: false,
+            checksum: None,
+            literal: Vec::new(),
+            matched: None,
+        }
+    }
+
+    /// Read until there are at least `needed` bytes after the current position,
+    /// or until the end of the content
+    fn fill(&mut self, needed: usize) -> std::io::Result<()> {
+        if self.pos >= MAX_LITERAL_SIZE {
+            self.buffer.drain(..self.pos);
+            self.pos = 0;
+        }
+        if self.eof || self.buffer.len() - self.pos >= needed {
+            return Ok(());
+        }
+        let mut chunk = vec![0u8; needed.max(64 * 1024)];
+        while !self.eof && self.buffer.len() - self.pos < needed {
+            match self.reader.read(&mut chunk)? {
+                0 => self.eof = true,
+                bytes => self.buffer.extend_from_slice(&chunk[..bytes]),
+            }
+        }
+        Ok(())
+    }
+
+    /// Index of the block of the basis equal to the window, if any
+    fn find_block(&self, weak: u32, window: &[u8]) -> Option<usize> {
+        let candidates = self.table.get(&weak)?;
+        let strong = hash_bytes(window);
+        candidates.iter().copied().find(|index| {
+            self.signature.block_len(*index) == window.len()
+                && self.signature.blocks[*index].strong == strong
+        })
+    }
+
+    fn take_literal(&mut self) -> DeltaOp {
+        DeltaOp::Literal(std::mem::take(&mut self.literal))
+    }
+
+    /// Get the next operation of the delta, or None if the whole content has been
+    /// covered
+    pub fn next_op(&mut self) -> std::io::Result<Option<DeltaOp>> {
+        let block_size = self.signature.block_size as usize;
+        if let Some(index) = self.matched.take() {
+            return Ok(Some(DeltaOp::Copy(index as u64)));
+        }
+        loop {
+            self.fill(block_size + 1)?;
+            let available = self.buffer.len() - self.pos;
+            if available == 0 {
+                return Ok(match self.literal.is_empty() {
+                    true => None,
+                    false => Some(self.take_literal()),
+                });
+            }
+
+            // Only the last block of the basis can be shorter than the block size
+            if available < block_size {
+                let window = &self.buffer[self.pos..];
+                let weak = RollingChecksum::new(window).digest();
+                let found = self.find_block(weak, window);
+                match found {
+                    Some(index) if self.literal.is_empty() => {
+                        self.pos = self.buffer.len();
+                        return Ok(Some(DeltaOp::Copy(index as u64)));
+                    }
+                    Some(index) => self.matched = Some(index),
+                    None => self.literal.extend_from_slice(window),
+                }
+                self.pos = self.buffer.len();
+                continue;
+            }
+
+            let window = &self.buffer[self.pos..self.pos + block_size];
+            let checksum = *self
+                .checksum
+                .get_or_insert_with(|| RollingChecksum::new(window));
+            if let Some(index) = self.find_block(checksum.digest(), window) {
+                self.pos += block_size;
+                self.checksum = None;
+                if self.literal.is_empty() {
+                    return Ok(Some(DeltaOp::Copy(index as u64)));
+                }
+                self.matched = Some(index);
+                return Ok(Some(self.take_literal()));
+            }
+
+            let outgoing = self.buffer[self.pos];
+            self.literal.push(outgoing);
+            match self.buffer.get(self.pos + block_size) {
+                Some(incoming) => {
+                    let mut checksum = checksum;
+                    checksum.roll(outgoing, *incoming);
+                    self.checksum = Some(checksum);
+                }
+                None => self.checksum = None,
+            }
+            self.pos += 1;
+            if self.literal.len() >= MAX_LITERAL_SIZE {
+                return Ok(Some(self.take_literal()));
+            }
+        }
+    }
+}
+
+/// Write the content described by the operation into writer, taking the copied
+/// blocks from the basis. Returns the number of bytes written
+pub fn apply_op<B, W>(
+    basis: &mut B,
+    block_size: u32,
+    op: &DeltaOp,
+    writer: &mut W,
+) -> std::io::Result<u64>
+where
+    B: Read + Seek,
+    W: Write,
+{
+    match op {
+        DeltaOp::Copy(index) => {
+            basis.seek(SeekFrom::Start(index * block_size as u64))?;
+            std::io::copy(&mut basis.take(block_size as u64), writer)
+        }
+        DeltaOp::Literal(content) => {
+            writer.write_all(content)?;
+            Ok(content.len() as u64)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::hash_bytes, apply_op, block_size_for, signature, DeltaEncoder, DeltaOp,
+        RollingChecksum,
+    };
+    use rand::{rngs::SmallRng, RngCore, SeedableRng};
+    use std::io::Cursor;
+
+    /// Compute the delta of new against old and apply it on old, returning the
+    /// number of bytes that had to be sent as literals
+    fn roundtrip(old: &[u8], new: &[u8]) -> usize {
+        let signature = signature(Cursor::new(old), block_size_for(old.len() as u64)).unwrap();
+        assert_eq!(signature.hash, hash_bytes(old));
+        assert_eq!(signature.len, old.len() as u64);
+
+        let mut encoder = DeltaEncoder::new(Cursor::new(new), &signature);
+        let mut basis = Cursor::new(old);
+        let mut result = Vec::new();
+        let mut literal_bytes = 0;
+        while let Some(op) = encoder.next_op().unwrap() {
+            if let DeltaOp::Literal(content) = &op {
+                assert!(!content.is_empty());
+                literal_bytes += content.len();
+            }
+            apply_op(&mut basis, signature.block_size, &op, &mut result).unwrap();
+        }
+        assert!(result == new);
+        assert_eq!(hash_bytes(&result), hash_bytes(new));
+        literal_bytes
+    }
+
+    #[test]
+    fn test() {
+        rolling();
+        small_edits();
+        unrelated_content();
+        edge_cases();
+    }
+
+    fn rolling() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut content = vec![0u8; 4096];
+        rng.fill_bytes(&mut content);
+        let len = 1000;
+        let mut checksum = RollingChecksum::new(&content[..len]);
+        for start in 1..content.len() - len {
+            checksum.roll(content[start - 1], content[start + len - 1]);
+            assert_eq!(checksum, RollingChecksum::new(&content[start..start + len]));
+        }
+    }
+
+    fn small_edits() {
+        let mut rng = SmallRng::seed_from_u64(1);
+        let mut old = vec![0u8; 2 * 1024 * 1024];
+        rng.fill_bytes(&mut old);
+
+        // Overwrite a small region
+        let mut new = old.clone();
+        new[1_500_000..1_500_100].copy_from_slice(&[42u8; 100]);
+        let literal_bytes = roundtrip(&old, &new);
+        assert!(literal_bytes < new.len() / 100);
+
+        // Insert and remove some bytes, shifting the rest of the content
+        let mut new = old.clone();
+        new.splice(500_000..500_000, [7u8; 10]);
+        new.drain(1_800_000..1_800_033);
+        let literal_bytes = roundtrip(&old, &new);
+        assert!(literal_bytes < new.len() / 100);
+
+        // Append some content and edit the end
+        let mut new = old.clone();
+        new.truncate(old.len() - 5);
+        new.extend_from_slice(b"some new content at the end");
+        let literal_bytes = roundtrip(&old, &new);
+        assert!(literal_bytes < new.len() / 100);
+
+        // Same content
+        assert_eq!(roundtrip(&old, &old), 0);
+    }
+
+    fn unrelated_content() {
+        let mut rng = SmallRng::seed_from_u64(2);
+        let mut old = vec![0u8; 1024 * 1024];
+        let mut new = vec![0u8; 1024 * 1024];
+        rng.fill_bytes(&mut old);
+        rng.fill_bytes(&mut new);
+        assert_eq!(roundtrip(&old, &new), new.len());
+    }
+
+    fn edge_cases() {
+        let content = b"some short content, shorter than a single block".to_vec();
+        assert_eq!(roundtrip(&[], &content), content.len());
+        assert_eq!(roundtrip(&content, &[]), 0);
+        assert_eq!(roundtrip(&content, &content), 0);
+        assert_eq!(roundtrip(&[], &[]), 0);
+
+        // Repeated blocks
+        let old = vec![1u8; 100 * 1024];
+        let mut new = vec![1u8; 150 * 1024];
+        new.extend_from_slice(&[2u8; 10]);
+        assert!(roundtrip(&old, &new) < 2048);
+    }
+}
@@ -1,3 +1,4 @@
+#[cfg(not(feature = "blake3"))]
 use sha2::{
     digest::{
         consts::{B0, B1},
@@ -8,10 +9,29 @@ use sha2::{
 };
 
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+mod blockdiff;
+pub use blockdiff::{
+    apply_op, block_size_for, signature, BlockSignature, DeltaEncoder, DeltaOp, RollingChecksum,
+    Signature,
+};
 
 #[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct Hash([u8; 32]);
 
+#[derive(Error, Debug, PartialEq)]
+pub enum ParseError {
+    #[error("Hash Parse Error: a hash is 64 hex characters long, found {0} characters")]
+    WrongLength(usize),
+
+    #[error("Hash Parse Error: found non-hex character {found:?} at position {position}")]
+    NonHex { position: usize, found: char },
+
+    #[error("Hash Parse Error: a hash is 32 bytes long, found {0} bytes")]
+    WrongByteLength(usize),
+}
+
 impl Hash {
     pub fn to_bytes(&self) -> Vec<u8> {
         let Hash(bytes) = self;
@@ -33,6 +53,29 @@ impl Hash {
 
         output[0..len as usize].to_string()
     }
+    /// Get the hash from its bytes (as given by `to_bytes`)
+    pub fn from_bytes(bytes: &[u8]) -> Result<Hash, ParseError> {
+        bytes
+            .try_into()
+            .map(Hash)
+            .map_err(|_| ParseError::WrongByteLength(bytes.len()))
+    }
+    /// Parse the full hex representation of a hash (as given by `to_hex(0)`),
+    /// either lowercase or uppercase
+    pub fn from_hex(s: &str) -> Result<Hash, ParseError> {
+        let chars = s.chars().collect::<Vec<char>>();
+        if chars.len() != 64 {
+            return Err(ParseError::WrongLength(chars.len()));
+        }
+        let mut bytes = [0u8; 32];
+        for (position, &found) in chars.iter().enumerate() {
+            let digit = found
+                .to_digit(16)
+                .ok_or(ParseError::NonHex { position, found })?;
+            bytes[position / 2] = (bytes[position / 2] << 4) | digit as u8;
+        }
+        Ok(Hash(bytes))
+    }
 }
 impl std::fmt::Display for Hash {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -46,37 +89,93 @@ impl std::fmt::Debug for Hash {
 }
 /// Convert the absurd output type of sha2's digest/finalize
 /// to a useful Hash
+#[cfg(not(feature = "blake3"))]
 #[allow(clippy::type_complexity)]
 fn to_hash(
     hash: GenericArray<u8, UInt<UInt<UInt<UInt<UInt<UInt<UTerm, B1>, B0>, B0>, B0>, B0>, B0>>,
 ) -> Hash {
-    Hash(hash.as_slice().try_into().unwrap())
+    Hash(hash.into())
+}
+
+/// Incremental hasher, to be fed with chunks of data as they become
+/// available (for example while receiving a file from a socket)
+pub struct Hasher {
+    #[cfg(not(feature = "blake3"))]
+    inner: Sha256,
+    #[cfg(feature = "blake3")]
+    inner: blake3::Hasher,
+}
+impl Hasher {
+    #[cfg(not(feature = "blake3"))]
+    pub fn new() -> Hasher {
+        Hasher {
+            inner: Sha256::new(),
+        }
+    }
+    #[cfg(feature = "blake3")]
+    pub fn new() -> Hasher {
+        Hasher {
+            inner: blake3::Hasher::new(),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    #[cfg(not(feature = "blake3"))]
+    pub fn finalize(self) -> Hash {
+        to_hash(self.inner.finalize())
+    }
+    #[cfg(feature = "blake3")]
+    pub fn finalize(self) -> Hash {
+        Hash(*self.inner.finalize().as_bytes())
+    }
+}
+impl Default for Hasher {
+    fn default() -> Self {
+        Hasher::new()
+    }
+}
+impl std::io::Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
 }
 
 /// Hash anything that can be converted to u8 array (usually
 /// Strings or &str)
 pub fn hash_bytes<T: std::convert::AsRef<[u8]>>(s: T) -> Hash {
-    to_hash(Sha256::digest(s))
+    let mut hasher = Hasher::new();
+    hasher.update(s.as_ref());
+    hasher.finalize()
 }
 
 /// Hash anything that can be streamed (usually files)
 pub fn hash_stream<T: std::io::Read>(mut stream: T) -> std::io::Result<Hash> {
-    let mut hasher = Sha256::new();
+    let mut hasher = Hasher::new();
     match std::io::copy(&mut stream, &mut hasher) {
-        Ok(_) => Ok(to_hash(hasher.finalize())),
+        Ok(_) => Ok(hasher.finalize()),
         Err(error) => Err(error),
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{hash_bytes, hash_stream, Hash};
+    use super::{hash_bytes, hash_stream, Hash, Hasher, ParseError};
 
     #[test]
     fn test() {
         to_bytes();
+        from_bytes();
         to_string();
+        from_hex();
         hash();
+        incremental();
     }
 
     fn to_bytes() {
@@ -90,6 +189,22 @@ mod tests {
         }
     }
 
+    fn from_bytes() {
+        for _ in 0..1000 {
+            let hash = Hash(rand::random());
+            assert_eq!(Hash::from_bytes(&hash.to_bytes()), Ok(hash.clone()));
+        }
+        assert_eq!(Hash::from_bytes(&[]), Err(ParseError::WrongByteLength(0)));
+        assert_eq!(
+            Hash::from_bytes(&[0; 31]),
+            Err(ParseError::WrongByteLength(31))
+        );
+        assert_eq!(
+            Hash::from_bytes(&[0; 33]),
+            Err(ParseError::WrongByteLength(33))
+        );
+    }
+
     fn to_string() {
         for b in 0..=15 {
             let h = Hash([17 * b; 32]);
@@ -121,6 +236,50 @@ mod tests {
         }
     }
 
+    fn from_hex() {
+        for _ in 0..1000 {
+            let hash = Hash(rand::random());
+            assert_eq!(Hash::from_hex(&hash.to_hex(0)), Ok(hash.clone()));
+            assert_eq!(
+                Hash::from_hex(&hash.to_hex(0).to_uppercase()),
+                Ok(hash.clone())
+            );
+        }
+        assert_eq!(Hash::from_hex(&"0a".repeat(32)), Ok(Hash([10; 32])));
+
+        assert_eq!(Hash::from_hex(""), Err(ParseError::WrongLength(0)));
+        assert_eq!(
+            Hash::from_hex(&"0".repeat(63)),
+            Err(ParseError::WrongLength(63))
+        );
+        assert_eq!(
+            Hash::from_hex(&"0".repeat(65)),
+            Err(ParseError::WrongLength(65))
+        );
+        assert_eq!(
+            Hash::from_hex(&format!("{}g{}", "0".repeat(10), "0".repeat(53))),
+            Err(ParseError::NonHex {
+                position: 10,
+                found: 'g'
+            })
+        );
+        assert_eq!(
+            Hash::from_hex(&format!("{}é", "0".repeat(63))),
+            Err(ParseError::NonHex {
+                position: 63,
+                found: 'é'
+            })
+        );
+        assert_eq!(
+            Hash::from_hex(&format!("+{}", "0".repeat(63))),
+            Err(ParseError::NonHex {
+                position: 0,
+                found: '+'
+            })
+        );
+    }
+
+    #[cfg(not(feature = "blake3"))]
     fn hash() {
         let tests = [
             (
@@ -148,4 +307,70 @@ mod tests {
             );
         }
     }
+
+    #[cfg(feature = "blake3")]
+    fn hash() {
+        let tests = [
+            (
+                "",
+                "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+            ),
+            (
+                "here is some random text",
+                "633b947e1e62ea374c89aab21e7982d05c46acd5294696d5b10a5b591bc7f000",
+            ),
+            (
+                "and also a different text",
+                "4624715c27f56dfbb9b45057eb6347e7459d37b71f1058fb0b3879f7a158719b",
+            ),
+            (
+                "boop beep boop bzzzz am robot executing tests",
+                "1eb73d69644b98a50e392bd783dffc56e0947bb2e1a70493e4b7faac95897a44",
+            ),
+        ];
+        for (text, hash_val) in tests {
+            assert_eq!(hash_bytes(text).to_string(), hash_val);
+            assert_eq!(
+                hash_stream(std::io::Cursor::new(text)).unwrap().to_string(),
+                hash_val
+            );
+        }
+    }
+
+    fn incremental() {
+        let texts = [
+            "",
+            "here is some random text",
+            "and also a different text",
+            "boop beep boop bzzzz am robot executing tests",
+        ];
+        for text in texts {
+            let bytes = text.as_bytes();
+            let expected = hash_bytes(text);
+
+            for chunk_size in [1, 7, bytes.len().max(1)] {
+                let mut hasher = Hasher::new();
+                for chunk in bytes.chunks(chunk_size) {
+                    hasher.update(chunk);
+                }
+                assert_eq!(hasher.finalize(), expected);
+            }
+
+            let mut hasher = Hasher::new();
+            hasher.update(bytes);
+            assert_eq!(hasher.finalize(), expected);
+        }
+
+        for _ in 0..100 {
+            let bytes: [u8; 32] = rand::random();
+            let expected = hash_bytes(bytes);
+            for chunk_size in [1, 7, 32] {
+                let mut hasher = Hasher::new();
+                for chunk in bytes.chunks(chunk_size) {
+                    hasher.update(chunk);
+                }
+                assert_eq!(hasher.finalize(), expected);
+            }
+        }
+    }
 }
@@ -1,10 +1,167 @@
 use std::io::BufRead;
+use std::str::FromStr;
+
+use thiserror::Error;
 
 // TODO add custom error handling
 
 // TODO redo connection: probably -N -v instead of -tt
 //	and find a way to check if connected
 
+#[derive(Error, Debug, PartialEq)]
+#[error("Ssh Tunnel Error: invalid value for StrictHostKeyChecking: '{0}'\nExpected one of: yes, no, accept-new")]
+pub struct ParseStrictHostKeyCheckingError(String);
+
+/// Policy used by ssh for the keys of unknown or changed hosts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StrictHostKeyChecking {
+    Yes,
+    No,
+    AcceptNew,
+}
+impl FromStr for StrictHostKeyChecking {
+    type Err = ParseStrictHostKeyCheckingError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "yes" => Ok(StrictHostKeyChecking::Yes),
+            "no" => Ok(StrictHostKeyChecking::No),
+            "accept-new" => Ok(StrictHostKeyChecking::AcceptNew),
+            _ => Err(ParseStrictHostKeyCheckingError(s.to_string())),
+        }
+    }
+}
+impl std::fmt::Display for StrictHostKeyChecking {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StrictHostKeyChecking::Yes => write!(f, "yes"),
+            StrictHostKeyChecking::No => write!(f, "no"),
+            StrictHostKeyChecking::AcceptNew => write!(f, "accept-new"),
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+#[error("Ssh Tunnel Error: invalid ProxyJump specification: '{spec}'\n{reason}\nExpected a comma separated list of [user@]host[:port]")]
+pub struct ParseProxyJumpError {
+    spec: String,
+    reason: String,
+}
+
+/// Chain of jump hosts through which ssh reaches the server, as given to ssh's
+/// `-J` option: a comma separated list of `[user@]host[:port]`, where host can be
+/// an IPv6 address in brackets
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxyJump(String);
+impl ProxyJump {
+    /// Check a single `[user@]host[:port]` hop, returning the reason why it is
+    /// not valid, if any
+    fn check_hop(hop: &str) -> Result<(), String> {
+        if hop.is_empty() {
+            return Err(String::from("empty jump host"));
+        }
+        if hop.starts_with('-') || hop.chars().any(char::is_whitespace) {
+            return Err(format!("invalid jump host '{hop}'"));
+        }
+        let host_port = match hop.rsplit_once('@') {
+            Some(("", _)) => return Err(format!("empty user in jump host '{hop}'")),
+            Some((_, host_port)) => host_port,
+            None => hop,
+        };
+        let (host, port) = match host_port.strip_prefix('[') {
+            Some(bracketed) => match bracketed.split_once(']') {
+                Some((host, "")) => (host, None),
+                Some((host, rest)) => match rest.strip_prefix(':') {
+                    Some(port) => (host, Some(port)),
+                    None => return Err(format!("invalid jump host '{hop}'")),
+                },
+                None => return Err(format!("unclosed bracket in jump host '{hop}'")),
+            },
+            None => match host_port.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (host_port, None),
+            },
+        };
+        if host.is_empty() {
+            return Err(format!("empty host in jump host '{hop}'"));
+        }
+        match port.map(str::parse::<u16>) {
+            Some(Ok(0) | Err(_)) => Err(format!("invalid port in jump host '{hop}'")),
+            _ => Ok(()),
+        }
+    }
+}
+impl FromStr for ProxyJump {
+    type Err = ParseProxyJumpError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        for hop in s.split(',') {
+            ProxyJump::check_hop(hop).map_err(|reason| ParseProxyJumpError {
+                spec: s.to_string(),
+                reason,
+            })?;
+        }
+        Ok(ProxyJump(s.to_string()))
+    }
+}
+impl std::fmt::Display for ProxyJump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ProxyJump(spec) = self;
+        write!(f, "{}", spec)
+    }
+}
+
+/// Options passed to the ssh invocation. Options left to `None` are not passed,
+/// so that ssh falls back to its own configuration
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SshOptions {
+    pub known_hosts: Option<String>,
+    pub strict_host_key_checking: Option<StrictHostKeyChecking>,
+    pub identity_file: Option<String>,
+    /// Port of the ssh server on the host
+    pub port: Option<u16>,
+    /// Jump hosts to go through to reach the host
+    pub proxy_jump: Option<ProxyJump>,
+}
+
+fn ssh_command(
+    local_port: u16,
+    server_port: u16,
+    host_user: &str,
+    host_address: &str,
+    options: &SshOptions,
+) -> std::process::Command {
+    let mut command = std::process::Command::new("ssh");
+    command
+        .arg("-tt")
+        .arg("-L")
+        .arg(format!("{}:localhost:{}", local_port, server_port,));
+    if let Some(port) = options.port {
+        command.arg("-p").arg(port.to_string());
+    }
+    if let Some(proxy_jump) = &options.proxy_jump {
+        command.arg("-J").arg(proxy_jump.to_string());
+    }
+    if let Some(known_hosts) = &options.known_hosts {
+        command
+            .arg("-o")
+            .arg(format!("UserKnownHostsFile={}", known_hosts));
+    }
+    if let Some(strict_host_key_checking) = &options.strict_host_key_checking {
+        command.arg("-o").arg(format!(
+            "StrictHostKeyChecking={}",
+            strict_host_key_checking
+        ));
+    }
+    if let Some(identity_file) = &options.identity_file {
+        command
+            .arg("-o")
+            .arg(format!("IdentityFile={}", identity_file))
+            .arg("-o")
+            .arg("IdentitiesOnly=yes");
+    }
+    command.arg(format!("{}@{}", host_user, host_address));
+    command
+}
+
 pub struct SshTunnel {
     process: std::process::Child,
     ready: bool,
@@ -16,15 +173,13 @@ impl SshTunnel {
         server_port: u16,
         host_user: String,
         host_address: String,
+        options: &SshOptions,
     ) -> std::io::Result<SshTunnel> {
-        let ssh_tunnel_handle = std::process::Command::new("ssh")
-            .arg("-tt")
-            .arg("-L")
-            .arg(format!("{}:localhost:{}", local_port, server_port,))
-            .arg(format!("{}@{}", host_user, host_address))
-            .stdin(std::process::Stdio::piped())
-            .stdout(std::process::Stdio::piped())
-            .spawn()?;
+        let ssh_tunnel_handle =
+            ssh_command(local_port, server_port, &host_user, &host_address, options)
+                .stdin(std::process::Stdio::piped())
+                .stdout(std::process::Stdio::piped())
+                .spawn()?;
 
         Ok(SshTunnel {
             process: ssh_tunnel_handle,
@@ -60,3 +215,149 @@ impl Drop for SshTunnel {
         self.termiate();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ssh_command, ParseStrictHostKeyCheckingError, ProxyJump, SshOptions, StrictHostKeyChecking,
+    };
+
+    fn args(options: &SshOptions) -> Vec<String> {
+        ssh_command(4000, 3000, "user", "example.com", options)
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test() {
+        parse();
+
+        parse_proxy_jump();
+
+        command();
+    }
+
+    fn parse() {
+        assert_eq!("yes".parse(), Ok(StrictHostKeyChecking::Yes));
+        assert_eq!("no".parse(), Ok(StrictHostKeyChecking::No));
+        assert_eq!("accept-new".parse(), Ok(StrictHostKeyChecking::AcceptNew));
+        assert_eq!(
+            "maybe".parse::<StrictHostKeyChecking>(),
+            Err(ParseStrictHostKeyCheckingError(String::from("maybe")))
+        );
+        assert!(ParseStrictHostKeyCheckingError(String::from("maybe"))
+            .to_string()
+            .contains("'maybe'"));
+        for policy in [
+            StrictHostKeyChecking::Yes,
+            StrictHostKeyChecking::No,
+            StrictHostKeyChecking::AcceptNew,
+        ] {
+            assert_eq!(policy.to_string().parse(), Ok(policy));
+        }
+    }
+
+    fn parse_proxy_jump() {
+        for spec in [
+            "bastion",
+            "user@bastion",
+            "user@bastion:2222",
+            "bastion.example.com:22",
+            "first,user@second:2222,third",
+            "[::1]",
+            "user@[fe80::1]:2222",
+            "192.168.1.1:22",
+        ] {
+            let proxy_jump = spec.parse::<ProxyJump>().unwrap();
+            assert_eq!(proxy_jump.to_string(), spec);
+        }
+        for spec in [
+            "",
+            "bastion,",
+            ",bastion",
+            "first,,second",
+            "@bastion",
+            "user@",
+            "user@:22",
+            "bastion:",
+            "bastion:0",
+            "bastion:65536",
+            "bastion:ssh",
+            "-oProxyCommand=evil",
+            "bast ion",
+            "[::1",
+            "[::1]22",
+        ] {
+            let err = spec.parse::<ProxyJump>().unwrap_err();
+            assert!(err.to_string().contains(&format!("'{spec}'")));
+        }
+    }
+
+    fn command() {
+        // Defaults leave the invocation as it has always been
+        assert_eq!(
+            args(&SshOptions::default()),
+            vec!["-tt", "-L", "4000:localhost:3000", "user@example.com"]
+        );
+
+        let options = SshOptions {
+            known_hosts: Some(String::from("/home/user/.ssh/bbup_known_hosts")),
+            strict_host_key_checking: Some(StrictHostKeyChecking::Yes),
+            identity_file: Some(String::from("/home/user/.ssh/id_bbup")),
+            ..SshOptions::default()
+        };
+        assert_eq!(
+            args(&options),
+            vec![
+                "-tt",
+                "-L",
+                "4000:localhost:3000",
+                "-o",
+                "UserKnownHostsFile=/home/user/.ssh/bbup_known_hosts",
+                "-o",
+                "StrictHostKeyChecking=yes",
+                "-o",
+                "IdentityFile=/home/user/.ssh/id_bbup",
+                "-o",
+                "IdentitiesOnly=yes",
+                "user@example.com"
+            ]
+        );
+
+        let options = SshOptions {
+            port: Some(2222),
+            proxy_jump: Some("jump@bastion.example.com:22,inner".parse().unwrap()),
+            ..SshOptions::default()
+        };
+        assert_eq!(
+            args(&options),
+            vec![
+                "-tt",
+                "-L",
+                "4000:localhost:3000",
+                "-p",
+                "2222",
+                "-J",
+                "jump@bastion.example.com:22,inner",
+                "user@example.com"
+            ]
+        );
+
+        let options = SshOptions {
+            strict_host_key_checking: Some(StrictHostKeyChecking::AcceptNew),
+            ..SshOptions::default()
+        };
+        assert_eq!(
+            args(&options),
+            vec![
+                "-tt",
+                "-L",
+                "4000:localhost:3000",
+                "-o",
+                "StrictHostKeyChecking=accept-new",
+                "user@example.com"
+            ]
+        );
+    }
+}